@@ -0,0 +1,49 @@
+//! Compares the strategies for hashing a file at sizes around the points where
+//! `hash_file` switches between them. Run with `cargo bench --bench hashing`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[path = "../src/digest_backend.rs"]
+mod digest_backend;
+#[path = "../src/file_hash.rs"]
+#[allow(dead_code)]
+mod file_hash;
+
+use digest_backend::Sha2Backend;
+use file_hash::{hash_file_with, HashStrategy};
+
+const SIZES: &[u64] = &[
+    64 * 1024,
+    file_hash::WHOLE_FILE_LIMIT,
+    4 * 1024 * 1024,
+    file_hash::DEFAULT_MMAP_THRESHOLD,
+    256 * 1024 * 1024,
+];
+
+fn hashing(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("hash_file");
+    group.sample_size(10);
+    for size in SIZES {
+        let path = dir.path().join(format!("{}.dat", size));
+        let content: Vec<u8> = (0..*size).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, content).unwrap();
+
+        group.throughput(Throughput::Bytes(*size));
+        for strategy in &[
+            HashStrategy::Whole,
+            HashStrategy::Buffered,
+            HashStrategy::Mapped,
+        ] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", strategy), size),
+                &path,
+                |b, path| b.iter(|| hash_file_with(path, *strategy, &Sha2Backend).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hashing);
+criterion_main!(benches);
@@ -0,0 +1,288 @@
+//! Conventions for invoice and parcel annotations, which a team can check
+//! before pushing, e.g.
+//!
+//! ```json
+//! {
+//!   "required": { "application/wasm": ["owner"], "*": ["license"] },
+//!   "patterns": { "owner": "^[a-z-]+@example\\.com$" }
+//! }
+//! ```
+//!
+//! `required` lists the annotations which every parcel of a media type must
+//! have, with `*` applying to all parcels. `patterns` gives a regular
+//! expression which the value of an annotation must match, wherever the
+//! annotation appears, on the invoice or on a parcel.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use regex::Regex;
+use serde::Deserialize;
+
+const ANY_MEDIA_TYPE: &str = "*";
+
+#[derive(Deserialize)]
+struct SchemaFile {
+    #[serde(default)]
+    required: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    patterns: BTreeMap<String, String>,
+}
+
+pub struct AnnotationSchema {
+    required: BTreeMap<String, Vec<String>>,
+    patterns: BTreeMap<String, Regex>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    /// A parcel lacks an annotation which parcels of its media type must have.
+    MissingAnnotation { parcel: String, key: String },
+    /// An annotation's value does not match the pattern for its key. `parcel`
+    /// is `None` for an annotation on the invoice.
+    PatternMismatch {
+        parcel: Option<String>,
+        key: String,
+        value: String,
+        pattern: String,
+    },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingAnnotation { parcel, key } => {
+                write!(
+                    f,
+                    "Parcel {} is missing required annotation '{}'",
+                    parcel, key
+                )
+            }
+            Self::PatternMismatch {
+                parcel,
+                key,
+                value,
+                pattern,
+            } => {
+                let location = match parcel {
+                    Some(parcel) => format!("parcel {}", parcel),
+                    None => "the invoice".to_owned(),
+                };
+                write!(
+                    f,
+                    "Annotation '{}' on {} is '{}', which does not match /{}/",
+                    key, location, value, pattern
+                )
+            }
+        }
+    }
+}
+
+impl AnnotationSchema {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: SchemaFile = serde_json::from_str(text)?;
+        let patterns = file
+            .patterns
+            .into_iter()
+            .map(|(key, pattern)| {
+                let regex = Regex::new(&pattern).map_err(|e| {
+                    anyhow::anyhow!("Invalid pattern for annotation '{}': {}", key, e)
+                })?;
+                Ok((key, regex))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            required: file.required,
+            patterns,
+        })
+    }
+
+    pub fn read_from(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        (|| {
+            let content = std::fs::read_to_string(&path)?;
+            Self::parse(&content)
+        })()
+        .map_err(|e: anyhow::Error| {
+            anyhow::anyhow!(
+                "Error reading annotation schema {}: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })
+    }
+
+    fn required_for<'a>(&'a self, media_type: &'a str) -> impl Iterator<Item = &'a String> {
+        self.required
+            .iter()
+            .filter(move |(mt, _)| mt.as_str() == media_type || mt.as_str() == ANY_MEDIA_TYPE)
+            .flat_map(|(_, keys)| keys)
+    }
+
+    fn check_pattern(
+        &self,
+        parcel: Option<&str>,
+        key: &str,
+        value: &str,
+        violations: &mut Vec<Violation>,
+    ) {
+        if let Some(regex) = self.patterns.get(key) {
+            if !regex.is_match(value) {
+                violations.push(Violation::PatternMismatch {
+                    parcel: parcel.map(|p| p.to_owned()),
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                    pattern: regex.as_str().to_owned(),
+                });
+            }
+        }
+    }
+}
+
+/// Checks the annotations of an invoice and its parcels against a schema,
+/// returning every violation found.
+pub fn validate_annotations(
+    invoice: &bindle::Invoice,
+    schema: &AnnotationSchema,
+) -> Result<(), Vec<Violation>> {
+    let mut violations = vec![];
+
+    for (key, value) in invoice.annotations.iter().flatten() {
+        schema.check_pattern(None, key, value, &mut violations);
+    }
+
+    for parcel in invoice.parcel.iter().flatten() {
+        let name = &parcel.label.name;
+        let annotations = parcel.label.annotations.clone().unwrap_or_default();
+        for key in schema.required_for(&parcel.label.media_type) {
+            if !annotations.contains_key(key) {
+                violations.push(Violation::MissingAnnotation {
+                    parcel: name.clone(),
+                    key: key.clone(),
+                });
+            }
+        }
+        for (key, value) in &annotations {
+            schema.check_pattern(Some(name.as_str()), key, value, &mut violations);
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, test_invoice};
+    use std::str::FromStr;
+
+    const SCHEMA: &str = r#"{
+        "required": { "application/wasm": ["owner"], "*": ["license"] },
+        "patterns": { "owner": "^[a-z-]+@example\\.com$", "tier": "^(gold|silver)$" }
+    }"#;
+
+    fn annotated(name: &str, media_type: &str, annotations: Vec<(&str, &str)>) -> bindle::Parcel {
+        let mut parcel = parcel_for(name, name.as_bytes());
+        parcel.label.media_type = media_type.to_owned();
+        parcel.label.annotations = Some(
+            annotations
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        );
+        parcel
+    }
+
+    fn invoice_of(parcels: Vec<bindle::Parcel>) -> bindle::Invoice {
+        let id = bindle::Id::from_str("birds/1.4.2").unwrap();
+        let mut invoice = test_invoice(&id, parcels);
+        invoice.annotations = Some(
+            vec![("tier".to_owned(), "gold".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        invoice
+    }
+
+    #[test]
+    fn test_conforming_invoice_passes() {
+        let schema = AnnotationSchema::parse(SCHEMA).unwrap();
+        let invoice = invoice_of(vec![
+            annotated(
+                "birds.wasm",
+                "application/wasm",
+                vec![("owner", "bird-team@example.com"), ("license", "MIT")],
+            ),
+            annotated("index.html", "text/html", vec![("license", "CC-BY")]),
+        ]);
+        assert_eq!(Ok(()), validate_annotations(&invoice, &schema));
+    }
+
+    #[test]
+    fn test_missing_required_annotations_are_reported() {
+        let schema = AnnotationSchema::parse(SCHEMA).unwrap();
+        let invoice = invoice_of(vec![
+            annotated("birds.wasm", "application/wasm", vec![("license", "MIT")]),
+            annotated("index.html", "text/html", vec![]),
+        ]);
+        let violations = validate_annotations(&invoice, &schema).unwrap_err();
+        assert_eq!(
+            vec![
+                Violation::MissingAnnotation {
+                    parcel: "birds.wasm".to_owned(),
+                    key: "owner".to_owned(),
+                },
+                Violation::MissingAnnotation {
+                    parcel: "index.html".to_owned(),
+                    key: "license".to_owned(),
+                },
+            ],
+            violations
+        );
+    }
+
+    #[test]
+    fn test_values_must_match_their_patterns() {
+        let schema = AnnotationSchema::parse(SCHEMA).unwrap();
+        let mut invoice = invoice_of(vec![annotated(
+            "birds.wasm",
+            "application/wasm",
+            vec![("owner", "someone@elsewhere.com"), ("license", "MIT")],
+        )]);
+        invoice
+            .annotations
+            .as_mut()
+            .unwrap()
+            .insert("tier".to_owned(), "bronze".to_owned());
+
+        let violations = validate_annotations(&invoice, &schema).unwrap_err();
+        assert_eq!(2, violations.len());
+        assert!(matches!(
+            &violations[0],
+            Violation::PatternMismatch { parcel: None, key, .. } if key == "tier"
+        ));
+        let message = violations[1].to_string();
+        assert!(
+            message.contains("birds.wasm"),
+            "names the parcel: '{}'",
+            message
+        );
+        assert!(
+            message.contains("someone@elsewhere.com"),
+            "gives the value: '{}'",
+            message
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_an_error() {
+        let err = AnnotationSchema::parse(r#"{ "patterns": { "owner": "(" } }"#)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("owner"), "names the annotation: '{}'", err);
+    }
+}
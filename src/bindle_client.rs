@@ -0,0 +1,435 @@
+//! Requests to the Bindle HTTP API. The bindle crate's client builds an HTTP
+//! client of its own, which can't be configured beyond ignoring certificate
+//! errors, so this sends the same requests through the client which
+//! `BindleConnectionInfo` builds, and reports failures with the same errors.
+
+use bindle::client::tokens::TokenManager;
+use bindle::client::{ClientError, Result};
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header;
+use reqwest::{StatusCode, Url};
+
+use crate::bindle_utils::AnyAuth;
+use crate::debug_bundle::{send_traced, ResponseRecording};
+use crate::retry::{retry_wait, send_with_retry, RetryError, RetryPolicy};
+
+const INVOICE_ENDPOINT: &str = "_i";
+const QUERY_ENDPOINT: &str = "_q";
+const TOML_MIME_TYPE: &str = "application/toml";
+
+#[derive(Clone)]
+pub struct BindleClient {
+    http: reqwest::Client,
+    base_url: Url,
+    auth: AnyAuth,
+    retry_policy: RetryPolicy,
+}
+
+/// Which part of the API a request was to, for interpreting its status.
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Invoice,
+    Parcel,
+    Query,
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Create,
+    Get,
+    Query,
+}
+
+impl BindleClient {
+    /// A client which retries requests which the server rejects because it is
+    /// overloaded for as long as `retry_policy` allows.
+    pub fn new(
+        http: reqwest::Client,
+        base_url: &str,
+        auth: AnyAuth,
+        retry_policy: RetryPolicy,
+    ) -> Result<Self> {
+        // Without a trailing slash, joining a path would replace the last
+        // segment of the base URL, such as `v1`.
+        let mut base_url = base_url.to_owned();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        Ok(Self {
+            http,
+            base_url: Url::parse(&base_url)?,
+            auth,
+            retry_policy,
+        })
+    }
+
+    /// The URL of the server's API, ending with a slash.
+    pub fn base_url(&self) -> &str {
+        self.base_url.as_str()
+    }
+
+    pub async fn get_invoice(&self, id: &bindle::Id) -> Result<bindle::Invoice> {
+        self.get_invoice_at(self.invoice_url(id)?).await
+    }
+
+    /// The invoice even if it has been yanked, which `get_invoice` refuses.
+    pub async fn get_yanked_invoice(&self, id: &bindle::Id) -> Result<bindle::Invoice> {
+        let mut url = self.invoice_url(id)?;
+        url.set_query(Some("yanked=true"));
+        self.get_invoice_at(url).await
+    }
+
+    async fn get_invoice_at(&self, url: Url) -> Result<bindle::Invoice> {
+        let request = self.http.get(url).header(header::ACCEPT, TOML_MIME_TYPE);
+        let response = self
+            .send(request, Endpoint::Invoice, Operation::Get)
+            .await?;
+        Ok(toml::from_slice(&response.bytes().await?)?)
+    }
+
+    pub async fn query_invoices(
+        &self,
+        options: bindle::QueryOptions,
+    ) -> Result<bindle::search::Matches> {
+        let request = self
+            .http
+            .get(self.base_url.join(QUERY_ENDPOINT)?)
+            .header(header::ACCEPT, TOML_MIME_TYPE)
+            .query(&options);
+        let response = self
+            .send(request, Endpoint::Query, Operation::Query)
+            .await?;
+        Ok(toml::from_slice(&response.bytes().await?)?)
+    }
+
+    /// Creates the invoice, returning the labels of the parcels the server
+    /// does not have yet.
+    pub async fn create_invoice(
+        &self,
+        invoice: bindle::Invoice,
+    ) -> Result<bindle::InvoiceCreateResponse> {
+        let request = self
+            .http
+            .post(self.base_url.join(INVOICE_ENDPOINT)?)
+            .header(header::ACCEPT, TOML_MIME_TYPE)
+            .header(header::CONTENT_TYPE, TOML_MIME_TYPE)
+            .body(toml::to_vec(&invoice)?);
+        let response = self
+            .send(request, Endpoint::Invoice, Operation::Create)
+            .await?;
+        Ok(toml::from_slice(&response.bytes().await?)?)
+    }
+
+    pub async fn create_parcel_from_stream<S>(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+        content: S,
+    ) -> Result<()>
+    where
+        S: Stream<Item = std::io::Result<bytes::Bytes>> + Send + Sync + 'static,
+    {
+        let request = self
+            .http
+            .post(self.parcel_url(id, sha256)?)
+            .body(reqwest::Body::wrap_stream(content));
+        self.send(request, Endpoint::Parcel, Operation::Create)
+            .await?;
+        Ok(())
+    }
+
+    /// Uploads the parcel in the file at `path`. The file is streamed, and
+    /// read again for each attempt if the server is overloaded.
+    pub async fn create_parcel_from_file(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        let url = self.parcel_url(id, sha256)?;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let request = self
+                .http
+                .post(url.clone())
+                .body(reqwest::Body::wrap_stream(file_content(path.to_owned())));
+            let request = self.auth.apply_auth_header(request).await?;
+            let response = send_traced("bindle", request, ResponseRecording::HeadersOnly, |r| {
+                r.send()
+            })
+            .await?;
+            match retry_wait(&response, attempt, &self.retry_policy).map_err(retry_error)? {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => {
+                    check_status(response, Endpoint::Parcel, Operation::Create).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub async fn get_parcel_stream(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+    ) -> Result<BoxStream<'static, Result<bytes::Bytes>>> {
+        let response = self.get_parcel_response(id, sha256).await?;
+        Ok(response.bytes_stream().map_err(ClientError::from).boxed())
+    }
+
+    async fn get_parcel_response(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+    ) -> Result<reqwest::Response> {
+        let request = self
+            .http
+            .get(self.parcel_url(id, sha256)?)
+            .header(header::ACCEPT, "*/*");
+        self.send(request, Endpoint::Parcel, Operation::Get).await
+    }
+
+    /// Whether the server has the parcel, asked without downloading it.
+    pub async fn parcel_exists(&self, id: &bindle::Id, sha256: &str) -> Result<bool> {
+        let request = self.http.head(self.parcel_url(id, sha256)?);
+        match self.send(request, Endpoint::Parcel, Operation::Get).await {
+            Ok(_) => Ok(true),
+            Err(ClientError::ParcelNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn invoice_url(&self, id: &bindle::Id) -> Result<Url> {
+        Ok(self
+            .base_url
+            .join(&format!("{}/{}", INVOICE_ENDPOINT, id))?)
+    }
+
+    fn parcel_url(&self, id: &bindle::Id, sha256: &str) -> Result<Url> {
+        Ok(self
+            .base_url
+            .join(&format!("{}/{}@{}", INVOICE_ENDPOINT, id, sha256))?)
+    }
+
+    /// Authenticates and sends a request, recording it in the debug bundle and
+    /// retrying it while the server is overloaded, and turns an error response
+    /// into the error for it.
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+        endpoint: Endpoint,
+        operation: Operation,
+    ) -> Result<reqwest::Response> {
+        let request = self.auth.apply_auth_header(request).await?;
+        // Parcel content is binary, and may be too big to hold.
+        let recording = match endpoint {
+            Endpoint::Parcel => ResponseRecording::HeadersOnly,
+            _ => ResponseRecording::Whole,
+        };
+        let response = send_traced("bindle", request, recording, |request| {
+            send_with_retry(request, &self.retry_policy)
+        })
+        .await
+        .map_err(retry_error)?;
+        check_status(response, endpoint, operation).await
+    }
+}
+
+/// The response if its status is a success, and otherwise the error which the
+/// bindle crate's client gives for the status.
+async fn check_status(
+    response: reqwest::Response,
+    endpoint: Endpoint,
+    operation: Operation,
+) -> Result<reqwest::Response> {
+    let status = response.status();
+    match (status, endpoint) {
+        (StatusCode::OK, _) => Ok(response),
+        (StatusCode::ACCEPTED, Endpoint::Invoice) | (StatusCode::CREATED, Endpoint::Invoice) => {
+            Ok(response)
+        }
+        // The server hides an invoice the user may not see.
+        (StatusCode::NOT_FOUND, Endpoint::Invoice) | (StatusCode::FORBIDDEN, Endpoint::Invoice) => {
+            match operation {
+                Operation::Get => Err(ClientError::InvoiceNotFound),
+                _ => Err(ClientError::ResourceNotFound),
+            }
+        }
+        (StatusCode::NOT_FOUND, Endpoint::Parcel) => match operation {
+            Operation::Get => Err(ClientError::ParcelNotFound),
+            _ => Err(ClientError::ResourceNotFound),
+        },
+        (StatusCode::CONFLICT, Endpoint::Invoice) => Err(ClientError::InvoiceAlreadyExists),
+        (StatusCode::CONFLICT, Endpoint::Parcel) => Err(ClientError::ParcelAlreadyExists),
+        (StatusCode::UNAUTHORIZED, _) => Err(ClientError::Unauthorized),
+        // A server without the query API, rather than one which failed to
+        // answer, so callers can tell it apart from a server error.
+        (StatusCode::NOT_IMPLEMENTED, Endpoint::Query) => Err(ClientError::InvalidRequest {
+            status_code: status,
+            message: error_message(response).await,
+        }),
+        _ if status.is_server_error() => {
+            Err(ClientError::ServerError(error_message(response).await))
+        }
+        _ if status.is_client_error() => Err(ClientError::InvalidRequest {
+            status_code: status,
+            message: error_message(response).await,
+        }),
+        _ => Err(ClientError::Other(format!(
+            "Unexpected response {}: {}",
+            status,
+            error_message(response).await.unwrap_or_default()
+        ))),
+    }
+}
+
+fn retry_error(e: RetryError) -> ClientError {
+    match e {
+        RetryError::Http(e) => ClientError::HttpClientError(e),
+        RetryError::WaitTooLong {
+            requested,
+            max_wait,
+        } => ClientError::Other(format!(
+            "The Bindle server is overloaded and asked us to wait {}s before retrying, which is longer than the maximum of {}s",
+            requested.as_secs(),
+            max_wait.as_secs()
+        )),
+    }
+}
+
+/// The content of a file, which is opened when the stream is first polled.
+fn file_content(
+    path: std::path::PathBuf,
+) -> impl Stream<Item = std::io::Result<bytes::Bytes>> + Send + Sync + 'static {
+    futures::stream::once(tokio::fs::File::open(path))
+        .map_ok(tokio_util::io::ReaderStream::new)
+        .try_flatten()
+}
+
+/// The message of a Bindle error response, if it has one.
+async fn error_message(response: reqwest::Response) -> Option<String> {
+    let body = response.bytes().await.ok()?;
+    toml::from_slice::<bindle::ErrorResponse>(&body)
+        .ok()
+        .map(|e| e.error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_utils::BindleConnectionInfo;
+    use crate::testing::{http_response, serve_in_turn, serve_once};
+    use std::str::FromStr;
+
+    fn client(url: &str) -> BindleClient {
+        BindleConnectionInfo::new(url, false, None, None)
+            .client()
+            .unwrap()
+    }
+
+    fn id() -> bindle::Id {
+        bindle::Id::from_str("birds/1.0.0").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_invoice_is_fetched_from_the_invoice_endpoint() {
+        let (url, server) = serve_once(http_response(
+            "200 OK",
+            "bindleVersion = \"1.0.0\"\n\n[bindle]\nname = \"birds\"\nversion = \"1.0.0\"\n",
+        ));
+        let invoice = client(&url).get_yanked_invoice(&id()).await.unwrap();
+        assert_eq!(id(), invoice.bindle.id);
+
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("GET /v1/_i/birds/1.0.0?yanked=true "),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_statuses_become_client_errors() {
+        let (url, _server) = serve_once(http_response("404 Not Found", ""));
+        assert!(matches!(
+            client(&url).get_invoice(&id()).await,
+            Err(ClientError::InvoiceNotFound)
+        ));
+
+        let (url, server) = serve_once(http_response("404 Not Found", ""));
+        assert!(!client(&url).parcel_exists(&id(), "abc").await.unwrap());
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("HEAD /v1/_i/birds/1.0.0@abc "),
+            "{}",
+            request
+        );
+
+        let (url, _server) = serve_once(http_response(
+            "400 Bad Request",
+            "error = \"no such group\"\n",
+        ));
+        match client(&url).get_parcel_stream(&id(), "abc").await.err() {
+            Some(ClientError::InvalidRequest {
+                status_code,
+                message,
+            }) => {
+                assert_eq!(StatusCode::BAD_REQUEST, status_code);
+                assert_eq!(Some("no such group".to_owned()), message);
+            }
+            other => panic!("expected InvalidRequest, got {:?}", other),
+        }
+    }
+
+    fn overloaded(retry_after: u64) -> String {
+        format!(
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            retry_after
+        )
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_retried_while_the_server_is_overloaded() {
+        let (url, server) = serve_in_turn(vec![
+            overloaded(0),
+            http_response(
+                "200 OK",
+                "bindleVersion = \"1.0.0\"\n\n[bindle]\nname = \"birds\"\nversion = \"1.0.0\"\n",
+            ),
+        ]);
+        let invoice = client(&url).get_invoice(&id()).await.unwrap();
+        assert_eq!(id(), invoice.bindle.id);
+        assert_eq!(2, server.join().unwrap().len());
+
+        let (url, _server) = serve_in_turn(vec![overloaded(3600)]);
+        let message = client(&url)
+            .get_invoice(&id())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("wait 3600s"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_parcel_file_upload_is_retried_with_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("parcel.dat");
+        std::fs::write(&path, b"parcel content").unwrap();
+        let (url, server) = serve_in_turn(vec![overloaded(0), http_response("200 OK", "")]);
+
+        client(&url)
+            .create_parcel_from_file(&id(), "abc", &path)
+            .await
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(2, requests.len());
+        assert!(
+            requests[1].starts_with("POST /v1/_i/birds/1.0.0@abc "),
+            "{}",
+            requests[1]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Subscribing to the publish and yank events which some Bindle deployments
+//! send, so that a watcher can refresh when the registry changes.
+//!
+//! Bindle itself does not define an event API. This expects a server-sent
+//! event stream at `<server>/events`, in which each event has the type
+//! `publish` or `yank` and the bindle ID as its data:
+//!
+//! ```text
+//! event: publish
+//! data: birds/1.4.2
+//! ```
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+use bindle::client::tokens::TokenManager;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt, TryStreamExt};
+
+use crate::bindle_utils::AnyAuth;
+use crate::debug_bundle::{send_traced, ResponseRecording};
+use crate::retry::{backoff, Backoff, RetryPolicy};
+
+const EVENTS_PATH: &str = "events";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistryEvent {
+    Published(bindle::Id),
+    Yanked(bindle::Id),
+}
+
+pub type EventByteStream = BoxStream<'static, anyhow::Result<bytes::Bytes>>;
+
+/// Opens connections to a server's event stream.
+#[async_trait::async_trait]
+pub trait EventSource {
+    async fn connect(&self) -> anyhow::Result<EventByteStream>;
+}
+
+pub struct HttpEventSource {
+    url: String,
+    client: reqwest::Client,
+    auth: AnyAuth,
+}
+
+impl HttpEventSource {
+    /// An event source which connects to the server at `base_url` with
+    /// `client`, which should be the one that `BindleConnectionInfo` builds.
+    pub fn new(client: reqwest::Client, base_url: &str, auth: AnyAuth) -> Self {
+        Self {
+            url: format!("{}/{}", base_url.trim_end_matches('/'), EVENTS_PATH),
+            client,
+            auth,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSource for HttpEventSource {
+    async fn connect(&self) -> anyhow::Result<EventByteStream> {
+        let request = self
+            .client
+            .get(&self.url)
+            .header(reqwest::header::ACCEPT, "text/event-stream");
+        let request = self
+            .auth
+            .apply_auth_header(request)
+            .await
+            .map_err(|e| anyhow::anyhow!("Error authenticating to {}: {}", self.url, e))?;
+        // The body is the stream of events, which never ends.
+        let response = send_traced(
+            "bindle",
+            request,
+            ResponseRecording::HeadersOnly,
+            |request| request.send(),
+        )
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| anyhow::anyhow!("Error subscribing to events at {}: {}", self.url, e))?;
+        Ok(response.bytes_stream().map_err(anyhow::Error::from).boxed())
+    }
+}
+
+/// The events from `source`, reconnecting whenever the connection drops. This
+/// waits longer between each consecutive failed attempt, as set by `policy`,
+/// and ends with an error once `policy.max_attempts` attempts in a row have
+/// failed. A connection which delivered events before it dropped is not a
+/// failure, and resets the count.
+pub fn subscribe_events<'a>(
+    source: &'a (impl EventSource + Sync),
+    policy: &'a RetryPolicy,
+) -> impl Stream<Item = anyhow::Result<RegistryEvent>> + 'a {
+    let state = Subscription {
+        connection: None,
+        parser: EventParser::default(),
+        pending: VecDeque::new(),
+        delivered: false,
+        failures: 0,
+    };
+    futures::stream::unfold(Some(state), move |state| async move {
+        let mut state = state?;
+        loop {
+            if let Some(event) = state.pending.pop_front() {
+                return Some((Ok(event), Some(state)));
+            }
+            if state.connection.is_none() {
+                if state.failures > 0 {
+                    match backoff(None, state.failures, policy) {
+                        Backoff::Wait(wait) => tokio::time::sleep(wait).await,
+                        Backoff::TooLong(_) => (), // only for requested waits
+                    }
+                }
+                match source.connect().await {
+                    Ok(connection) => {
+                        state.connection = Some(connection);
+                        state.delivered = false;
+                    }
+                    Err(e) => {
+                        state.failures += 1;
+                        if state.failures >= policy.max_attempts {
+                            return Some((Err(e), None));
+                        }
+                        continue;
+                    }
+                }
+            }
+            let chunk = match state.connection.as_mut() {
+                Some(connection) => connection.next().await,
+                None => continue,
+            };
+            match chunk {
+                Some(Ok(chunk)) => {
+                    let events = state.parser.push(&chunk);
+                    state.delivered |= !events.is_empty();
+                    state.pending.extend(events);
+                }
+                Some(Err(_)) | None => {
+                    // A new connection starts a new event.
+                    state.connection = None;
+                    state.parser = EventParser::default();
+                    if state.delivered {
+                        state.failures = 0;
+                        continue;
+                    }
+                    state.failures += 1;
+                    if state.failures >= policy.max_attempts {
+                        let e = anyhow::anyhow!(
+                            "The event stream kept disconnecting: gave up after {} attempts",
+                            state.failures
+                        );
+                        return Some((Err(e), None));
+                    }
+                }
+            }
+        }
+    })
+}
+
+struct Subscription {
+    connection: Option<EventByteStream>,
+    parser: EventParser,
+    pending: VecDeque<RegistryEvent>,
+    /// Whether the current connection has delivered any events.
+    delivered: bool,
+    failures: u32,
+}
+
+/// Assembles server-sent events from chunks of the stream, which may split
+/// lines anywhere.
+#[derive(Default)]
+struct EventParser {
+    buffer: Vec<u8>,
+    event_type: Option<String>,
+    data: Vec<String>,
+}
+
+impl EventParser {
+    fn push(&mut self, chunk: &[u8]) -> Vec<RegistryEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = vec![];
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if line.is_empty() {
+                events.extend(self.dispatch());
+            } else if let Some(value) = field_value(line, "event") {
+                self.event_type = Some(value.to_owned());
+            } else if let Some(value) = field_value(line, "data") {
+                self.data.push(value.to_owned());
+            }
+            // Comments and other fields, such as retry and id, are ignored.
+        }
+        events
+    }
+
+    fn dispatch(&mut self) -> Option<RegistryEvent> {
+        let event_type = self.event_type.take();
+        let data = std::mem::take(&mut self.data).join("\n");
+        let id = bindle::Id::try_from(data.trim()).ok()?;
+        match event_type.as_deref() {
+            Some("publish") => Some(RegistryEvent::Published(id)),
+            Some("yank") => Some(RegistryEvent::Yanked(id)),
+            _ => None,
+        }
+    }
+}
+
+fn field_value<'a>(line: &'a str, field: &str) -> Option<&'a str> {
+    let value = line.strip_prefix(field)?.strip_prefix(':')?;
+    Some(value.strip_prefix(' ').unwrap_or(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Each connection plays back the next script of chunks, then drops. A
+    /// `None` script means the connection attempt fails.
+    struct ScriptedEventSource {
+        scripts: Mutex<VecDeque<Option<Vec<&'static str>>>>,
+    }
+
+    impl ScriptedEventSource {
+        fn new(scripts: Vec<Option<Vec<&'static str>>>) -> Self {
+            Self {
+                scripts: Mutex::new(scripts.into_iter().collect()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl EventSource for ScriptedEventSource {
+        async fn connect(&self) -> anyhow::Result<EventByteStream> {
+            match self.scripts.lock().unwrap().pop_front() {
+                Some(Some(chunks)) => {
+                    let chunks = chunks
+                        .into_iter()
+                        .map(|c| Ok(bytes::Bytes::from_static(c.as_bytes())));
+                    Ok(futures::stream::iter(chunks).boxed())
+                }
+                _ => Err(anyhow::anyhow!("connection refused")),
+            }
+        }
+    }
+
+    fn no_wait(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_wait: Duration::from_secs(0),
+            max_attempts,
+        }
+    }
+
+    fn id(text: &str) -> bindle::Id {
+        bindle::Id::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_events_are_assembled_across_chunks() {
+        let mut parser = EventParser::default();
+        assert!(parser.push(b"event: pub").is_empty());
+        assert!(parser.push(b"lish\r\ndata: birds/1.4").is_empty());
+        let events = parser.push(b".2\r\n\r\n: keepalive\n\nevent: yank\ndata: birds/1.3.9\n\n");
+        assert_eq!(
+            vec![
+                RegistryEvent::Published(id("birds/1.4.2")),
+                RegistryEvent::Yanked(id("birds/1.3.9")),
+            ],
+            events
+        );
+    }
+
+    #[test]
+    fn test_unknown_events_are_ignored() {
+        let mut parser = EventParser::default();
+        let events = parser.push(b"event: rename\ndata: birds/1.4.2\n\ndata: birds/1.4.2\n\n");
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_reconnects_after_a_drop() {
+        let source = ScriptedEventSource::new(vec![
+            Some(vec!["event: publish\ndata: birds/1.0.0\n\nevent: pub"]),
+            None,
+            Some(vec!["event: yank\ndata: birds/1.0.0\n\n"]),
+        ]);
+        let policy = no_wait(3);
+        let events: Vec<_> = subscribe_events(&source, &policy).collect().await;
+
+        assert_eq!(
+            RegistryEvent::Published(id("birds/1.0.0")),
+            *events[0].as_ref().unwrap()
+        );
+        // The half-received event from the dropped connection is discarded
+        assert_eq!(
+            RegistryEvent::Yanked(id("birds/1.0.0")),
+            *events[1].as_ref().unwrap()
+        );
+        // The scripts then run out, so every later attempt fails
+        assert_eq!(3, events.len());
+        assert!(events[2].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_a_connection_which_delivered_events_is_not_a_failure() {
+        let source = ScriptedEventSource::new(vec![
+            Some(vec!["event: publish\ndata: birds/1.0.0\n\n"]),
+            Some(vec!["event: publish\ndata: birds/1.1.0\n\n"]),
+            Some(vec![": keepalive\n"]),
+        ]);
+        let policy = no_wait(1);
+        let events: Vec<_> = subscribe_events(&source, &policy).collect().await;
+
+        assert_eq!(3, events.len());
+        assert_eq!(
+            RegistryEvent::Published(id("birds/1.1.0")),
+            *events[1].as_ref().unwrap()
+        );
+        // The last connection delivered nothing before it dropped
+        let err = events[2].as_ref().unwrap_err().to_string();
+        assert!(err.contains("kept disconnecting"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_gives_up_after_repeated_failures() {
+        let source = ScriptedEventSource::new(vec![None, None, None, Some(vec![])]);
+        let policy = no_wait(3);
+        let events: Vec<_> = subscribe_events(&source, &policy).collect().await;
+        assert_eq!(1, events.len());
+        let err = events[0].as_ref().unwrap_err().to_string();
+        assert!(err.contains("refused"), "reports the last error: '{}'", err);
+    }
+}
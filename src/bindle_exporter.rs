@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::bindle_fetcher::{fetch_parcel_to_file, FetchOptions, ParcelHandle};
+use crate::bindle_utils::{BindleSource, InvoiceHelpers};
+use crate::parcel_layout::ParcelLayout;
+
+/// Parcels up to this size, such as most static assets, are held in memory
+/// while they are archived. Larger ones are downloaded to a file first, so
+/// that they never are.
+const IN_MEMORY_PARCEL_SIZE: u64 = 64 * 1024;
+
+/// What `export_closure` put in the archive.
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    /// Every parcel of the closure, as (name, sha256), including those whose
+    /// content is shared with another parcel.
+    pub parcel_references: Vec<(String, String)>,
+    /// The sha256 of each parcel body written to the archive. Each is written once.
+    pub bodies_written: Vec<String>,
+}
+
+/// Writes a bindle and its required closure to a tar archive: the invoice as
+/// `invoice.toml`, and the content of each parcel which the bindle always needs
+/// under `parcels/`, at the path given by `layout`. Parcels which share a
+/// sha256 share a single body in the archive. Large parcels are downloaded
+/// into `scratch_dir` on their way into the archive.
+pub async fn export_closure<W: Write>(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    options: &FetchOptions,
+    layout: &ParcelLayout,
+    dest: W,
+    scratch_dir: &Path,
+) -> anyhow::Result<ExportSummary> {
+    let invoice = source.fetch_invoice(bindle_id).await?;
+    let mut archive = tar::Builder::new(dest);
+    let mut summary = ExportSummary::default();
+
+    let invoice_text = toml::to_string_pretty(&invoice)?;
+    append_file(
+        &mut archive,
+        "invoice.toml",
+        invoice_text.len() as u64,
+        invoice_text.as_bytes(),
+    )?;
+
+    // The closure lists each body once, but every parcel which shares one is
+    // recorded.
+    let closure: HashSet<String> = invoice
+        .required_closure()
+        .into_iter()
+        .map(|p| p.label.sha256)
+        .collect();
+    let mut written = HashSet::new();
+    for parcel in invoice
+        .parcel
+        .iter()
+        .flatten()
+        .filter(|p| closure.contains(&p.label.sha256))
+    {
+        let sha256 = &parcel.label.sha256;
+        summary
+            .parcel_references
+            .push((parcel.label.name.clone(), sha256.clone()));
+        if !written.insert(sha256.clone()) {
+            continue;
+        }
+        let path = format!("parcels/{}", layout.relative_path(sha256));
+        let handle = ParcelHandle::new(source, bindle_id, parcel, options);
+        if handle.label().size <= IN_MEMORY_PARCEL_SIZE {
+            let content = handle.into_bytes().await?;
+            append_file(&mut archive, &path, content.len() as u64, &content[..])?;
+        } else {
+            let staged = scratch_dir.join(format!("{}.dat", sha256));
+            fetch_parcel_to_file(source, bindle_id, parcel, &staged, options).await?;
+            let file = std::fs::File::open(&staged)?;
+            append_file(&mut archive, &path, file.metadata()?.len(), file)?;
+            std::fs::remove_file(&staged)?;
+        }
+        summary.bodies_written.push(sha256.clone());
+    }
+
+    archive.finish()?;
+    Ok(summary)
+}
+
+fn append_file<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &str,
+    size: u64,
+    content: impl Read,
+) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive
+        .append_data(&mut header, path, content)
+        .map_err(|e| anyhow::anyhow!("Error writing {} to archive: {}", path, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, sha256_of, test_invoice, FakeBindleServer};
+    use std::str::FromStr;
+
+    fn archive_entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        tar::Archive::new(archive)
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let mut entry = e.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().to_string();
+                let mut content = vec![];
+                std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+                (path, content)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_shared_parcel_content_is_written_once() {
+        let id = bindle::Id::from_str("dedup/export/1.0.0").unwrap();
+        let shared = "the same bytes, twice";
+        let invoice = test_invoice(
+            &id,
+            vec![
+                parcel_for("one.txt", shared.as_bytes()),
+                parcel_for("two.txt", shared.as_bytes()),
+                parcel_for("other.txt", "different bytes".as_bytes()),
+            ],
+        );
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(shared.as_bytes())
+            .with_parcel("different bytes".as_bytes());
+
+        let scratch = tempfile::tempdir().unwrap();
+        let mut archive = vec![];
+        let summary = export_closure(
+            &server,
+            &id,
+            &FetchOptions::default(),
+            &ParcelLayout::Flat,
+            &mut archive,
+            scratch.path(),
+        )
+        .await
+        .unwrap();
+
+        let entries = archive_entries(&archive);
+        let shared_path = format!("parcels/{}.dat", sha256_of(shared.as_bytes()));
+        assert_eq!(1, entries.iter().filter(|(p, _)| p == &shared_path).count());
+        assert_eq!(
+            2,
+            entries
+                .iter()
+                .filter(|(p, _)| p.starts_with("parcels/"))
+                .count()
+        );
+        assert_eq!(2, summary.bodies_written.len());
+
+        // The archived invoice still lists both parcels which share the body.
+        let (_, invoice_text) = entries
+            .iter()
+            .find(|(p, _)| p == "invoice.toml")
+            .expect("no invoice in archive");
+        let archived: bindle::Invoice =
+            toml::from_str(std::str::from_utf8(invoice_text).unwrap()).unwrap();
+        assert_eq!(3, archived.parcel.unwrap().len());
+
+        let names: Vec<_> = summary
+            .parcel_references
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(vec!["one.txt", "two.txt", "other.txt"], names);
+        assert_eq!(
+            summary.parcel_references[0].1,
+            summary.parcel_references[1].1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_parcel_is_staged_through_a_file() {
+        let id = bindle::Id::from_str("large/export/1.0.0").unwrap();
+        let content: Vec<u8> = (0..IN_MEMORY_PARCEL_SIZE + 1).map(|i| i as u8).collect();
+        let invoice = test_invoice(&id, vec![parcel_for("large.bin", &content)]);
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(&content);
+
+        let scratch = tempfile::tempdir().unwrap();
+        let mut archive = vec![];
+        export_closure(
+            &server,
+            &id,
+            &FetchOptions::default(),
+            &ParcelLayout::Flat,
+            &mut archive,
+            scratch.path(),
+        )
+        .await
+        .unwrap();
+
+        let path = format!("parcels/{}.dat", sha256_of(&content));
+        let entries = archive_entries(&archive);
+        let (_, archived) = entries.iter().find(|(p, _)| p == &path).unwrap();
+        assert_eq!(&content, archived);
+        assert_eq!(0, std::fs::read_dir(scratch.path()).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn test_parcels_in_optional_groups_are_not_exported() {
+        let id = bindle::Id::from_str("optional/export/1.0.0").unwrap();
+        let mut optional = parcel_for("extra.txt", b"extra");
+        optional.conditions = Some(bindle::Condition {
+            member_of: Some(vec!["extras".to_owned()]),
+            requires: None,
+        });
+        let invoice = test_invoice(&id, vec![parcel_for("main.txt", b"main"), optional]);
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(b"main")
+            .with_parcel(b"extra");
+
+        let scratch = tempfile::tempdir().unwrap();
+        let mut archive = vec![];
+        let summary = export_closure(
+            &server,
+            &id,
+            &FetchOptions::default(),
+            &ParcelLayout::Flat,
+            &mut archive,
+            scratch.path(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(vec![sha256_of(b"main")], summary.bodies_written);
+        assert_eq!(1, summary.parcel_references.len());
+        assert_eq!(1, server.parcel_requests());
+    }
+
+    #[tokio::test]
+    async fn test_parcels_are_archived_at_layout_paths() {
+        let id = bindle::Id::from_str("layout/export/1.0.0").unwrap();
+        let content = "laid out".as_bytes();
+        let invoice = test_invoice(&id, vec![parcel_for("file.txt", content)]);
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(content);
+
+        let scratch = tempfile::tempdir().unwrap();
+        let mut archive = vec![];
+        export_closure(
+            &server,
+            &id,
+            &FetchOptions::default(),
+            &ParcelLayout::Nested,
+            &mut archive,
+            scratch.path(),
+        )
+        .await
+        .unwrap();
+
+        let sha256 = sha256_of(content);
+        let expected = format!("parcels/{}/{}/{}", &sha256[0..2], &sha256[2..4], sha256);
+        assert!(archive_entries(&archive)
+            .iter()
+            .any(|(p, _)| p == &expected));
+    }
+}
@@ -0,0 +1,878 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bindle::Parcel;
+use colored::Colorize;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::bindle_utils::{BindleSource, InvoiceHelpers, ParcelStream};
+use crate::digest_backend::{DigestBackend, Sha2Backend};
+use crate::file_hash::file_sha256;
+
+/// Settings which govern how parcels are downloaded from a Bindle server.
+#[derive(Clone)]
+pub struct FetchOptions {
+    /// Refuse to download any parcel larger than this many bytes.
+    pub max_parcel_size: Option<u64>,
+    /// Read parcel content no faster than this many bytes per second.
+    pub max_bytes_per_second: Option<u64>,
+    /// When to check parcel files against their digests.
+    pub verify: VerifyPolicy,
+    /// How to compute the digests of parcels and parcel files.
+    pub digest: Arc<dyn DigestBackend>,
+    /// What to do to parcels written to disk once they have been verified.
+    pub transforms: Arc<ParcelTransforms>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            max_parcel_size: None,
+            max_bytes_per_second: None,
+            verify: VerifyPolicy::default(),
+            digest: Arc::new(Sha2Backend),
+            transforms: Arc::new(ParcelTransforms::default()),
+        }
+    }
+}
+
+/// A post-processing step for parcel content, such as decompressing it.
+pub type Transform = Box<dyn Fn(Vec<u8>) -> anyhow::Result<Vec<u8>> + Send + Sync>;
+
+/// Transforms to apply to downloaded parcels, by the media type on the
+/// parcel's label.
+#[derive(Default)]
+pub struct ParcelTransforms {
+    by_media_type: HashMap<String, Transform>,
+}
+
+impl ParcelTransforms {
+    pub fn with_transform(
+        mut self,
+        media_type: &str,
+        transform: impl Fn(Vec<u8>) -> anyhow::Result<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        self.by_media_type
+            .insert(media_type.to_owned(), Box::new(transform));
+        self
+    }
+
+    fn applies_to(&self, parcel: &Parcel) -> bool {
+        self.by_media_type.contains_key(&parcel.label.media_type)
+    }
+
+    fn apply(&self, parcel: &Parcel, content: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match self.by_media_type.get(&parcel.label.media_type) {
+            None => Ok(content),
+            Some(transform) => transform(content).map_err(|e| {
+                anyhow::anyhow!("Error transforming parcel {}: {}", parcel.label.name, e)
+            }),
+        }
+    }
+}
+
+/// When to check parcel files written to disk against their digests.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum VerifyPolicy {
+    /// Hash a file which already exists before trusting it, and verify every
+    /// download.
+    #[default]
+    Always,
+    /// Trust a file which already exists if its size is right, but verify
+    /// every download. For disks too slow to re-hash everything.
+    MissingOnly,
+    /// Verify nothing. For emergencies only.
+    Never,
+}
+
+impl VerifyPolicy {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "always" => Ok(Self::Always),
+            "missing-only" => Ok(Self::MissingOnly),
+            "never" => Ok(Self::Never),
+            _ => Err(anyhow::anyhow!(
+                "Invalid verification policy '{}': choose 'always', 'missing-only' or 'never'",
+                text
+            )),
+        }
+    }
+
+    /// Warns, prominently, if parcels will not be verified at all.
+    pub fn warn_if_disabled(&self) {
+        if *self == Self::Never {
+            eprintln!(
+                "{}",
+                "warning: parcel verification is disabled. Parcels are not checked against their digests, so corrupt or tampered content will be used without complaint"
+                    .red()
+                    .bold()
+            );
+        }
+    }
+}
+
+/// The error for a parcel whose content is not the size its label declares.
+/// This means the content is corrupt, or the server is misbehaving, whatever
+/// its digest.
+#[derive(Debug)]
+pub struct SizeMismatch {
+    pub parcel: String,
+    pub declared: u64,
+    pub actual: u64,
+}
+
+impl std::fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Parcel {} should be {} bytes, but {} bytes were received",
+            self.parcel, self.declared, self.actual
+        )
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+/// Whether `fetch_parcel_to_file` downloaded a parcel or used the file which
+/// was already there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FetchedParcel {
+    Downloaded,
+    AlreadyPresent,
+}
+
+/// How to divide parcels into batches for downloading.
+#[derive(Clone, Copy, Debug)]
+pub enum BatchStrategy {
+    /// No more than this many parcels in a batch.
+    MaxCount(usize),
+    /// No more than this many bytes in a batch, by the parcels' labels. A parcel
+    /// larger than this has a batch to itself.
+    MaxBytes(u64),
+}
+
+/// The parcels of an invoice's required closure, each once, in batches for a
+/// throttled download. Parcels keep the order of `required_closure`.
+pub fn batch_closure(invoice: &bindle::Invoice, strategy: BatchStrategy) -> Vec<Vec<Parcel>> {
+    let parcels = invoice.required_closure();
+    let mut batches = vec![];
+    let mut batch: Vec<Parcel> = vec![];
+    let mut batch_bytes = 0;
+    for parcel in parcels {
+        let is_full = match strategy {
+            BatchStrategy::MaxCount(max) => batch.len() >= max.max(1),
+            BatchStrategy::MaxBytes(max) => batch_bytes + parcel.label.size > max,
+        };
+        if is_full && !batch.is_empty() {
+            batches.push(std::mem::take(&mut batch));
+            batch_bytes = 0;
+        }
+        batch_bytes += parcel.label.size;
+        batch.push(parcel);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Downloads the content of a parcel, streaming it so that a parcel which exceeds
+/// `max_parcel_size` is abandoned as soon as it is known to be too big.
+pub async fn fetch_parcel(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    parcel: &Parcel,
+    options: &FetchOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let mut stream = ParcelHandle::new(source, bindle_id, parcel, options)
+        .reader()
+        .await?;
+    let mut content = vec![];
+    while let Some(chunk) = stream.next().await {
+        content.extend_from_slice(&chunk?);
+        check_not_too_big(parcel, content.len() as u64, options)?;
+    }
+    check_size(parcel, content.len() as u64)?;
+    Ok(content)
+}
+
+/// A parcel whose content has not been downloaded yet. Its label is available
+/// straight away, but nothing is requested from the server until the content
+/// is asked for, so a caller which may not need the content only pays for it
+/// if it does.
+pub struct ParcelHandle<'a, S> {
+    source: &'a S,
+    bindle_id: &'a bindle::Id,
+    parcel: &'a Parcel,
+    options: &'a FetchOptions,
+}
+
+impl<'a, S: BindleSource> ParcelHandle<'a, S> {
+    pub fn new(
+        source: &'a S,
+        bindle_id: &'a bindle::Id,
+        parcel: &'a Parcel,
+        options: &'a FetchOptions,
+    ) -> Self {
+        Self {
+            source,
+            bindle_id,
+            parcel,
+            options,
+        }
+    }
+
+    pub fn label(&self) -> &bindle::Label {
+        &self.parcel.label
+    }
+
+    /// Downloads the content, as `fetch_parcel` does.
+    pub async fn into_bytes(self) -> anyhow::Result<Vec<u8>> {
+        fetch_parcel(self.source, self.bindle_id, self.parcel, self.options).await
+    }
+
+    /// Starts downloading the content, returning it as a stream of chunks
+    /// paced by `max_bytes_per_second`. A parcel whose label exceeds
+    /// `max_parcel_size` is refused without being requested, but the size of
+    /// what arrives is for the caller to check.
+    pub async fn reader(self) -> anyhow::Result<ParcelStream> {
+        check_not_too_big(self.parcel, self.parcel.label.size, self.options)?;
+        let stream = self
+            .source
+            .fetch_parcel_stream(self.bindle_id, &self.parcel.label.sha256)
+            .await?;
+        Ok(match self.options.max_bytes_per_second {
+            Some(rate) => paced(stream, rate),
+            None => stream,
+        })
+    }
+}
+
+/// The label may understate the size, so this is checked again as content
+/// arrives.
+fn check_not_too_big(parcel: &Parcel, size: u64, options: &FetchOptions) -> anyhow::Result<()> {
+    match options.max_parcel_size {
+        Some(max) if size > max => Err(too_big(&parcel.label.name, size, max)),
+        _ => Ok(()),
+    }
+}
+
+/// Makes sure that `path` holds the content of a parcel, downloading it unless
+/// the file is already there and `options.verify` allows it to be trusted.
+/// If `options.transforms` has a transform for the parcel's media type, it is
+/// applied once the content has been verified, so the digest is always
+/// checked against the bytes as stored in the bindle. A transformed file no
+/// longer matches the parcel's label, so it is downloaded again each time,
+/// unless `options.verify` is `Never`.
+pub async fn fetch_parcel_to_file(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    parcel: &Parcel,
+    path: &Path,
+    options: &FetchOptions,
+) -> anyhow::Result<FetchedParcel> {
+    let transformed = options.transforms.applies_to(parcel);
+    if (!transformed || options.verify == VerifyPolicy::Never)
+        && is_trusted_file(parcel, path, options)?
+    {
+        return Ok(FetchedParcel::AlreadyPresent);
+    }
+
+    // Write under a temporary name so that an interrupted download never
+    // leaves a truncated parcel to be trusted later.
+    let mut partial_file = path.as_os_str().to_owned();
+    partial_file.push(".partial");
+    let partial_file = PathBuf::from(partial_file);
+    let written = match write_parcel_file(source, bindle_id, parcel, &partial_file, options).await {
+        Ok(()) if transformed => transform_file(parcel, &partial_file, options).await,
+        written => written,
+    };
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&partial_file).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&partial_file, path).await?;
+    Ok(FetchedParcel::Downloaded)
+}
+
+/// Applies the transform for a parcel's media type to the file at `path`. A
+/// transform may be slow, or run a command, so it runs on a blocking thread.
+async fn transform_file(
+    parcel: &Parcel,
+    path: &Path,
+    options: &FetchOptions,
+) -> anyhow::Result<()> {
+    let content = tokio::fs::read(path).await?;
+    let transforms = options.transforms.clone();
+    let parcel = parcel.clone();
+    let transformed =
+        tokio::task::spawn_blocking(move || transforms.apply(&parcel, content)).await??;
+    tokio::fs::write(path, transformed).await?;
+    Ok(())
+}
+
+/// Streams the content of a parcel into `path`, hashing it on the way, so that
+/// it is never all held in memory, and checks it once it is all written.
+async fn write_parcel_file(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    parcel: &Parcel,
+    path: &Path,
+    options: &FetchOptions,
+) -> anyhow::Result<()> {
+    let mut stream = ParcelHandle::new(source, bindle_id, parcel, options)
+        .reader()
+        .await?;
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut hasher = options.digest.hasher();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        check_not_too_big(parcel, size, options)?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    check_size(parcel, size)?;
+    if options.verify != VerifyPolicy::Never {
+        check_sha256(parcel, &hasher.finish())?;
+    }
+    Ok(())
+}
+
+fn is_trusted_file(parcel: &Parcel, path: &Path, options: &FetchOptions) -> anyhow::Result<bool> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(false),
+    };
+    let trusted = match options.verify {
+        VerifyPolicy::Always => file_sha256(path, options.digest.as_ref())? == parcel.label.sha256,
+        VerifyPolicy::MissingOnly => metadata.len() == parcel.label.size,
+        VerifyPolicy::Never => true,
+    };
+    Ok(trusted)
+}
+
+fn check_sha256(parcel: &Parcel, actual: &str) -> anyhow::Result<()> {
+    if actual != parcel.label.sha256 {
+        return Err(anyhow::anyhow!(
+            "Parcel {} is corrupt: expected sha256 {} but received {}",
+            parcel.label.name,
+            parcel.label.sha256,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Downloads a parcel without keeping its content, returning the sha256 of the
+/// bytes received, as computed by `digest`, so that it can be checked against
+/// the parcel's label. A parcel of the wrong size is a `SizeMismatch` error.
+pub async fn fetch_parcel_sha256(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    parcel: &Parcel,
+    digest: &dyn DigestBackend,
+) -> anyhow::Result<String> {
+    let mut stream = source
+        .fetch_parcel_stream(bindle_id, &parcel.label.sha256)
+        .await?;
+    let mut hasher = digest.hasher();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+    check_size(parcel, size)?;
+    Ok(hasher.finish())
+}
+
+fn check_size(parcel: &Parcel, actual: u64) -> anyhow::Result<()> {
+    if actual != parcel.label.size {
+        return Err(SizeMismatch {
+            parcel: parcel.label.name.clone(),
+            declared: parcel.label.size,
+            actual,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Slows a stream so that it delivers no more than `max_bytes_per_second` on
+/// average. Each chunk is held back until the time by which the bytes received so
+/// far are allowed, so reads from the underlying connection are spread out too.
+fn paced(stream: ParcelStream, max_bytes_per_second: u64) -> ParcelStream {
+    let rate = max_bytes_per_second.max(1) as f64;
+    let start = tokio::time::Instant::now();
+    let mut received = 0u64;
+    stream
+        .then(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                received += bytes.len() as u64;
+            }
+            let allowed_at = start + std::time::Duration::from_secs_f64(received as f64 / rate);
+            async move {
+                tokio::time::sleep_until(allowed_at).await;
+                chunk
+            }
+        })
+        .boxed()
+}
+
+fn too_big(name: &str, size: u64, max: u64) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Parcel {} is at least {} bytes, which exceeds the maximum parcel size of {} bytes",
+        name,
+        size,
+        max
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::digest_backend::Sha2Backend;
+    use crate::testing::{parcel_for, test_invoice, FakeBindleServer};
+    use itertools::Itertools;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn test_bindle_id() -> bindle::Id {
+        bindle::Id::from_str("big/parcels/1.0.0").unwrap()
+    }
+
+    const SMALL: &[u8] = b"tiny";
+    const LARGE: &[u8] = b"this parcel is much too large to be allowed";
+
+    fn limited_to(max: u64) -> FetchOptions {
+        FetchOptions {
+            max_parcel_size: Some(max),
+            ..FetchOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parcel_within_limit_is_fetched() {
+        let server = FakeBindleServer::new().with_parcel(SMALL);
+        let parcel = parcel_for("small.txt", SMALL);
+        let content = fetch_parcel(&server, &test_bindle_id(), &parcel, &limited_to(10))
+            .await
+            .unwrap();
+        assert_eq!(SMALL, &content[..]);
+    }
+
+    #[tokio::test]
+    async fn test_parcel_handle_downloads_only_when_asked() {
+        let server = FakeBindleServer::new()
+            .with_parcel(SMALL)
+            .with_parcel(LARGE);
+        let small = parcel_for("small.txt", SMALL);
+        let large = parcel_for("large.txt", LARGE);
+        let id = test_bindle_id();
+        let options = FetchOptions::default();
+        let small_handle = ParcelHandle::new(&server, &id, &small, &options);
+        let large_handle = ParcelHandle::new(&server, &id, &large, &options);
+        assert_eq!("small.txt", small_handle.label().name);
+        assert_eq!(LARGE.len() as u64, large_handle.label().size);
+        assert_eq!(0, server.parcel_requests());
+
+        assert_eq!(SMALL, &small_handle.into_bytes().await.unwrap()[..]);
+        assert_eq!(1, server.parcel_requests());
+
+        let chunks: Vec<_> = large_handle.reader().await.unwrap().collect().await;
+        let content: Vec<u8> = chunks.into_iter().flat_map(|c| c.unwrap()).collect();
+        assert_eq!(LARGE, &content[..]);
+        assert_eq!(2, server.parcel_requests());
+    }
+
+    #[tokio::test]
+    async fn test_parcel_declared_over_limit_is_not_downloaded() {
+        let server = FakeBindleServer::new().with_parcel(LARGE);
+        let parcel = parcel_for("large.txt", LARGE);
+        let result = fetch_parcel(&server, &test_bindle_id(), &parcel, &limited_to(10)).await;
+
+        let message = result.unwrap_err().to_string();
+        assert!(
+            message.contains("large.txt"),
+            "error names parcel: '{}'",
+            message
+        );
+        assert!(
+            message.contains("10 bytes"),
+            "error gives limit: '{}'",
+            message
+        );
+        assert_eq!(0, server.chunks_served());
+    }
+
+    #[tokio::test]
+    async fn test_parcel_streamed_over_limit_is_abandoned() {
+        let server = FakeBindleServer::new().with_parcel(LARGE);
+        let mut parcel = parcel_for("large.txt", LARGE);
+        parcel.label.size = 5; // the label lies
+        let result = fetch_parcel(&server, &test_bindle_id(), &parcel, &limited_to(10)).await;
+
+        assert!(result.is_err());
+        let total_chunks = LARGE.len().div_ceil(server.chunk_size);
+        assert!(server.chunks_served() < total_chunks);
+    }
+
+    #[tokio::test]
+    async fn test_parcel_streamed_to_file_over_limit_is_abandoned() {
+        let server = FakeBindleServer::new().with_parcel(LARGE);
+        let mut parcel = parcel_for("large.txt", LARGE);
+        parcel.label.size = 5; // the label lies
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.dat");
+        let result =
+            fetch_parcel_to_file(&server, &test_bindle_id(), &parcel, &path, &limited_to(10)).await;
+
+        assert!(result.is_err());
+        let total_chunks = LARGE.len().div_ceil(server.chunk_size);
+        assert!(server.chunks_served() < total_chunks);
+        assert!(!path.exists());
+        assert!(!dir.path().join("large.dat.partial").exists());
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_cap_paces_download() {
+        let server = FakeBindleServer::new().with_parcel(LARGE);
+        let parcel = parcel_for("large.txt", LARGE);
+        let options = FetchOptions {
+            max_bytes_per_second: Some(200),
+            ..FetchOptions::default()
+        };
+
+        let start = std::time::Instant::now();
+        let content = fetch_parcel(&server, &test_bindle_id(), &parcel, &options)
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(LARGE, &content[..]);
+        let expected = std::time::Duration::from_secs_f64(LARGE.len() as f64 / 200.0);
+        assert!(
+            elapsed >= expected,
+            "took {:?}, expected at least {:?}",
+            elapsed,
+            expected
+        );
+    }
+
+    fn reversing(options: FetchOptions) -> FetchOptions {
+        let transforms =
+            ParcelTransforms::default().with_transform("application/x-reversed", |mut content| {
+                content.reverse();
+                Ok(content)
+            });
+        FetchOptions {
+            transforms: Arc::new(transforms),
+            ..options
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transform_applies_to_its_media_type_after_verification() {
+        let server = FakeBindleServer::new()
+            .with_parcel(b"olleh")
+            .with_parcel(SMALL);
+        let mut reversed = parcel_for("hello.rev", b"olleh");
+        reversed.label.media_type = "application/x-reversed".to_owned();
+        let plain = parcel_for("small.txt", SMALL);
+        let options = reversing(FetchOptions::default());
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("hello.rev");
+        fetch_parcel_to_file(&server, &test_bindle_id(), &reversed, &path, &options)
+            .await
+            .unwrap();
+        assert_eq!(b"hello", &std::fs::read(&path).unwrap()[..]);
+
+        let path = dir.path().join("small.txt");
+        fetch_parcel_to_file(&server, &test_bindle_id(), &plain, &path, &options)
+            .await
+            .unwrap();
+        assert_eq!(SMALL, &std::fs::read(&path).unwrap()[..]);
+    }
+
+    #[tokio::test]
+    async fn test_transformed_file_is_downloaded_again() {
+        let server = FakeBindleServer::new().with_parcel(b"olleh");
+        let mut reversed = parcel_for("hello.rev", b"olleh");
+        reversed.label.media_type = "application/x-reversed".to_owned();
+        let options = reversing(FetchOptions::default());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.rev");
+
+        for _ in 0..2 {
+            let fetched =
+                fetch_parcel_to_file(&server, &test_bindle_id(), &reversed, &path, &options)
+                    .await
+                    .unwrap();
+            assert_eq!(FetchedParcel::Downloaded, fetched);
+            assert_eq!(b"hello", &std::fs::read(&path).unwrap()[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_parcel_is_not_transformed() {
+        let mut reversed = parcel_for("hello.rev", b"olleh");
+        reversed.label.media_type = "application/x-reversed".to_owned();
+        let mut server = FakeBindleServer::new();
+        server
+            .parcels
+            .insert(reversed.label.sha256.clone(), b"olleH".to_vec());
+        let transformed = Arc::new(AtomicBool::new(false));
+        let flag = transformed.clone();
+        let transforms =
+            ParcelTransforms::default().with_transform("application/x-reversed", move |content| {
+                flag.store(true, Ordering::SeqCst);
+                Ok(content)
+            });
+        let options = FetchOptions {
+            transforms: Arc::new(transforms),
+            ..FetchOptions::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.rev");
+
+        let result =
+            fetch_parcel_to_file(&server, &test_bindle_id(), &reversed, &path, &options).await;
+
+        let message = result.unwrap_err().to_string();
+        assert!(
+            message.contains("hello.rev"),
+            "error names parcel: '{}'",
+            message
+        );
+        assert!(!transformed.load(Ordering::SeqCst));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_short_parcel_is_a_size_mismatch() {
+        let parcel = parcel_for("large.txt", LARGE);
+        let mut server = FakeBindleServer::new();
+        server
+            .parcels
+            .insert(parcel.label.sha256.clone(), LARGE[..10].to_vec());
+
+        let err = fetch_parcel(
+            &server,
+            &test_bindle_id(),
+            &parcel,
+            &FetchOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        let mismatch = err.downcast_ref::<SizeMismatch>().unwrap();
+        assert_eq!(LARGE.len() as u64, mismatch.declared);
+        assert_eq!(10, mismatch.actual);
+
+        let err = fetch_parcel_sha256(&server, &test_bindle_id(), &parcel, &Sha2Backend)
+            .await
+            .unwrap_err();
+        assert!(err.is::<SizeMismatch>());
+    }
+
+    async fn fetch_over_corrupt_file(
+        policy: VerifyPolicy,
+        corrupt: &[u8],
+    ) -> (FetchedParcel, Vec<u8>) {
+        let server = FakeBindleServer::new().with_parcel(SMALL);
+        let parcel = parcel_for("small.txt", SMALL);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("small.dat");
+        std::fs::write(&path, corrupt).unwrap();
+        let options = FetchOptions {
+            verify: policy,
+            ..FetchOptions::default()
+        };
+
+        let fetched = fetch_parcel_to_file(&server, &test_bindle_id(), &parcel, &path, &options)
+            .await
+            .unwrap();
+        (fetched, std::fs::read(&path).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_always_replaces_corrupt_existing_file() {
+        let (fetched, content) = fetch_over_corrupt_file(VerifyPolicy::Always, b"tinY").await;
+        assert_eq!(FetchedParcel::Downloaded, fetched);
+        assert_eq!(SMALL, &content[..]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_only_trusts_existing_file_of_right_size() {
+        let (fetched, content) = fetch_over_corrupt_file(VerifyPolicy::MissingOnly, b"tinY").await;
+        assert_eq!(FetchedParcel::AlreadyPresent, fetched);
+        assert_eq!(b"tinY", &content[..]);
+
+        let (fetched, content) = fetch_over_corrupt_file(VerifyPolicy::MissingOnly, b"tin").await;
+        assert_eq!(FetchedParcel::Downloaded, fetched);
+        assert_eq!(SMALL, &content[..]);
+    }
+
+    #[tokio::test]
+    async fn test_never_trusts_any_existing_file() {
+        let (fetched, content) = fetch_over_corrupt_file(VerifyPolicy::Never, b"tin").await;
+        assert_eq!(FetchedParcel::AlreadyPresent, fetched);
+        assert_eq!(b"tin", &content[..]);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_download_is_refused_unless_verification_is_off() {
+        let parcel = parcel_for("small.txt", SMALL);
+        let mut server = FakeBindleServer::new();
+        server
+            .parcels
+            .insert(parcel.label.sha256.clone(), b"tinY".to_vec());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("small.dat");
+
+        for policy in &[VerifyPolicy::Always, VerifyPolicy::MissingOnly] {
+            let options = FetchOptions {
+                verify: *policy,
+                ..FetchOptions::default()
+            };
+            let result =
+                fetch_parcel_to_file(&server, &test_bindle_id(), &parcel, &path, &options).await;
+            assert!(result.is_err(), "{:?} accepted a corrupt download", policy);
+            assert!(!path.exists());
+            assert!(!dir.path().join("small.dat.partial").exists());
+        }
+
+        let options = FetchOptions {
+            verify: VerifyPolicy::Never,
+            ..FetchOptions::default()
+        };
+        fetch_parcel_to_file(&server, &test_bindle_id(), &parcel, &path, &options)
+            .await
+            .unwrap();
+        assert_eq!(b"tinY", &std::fs::read(&path).unwrap()[..]);
+    }
+
+    #[derive(Default)]
+    struct CountingBackend {
+        hashers: std::sync::atomic::AtomicUsize,
+    }
+
+    impl DigestBackend for CountingBackend {
+        fn hasher(&self) -> Box<dyn crate::digest_backend::DigestHasher> {
+            self.hashers.fetch_add(1, Ordering::SeqCst);
+            Sha2Backend.hasher()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_downloads_and_existing_files_are_hashed_by_the_given_backend() {
+        let server = FakeBindleServer::new().with_parcel(SMALL);
+        let parcel = parcel_for("small.txt", SMALL);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("small.dat");
+        let backend = Arc::new(CountingBackend::default());
+        let options = FetchOptions {
+            digest: backend.clone(),
+            ..FetchOptions::default()
+        };
+
+        let fetched = fetch_parcel_to_file(&server, &test_bindle_id(), &parcel, &path, &options)
+            .await
+            .unwrap();
+        assert_eq!(FetchedParcel::Downloaded, fetched);
+        assert_eq!(1, backend.hashers.load(Ordering::SeqCst));
+
+        let fetched = fetch_parcel_to_file(&server, &test_bindle_id(), &parcel, &path, &options)
+            .await
+            .unwrap();
+        assert_eq!(FetchedParcel::AlreadyPresent, fetched);
+        assert_eq!(2, backend.hashers.load(Ordering::SeqCst));
+    }
+
+    fn closure_of(sizes: &[usize]) -> bindle::Invoice {
+        let parcels = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| {
+                let content = format!("{:0width$}", i, width = size);
+                parcel_for(&format!("{}.dat", i), content.as_bytes())
+            })
+            .collect();
+        test_invoice(&test_bindle_id(), parcels)
+    }
+
+    fn assert_each_parcel_once(invoice: &bindle::Invoice, batches: &[Vec<Parcel>]) {
+        let batched = batches
+            .iter()
+            .flatten()
+            .map(|p| p.label.sha256.clone())
+            .sorted()
+            .collect_vec();
+        let expected = invoice
+            .required_closure()
+            .into_iter()
+            .map(|p| p.label.sha256)
+            .sorted()
+            .collect_vec();
+        assert_eq!(expected, batched);
+    }
+
+    #[test]
+    fn test_batches_by_count() {
+        let invoice = closure_of(&[5, 5, 5, 5, 5, 5, 5]);
+        let batches = batch_closure(&invoice, BatchStrategy::MaxCount(3));
+        assert_eq!(vec![3, 3, 1], batches.iter().map(|b| b.len()).collect_vec());
+        assert_each_parcel_once(&invoice, &batches);
+    }
+
+    #[test]
+    fn test_batches_by_bytes() {
+        let invoice = closure_of(&[40, 30, 20, 50, 10, 60, 5]);
+        let batches = batch_closure(&invoice, BatchStrategy::MaxBytes(70));
+        for batch in &batches {
+            let bytes: u64 = batch.iter().map(|p| p.label.size).sum();
+            assert!(bytes <= 70, "batch of {} bytes is over the limit", bytes);
+        }
+        assert_each_parcel_once(&invoice, &batches);
+    }
+
+    #[test]
+    fn test_oversized_parcel_gets_its_own_batch() {
+        let invoice = closure_of(&[10, 100, 10]);
+        let batches = batch_closure(&invoice, BatchStrategy::MaxBytes(50));
+        assert_eq!(vec![1, 1, 1], batches.iter().map(|b| b.len()).collect_vec());
+        assert_each_parcel_once(&invoice, &batches);
+    }
+
+    #[test]
+    fn test_shared_parcels_are_batched_once() {
+        let mut invoice = closure_of(&[5, 5]);
+        let mut copy = invoice.parcel.as_ref().unwrap()[0].clone();
+        copy.label.name = "copy.dat".to_owned();
+        invoice.parcel.as_mut().unwrap().push(copy);
+        let batches = batch_closure(&invoice, BatchStrategy::MaxCount(10));
+        assert_eq!(2, batches[0].len());
+        assert_each_parcel_once(&invoice, &batches);
+    }
+
+    #[test]
+    fn test_parcels_in_optional_groups_are_not_batched() {
+        let mut invoice = closure_of(&[5, 5, 5]);
+        invoice.parcel.as_mut().unwrap()[1].conditions = Some(bindle::Condition {
+            member_of: Some(vec!["extras".to_owned()]),
+            requires: None,
+        });
+        let batches = batch_closure(&invoice, BatchStrategy::MaxCount(10));
+        let names = batches[0]
+            .iter()
+            .map(|p| p.label.name.as_str())
+            .collect_vec();
+        assert_eq!(vec!["0.dat", "2.dat"], names);
+    }
+}
@@ -1,16 +1,606 @@
 use std::path::Path;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
 
-use bindle::standalone::StandaloneRead;
+use bindle::client::ClientError;
+use futures::{Stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use tokio::io::AsyncReadExt;
 
-use crate::bindle_utils::BindleConnectionInfo;
+use crate::bindle_client::BindleClient;
+use crate::bindle_utils::{BindleConnectionInfo, BindleSource};
+use crate::retry::{backoff, Backoff, RetryPolicy};
+use crate::standalone::StandaloneBindle;
+
+/// How often to report progress while streaming a parcel.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+/// How many parcels to upload at once.
+const MAX_CONCURRENT_UPLOADS: usize = 4;
+
+/// How to send parcels to the Bindle server.
+#[derive(Clone, Debug, Default)]
+pub struct UploadOptions {
+    /// Stream each parcel in chunks of this many bytes, reporting progress as
+    /// it goes and retrying a parcel whose upload fails. If this is not set,
+    /// the bindle is pushed in the usual way, one request per parcel.
+    pub chunk_size: Option<usize>,
+    pub retry_policy: RetryPolicy,
+}
+
+/// The context of a push which failed part way through, saying which
+/// of the parcels that the server lacked were uploaded before the failure.
+/// Pushing again uploads only those which the server still lacks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PushIncomplete {
+    pub bindle_id: bindle::Id,
+    pub parcels_uploaded: Vec<String>,
+    pub parcels_remaining: Vec<String>,
+}
+
+impl std::fmt::Display for PushIncomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Push of {} stopped with {} of {} parcels uploaded",
+            self.bindle_id,
+            self.parcels_uploaded.len(),
+            self.parcels_uploaded.len() + self.parcels_remaining.len()
+        )
+    }
+}
+
+pub type ChunkStream = Pin<Box<dyn Stream<Item = std::io::Result<Vec<u8>>> + Send + Sync>>;
+
+/// Where streamed parcel content is uploaded to.
+#[async_trait::async_trait]
+pub trait ParcelSink {
+    /// Sends the whole content of a parcel.
+    async fn send_parcel(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+        content: ChunkStream,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl ParcelSink for BindleClient {
+    async fn send_parcel(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+        content: ChunkStream,
+    ) -> anyhow::Result<()> {
+        self.create_parcel_from_stream(id, sha256, content.map_ok(bytes::Bytes::from))
+            .await
+            .map_err(|e| {
+                anyhow::Error::new(e).context(format!("Error uploading parcel {}", sha256))
+            })
+    }
+}
+
+/// Whether the parcels a push would upload fit in the server's quota.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QuotaCheck {
+    Fits {
+        required: u64,
+        remaining: u64,
+    },
+    Exceeds {
+        required: u64,
+        remaining: u64,
+    },
+    /// The server does not say what quota is left.
+    Unknown,
+}
+
+impl std::fmt::Display for QuotaCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fits {
+                required,
+                remaining,
+            } => write!(
+                f,
+                "the push needs {} bytes of the {} remaining in the quota",
+                required, remaining
+            ),
+            Self::Exceeds {
+                required,
+                remaining,
+            } => write!(
+                f,
+                "the push needs {} bytes but only {} remain in the quota",
+                required, remaining
+            ),
+            Self::Unknown => write!(f, "the server does not report its quota"),
+        }
+    }
+}
+
+/// Checks, before pushing, whether the parcels of `invoice` which the server
+/// does not already have fit in what remains of its quota. Each parcel is
+/// counted once, by the size on its label. If the server does not report a
+/// quota, no parcels are checked.
+pub async fn check_quota(
+    client: &impl BindleSource,
+    invoice: &bindle::Invoice,
+) -> anyhow::Result<QuotaCheck> {
+    let id = &invoice.bindle.id;
+    let remaining = match client.remaining_quota(id).await? {
+        None => return Ok(QuotaCheck::Unknown),
+        Some(remaining) => remaining,
+    };
+    let mut required = 0;
+    for parcel in invoice
+        .parcel
+        .iter()
+        .flatten()
+        .unique_by(|p| &p.label.sha256)
+    {
+        if !client.parcel_exists(id, &parcel.label.sha256).await? {
+            required += parcel.label.size;
+        }
+    }
+    Ok(if required <= remaining {
+        QuotaCheck::Fits {
+            required,
+            remaining,
+        }
+    } else {
+        QuotaCheck::Exceeds {
+            required,
+            remaining,
+        }
+    })
+}
 
 pub async fn push_all(
     path: impl AsRef<Path>,
     bindle_id: &bindle::Id,
     bindle_connection: &BindleConnectionInfo,
+    options: &UploadOptions,
 ) -> anyhow::Result<()> {
-    let reader = StandaloneRead::new(&path, bindle_id).await?;
     let client = bindle_connection.client()?;
-    reader.push(&client).await.map_err(|e| anyhow::anyhow!("Error pushing bindle to server: {}", e))?;
+    let standalone = StandaloneBindle::read(&path, bindle_id).await?;
+    // Rather than fill the quota and fail part way through the upload.
+    let quota = check_quota(&client, &standalone.invoice).await?;
+    if let QuotaCheck::Exceeds { .. } = quota {
+        anyhow::bail!("Not pushing {}: {}", bindle_id, quota);
+    }
+    let response = client
+        .create_invoice(standalone.invoice.clone())
+        .await
+        .map_err(|e| anyhow::Error::new(e).context("Error pushing bindle to server"))?;
+    let missing = response.missing.unwrap_or_default();
+    let mut uploads = futures::stream::iter(missing.iter().cloned())
+        .map(|label| upload_missing_parcel(&client, bindle_id, label, &standalone, options))
+        .buffer_unordered(MAX_CONCURRENT_UPLOADS);
+    let mut parcels_uploaded = vec![];
+    while let Some((label, uploaded)) = uploads.next().await {
+        // Returning drops the stream, which cancels the uploads in progress.
+        if let Err(e) = uploaded {
+            let parcels_remaining = missing
+                .iter()
+                .map(|l| l.sha256.clone())
+                .filter(|sha256| !parcels_uploaded.contains(sha256))
+                .collect();
+            return Err(e.context(PushIncomplete {
+                bindle_id: bindle_id.clone(),
+                parcels_uploaded,
+                parcels_remaining,
+            }));
+        }
+        parcels_uploaded.push(label.sha256);
+    }
     Ok(())
 }
+
+/// Uploads a parcel which the server lacks, as `options` say, returning its
+/// label with the outcome.
+async fn upload_missing_parcel(
+    client: &BindleClient,
+    id: &bindle::Id,
+    label: bindle::Label,
+    standalone: &StandaloneBindle,
+    options: &UploadOptions,
+) -> (bindle::Label, anyhow::Result<()>) {
+    let path = standalone.parcel_path(&label.sha256);
+    let uploaded = match options.chunk_size {
+        None => upload_whole_parcel(client, id, &label, &path).await,
+        Some(chunk_size) => {
+            upload_parcel(client, id, &label, &path, chunk_size, &options.retry_policy).await
+        }
+    };
+    (label, uploaded)
+}
+
+/// Uploads a parcel file in one request, as it is.
+async fn upload_whole_parcel(
+    client: &BindleClient,
+    id: &bindle::Id,
+    label: &bindle::Label,
+    path: &Path,
+) -> anyhow::Result<()> {
+    client
+        .create_parcel_from_file(id, &label.sha256, path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Error uploading parcel {}: {}", label.name, e))
+}
+
+/// Uploads a parcel file in chunks, trying again if the connection fails or
+/// the server fails or is overloaded. Bindle cannot resume an upload, so each
+/// attempt sends the whole file.
+async fn upload_parcel(
+    sink: &(impl ParcelSink + Sync),
+    id: &bindle::Id,
+    label: &bindle::Label,
+    path: &Path,
+    chunk_size: usize,
+    policy: &RetryPolicy,
+) -> anyhow::Result<()> {
+    let mut attempt = 1;
+    loop {
+        let content = chunked_file(path, chunk_size, &label.name).await?;
+        match sink.send_parcel(id, &label.sha256, content).await {
+            Ok(()) => return Ok(()),
+            Err(e) if !is_transient(&e) => {
+                return Err(e.context(format!("Error uploading {}", label.name)));
+            }
+            Err(e) if attempt >= policy.max_attempts => {
+                return Err(anyhow::anyhow!(
+                    "Gave up uploading {} after {} attempts: {:#}",
+                    label.name,
+                    attempt,
+                    e
+                ));
+            }
+            Err(e) => {
+                eprintln!("Upload of {} failed, retrying: {:#}", label.name, e);
+                if let Backoff::Wait(wait) = backoff(None, attempt, policy) {
+                    tokio::time::sleep(wait).await;
+                }
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Whether an upload which failed with `e` might succeed if it were sent again:
+/// that is, if the connection failed, or the server failed or was overloaded.
+/// A request which the server refused, such as for bad credentials or a
+/// parcel too big, would only be refused again.
+fn is_transient(e: &anyhow::Error) -> bool {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<ClientError>() {
+            return match e {
+                ClientError::ServerError(_) => true,
+                ClientError::InvalidRequest { status_code, .. } => {
+                    is_transient_status(*status_code)
+                }
+                ClientError::HttpClientError(e) => is_transient_http(e),
+                _ => false,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return is_transient_http(e);
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+    false
+}
+
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_transient_http(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => is_transient_status(status),
+        None => !e.is_builder(),
+    }
+}
+
+/// The content of `path`, in chunks of at most `chunk_size` bytes, reporting
+/// progress every so often.
+async fn chunked_file(
+    path: &Path,
+    chunk_size: usize,
+    display_name: &str,
+) -> anyhow::Result<ChunkStream> {
+    let file = tokio::fs::File::open(path).await.map_err(|e| {
+        anyhow::anyhow!(
+            "Error reading parcel file {}: {}",
+            path.to_string_lossy(),
+            e
+        )
+    })?;
+    let total = file.metadata().await?.len();
+
+    let display_name = display_name.to_owned();
+    let progress = (0, Instant::now());
+    let buf = vec![0; chunk_size.max(1)];
+    let chunks = futures::stream::try_unfold(
+        (file, buf, progress),
+        move |(mut file, mut buf, (mut sent, mut last_report))| {
+            let display_name = display_name.clone();
+            async move {
+                let count = file.read(&mut buf).await?;
+                if count == 0 {
+                    return Ok(None);
+                }
+                sent += count as u64;
+                if last_report.elapsed() >= PROGRESS_INTERVAL {
+                    eprintln!("Uploading {}: {}/{} bytes", display_name, sent, total);
+                    last_report = Instant::now();
+                }
+                let chunk = buf[..count].to_vec();
+                Ok(Some((chunk, (file, buf, (sent, last_report)))))
+            }
+        },
+    );
+    let stream: ChunkStream = Box::pin(chunks);
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    /// Receives uploads, dropping the connection once after a given number of
+    /// bytes, as a flaky ingress might.
+    struct FlakySink {
+        drop_after: Mutex<Option<usize>>,
+        received: Mutex<Vec<u8>>,
+        attempts: Mutex<usize>,
+    }
+
+    impl FlakySink {
+        fn new(drop_after: usize) -> Self {
+            Self {
+                drop_after: Mutex::new(Some(drop_after)),
+                received: Mutex::new(vec![]),
+                attempts: Mutex::new(0),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ParcelSink for FlakySink {
+        async fn send_parcel(
+            &self,
+            _id: &bindle::Id,
+            _sha256: &str,
+            mut content: ChunkStream,
+        ) -> anyhow::Result<()> {
+            *self.attempts.lock().unwrap() += 1;
+            let mut received = vec![];
+            let drop_after = self.drop_after.lock().unwrap().take();
+            while let Some(chunk) = content.next().await {
+                let chunk = chunk?;
+                assert!(chunk.len() <= 16, "chunk of {} bytes", chunk.len());
+                received.extend(chunk);
+                if matches!(drop_after, Some(n) if received.len() >= n) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "connection reset by peer",
+                    )
+                    .into());
+                }
+            }
+            *self.received.lock().unwrap() = received;
+            Ok(())
+        }
+    }
+
+    fn no_wait() -> RetryPolicy {
+        RetryPolicy {
+            max_wait: Duration::from_secs(0),
+            max_attempts: 3,
+        }
+    }
+
+    fn parcel_file(dir: &Path, content: &[u8]) -> (bindle::Label, std::path::PathBuf) {
+        let path = dir.join("big.wasm");
+        std::fs::write(&path, content).unwrap();
+        let label = crate::testing::parcel_for("big.wasm", content).label;
+        (label, path)
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_restarts_from_the_beginning() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..100u8).collect();
+        let (label, path) = parcel_file(dir.path(), &content);
+        let id = bindle::Id::from_str("big/1.0.0").unwrap();
+        let sink = FlakySink::new(40);
+
+        upload_parcel(&sink, &id, &label, &path, 16, &no_wait())
+            .await
+            .unwrap();
+
+        assert_eq!(2, *sink.attempts.lock().unwrap());
+        assert_eq!(content, *sink.received.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_upload_gives_up_eventually() {
+        struct DeadSink;
+        #[async_trait::async_trait]
+        impl ParcelSink for DeadSink {
+            async fn send_parcel(
+                &self,
+                _id: &bindle::Id,
+                _sha256: &str,
+                _content: ChunkStream,
+            ) -> anyhow::Result<()> {
+                Err(ClientError::ServerError(Some("502 Bad Gateway".to_owned())).into())
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (label, path) = parcel_file(dir.path(), b"content");
+        let id = bindle::Id::from_str("big/1.0.0").unwrap();
+        let err = upload_parcel(&DeadSink, &id, &label, &path, 4, &no_wait())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("3 attempts"),
+            "says how hard it tried: '{}'",
+            err
+        );
+        assert!(
+            err.contains("Bad Gateway"),
+            "gives the last error: '{}'",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refused_upload_is_not_retried() {
+        struct RefusingSink {
+            attempts: Mutex<usize>,
+        }
+        #[async_trait::async_trait]
+        impl ParcelSink for RefusingSink {
+            async fn send_parcel(
+                &self,
+                _id: &bindle::Id,
+                _sha256: &str,
+                _content: ChunkStream,
+            ) -> anyhow::Result<()> {
+                *self.attempts.lock().unwrap() += 1;
+                Err(ClientError::InvalidRequest {
+                    status_code: reqwest::StatusCode::PAYLOAD_TOO_LARGE,
+                    message: None,
+                }
+                .into())
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (label, path) = parcel_file(dir.path(), b"content");
+        let id = bindle::Id::from_str("big/1.0.0").unwrap();
+        let sink = RefusingSink {
+            attempts: Mutex::new(0),
+        };
+        let err = upload_parcel(&sink, &id, &label, &path, 4, &no_wait())
+            .await
+            .unwrap_err();
+        assert_eq!(1, *sink.attempts.lock().unwrap());
+        assert!(format!("{:#}", err).contains("413"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn test_upload_is_retried_when_the_server_resets_the_connection() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            // Closing the first connection with most of the upload unread
+            // resets it.
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut buf).unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            while !request.ends_with(b"0\r\n\r\n") {
+                let count = stream.read(&mut buf).unwrap();
+                if count == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..count]);
+            }
+            stream
+                .write_all(crate::testing::http_response("200 OK", "").as_bytes())
+                .unwrap();
+            request.len()
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+        let (label, path) = parcel_file(dir.path(), &content);
+        let id = bindle::Id::from_str("big/1.0.0").unwrap();
+        let client = BindleConnectionInfo::new(url, false, None, None)
+            .client()
+            .unwrap();
+
+        upload_parcel(&client, &id, &label, &path, 64 * 1024, &no_wait())
+            .await
+            .unwrap();
+        assert!(server.join().unwrap() > content.len());
+    }
+
+    fn quota_invoice() -> bindle::Invoice {
+        use crate::testing::{parcel_for, test_invoice};
+
+        let id = bindle::Id::from_str("quota/1.0.0").unwrap();
+        test_invoice(
+            &id,
+            vec![
+                parcel_for("existing.dat", b"already on the server"),
+                parcel_for("new.wasm", b"twelve bytes"),
+                parcel_for("copy.wasm", b"twelve bytes"),
+            ],
+        )
+    }
+
+    fn quota_server(remaining_quota: Option<u64>) -> crate::testing::FakeBindleServer {
+        let mut server =
+            crate::testing::FakeBindleServer::new().with_parcel(b"already on the server");
+        server.remaining_quota = remaining_quota;
+        server
+    }
+
+    #[tokio::test]
+    async fn test_check_quota_fits_counting_only_new_parcels() {
+        assert_eq!(
+            QuotaCheck::Fits {
+                required: 12,
+                remaining: 12
+            },
+            check_quota(&quota_server(Some(12)), &quota_invoice())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_quota_exceeds() {
+        assert_eq!(
+            QuotaCheck::Exceeds {
+                required: 12,
+                remaining: 11
+            },
+            check_quota(&quota_server(Some(11)), &quota_invoice())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_quota_unknown_without_a_quota() {
+        assert_eq!(
+            QuotaCheck::Unknown,
+            check_quota(&quota_server(None), &quota_invoice())
+                .await
+                .unwrap()
+        );
+    }
+}
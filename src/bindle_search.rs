@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+
+use crate::bindle_utils::BindleSource;
+
+/// The number of invoices to ask the server for at a time.
+const PAGE_SIZE: u8 = 50;
+
+/// The headline facts about an invoice, for listing.
+#[derive(Clone, Debug)]
+pub struct InvoiceSummary {
+    pub id: bindle::Id,
+    pub description: Option<String>,
+    pub parcel_count: usize,
+    pub yanked: bool,
+}
+
+impl From<&bindle::Invoice> for InvoiceSummary {
+    fn from(invoice: &bindle::Invoice) -> Self {
+        Self {
+            id: invoice.bindle.id.clone(),
+            description: invoice.bindle.description.clone(),
+            parcel_count: invoice.parcel.as_ref().map(|p| p.len()).unwrap_or_default(),
+            yanked: invoice.yanked.unwrap_or_default(),
+        }
+    }
+}
+
+/// The error for a query which the Bindle server can't answer because it does
+/// not provide the query API at all, as some minimal deployments don't.
+/// Callers which can do without the query should check for this and fall
+/// back, rather than report the raw HTTP error.
+#[derive(Debug)]
+pub struct QueryUnsupported;
+
+impl std::fmt::Display for QueryUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the Bindle server does not support queries")
+    }
+}
+
+impl std::error::Error for QueryUnsupported {}
+
+/// The base URLs of the Bindle servers found not to provide the query API, so
+/// that each is probed only once however many lookups a run makes.
+static QUERY_UNSUPPORTED: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Remembers that the server at `base_url` has no query API, and why.
+pub fn remember_query_unsupported(base_url: &str, reason: String) {
+    crate::debug_bundle::note_capability("bindleQuery", reason);
+    QUERY_UNSUPPORTED
+        .lock()
+        .unwrap()
+        .insert(base_url.to_owned());
+}
+
+/// Whether the server at `base_url` is already known to have no query API.
+pub fn is_known_query_unsupported(base_url: &str) -> bool {
+    QUERY_UNSUPPORTED.lock().unwrap().contains(base_url)
+}
+
+/// Whether an HTTP status from the query endpoint means that the server has no
+/// query API.
+pub fn is_query_unsupported_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::NOT_IMPLEMENTED
+}
+
+/// Summaries of the invoices matching `query`. This fetches a page of results
+/// at a time, only when the previous page has been consumed, so that listing a
+/// whole registry does not hold every invoice in memory at once. An error
+/// fetching a page ends the stream.
+pub fn stream_invoices<'a>(
+    source: &'a (impl BindleSource + Sync),
+    query: &'a str,
+) -> impl Stream<Item = anyhow::Result<InvoiceSummary>> + 'a {
+    stream_full_invoices(source, query).map(|inv| inv.map(|inv| InvoiceSummary::from(&inv)))
+}
+
+/// The invoices matching `query`, fetched a page at a time like
+/// `stream_invoices`.
+pub fn stream_full_invoices<'a>(
+    source: &'a (impl BindleSource + Sync),
+    query: &'a str,
+) -> impl Stream<Item = anyhow::Result<bindle::Invoice>> + 'a {
+    futures::stream::unfold(Some(0), move |offset| async move {
+        let offset = offset?;
+        match source.query_invoice_page(query, offset, PAGE_SIZE).await {
+            Err(e) => Some((futures::stream::iter(vec![Err(e)]), None)),
+            Ok(page) => {
+                let fetched = page.invoices.len() as u64;
+                let next_offset = if page.more && fetched > 0 {
+                    Some(offset + fetched)
+                } else {
+                    None
+                };
+                let invoices: Vec<_> = page.invoices.into_iter().map(Ok).collect();
+                Some((futures::stream::iter(invoices), next_offset))
+            }
+        }
+    })
+    .flatten()
+}
+
+/// The highest version of the bindle named `name` on the server, by semver
+/// precedence, or `None` if the server has no versions of it. This fails with
+/// `QueryUnsupported` if the server can't be queried.
+pub async fn latest_version(
+    source: &(impl BindleSource + Sync),
+    name: &str,
+) -> anyhow::Result<Option<semver::Version>> {
+    let mut invoices = Box::pin(stream_invoices(source, name));
+    let mut latest: Option<semver::Version> = None;
+    while let Some(summary) = invoices.next().await {
+        let summary = summary?;
+        // The query matches any name containing `name`
+        if summary.id.name() != name {
+            continue;
+        }
+        if let Ok(version) = semver::Version::parse(&summary.id.version_string()) {
+            if latest.as_ref().is_none_or(|l| &version > l) {
+                latest = Some(version);
+            }
+        }
+    }
+    Ok(latest)
+}
+
+/// Checks that a version being pushed comes after the latest on the server.
+/// If it doesn't, channels which follow the latest version will not pick it
+/// up, so this returns a message naming both versions.
+pub fn check_version_ordering(
+    pushing: &semver::Version,
+    latest: Option<&semver::Version>,
+) -> Result<(), String> {
+    match latest {
+        Some(latest) if pushing <= latest => Err(format!(
+            "version {} is not greater than the latest version on the server, {}: channels which follow the latest version will keep serving {}",
+            pushing, latest, latest
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{test_invoice, FakeBindleServer};
+    use std::str::FromStr;
+
+    fn registry(count: usize) -> FakeBindleServer {
+        (0..count).fold(FakeBindleServer::new(), |server, i| {
+            let id = bindle::Id::from_str(&format!("registry/app{:03}/1.0.0", i)).unwrap();
+            server.with_invoice(test_invoice(&id, vec![]))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_pages_are_fetched_only_as_they_are_consumed() {
+        let server = registry(PAGE_SIZE as usize * 4 + 7);
+
+        let mut invoices = Box::pin(stream_invoices(&server, "registry/"));
+        assert_eq!(0, server.pages_served());
+
+        for _ in 0..PAGE_SIZE {
+            invoices.next().await.unwrap().unwrap();
+        }
+        assert_eq!(1, server.pages_served());
+
+        invoices.next().await.unwrap().unwrap();
+        assert_eq!(2, server.pages_served());
+    }
+
+    #[tokio::test]
+    async fn test_every_matching_invoice_is_streamed_once() {
+        let count = PAGE_SIZE as usize * 4 + 7;
+        let server = registry(count);
+
+        let names: Vec<_> = stream_invoices(&server, "registry/")
+            .map(|s| s.unwrap().id.name().to_owned())
+            .collect()
+            .await;
+
+        assert_eq!(count, names.len());
+        assert_eq!("registry/app000", names[0]);
+        assert_eq!(format!("registry/app{:03}", count - 1), names[count - 1]);
+        assert_eq!(5, server.pages_served());
+    }
+
+    #[tokio::test]
+    async fn test_no_matches_is_an_empty_stream() {
+        let server = registry(3);
+        let summaries: Vec<_> = stream_invoices(&server, "nothing").collect().await;
+        assert!(summaries.is_empty());
+        assert_eq!(1, server.pages_served());
+    }
+
+    fn version(text: &str) -> semver::Version {
+        semver::Version::parse(text).unwrap()
+    }
+
+    fn is_ordered(pushing: &str, latest: &str) -> bool {
+        check_version_ordering(&version(pushing), Some(&version(latest))).is_ok()
+    }
+
+    #[test]
+    fn test_lower_or_equal_versions_are_out_of_order() {
+        assert!(!is_ordered("1.3.9", "1.4.2"));
+        assert!(!is_ordered("1.4.2", "1.4.2"));
+        assert!(is_ordered("1.4.3", "1.4.2"));
+        assert!(is_ordered("1.10.0", "1.9.0"));
+        assert!(check_version_ordering(&version("0.1.0"), None).is_ok());
+
+        let message = check_version_ordering(&version("1.3.9"), Some(&version("1.4.2")))
+            .unwrap_err();
+        assert!(message.contains("1.3.9"), "names pushed version: '{}'", message);
+        assert!(message.contains("1.4.2"), "names latest version: '{}'", message);
+    }
+
+    #[test]
+    fn test_prereleases_come_before_their_release() {
+        assert!(!is_ordered("1.4.2-rc.1", "1.4.2"));
+        assert!(is_ordered("1.4.2", "1.4.2-rc.1"));
+        assert!(is_ordered("1.4.3-rc.1", "1.4.2"));
+    }
+
+    #[test]
+    fn test_prereleases_are_ordered_by_semver_precedence() {
+        // Numeric identifiers compare numerically, not as text
+        assert!(is_ordered("1.0.0-alpha.10", "1.0.0-alpha.2"));
+        // A longer set of identifiers comes after its prefix
+        assert!(is_ordered("1.0.0-alpha.1", "1.0.0-alpha"));
+        // Alphanumeric identifiers compare as text, after numeric ones
+        assert!(is_ordered("1.0.0-beta", "1.0.0-alpha.beta"));
+        assert!(is_ordered("1.0.0-alpha.beta", "1.0.0-alpha.1"));
+    }
+
+    #[tokio::test]
+    async fn test_latest_version_is_of_the_exact_name() {
+        let server = ["birds/1.4.2", "birds/1.3.9", "birds/1.5.0-rc.1", "birds-extra/9.0.0"]
+            .iter()
+            .fold(FakeBindleServer::new(), |server, id| {
+                let id = bindle::Id::from_str(id).unwrap();
+                server.with_invoice(test_invoice(&id, vec![]))
+            });
+
+        let latest = latest_version(&server, "birds").await.unwrap();
+        assert_eq!(Some(version("1.5.0-rc.1")), latest);
+        assert_eq!(None, latest_version(&server, "fish").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_server_without_query_api_is_reported_as_such() {
+        let server = registry(3).without_query_api();
+
+        let results: Vec<_> = stream_invoices(&server, "registry/").collect().await;
+        assert_eq!(1, results.len());
+        assert!(results[0].as_ref().unwrap_err().is::<QueryUnsupported>());
+
+        let err = latest_version(&server, "registry/app000")
+            .await
+            .unwrap_err();
+        assert!(err.is::<QueryUnsupported>());
+    }
+
+    #[test]
+    fn test_not_found_and_not_implemented_mean_no_query_api() {
+        assert!(is_query_unsupported_status(reqwest::StatusCode::NOT_FOUND));
+        assert!(is_query_unsupported_status(
+            reqwest::StatusCode::NOT_IMPLEMENTED
+        ));
+        assert!(!is_query_unsupported_status(
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        ));
+        assert!(!is_query_unsupported_status(
+            reqwest::StatusCode::UNAUTHORIZED
+        ));
+    }
+}
@@ -1,10 +1,20 @@
+use futures::{StreamExt, TryStreamExt};
 use itertools::Itertools;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::Duration;
 
-use bindle::client::{
-    tokens::{HttpBasic, NoToken, TokenManager},
-    Client, ClientBuilder,
+use bindle::client::tokens::{HttpBasic, NoToken, TokenManager};
+
+use crate::bindle_client::BindleClient;
+use crate::bindle_search::{
+    is_known_query_unsupported, is_query_unsupported_status, remember_query_unsupported,
+    QueryUnsupported,
 };
+use crate::credential_helper::{CredentialHelper, HelperCredentials};
+use crate::profiles::{Profile, ProfileAuth, Profiles};
+use crate::retry::RetryPolicy;
+use crate::warnings::Warned;
 
 #[derive(Clone)]
 pub struct AnyAuth {
@@ -18,10 +28,26 @@ impl TokenManager for AnyAuth {
     }
 }
 
+/// A token issued by the Bindle server's identity provider, sent as is.
+struct BearerToken {
+    token: String,
+}
+
+#[async_trait::async_trait]
+impl TokenManager for BearerToken {
+    async fn apply_auth_header(&self, builder: reqwest::RequestBuilder) -> bindle::client::Result<reqwest::RequestBuilder> {
+        Ok(builder.bearer_auth(&self.token))
+    }
+}
+
 pub struct BindleConnectionInfo {
     base_url: String,
     allow_insecure: bool,
     token_manager: AnyAuth,
+    pinned_certificates: Vec<String>,
+    timeout: Option<Duration>,
+    proxy: Option<reqwest::Proxy>,
+    retry_policy: RetryPolicy,
 }
 
 impl BindleConnectionInfo {
@@ -31,23 +57,415 @@ impl BindleConnectionInfo {
         username: Option<String>,
         password: Option<String>,
     ) -> Self {
+        let base_url = base_url.into();
+        crate::debug_bundle::add_url_secret(&base_url);
         let token_manager: Box<dyn TokenManager + Send + Sync> = match (username, password) {
-            (Some(u), Some(p)) => Box::new(HttpBasic::new(&u, &p)),
-            _ => Box::new(NoToken::default()),
+            (Some(u), Some(p)) => {
+                crate::debug_bundle::add_secret(&p);
+                Box::new(HttpBasic::new(&u, &p))
+            }
+            _ => Box::new(NoToken),
         };
 
         Self {
-            base_url: base_url.into(),
+            base_url,
             allow_insecure,
             token_manager: AnyAuth { token_manager: Arc::new(token_manager) },
+            pinned_certificates: vec![],
+            timeout: None,
+            proxy: None,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Uses the settings of a saved profile. Any of `overrides` which are set,
+    /// such as flags given on the command line, take precedence over it.
+    pub fn from_profile(
+        profiles: &Profiles,
+        name: &str,
+        overrides: Profile,
+    ) -> anyhow::Result<Self> {
+        Self::from_settings(&profiles.get(name)?.clone().overridden_by(overrides))
+    }
+
+    /// Uses the given settings, which must include the server URL.
+    pub fn from_settings(settings: &Profile) -> anyhow::Result<Self> {
+        let base_url = settings
+            .url
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("No Bindle server URL was given"))?;
+        let allow_insecure = settings.insecure.unwrap_or_default();
+        let connection = match &settings.auth {
+            None | Some(ProfileAuth::None) => Self::new(base_url, allow_insecure, None, None),
+            Some(ProfileAuth::Basic { username, password }) => Self::new(
+                base_url,
+                allow_insecure,
+                Some(username.clone()),
+                Some(password.clone()),
+            ),
+            Some(ProfileAuth::CredentialsFile { path }) => {
+                Self::from_credentials_file(base_url, allow_insecure, path)?
+            }
+            Some(ProfileAuth::CredentialHelper { command }) => Self::from_credential_helper(
+                base_url,
+                allow_insecure,
+                &CredentialHelper::new(command),
+            )?,
+        };
+        let pins = settings
+            .pin_cert_sha256
+            .iter()
+            .flatten()
+            .map(|pin| crate::cert_pinning::parse_pin(pin))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            timeout: settings.timeout_secs.map(Duration::from_secs),
+            ..connection.with_pinned_certificates(pins)?
+        })
+    }
+
+    /// Uses the credentials which a Docker-style credentials file gives for the
+    /// server's host, or no credentials if the file has no entry for it.
+    pub fn from_credentials_file<I: Into<String>>(
+        base_url: I,
+        allow_insecure: bool,
+        path: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<Self> {
+        let base_url = base_url.into();
+        let (username, password) =
+            match crate::credentials_file::find_credentials(path, &base_url)? {
+                Some(c) => (Some(c.username), Some(c.password)),
+                None => (None, None),
+            };
+        Ok(Self::new(base_url, allow_insecure, username, password))
+    }
+
+    /// Uses the credentials which a credential helper gives for the server.
+    pub fn from_credential_helper<I: Into<String>>(
+        base_url: I,
+        allow_insecure: bool,
+        helper: &CredentialHelper,
+    ) -> anyhow::Result<Self> {
+        let base_url = base_url.into();
+        match helper.get(&base_url)? {
+            HelperCredentials::Login { username, password } => Ok(Self::new(
+                base_url,
+                allow_insecure,
+                Some(username),
+                Some(password),
+            )),
+            HelperCredentials::Token(token) => {
+                crate::debug_bundle::add_secret(&token);
+                let token_manager: Box<dyn TokenManager + Send + Sync> =
+                    Box::new(BearerToken { token });
+                Ok(Self {
+                    token_manager: AnyAuth {
+                        token_manager: Arc::new(token_manager),
+                    },
+                    ..Self::new(base_url, allow_insecure, None, None)
+                })
+            }
+        }
+    }
+
+    /// Sends requests through the proxy at `proxy_url`, authenticating to it
+    /// using Negotiate (SPNEGO/Kerberos). If this platform has no supported
+    /// Negotiate implementation, this warns and leaves the connection unchanged.
+    #[cfg(feature = "negotiate-proxy")]
+    pub fn with_negotiate_proxy(self, proxy_url: &str) -> anyhow::Result<Self> {
+        self.with_negotiate_proxy_using(proxy_url, crate::negotiate::platform_token_source())
+    }
+
+    #[cfg(feature = "negotiate-proxy")]
+    pub(crate) fn with_negotiate_proxy_using(
+        self,
+        proxy_url: &str,
+        token_source: Option<Arc<dyn crate::negotiate::NegotiateTokenSource + Send + Sync>>,
+    ) -> anyhow::Result<Self> {
+        match token_source {
+            None => {
+                eprintln!(
+                    "warning: Negotiate proxy authentication is not supported on this platform; connecting to {} without it",
+                    proxy_url
+                );
+                Ok(self)
+            }
+            Some(token_source) => Ok(Self {
+                proxy: Some(crate::negotiate::negotiate_proxy(
+                    proxy_url,
+                    token_source.as_ref(),
+                )?),
+                ..self
+            }),
+        }
+    }
+
+    /// Waits no longer than `max_wait` when the server is overloaded and asks
+    /// us to retry later.
+    pub fn with_max_retry_wait(self, max_wait: Duration) -> Self {
+        Self {
+            retry_policy: RetryPolicy {
+                max_wait,
+                ..self.retry_policy
+            },
+            ..self
+        }
+    }
+
+    /// Refuses to connect unless the server's certificate has one of the given
+    /// keys, by their SPKI SHA-256 digests. This is an error if certificate
+    /// errors are being ignored, as the pin would then prove nothing.
+    pub fn with_pinned_certificates(self, pins: Vec<String>) -> anyhow::Result<Self> {
+        if self.allow_insecure && !pins.is_empty() {
+            anyhow::bail!("Certificate pinning can't be used while ignoring certificate errors");
+        }
+        Ok(Self {
+            pinned_certificates: pins,
+            ..self
+        })
+    }
+
+    #[cfg(test)]
+    pub fn token_manager(&self) -> &AnyAuth {
+        &self.token_manager
+    }
+
+    #[cfg(test)]
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    #[cfg(test)]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// A client for the server.
+    pub fn client(&self) -> anyhow::Result<BindleClient> {
+        Ok(BindleClient::new(
+            self.http_client(self.timeout)?,
+            &self.base_url,
+            self.token_manager.clone(),
+            self.retry_policy.clone(),
+        )?)
+    }
+
+    /// The HTTP client for connections to the server, which gives up on each
+    /// request which takes longer than `timeout`. If certificates are pinned,
+    /// it checks the pins in each TLS handshake.
+    fn http_client(&self, timeout: Option<Duration>) -> anyhow::Result<reqwest::Client> {
+        let mut builder =
+            reqwest::Client::builder().danger_accept_invalid_certs(self.allow_insecure);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
         }
+        let builder = if self.pinned_certificates.is_empty() {
+            builder
+        } else {
+            builder.use_preconfigured_tls(crate::cert_pinning::pinned_tls_config(
+                &self.pinned_certificates,
+            )?)
+        };
+        Ok(builder.build()?)
+    }
+
+    /// Checks that the server can be reached, that its certificate has a
+    /// pinned key if any are pinned, and that it accepts the credentials.
+    pub async fn ping(&self) -> Result<crate::ping::ServerInfo, crate::ping::PingError> {
+        let http = self
+            .http_client(self.timeout)
+            .map_err(|e| crate::ping::PingError::Unreachable {
+                url: self.base_url.clone(),
+                message: e.to_string(),
+            })?;
+        crate::ping::ping(&http, &self.base_url, &self.token_manager).await
+    }
+
+    /// The server's stream of publish and yank events, authenticated in the same
+    /// way as the client. The stream is long-lived, so the timeout does not
+    /// apply to it.
+    pub fn event_source(&self) -> anyhow::Result<crate::bindle_events::HttpEventSource> {
+        Ok(crate::bindle_events::HttpEventSource::new(
+            self.http_client(None)?,
+            &self.base_url,
+            self.token_manager.clone(),
+        ))
+    }
+}
+
+pub type ParcelStream = futures::stream::BoxStream<'static, anyhow::Result<bytes::Bytes>>;
+
+/// Read access to a Bindle server.
+#[async_trait::async_trait]
+pub trait BindleSource {
+    async fn fetch_invoice(&self, id: &bindle::Id) -> anyhow::Result<bindle::Invoice>;
+    /// The invoice even if it has been yanked, which `fetch_invoice` refuses.
+    async fn fetch_yanked_invoice(&self, id: &bindle::Id) -> anyhow::Result<bindle::Invoice>;
+    async fn fetch_parcel_stream(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+    ) -> anyhow::Result<ParcelStream>;
+    /// Whether the server has the parcel, without downloading its content.
+    async fn parcel_exists(&self, id: &bindle::Id, sha256: &str) -> anyhow::Result<bool>;
+    /// How many more bytes the server will store for a push of `id`, if it
+    /// enforces a quota and says what is left of it.
+    async fn remaining_quota(&self, id: &bindle::Id) -> anyhow::Result<Option<u64>>;
+    /// One page of the invoices matching `query`, starting at `offset`.
+    async fn query_invoice_page(
+        &self,
+        query: &str,
+        offset: u64,
+        limit: u8,
+    ) -> anyhow::Result<InvoicePage>;
+}
+
+/// What to do on resolving an invoice which has been yanked.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum YankedPolicy {
+    /// Fail with `InvoiceYanked`.
+    #[default]
+    Reject,
+    /// Use the invoice, with a warning that it has been yanked.
+    WarnAndAllow,
+    /// Use the invoice as if it had not been yanked.
+    Allow,
+}
+
+impl YankedPolicy {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "reject" => Ok(Self::Reject),
+            "warn" => Ok(Self::WarnAndAllow),
+            "allow" => Ok(Self::Allow),
+            _ => Err(anyhow::anyhow!(
+                "Invalid yanked policy '{}': choose one of reject, warn, allow",
+                text
+            )),
+        }
+    }
+}
+
+/// The error for resolving a yanked invoice under `YankedPolicy::Reject`.
+#[derive(Debug)]
+pub struct InvoiceYanked {
+    pub id: bindle::Id,
+}
+
+impl std::fmt::Display for InvoiceYanked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bindle {} has been yanked", self.id)
+    }
+}
+
+impl std::error::Error for InvoiceYanked {}
+
+/// Fetches an invoice, applying `policy` if it has been yanked.
+pub async fn resolve_invoice(
+    source: &impl BindleSource,
+    id: &bindle::Id,
+    policy: YankedPolicy,
+) -> anyhow::Result<Warned<bindle::Invoice>> {
+    let invoice = source.fetch_yanked_invoice(id).await?;
+    if !invoice.yanked.unwrap_or_default() {
+        return Ok(invoice.into());
+    }
+    match policy {
+        YankedPolicy::Reject => Err(InvoiceYanked { id: id.clone() }.into()),
+        YankedPolicy::WarnAndAllow => {
+            let warning = format!("Bindle {} has been yanked, but is being used anyway", id);
+            Ok(Warned::from((invoice, warning)))
+        }
+        YankedPolicy::Allow => Ok(invoice.into()),
+    }
+}
+
+/// A page of search results from a Bindle server.
+#[derive(Debug)]
+pub struct InvoicePage {
+    pub invoices: Vec<bindle::Invoice>,
+    /// Whether there are more results after this page.
+    pub more: bool,
+}
+
+#[async_trait::async_trait]
+impl BindleSource for BindleClient {
+    async fn fetch_invoice(&self, id: &bindle::Id) -> anyhow::Result<bindle::Invoice> {
+        self.get_invoice(id)
+            .await
+            .map_err(|e| anyhow::anyhow!("Error fetching invoice {}: {}", id, e))
+    }
+
+    async fn fetch_yanked_invoice(&self, id: &bindle::Id) -> anyhow::Result<bindle::Invoice> {
+        self.get_yanked_invoice(id)
+            .await
+            .map_err(|e| anyhow::anyhow!("Error fetching invoice {}: {}", id, e))
+    }
+
+    async fn fetch_parcel_stream(
+        &self,
+        id: &bindle::Id,
+        sha256: &str,
+    ) -> anyhow::Result<ParcelStream> {
+        let stream = self
+            .get_parcel_stream(id, sha256)
+            .await
+            .map_err(|e| anyhow::anyhow!("Error fetching parcel {} of {}: {}", sha256, id, e))?;
+        let sha256 = sha256.to_owned();
+        Ok(stream
+            .map_err(move |e| anyhow::anyhow!("Error downloading parcel {}: {}", sha256, e))
+            .boxed())
     }
 
-    pub fn client(&self) -> bindle::client::Result<Client<AnyAuth>> {
-        let builder = ClientBuilder::default()
-            .http2_prior_knowledge(false)
-            .danger_accept_invalid_certs(self.allow_insecure);
-        builder.build(&self.base_url, self.token_manager.clone())
+    async fn parcel_exists(&self, id: &bindle::Id, sha256: &str) -> anyhow::Result<bool> {
+        self.parcel_exists(id, sha256)
+            .await
+            .map_err(|e| anyhow::anyhow!("Error checking parcel {} of {}: {}", sha256, id, e))
+    }
+
+    async fn remaining_quota(&self, _id: &bindle::Id) -> anyhow::Result<Option<u64>> {
+        // The Bindle API does not report quotas.
+        Ok(None)
+    }
+
+    async fn query_invoice_page(
+        &self,
+        query: &str,
+        offset: u64,
+        limit: u8,
+    ) -> anyhow::Result<InvoicePage> {
+        if is_known_query_unsupported(self.base_url()) {
+            return Err(QueryUnsupported.into());
+        }
+        let options = bindle::QueryOptions {
+            query: Some(query.to_owned()),
+            offset: Some(offset),
+            limit: Some(limit),
+            ..bindle::QueryOptions::default()
+        };
+        let matches = self.query_invoices(options).await.map_err(|e| match e {
+            bindle::client::ClientError::ResourceNotFound => {
+                remember_query_unsupported(self.base_url(), "unsupported (not found)".to_owned());
+                QueryUnsupported.into()
+            }
+            bindle::client::ClientError::InvalidRequest { status_code, .. }
+                if is_query_unsupported_status(status_code) =>
+            {
+                remember_query_unsupported(
+                    self.base_url(),
+                    format!("unsupported ({})", status_code),
+                );
+                QueryUnsupported.into()
+            }
+            e => anyhow::anyhow!("Error searching for invoices matching '{}': {}", query, e),
+        })?;
+        Ok(InvoicePage {
+            invoices: matches.invoices,
+            more: matches.more,
+        })
     }
 }
 
@@ -55,13 +473,156 @@ pub trait ParcelHelpers {
     fn has_annotation(&self, key: &str) -> bool;
     fn requires(&self) -> Vec<String>;
     fn is_member_of(&self, group: &str) -> bool;
+    fn memberships(&self) -> Vec<String>;
+    fn wagi_feature(&self, key: &str) -> Option<&str>;
 }
 
 pub trait InvoiceHelpers {
     fn parcels_in(&self, group: &str) -> Vec<bindle::Parcel>;
     fn parcels_required_by(&self, parcel: &bindle::Parcel) -> Vec<bindle::Parcel>;
+    /// The parcels in a group and the parcels they require, directly or
+    /// indirectly, with the groups which were visited to find them.
+    fn closure_of_group(&self, group: &str) -> GroupClosure;
+    /// The parcels which are always needed: those in no group, those in the
+    /// groups the invoice marks as required, and the parcels those require,
+    /// directly or indirectly.
+    fn required_closure(&self) -> Vec<bindle::Parcel>;
+    /// The fewest groups which must be activated for the invoice to include
+    /// a parcel, beginning with the one to choose and followed by the groups
+    /// it activates in turn. Only groups the invoice declares can be chosen;
+    /// others are activated by the parcels which require them. This is empty
+    /// if the parcel is always needed, and an error if nothing leads to it.
+    fn minimal_activation_for(&self, parcel: &bindle::Parcel) -> anyhow::Result<Vec<String>>;
+    /// The first parcel which WAGI serves a route from, if any.
+    fn entrypoint_parcel(&self) -> Option<&bindle::Parcel>;
+    fn has_entrypoint(&self) -> bool;
+    /// Checks for mistakes which would stop Hippo serving the application,
+    /// returning a description of every problem found.
+    fn validate_for_hippo(&self) -> Result<(), Vec<String>>;
+    /// The parcels in the groups named by a profile annotation, and the
+    /// parcels those require, directly or indirectly. Parcels which are not
+    /// in any of the groups are not included.
+    fn profile_closure(&self, profile: &str) -> anyhow::Result<Vec<bindle::Parcel>>;
+    /// The routes WAGI serves, from the `wagi.route` feature of each parcel,
+    /// normalised and sorted by path. It is an error for two different parcels
+    /// to claim the same route.
+    fn routes(&self) -> Result<Vec<Route>, RouteError>;
+    /// The versions of the host which the application can run on, from the
+    /// `hippo_host_version` annotation. This is `None` if the invoice has no
+    /// such annotation, or if it is not a valid semver requirement.
+    fn host_requirement(&self) -> Option<semver::VersionReq>;
+    /// Whether the application can run on the given version of the host. An
+    /// invoice with no host requirement is compatible with every host, but one
+    /// whose requirement cannot be parsed is compatible with none.
+    fn is_compatible_with(&self, host_version: &semver::Version) -> bool;
+}
+
+/// The parcels which a group brings in, and where they came from.
+#[derive(Clone, Debug, Default)]
+pub struct GroupClosure {
+    /// Each parcel once, in the order in which it was found.
+    pub parcels: Vec<bindle::Parcel>,
+    /// The groups visited, in the order in which they were visited.
+    pub groups: Vec<GroupContribution>,
+    /// The requested group if the invoice neither declares it nor has any
+    /// parcels in it. The closure is then empty.
+    pub unknown_group: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupContribution {
+    pub name: String,
+    /// The digests of the parcels in the group, including any which an
+    /// earlier group had already brought in.
+    pub parcel_shas: Vec<String>,
 }
 
+/// A route which an application serves, and the parcel which handles it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Route {
+    pub path: String,
+    pub parcel_sha: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    /// More than one parcel claims the route at `path`.
+    Conflict {
+        path: String,
+        parcel_shas: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Conflict { path, parcel_shas } => write!(
+                f,
+                "Route {} is claimed by more than one parcel: {}",
+                path,
+                parcel_shas.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// A route in the form in which routes are compared: with a leading slash, and
+/// without empty segments or a trailing slash.
+pub fn normalize_route(route: &str) -> String {
+    format!(
+        "/{}",
+        route.trim().split('/').filter(|s| !s.is_empty()).join("/")
+    )
+}
+
+/// Whether WAGI would send requests for the normalised route `route` to the
+/// handler of the normalised route `wildcard` instead, because `wildcard` ends
+/// in `/...` and `route` is under it. A route does not shadow itself.
+pub fn route_shadows(wildcard: &str, route: &str) -> bool {
+    match wildcard.strip_suffix("/...") {
+        None => false,
+        Some(prefix) => {
+            wildcard != route
+                && (prefix.is_empty()
+                    || route == prefix
+                    || route.starts_with(&format!("{}/", prefix)))
+        }
+    }
+}
+
+/// The digests of the parcels which nothing would refer to once `removing` is
+/// deleted: those of its parcels which no other invoice in `all` also lists.
+/// `all` may include `removing` itself. The digests are sorted, each once.
+pub fn orphaned_by_removal(all: &[bindle::Invoice], removing: &bindle::Invoice) -> Vec<String> {
+    let kept: HashSet<_> = all
+        .iter()
+        .filter(|inv| inv.bindle.id != removing.bindle.id)
+        .flat_map(|inv| inv.parcel.iter().flatten())
+        .map(|p| &p.label.sha256)
+        .collect();
+    removing
+        .parcel
+        .iter()
+        .flatten()
+        .map(|p| &p.label.sha256)
+        .filter(|sha| !kept.contains(sha))
+        .sorted()
+        .dedup()
+        .cloned()
+        .collect()
+}
+
+/// The prefix of invoice annotations which define activation profiles. The
+/// annotation `hippo_profile_<name>` is a comma-separated list of the groups
+/// which the profile activates.
+const PROFILE_ANNOTATION_PREFIX: &str = "hippo_profile_";
+
+/// The invoice annotation which gives the semver requirement on the version of
+/// the host, e.g. `>=0.9, <2`.
+pub const HOST_VERSION_ANNOTATION: &str = "hippo_host_version";
+
 impl ParcelHelpers for bindle::Parcel {
     fn has_annotation(&self, key: &str) -> bool {
         match self.label.annotations.as_ref() {
@@ -89,6 +650,25 @@ impl ParcelHelpers for bindle::Parcel {
             },
         }
     }
+
+    fn memberships(&self) -> Vec<String> {
+        match self.conditions.as_ref() {
+            None => vec![],
+            Some(conditions) => match conditions.member_of.as_ref() {
+                None => vec![],
+                Some(groups) => groups.clone(),
+            },
+        }
+    }
+
+    fn wagi_feature(&self, key: &str) -> Option<&str> {
+        self.label
+            .feature
+            .as_ref()?
+            .get("wagi")?
+            .get(key)
+            .map(|v| v.as_str())
+    }
 }
 
 impl InvoiceHelpers for bindle::Invoice {
@@ -104,28 +684,1076 @@ impl InvoiceHelpers for bindle::Invoice {
     }
 
     fn parcels_required_by(&self, parcel: &bindle::Parcel) -> Vec<bindle::Parcel> {
-        parcels_required_by_acc(self, parcel.requires(), vec![])
+        closure_of_groups(self, parcel.requires()).parcels
+    }
+
+    fn required_closure(&self) -> Vec<bindle::Parcel> {
+        let global = self
+            .parcel
+            .iter()
+            .flatten()
+            .filter(|p| p.memberships().is_empty())
+            .cloned()
+            .collect_vec();
+        let groups = self
+            .group
+            .iter()
+            .flatten()
+            .filter(|g| g.required == Some(true))
+            .map(|g| g.name.clone())
+            .chain(global.iter().flat_map(|p| p.requires()))
+            .collect();
+        global
             .into_iter()
+            .chain(closure_of_groups(self, groups).parcels)
             .unique_by(|p| p.label.sha256.clone())
-            .collect_vec()
+            .collect()
+    }
+
+    fn minimal_activation_for(&self, parcel: &bindle::Parcel) -> anyhow::Result<Vec<String>> {
+        let sha256 = &parcel.label.sha256;
+        let parcels = self.parcel.as_ref().map(|v| &v[..]).unwrap_or_default();
+        let listings = parcels
+            .iter()
+            .filter(|p| &p.label.sha256 == sha256)
+            .collect_vec();
+        if listings.is_empty() {
+            anyhow::bail!(
+                "Parcel {} ({}) is not in bindle {}",
+                parcel.label.name,
+                sha256,
+                self.bindle.id
+            );
+        }
+        if self
+            .required_closure()
+            .iter()
+            .any(|p| &p.label.sha256 == sha256)
+        {
+            return Ok(vec![]);
+        }
+
+        // Search back from the groups the parcel is in, through the parcels
+        // which require each group, so that the first declared group found
+        // heads the shortest chain. Each group maps to the one it leads to.
+        let declared: HashSet<_> = self.group.iter().flatten().map(|g| &g.name).collect();
+        let mut leads_to: HashMap<String, Option<String>> = HashMap::new();
+        let mut pending = VecDeque::new();
+        for group in listings.iter().flat_map(|p| p.memberships()) {
+            if !leads_to.contains_key(&group) {
+                leads_to.insert(group.clone(), None);
+                pending.push_back(group);
+            }
+        }
+        while let Some(group) = pending.pop_front() {
+            if declared.contains(&group) {
+                let mut chain = vec![group];
+                while let Some(Some(next)) = leads_to.get(chain.last().unwrap()) {
+                    chain.push(next.clone());
+                }
+                return Ok(chain);
+            }
+            let requirers = parcels.iter().filter(|p| p.requires().contains(&group));
+            for outer in requirers.flat_map(|p| p.memberships()) {
+                if !leads_to.contains_key(&outer) {
+                    leads_to.insert(outer.clone(), Some(group.clone()));
+                    pending.push_back(outer);
+                }
+            }
+        }
+        Err(anyhow::anyhow!(
+            "Parcel {} ({}) cannot be reached: no declared group leads to it",
+            parcel.label.name,
+            sha256
+        ))
+    }
+
+    fn closure_of_group(&self, group: &str) -> GroupClosure {
+        let is_declared = self.group.iter().flatten().any(|g| g.name == group);
+        if !is_declared && self.parcels_in(group).is_empty() {
+            return GroupClosure {
+                unknown_group: Some(group.to_owned()),
+                ..GroupClosure::default()
+            };
+        }
+        closure_of_groups(self, vec![group.to_owned()])
+    }
+
+    fn entrypoint_parcel(&self) -> Option<&bindle::Parcel> {
+        self.parcel
+            .iter()
+            .flatten()
+            .find(|p| p.wagi_feature("route").is_some())
+    }
+
+    fn has_entrypoint(&self) -> bool {
+        self.entrypoint_parcel().is_some()
+    }
+
+    fn validate_for_hippo(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+
+        if !self.has_entrypoint() {
+            problems.push("No parcel has a WAGI route, so Hippo will not serve anything".to_owned());
+        }
+
+        let declared_groups: Vec<_> = self.group.iter().flatten().map(|g| &g.name).collect();
+        let parcels = self.parcel.as_ref().map(|v| &v[..]).unwrap_or_default();
+        for parcel in parcels {
+            let referenced_groups = parcel.requires().into_iter().chain(parcel.memberships());
+            for group in referenced_groups.unique() {
+                if !declared_groups.contains(&&group) {
+                    problems.push(format!(
+                        "Parcel {} refers to group {}, which is not declared",
+                        parcel.label.name, group
+                    ));
+                }
+            }
+        }
+
+        if let Some(Err(e)) = host_version_annotation(self) {
+            problems.push(e);
+        }
+
+        let key = |parcel: &bindle::Parcel| {
+            (
+                parcel.label.sha256.clone(),
+                parcel.label.name.clone(),
+                parcel.requires(),
+                parcel.memberships(),
+            )
+        };
+        let listings = parcels.iter().map(key).counts();
+        let mut reported = HashSet::new();
+        for parcel in parcels {
+            let count = listings[&key(parcel)];
+            if count > 1 && reported.insert(key(parcel)) {
+                problems.push(format!(
+                    "Parcel {} ({}) is listed {} times",
+                    parcel.label.name, parcel.label.sha256, count
+                ));
+            }
+        }
+
+        // Each copy of a duplicated parcel has the same other problems.
+        let problems: Vec<_> = problems.into_iter().unique().collect();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn profile_closure(&self, profile: &str) -> anyhow::Result<Vec<bindle::Parcel>> {
+        let annotations = self.annotations.clone().unwrap_or_default();
+        let key = format!("{}{}", PROFILE_ANNOTATION_PREFIX, profile);
+        let groups = match annotations.get(&key) {
+            Some(groups) => groups
+                .split(',')
+                .map(|g| g.trim().to_owned())
+                .filter(|g| !g.is_empty())
+                .collect(),
+            None => {
+                let available = annotations
+                    .keys()
+                    .filter_map(|k| k.strip_prefix(PROFILE_ANNOTATION_PREFIX))
+                    .join(", ");
+                anyhow::bail!(
+                    "Profile '{}' is not defined. Available profiles are: {}",
+                    profile,
+                    available
+                );
+            }
+        };
+        Ok(closure_of_groups(self, groups).parcels)
+    }
+
+    fn host_requirement(&self) -> Option<semver::VersionReq> {
+        host_version_annotation(self).and_then(Result::ok)
+    }
+
+    fn is_compatible_with(&self, host_version: &semver::Version) -> bool {
+        match host_version_annotation(self) {
+            None => true,
+            Some(Ok(requirement)) => requirement.matches(host_version),
+            Some(Err(_)) => false,
+        }
+    }
+
+    fn routes(&self) -> Result<Vec<Route>, RouteError> {
+        let routes = self
+            .parcel
+            .iter()
+            .flatten()
+            .filter_map(|p| {
+                p.wagi_feature("route").map(|path| Route {
+                    path: normalize_route(path),
+                    parcel_sha: p.label.sha256.clone(),
+                })
+            })
+            .sorted()
+            .dedup() // the same parcel may be listed more than once
+            .collect_vec();
+        let conflict = routes
+            .iter()
+            .group_by(|r| &r.path)
+            .into_iter()
+            .map(|(path, group)| {
+                let shas = group.map(|r| r.parcel_sha.clone()).collect_vec();
+                (path.clone(), shas)
+            })
+            .find(|(_, shas)| shas.len() > 1);
+        match conflict {
+            Some((path, parcel_shas)) => Err(RouteError::Conflict { path, parcel_shas }),
+            None => Ok(routes),
+        }
     }
 }
 
-fn parcels_required_by_acc(
+fn host_version_annotation(
     invoice: &bindle::Invoice,
-    mut groups: Vec<String>,
-    mut acc: Vec<bindle::Parcel>,
-) -> Vec<bindle::Parcel> {
-    match groups.pop() {
-        None => acc,
-        Some(group) => {
-            let mut members = invoice.parcels_in(&group);
-            let mut required_groups: Vec<_> =
-                members.iter().flat_map(|p| p.requires()).unique().collect();
-            acc.append(&mut members);
-            groups.append(&mut required_groups);
-            let new_groups = groups.into_iter().unique().collect();
-            parcels_required_by_acc(invoice, new_groups, acc)
+) -> Option<Result<semver::VersionReq, String>> {
+    let text = invoice.annotations.as_ref()?.get(HOST_VERSION_ANNOTATION)?;
+    Some(semver::VersionReq::parse(text).map_err(|e| {
+        format!(
+            "Annotation {} is not a valid version requirement ('{}'): {}",
+            HOST_VERSION_ANNOTATION, text, e
+        )
+    }))
+}
+
+/// Checks the names of the groups an invoice declares or refers to, returning
+/// a description of every problem found. Names which are empty, have leading or
+/// trailing whitespace, or differ only in case from another name are problems.
+pub fn validate_group_names(invoice: &bindle::Invoice) -> Result<(), Vec<String>> {
+    let mut problems = vec![];
+
+    let declared = invoice.group.iter().flatten().map(|g| g.name.clone());
+    let referenced = invoice
+        .parcel
+        .iter()
+        .flatten()
+        .flat_map(|p| p.requires().into_iter().chain(p.memberships()));
+    let names = declared.chain(referenced).unique().collect_vec();
+
+    for name in &names {
+        if name.trim().is_empty() {
+            problems.push(format!("Group name '{}' is empty", name));
+        } else if name.trim() != name {
+            problems.push(format!(
+                "Group name '{}' has leading or trailing whitespace",
+                name
+            ));
+        }
+    }
+
+    let case_collisions = names
+        .iter()
+        .into_group_map_by(|n| n.to_lowercase())
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .sorted();
+    for (_, colliding) in case_collisions {
+        problems.push(format!(
+            "Group names {} differ only in case",
+            colliding.iter().map(|n| format!("'{}'", n)).join(", ")
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+/// The names of the asset parcels of an invoice which are empty. They are
+/// valid parcels, but some Bindle servers reject parcels of zero length.
+pub fn empty_files(invoice: &bindle::Invoice) -> Vec<String> {
+    invoice
+        .parcel
+        .iter()
+        .flatten()
+        .filter(|p| is_empty_file(p))
+        .map(|p| p.label.name.clone())
+        .collect()
+}
+
+/// Leaves the empty asset parcels out of an invoice, with a warning naming
+/// them.
+pub fn omit_empty_files(mut invoice: bindle::Invoice) -> Warned<bindle::Invoice> {
+    let skipped = empty_files(&invoice);
+    if skipped.is_empty() {
+        return Warned::from(invoice);
+    }
+    if let Some(parcels) = invoice.parcel.as_mut() {
+        parcels.retain(|p| !is_empty_file(p));
+    }
+    let warning = format!(
+        "Skipped {} empty file(s): {}",
+        skipped.len(),
+        skipped.join(", ")
+    );
+    Warned::from((invoice, warning))
+}
+
+fn is_empty_file(parcel: &bindle::Parcel) -> bool {
+    // An empty handler is a mistake which skipping would only hide.
+    parcel.label.size == 0 && parcel.wagi_feature("route").is_none()
+}
+
+/// The parcels which any of the invoices always needs, each once, for example
+/// to warm a cache shared by applications which are deployed together.
+pub fn combined_closure(invoices: &[&bindle::Invoice]) -> Vec<bindle::Parcel> {
+    invoices
+        .iter()
+        .flat_map(|invoice| invoice.required_closure())
+        .unique_by(|p| p.label.sha256.clone())
+        .collect()
+}
+
+/// The parcels in any of `groups`, and the parcels they require, following
+/// requirements until no new groups turn up. Groups are visited breadth first,
+/// so that nearer groups come before further ones.
+fn closure_of_groups(invoice: &bindle::Invoice, groups: Vec<String>) -> GroupClosure {
+    let mut visited = HashSet::new();
+    let mut pending: VecDeque<_> = groups.into();
+    let mut closure = GroupClosure::default();
+    while let Some(group) = pending.pop_front() {
+        if !visited.insert(group.clone()) {
+            continue;
+        }
+        let members = invoice.parcels_in(&group);
+        pending.extend(members.iter().flat_map(|p| p.requires()));
+        closure.groups.push(GroupContribution {
+            name: group,
+            parcel_shas: members.iter().map(|p| p.label.sha256.clone()).unique().collect(),
+        });
+        closure.parcels.extend(members);
+    }
+    closure.parcels = closure
+        .parcels
+        .into_iter()
+        .unique_by(|p| p.label.sha256.clone())
+        .collect_vec();
+    closure
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_search::latest_version;
+    use crate::testing::{
+        http_response, parcel_for, serve_once, sha256_of, test_invoice, FakeBindleServer,
+    };
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    fn handler_parcel(name: &str, requires: &str) -> bindle::Parcel {
+        let mut parcel = parcel_for(name, name.as_bytes());
+        let wagi: BTreeMap<_, _> = vec![("route".to_owned(), format!("/{}", name))]
+            .into_iter()
+            .collect();
+        parcel.label.feature = Some(vec![("wagi".to_owned(), wagi)].into_iter().collect());
+        parcel.conditions = Some(bindle::Condition {
+            member_of: None,
+            requires: Some(vec![requires.to_owned()]),
+        });
+        parcel
+    }
+
+    fn asset_parcel(name: &str, member_of: &str) -> bindle::Parcel {
+        let mut parcel = parcel_for(name, name.as_bytes());
+        parcel.conditions = Some(bindle::Condition {
+            member_of: Some(vec![member_of.to_owned()]),
+            requires: None,
+        });
+        parcel
+    }
+
+    fn group(name: &str) -> bindle::Group {
+        bindle::Group {
+            name: name.to_owned(),
+            required: None,
+            satisfied_by: None,
         }
     }
+
+    fn hippo_invoice(parcels: Vec<bindle::Parcel>, groups: Vec<&str>) -> bindle::Invoice {
+        let id = bindle::Id::from_str("hippo/app/1.0.0").unwrap();
+        let mut invoice = test_invoice(&id, parcels);
+        invoice.group = Some(groups.into_iter().map(group).collect());
+        invoice
+    }
+
+    #[test]
+    fn test_valid_hippo_invoice_passes_validation() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files"),
+                asset_parcel("index.html", "main-files"),
+            ],
+            vec!["main-files"],
+        );
+        assert!(invoice.has_entrypoint());
+        assert_eq!(
+            "main.wasm",
+            invoice.entrypoint_parcel().unwrap().label.name
+        );
+        assert!(invoice.validate_for_hippo().is_ok());
+    }
+
+    #[test]
+    fn test_invoice_without_entrypoint_fails_validation() {
+        let invoice = hippo_invoice(vec![asset_parcel("index.html", "main-files")], vec!["main-files"]);
+        assert!(!invoice.has_entrypoint());
+        let problems = invoice.validate_for_hippo().unwrap_err();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("No parcel has a WAGI route"));
+    }
+
+    #[test]
+    fn test_dangling_groups_fail_validation() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "missing-files"),
+                asset_parcel("index.html", "other-missing-files"),
+            ],
+            vec![],
+        );
+        let problems = invoice.validate_for_hippo().unwrap_err();
+        assert_eq!(2, problems.len());
+        assert!(problems[0].contains("missing-files"));
+        assert!(problems[1].contains("other-missing-files"));
+    }
+
+    #[test]
+    fn test_duplicate_parcels_fail_validation() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files"),
+                asset_parcel("index.html", "main-files"),
+                asset_parcel("index.html", "main-files"),
+            ],
+            vec!["main-files"],
+        );
+        let problems = invoice.validate_for_hippo().unwrap_err();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("index.html"));
+        assert!(problems[0].contains("listed 2 times"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn test_parcel_listed_many_times_is_reported_once() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files"),
+                asset_parcel("index.html", "main-files"),
+                asset_parcel("index.html", "main-files"),
+                asset_parcel("index.html", "main-files"),
+            ],
+            vec!["main-files"],
+        );
+        let problems = invoice.validate_for_hippo().unwrap_err();
+        assert_eq!(1, problems.len(), "{:?}", problems);
+        assert!(problems[0].contains("listed 3 times"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn test_all_problems_are_reported_together() {
+        let invoice = hippo_invoice(
+            vec![
+                asset_parcel("index.html", "missing-files"),
+                asset_parcel("index.html", "missing-files"),
+            ],
+            vec![],
+        );
+        let problems = invoice.validate_for_hippo().unwrap_err();
+        // no entrypoint, one dangling reference, one duplicate
+        assert_eq!(3, problems.len(), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_profiles_activate_their_own_closures() {
+        let mut handler = handler_parcel("main.wasm", "main-files");
+        handler.conditions.as_mut().unwrap().member_of = Some(vec!["server".to_owned()]);
+        let mut invoice = hippo_invoice(
+            vec![
+                handler,
+                asset_parcel("index.html", "main-files"),
+                asset_parcel("styles.css", "theme"),
+                asset_parcel("debug.js", "debug-tools"),
+                asset_parcel("unused.txt", "unused"),
+            ],
+            vec!["server", "main-files", "theme", "debug-tools", "unused"],
+        );
+        invoice.annotations = Some(
+            vec![
+                ("hippo_profile_minimal".to_owned(), "server".to_owned()),
+                ("hippo_profile_dev".to_owned(), "server, theme,debug-tools".to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let names = |parcels: Vec<bindle::Parcel>| {
+            parcels
+                .into_iter()
+                .map(|p| p.label.name)
+                .sorted()
+                .collect_vec()
+        };
+        assert_eq!(
+            vec!["index.html", "main.wasm"],
+            names(invoice.profile_closure("minimal").unwrap())
+        );
+        assert_eq!(
+            vec!["debug.js", "index.html", "main.wasm", "styles.css"],
+            names(invoice.profile_closure("dev").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_undefined_profile_is_an_error() {
+        let mut invoice = hippo_invoice(vec![asset_parcel("index.html", "main-files")], vec!["main-files"]);
+        invoice.annotations = Some(
+            vec![("hippo_profile_minimal".to_owned(), "main-files".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        let err = invoice.profile_closure("full").unwrap_err().to_string();
+        assert!(err.contains("'full'"), "names the profile: '{}'", err);
+        assert!(err.contains("minimal"), "lists profiles: '{}'", err);
+    }
+
+    fn invoice_requiring_host(requirement: Option<&str>) -> bindle::Invoice {
+        let mut invoice = hippo_invoice(vec![handler_parcel("main.wasm", "files")], vec!["files"]);
+        invoice.annotations = requirement.map(|r| {
+            vec![(HOST_VERSION_ANNOTATION.to_owned(), r.to_owned())]
+                .into_iter()
+                .collect()
+        });
+        invoice
+    }
+
+    fn version(text: &str) -> semver::Version {
+        semver::Version::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_host_within_requirement_is_compatible() {
+        let invoice = invoice_requiring_host(Some(">=0.9, <2"));
+        assert_eq!(
+            Some(semver::VersionReq::parse(">=0.9, <2").unwrap()),
+            invoice.host_requirement()
+        );
+        assert!(invoice.is_compatible_with(&version("0.9.0")));
+        assert!(invoice.is_compatible_with(&version("1.4.2")));
+    }
+
+    #[test]
+    fn test_host_outside_requirement_is_incompatible() {
+        let invoice = invoice_requiring_host(Some(">=1.2"));
+        assert!(!invoice.is_compatible_with(&version("1.1.9")));
+        assert!(!invoice.is_compatible_with(&version("0.9.0")));
+    }
+
+    #[test]
+    fn test_missing_host_requirement_is_compatible_with_any_host() {
+        let invoice = invoice_requiring_host(None);
+        assert_eq!(None, invoice.host_requirement());
+        assert!(invoice.is_compatible_with(&version("0.1.0")));
+        assert!(invoice.validate_for_hippo().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_host_requirement_is_compatible_with_no_host() {
+        let invoice = invoice_requiring_host(Some("newer than 1.0"));
+        assert_eq!(None, invoice.host_requirement());
+        assert!(!invoice.is_compatible_with(&version("1.4.2")));
+        let problems = invoice.validate_for_hippo().unwrap_err();
+        assert!(
+            problems[0].contains("'newer than 1.0'"),
+            "reports the requirement: '{}'",
+            problems[0]
+        );
+    }
+
+    fn layered_invoice() -> bindle::Invoice {
+        let mut page = asset_parcel("index.html", "main-files");
+        page.conditions.as_mut().unwrap().requires = Some(vec!["theme".to_owned()]);
+        let mut shared = asset_parcel("shared.css", "theme");
+        shared.conditions.as_mut().unwrap().member_of =
+            Some(vec!["main-files".to_owned(), "theme".to_owned()]);
+        hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files"),
+                page,
+                shared,
+                asset_parcel("styles.css", "theme"),
+                asset_parcel("unused.txt", "unused"),
+            ],
+            vec!["main-files", "theme", "unused", "empty"],
+        )
+    }
+
+    #[test]
+    fn test_required_closure_has_ungrouped_and_required_parcels() {
+        let mut invoice = hippo_invoice(
+            vec![
+                handler_parcel("first.wasm", "first-files"),
+                asset_parcel("shared.css", "first-files"),
+                asset_parcel("logo.png", "branding"),
+                asset_parcel("unused.txt", "unused"),
+            ],
+            vec!["first-files", "branding", "unused"],
+        );
+        invoice.group.as_mut().unwrap()[1].required = Some(true);
+        let mut copy = invoice.parcel.as_ref().unwrap()[1].clone();
+        copy.label.name = "copy.css".to_owned();
+        invoice.parcel.as_mut().unwrap().push(copy);
+
+        let names = invoice
+            .required_closure()
+            .into_iter()
+            .map(|p| p.label.name)
+            .sorted()
+            .collect_vec();
+        assert_eq!(vec!["first.wasm", "logo.png", "shared.css"], names);
+    }
+
+    #[test]
+    fn test_combined_closure_has_each_shared_parcel_once() {
+        let mut first = hippo_invoice(
+            vec![
+                handler_parcel("first.wasm", "first-files"),
+                asset_parcel("shared.css", "first-files"),
+                asset_parcel("logo.png", "branding"),
+                asset_parcel("unused.txt", "unused"),
+            ],
+            vec!["first-files", "branding", "unused"],
+        );
+        first.group.as_mut().unwrap()[1].required = Some(true);
+        let second = hippo_invoice(
+            vec![
+                handler_parcel("second.wasm", "second-files"),
+                asset_parcel("shared.css", "second-files"),
+                asset_parcel("logo.png", "second-files"),
+            ],
+            vec!["second-files"],
+        );
+
+        let names = combined_closure(&[&first, &second])
+            .into_iter()
+            .map(|p| p.label.name)
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            vec!["first.wasm", "logo.png", "second.wasm", "shared.css"],
+            names
+        );
+    }
+
+    fn yanked_server() -> (FakeBindleServer, bindle::Id) {
+        let id = bindle::Id::from_str("yanked/app/1.0.0").unwrap();
+        let mut invoice = test_invoice(&id, vec![]);
+        invoice.yanked = Some(true);
+        (FakeBindleServer::new().with_invoice(invoice), id)
+    }
+
+    #[tokio::test]
+    async fn test_reject_policy_refuses_yanked_invoice() {
+        let (server, id) = yanked_server();
+        let err = resolve_invoice(&server, &id, YankedPolicy::Reject)
+            .await
+            .unwrap_err();
+        assert_eq!(id, err.downcast_ref::<InvoiceYanked>().unwrap().id);
+        assert_eq!(YankedPolicy::Reject, YankedPolicy::default());
+    }
+
+    #[tokio::test]
+    async fn test_warn_policy_allows_yanked_invoice_with_warning() {
+        let (server, id) = yanked_server();
+        let (invoice, warnings) = resolve_invoice(&server, &id, YankedPolicy::WarnAndAllow)
+            .await
+            .unwrap()
+            .into();
+        assert_eq!(id, invoice.bindle.id);
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("yanked/app/1.0.0"), "{}", warnings[0]);
+    }
+
+    #[tokio::test]
+    async fn test_allow_policy_allows_yanked_invoice_silently() {
+        let (server, id) = yanked_server();
+        let (invoice, warnings) = resolve_invoice(&server, &id, YankedPolicy::Allow)
+            .await
+            .unwrap()
+            .into();
+        assert_eq!(id, invoice.bindle.id);
+        assert!(warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unyanked_invoice_resolves_under_any_policy() {
+        let id = bindle::Id::from_str("fine/app/1.0.0").unwrap();
+        let server = FakeBindleServer::new().with_invoice(test_invoice(&id, vec![]));
+        let (_, warnings) = resolve_invoice(&server, &id, YankedPolicy::Reject)
+            .await
+            .unwrap()
+            .into();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_minimal_activation_follows_nested_groups() {
+        let mut bridge = asset_parcel("bridge.wasm", "outer");
+        bridge.conditions.as_mut().unwrap().requires = Some(vec!["inner".to_owned()]);
+        let deep = asset_parcel("deep.txt", "inner");
+        let lost = asset_parcel("lost.txt", "nowhere");
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files"),
+                asset_parcel("index.html", "main-files"),
+                bridge,
+                deep.clone(),
+                lost.clone(),
+            ],
+            vec!["main-files", "outer"],
+        );
+
+        assert_eq!(
+            vec!["outer", "inner"],
+            invoice.minimal_activation_for(&deep).unwrap()
+        );
+        let index = asset_parcel("index.html", "main-files");
+        assert!(invoice.minimal_activation_for(&index).unwrap().is_empty());
+        let err = invoice.minimal_activation_for(&lost).unwrap_err();
+        assert!(err.to_string().contains("lost.txt"), "{}", err);
+    }
+
+    #[test]
+    fn test_group_closure_reports_the_groups_visited() {
+        let invoice = layered_invoice();
+        let closure = invoice.closure_of_group("main-files");
+
+        assert_eq!(None, closure.unknown_group);
+        let names = closure
+            .parcels
+            .iter()
+            .map(|p| p.label.name.as_str())
+            .collect_vec();
+        assert_eq!(vec!["index.html", "shared.css", "styles.css"], names);
+        let groups = closure.groups.iter().map(|g| g.name.as_str()).collect_vec();
+        assert_eq!(vec!["main-files", "theme"], groups);
+        assert_eq!(2, closure.groups[0].parcel_shas.len());
+        // shared.css is in both groups, but is only listed once
+        assert_eq!(2, closure.groups[1].parcel_shas.len());
+    }
+
+    #[test]
+    fn test_unknown_group_has_a_marked_empty_closure() {
+        let invoice = layered_invoice();
+
+        let closure = invoice.closure_of_group("mian-files");
+        assert_eq!(Some("mian-files".to_owned()), closure.unknown_group);
+        assert!(closure.parcels.is_empty());
+        assert!(closure.groups.is_empty());
+
+        // A declared group with no parcels is not unknown
+        let closure = invoice.closure_of_group("empty");
+        assert_eq!(None, closure.unknown_group);
+        assert!(closure.parcels.is_empty());
+        assert_eq!(1, closure.groups.len());
+    }
+
+    #[test]
+    fn test_parcels_required_by_follows_requirements() {
+        let invoice = layered_invoice();
+        let handler = invoice.entrypoint_parcel().unwrap();
+        let names = invoice
+            .parcels_required_by(handler)
+            .into_iter()
+            .map(|p| p.label.name)
+            .sorted()
+            .collect_vec();
+        assert_eq!(vec!["index.html", "shared.css", "styles.css"], names);
+    }
+
+    #[test]
+    fn test_cyclic_requirements_terminate() {
+        let mut a = asset_parcel("a.txt", "a");
+        a.conditions.as_mut().unwrap().requires = Some(vec!["b".to_owned()]);
+        let mut b = asset_parcel("b.txt", "b");
+        b.conditions.as_mut().unwrap().requires = Some(vec!["a".to_owned()]);
+        let invoice = hippo_invoice(vec![a, b], vec!["a", "b"]);
+        assert_eq!(2, invoice.closure_of_group("a").parcels.len());
+    }
+
+    #[test]
+    fn test_well_formed_group_names_pass_validation() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files"),
+                asset_parcel("index.html", "main-files"),
+            ],
+            vec!["main-files"],
+        );
+        assert!(validate_group_names(&invoice).is_ok());
+    }
+
+    #[test]
+    fn test_empty_group_names_fail_validation() {
+        let invoice = hippo_invoice(vec![asset_parcel("index.html", "")], vec!["", " "]);
+        let problems = validate_group_names(&invoice).unwrap_err();
+        assert_eq!(2, problems.len());
+        assert!(problems.iter().all(|p| p.contains("is empty")));
+    }
+
+    #[test]
+    fn test_group_names_with_surrounding_whitespace_fail_validation() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files "),
+                asset_parcel("index.html", "main-files "),
+            ],
+            vec!["main-files "],
+        );
+        let problems = validate_group_names(&invoice).unwrap_err();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("whitespace"));
+    }
+
+    #[test]
+    fn test_group_names_differing_only_in_case_fail_validation() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "Main-Files"),
+                asset_parcel("index.html", "main-files"),
+            ],
+            vec!["main-files"],
+        );
+        let problems = validate_group_names(&invoice).unwrap_err();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("'main-files'"));
+        assert!(problems[0].contains("'Main-Files'"));
+    }
+
+    fn invoice_with_empty_file() -> bindle::Invoice {
+        let mut robots = parcel_for("robots.txt", b"");
+        robots.conditions = asset_parcel("robots.txt", "main-files").conditions;
+        hippo_invoice(
+            vec![
+                handler_parcel("main.wasm", "main-files"),
+                asset_parcel("index.html", "main-files"),
+                robots,
+            ],
+            vec!["main-files"],
+        )
+    }
+
+    #[test]
+    fn test_empty_files_are_counted_but_kept() {
+        let invoice = invoice_with_empty_file();
+        assert_eq!(vec!["robots.txt"], empty_files(&invoice));
+        assert_eq!(3, invoice.parcel.as_ref().unwrap().len());
+        assert!(validate_group_names(&invoice).is_ok());
+    }
+
+    #[test]
+    fn test_empty_files_can_be_omitted() {
+        let (invoice, warnings) = omit_empty_files(invoice_with_empty_file()).into();
+        let names = invoice
+            .parcel
+            .unwrap()
+            .iter()
+            .map(|p| p.label.name.clone())
+            .collect_vec();
+        assert_eq!(vec!["main.wasm", "index.html"], names);
+        assert_eq!(1, warnings.len());
+        assert!(
+            warnings[0].contains("robots.txt"),
+            "lists the skipped file: '{}'",
+            warnings[0]
+        );
+    }
+
+    async fn authorization_header(connection: &BindleConnectionInfo) -> Option<String> {
+        let builder = reqwest::Client::new().get("http://bindle.test/v1/_q");
+        let request = connection
+            .token_manager()
+            .apply_auth_header(builder)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        request
+            .headers()
+            .get(reqwest::header::AUTHORIZATION)
+            .map(|h| h.to_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn test_credentials_file_entry_is_used_for_matching_host() {
+        let connection = BindleConnectionInfo::from_credentials_file(
+            "https://bindle.example.com:8443/v1",
+            false,
+            "./testdata/credentials/credentials.json",
+        )
+        .unwrap();
+        let expected = format!("Basic {}", base64::encode("port8443:port8443-password"));
+        assert_eq!(Some(expected), authorization_header(&connection).await);
+    }
+
+    #[tokio::test]
+    async fn test_credentials_file_without_entry_means_no_auth() {
+        let connection = BindleConnectionInfo::from_credentials_file(
+            "http://localhost:8080/v1",
+            false,
+            "./testdata/credentials/credentials.json",
+        )
+        .unwrap();
+        assert_eq!(None, authorization_header(&connection).await);
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_credential_helper_credentials_are_used() {
+        let helper = |mode: &str| {
+            CredentialHelper::new(format!("sh ./testdata/credential_helper/stub.sh {}", mode))
+        };
+        let url = "https://bindle.example.com/v1";
+
+        let connection =
+            BindleConnectionInfo::from_credential_helper(url, false, &helper("token")).unwrap();
+        assert_eq!(
+            Some("Bearer helper-token".to_owned()),
+            authorization_header(&connection).await
+        );
+
+        let connection =
+            BindleConnectionInfo::from_credential_helper(url, false, &helper("login")).unwrap();
+        let expected = format!(
+            "Basic {}",
+            base64::encode(format!("helper-user:secret for {}", url))
+        );
+        assert_eq!(Some(expected), authorization_header(&connection).await);
+    }
+
+    #[test]
+    fn test_routes_are_listed_in_path_order() {
+        let invoice = hippo_invoice(
+            vec![
+                handler_parcel("zebra.wasm", "zebra-files"),
+                asset_parcel("stripes.txt", "zebra-files"),
+                handler_parcel("aardvark.wasm", "aardvark-files"),
+            ],
+            vec!["zebra-files", "aardvark-files"],
+        );
+        let routes = invoice.routes().unwrap();
+        let paths = routes.iter().map(|r| r.path.as_str()).collect_vec();
+        assert_eq!(vec!["/aardvark.wasm", "/zebra.wasm"], paths);
+        assert_eq!(
+            parcel_for("aardvark.wasm", b"aardvark.wasm").label.sha256,
+            routes[0].parcel_sha
+        );
+    }
+
+    #[test]
+    fn test_parcels_claiming_the_same_route_conflict() {
+        let mut impostor = handler_parcel("impostor.wasm", "lion-files");
+        let wagi: BTreeMap<_, _> = vec![("route".to_owned(), "/lion.wasm".to_owned())]
+            .into_iter()
+            .collect();
+        impostor.label.feature = Some(vec![("wagi".to_owned(), wagi)].into_iter().collect());
+        let lion = handler_parcel("lion.wasm", "lion-files");
+        let invoice = hippo_invoice(vec![lion.clone(), lion, impostor], vec!["lion-files"]);
+
+        match invoice.routes() {
+            Err(RouteError::Conflict { path, parcel_shas }) => {
+                assert_eq!("/lion.wasm", path);
+                assert_eq!(2, parcel_shas.len());
+            }
+            Ok(routes) => panic!("expected a conflict, got {:?}", routes),
+        }
+    }
+
+    #[test]
+    fn test_routes_are_compared_normalised() {
+        let mut trailing = handler_parcel("trailing.wasm", "lion-files");
+        let wagi: BTreeMap<_, _> = vec![("route".to_owned(), "//lion.wasm/".to_owned())]
+            .into_iter()
+            .collect();
+        trailing.label.feature = Some(vec![("wagi".to_owned(), wagi)].into_iter().collect());
+        let invoice = hippo_invoice(
+            vec![handler_parcel("lion.wasm", "lion-files"), trailing],
+            vec!["lion-files"],
+        );
+        match invoice.routes() {
+            Err(RouteError::Conflict { path, .. }) => assert_eq!("/lion.wasm", path),
+            Ok(routes) => panic!("expected a conflict, got {:?}", routes),
+        }
+        assert_eq!("/", normalize_route(""));
+        assert_eq!("/static/...", normalize_route("static//..."));
+    }
+
+    #[test]
+    fn test_wildcard_routes_shadow_the_routes_under_them() {
+        assert!(route_shadows("/static/...", "/static"));
+        assert!(route_shadows("/static/...", "/static/css/site.css"));
+        assert!(route_shadows("/...", "/anything"));
+        assert!(!route_shadows("/static/...", "/statics"));
+        assert!(!route_shadows("/static/...", "/static/..."));
+        assert!(!route_shadows("/static", "/static/css"));
+    }
+
+    #[test]
+    fn test_orphaned_by_removal_keeps_shared_parcels() {
+        let id = |s: &str| bindle::Id::from_str(s).unwrap();
+        let removing = test_invoice(
+            &id("old/1.0.0"),
+            vec![
+                parcel_for("shared.wasm", b"shared"),
+                parcel_for("exclusive.dat", b"exclusive"),
+                parcel_for("copy-of-exclusive.dat", b"exclusive"),
+                parcel_for("also-exclusive.dat", b"also exclusive"),
+            ],
+        );
+        let other = test_invoice(
+            &id("new/1.0.0"),
+            vec![
+                parcel_for("renamed-shared.wasm", b"shared"),
+                parcel_for("new.dat", b"new"),
+            ],
+        );
+        let all = vec![removing.clone(), other];
+
+        let mut expected = vec![sha256_of(b"exclusive"), sha256_of(b"also exclusive")];
+        expected.sort();
+        assert_eq!(expected, orphaned_by_removal(&all, &removing));
+        assert!(orphaned_by_removal(&all, &all[1]).contains(&sha256_of(b"new")));
+    }
+
+    #[tokio::test]
+    async fn test_server_without_queries_is_probed_once() {
+        let (url, server) = serve_once(http_response("501 Not Implemented", ""));
+        let client = BindleConnectionInfo::new(&url, false, None, None)
+            .client()
+            .unwrap();
+
+        let first = client.query_invoice_page("birds", 0, 10).await.unwrap_err();
+        assert!(first.is::<QueryUnsupported>(), "{}", first);
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /v1/_q?"), "{}", request);
+
+        // The server has stopped listening, so these would fail differently if
+        // they asked it again, however the client was built.
+        let second = client.query_invoice_page("birds", 0, 10).await.unwrap_err();
+        assert!(second.is::<QueryUnsupported>(), "{}", second);
+        let other_client = BindleConnectionInfo::new(&url, false, None, None)
+            .client()
+            .unwrap();
+        let lookup = latest_version(&other_client, "birds").await.unwrap_err();
+        assert!(lookup.is::<QueryUnsupported>(), "{}", lookup);
+    }
 }
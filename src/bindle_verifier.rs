@@ -0,0 +1,442 @@
+use std::sync::Arc;
+
+use bindle::Parcel;
+use futures::StreamExt;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::bindle_fetcher::{fetch_parcel_sha256, SizeMismatch, VerifyPolicy};
+use crate::bindle_utils::{BindleSource, InvoiceHelpers};
+use crate::digest_backend::DigestBackend;
+
+pub struct VerifyOptions {
+    /// How thoroughly to check parcels: `Always` downloads and hashes each
+    /// one, `MissingOnly` only checks that the server has it, and `Never`
+    /// checks none of them.
+    pub verify: VerifyPolicy,
+    /// Check only the parcels in this group.
+    pub group: Option<String>,
+    /// The most parcels to check at once.
+    pub max_concurrency: usize,
+    pub mode: VerifyMode,
+    /// How to compute the digests of parcels in a deep check.
+    pub digest: Arc<dyn DigestBackend>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyMode {
+    /// Check every parcel, so that the report lists every problem.
+    ReportAll,
+    /// Stop at the first problem, abandoning any checks still in progress.
+    FailFast,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    /// The number of parcels checked.
+    pub checked: usize,
+    /// Parcels the server does not have, as (name, sha256).
+    pub missing: Vec<(String, String)>,
+    /// Parcels whose content does not match their sha256, as (name, sha256).
+    pub corrupt: Vec<(String, String)>,
+    /// Parcels which could not be checked, as (name, sha256, error).
+    pub errors: Vec<(String, String, String)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.errors.is_empty()
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, sha256) in &self.missing {
+            writeln!(f, "missing: {} ({})", name, sha256)?;
+        }
+        for (name, sha256) in &self.corrupt {
+            writeln!(f, "corrupt: {} ({})", name, sha256)?;
+        }
+        for (name, sha256, error) in &self.errors {
+            writeln!(f, "error: {} ({}): {}", name, sha256, error)?;
+        }
+        write!(
+            f,
+            "checked {} parcels: {} missing, {} corrupt, {} could not be checked",
+            self.checked,
+            self.missing.len(),
+            self.corrupt.len(),
+            self.errors.len()
+        )
+    }
+}
+
+enum ParcelStatus {
+    Ok,
+    Missing,
+    Corrupt,
+    Error(anyhow::Error),
+}
+
+/// Checks that every parcel of a stored bindle can be retrieved, and, if
+/// `verify` is `Always`, that its content matches its digest. In `ReportAll`
+/// mode this checks every parcel even if some fail, so that the report is
+/// complete; in `FailFast` mode the report covers only the parcels checked
+/// before the first failure.
+pub async fn verify_bindle(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    options: &VerifyOptions,
+) -> anyhow::Result<VerifyReport> {
+    let invoice = source.fetch_invoice(bindle_id).await?;
+    let parcels = match &options.group {
+        None => invoice.parcel.clone().unwrap_or_default(),
+        Some(group) => {
+            let declared = invoice
+                .group
+                .iter()
+                .flatten()
+                .map(|g| &g.name)
+                .collect_vec();
+            if !declared.contains(&group) {
+                anyhow::bail!(
+                    "Bindle {} has no group named '{}'. Available groups are: {}",
+                    bindle_id,
+                    group,
+                    declared.iter().join(", ")
+                );
+            }
+            invoice.parcels_in(group)
+        }
+    };
+    if options.verify == VerifyPolicy::Never {
+        return Ok(VerifyReport::default());
+    }
+    let parcels = parcels
+        .into_iter()
+        .unique_by(|p| p.label.sha256.clone())
+        .collect_vec();
+
+    let mut checks = futures::stream::iter(parcels)
+        .map(|parcel| check_parcel(source, bindle_id, parcel, options))
+        .buffer_unordered(options.max_concurrency.max(1));
+    let mut statuses = vec![];
+    while let Some((parcel, status)) = checks.next().await {
+        let failed = !matches!(status, ParcelStatus::Ok);
+        statuses.push((parcel, status));
+        if failed && options.mode == VerifyMode::FailFast {
+            // Dropping the stream cancels the checks in progress
+            break;
+        }
+    }
+
+    let mut report = VerifyReport {
+        checked: statuses.len(),
+        ..VerifyReport::default()
+    };
+    for (parcel, status) in statuses
+        .into_iter()
+        .sorted_by_key(|(p, _)| p.label.name.clone())
+    {
+        let key = (parcel.label.name.clone(), parcel.label.sha256.clone());
+        match status {
+            ParcelStatus::Ok => (),
+            ParcelStatus::Missing => report.missing.push(key),
+            ParcelStatus::Corrupt => report.corrupt.push(key),
+            ParcelStatus::Error(e) => report.errors.push((key.0, key.1, e.to_string())),
+        }
+    }
+    Ok(report)
+}
+
+// This takes the parcel by value, as the stream of checks can't be sent
+// between threads if each borrows its parcel.
+async fn check_parcel(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    parcel: Parcel,
+    options: &VerifyOptions,
+) -> (Parcel, ParcelStatus) {
+    let status = parcel_status(source, bindle_id, &parcel, options).await;
+    (parcel, status)
+}
+
+async fn parcel_status(
+    source: &impl BindleSource,
+    bindle_id: &bindle::Id,
+    parcel: &Parcel,
+    options: &VerifyOptions,
+) -> ParcelStatus {
+    let sha256 = &parcel.label.sha256;
+    match source.parcel_exists(bindle_id, sha256).await {
+        Err(e) => return ParcelStatus::Error(e),
+        Ok(false) => return ParcelStatus::Missing,
+        Ok(true) => (),
+    }
+    if options.verify != VerifyPolicy::Always {
+        return ParcelStatus::Ok;
+    }
+    match fetch_parcel_sha256(source, bindle_id, parcel, options.digest.as_ref()).await {
+        // A parcel of the wrong length, such as a truncated one, is corrupt.
+        Err(e) if e.is::<SizeMismatch>() => ParcelStatus::Corrupt,
+        Err(e) => ParcelStatus::Error(e),
+        Ok(actual) if &actual == sha256 => ParcelStatus::Ok,
+        Ok(_) => ParcelStatus::Corrupt,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::digest_backend::{DigestHasher, Sha2Backend};
+    use crate::testing::{parcel_for, sha256_of, test_invoice, FakeBindleServer};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn test_bindle_id() -> bindle::Id {
+        bindle::Id::from_str("verify/me/1.0.0").unwrap()
+    }
+
+    /// A server with one good parcel, one missing parcel and one corrupt parcel.
+    fn damaged_server() -> FakeBindleServer {
+        let mut in_group = parcel_for("good.txt", b"good");
+        in_group.conditions = Some(bindle::Condition {
+            member_of: Some(vec!["assets".to_owned()]),
+            requires: None,
+        });
+        let mut invoice = test_invoice(
+            &test_bindle_id(),
+            vec![
+                in_group,
+                parcel_for("missing.txt", b"missing"),
+                parcel_for("corrupt.txt", b"corrupt"),
+            ],
+        );
+        invoice.group = Some(vec![bindle::Group {
+            name: "assets".to_owned(),
+            required: None,
+            satisfied_by: None,
+        }]);
+        let mut server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(b"good");
+        server
+            .parcels
+            .insert(sha256_of(b"corrupt"), b"c0rrupt".to_vec());
+        server
+    }
+
+    fn options(verify: VerifyPolicy, group: Option<&str>) -> VerifyOptions {
+        VerifyOptions {
+            verify,
+            group: group.map(|g| g.to_owned()),
+            max_concurrency: 2,
+            mode: VerifyMode::ReportAll,
+            digest: Arc::new(Sha2Backend),
+        }
+    }
+
+    /// A server with two corrupt parcels among good ones.
+    fn doubly_corrupt_server() -> FakeBindleServer {
+        let invoice = test_invoice(
+            &test_bindle_id(),
+            vec![
+                parcel_for("good1.txt", b"good1"),
+                parcel_for("corrupt1.txt", b"corrupt1"),
+                parcel_for("corrupt2.txt", b"corrupt2"),
+                parcel_for("good2.txt", b"good2"),
+            ],
+        );
+        let mut server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(b"good1")
+            .with_parcel(b"good2");
+        server
+            .parcels
+            .insert(sha256_of(b"corrupt1"), b"c0rrupt1".to_vec());
+        server
+            .parcels
+            .insert(sha256_of(b"corrupt2"), b"c0rrupt2".to_vec());
+        server
+    }
+
+    fn names(parcels: &[(String, String)]) -> Vec<&str> {
+        parcels.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_existence_check_finds_missing_parcels_only() {
+        let report = verify_bindle(
+            &damaged_server(),
+            &test_bindle_id(),
+            &options(VerifyPolicy::MissingOnly, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(3, report.checked);
+        assert_eq!(vec!["missing.txt"], names(&report.missing));
+        assert!(report.corrupt.is_empty());
+        assert!(!report.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_never_checks_no_parcels() {
+        let server = damaged_server();
+        let report = verify_bindle(
+            &server,
+            &test_bindle_id(),
+            &options(VerifyPolicy::Never, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(0, report.checked);
+        assert!(report.is_ok());
+        assert_eq!(0, server.parcel_requests());
+    }
+
+    #[tokio::test]
+    async fn test_deep_check_finds_missing_and_corrupt_parcels() {
+        let report = verify_bindle(
+            &damaged_server(),
+            &test_bindle_id(),
+            &options(VerifyPolicy::Always, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(3, report.checked);
+        assert_eq!(vec!["missing.txt"], names(&report.missing));
+        assert_eq!(vec!["corrupt.txt"], names(&report.corrupt));
+    }
+
+    #[tokio::test]
+    async fn test_report_all_mode_reports_every_corrupt_parcel() {
+        let report = verify_bindle(
+            &doubly_corrupt_server(),
+            &test_bindle_id(),
+            &options(VerifyPolicy::Always, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(4, report.checked);
+        assert_eq!(vec!["corrupt1.txt", "corrupt2.txt"], names(&report.corrupt));
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast_mode_stops_at_first_corrupt_parcel() {
+        let options = VerifyOptions {
+            max_concurrency: 1,
+            mode: VerifyMode::FailFast,
+            ..options(VerifyPolicy::Always, None)
+        };
+        let report = verify_bindle(&doubly_corrupt_server(), &test_bindle_id(), &options)
+            .await
+            .unwrap();
+        assert_eq!(2, report.checked);
+        assert_eq!(vec!["corrupt1.txt"], names(&report.corrupt));
+        assert!(!report.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deep_check_reports_truncated_parcel_as_corrupt() {
+        let invoice = test_invoice(
+            &test_bindle_id(),
+            vec![parcel_for("truncated.txt", b"truncated")],
+        );
+        let mut server = FakeBindleServer::new().with_invoice(invoice);
+        server
+            .parcels
+            .insert(sha256_of(b"truncated"), b"trunc".to_vec());
+
+        let report = verify_bindle(
+            &server,
+            &test_bindle_id(),
+            &options(VerifyPolicy::Always, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(vec!["truncated.txt"], names(&report.corrupt));
+        assert!(report.errors.is_empty());
+        assert!(!report.is_ok());
+    }
+
+    /// A backend which counts the hashers it makes and the bytes they hash.
+    #[derive(Default)]
+    struct CountingBackend {
+        hashers: AtomicUsize,
+        bytes: Arc<AtomicUsize>,
+    }
+
+    struct CountingHasher {
+        inner: Box<dyn DigestHasher>,
+        bytes: Arc<AtomicUsize>,
+    }
+
+    impl DigestBackend for CountingBackend {
+        fn hasher(&self) -> Box<dyn DigestHasher> {
+            self.hashers.fetch_add(1, Ordering::SeqCst);
+            Box::new(CountingHasher {
+                inner: Sha2Backend.hasher(),
+                bytes: self.bytes.clone(),
+            })
+        }
+    }
+
+    impl DigestHasher for CountingHasher {
+        fn update(&mut self, data: &[u8]) {
+            self.bytes.fetch_add(data.len(), Ordering::SeqCst);
+            self.inner.update(data);
+        }
+
+        fn finish(self: Box<Self>) -> String {
+            self.inner.finish()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deep_check_uses_the_given_digest_backend() {
+        let backend = Arc::new(CountingBackend::default());
+        let options = VerifyOptions {
+            digest: backend.clone(),
+            ..options(VerifyPolicy::Always, None)
+        };
+        let report = verify_bindle(&damaged_server(), &test_bindle_id(), &options)
+            .await
+            .unwrap();
+
+        // The missing parcel is never downloaded, so only two are hashed.
+        assert_eq!(2, backend.hashers.load(Ordering::SeqCst));
+        assert_eq!(
+            b"good".len() + b"c0rrupt".len(),
+            backend.bytes.load(Ordering::SeqCst)
+        );
+        assert_eq!(vec!["missing.txt"], names(&report.missing));
+        assert_eq!(vec!["corrupt.txt"], names(&report.corrupt));
+    }
+
+    #[tokio::test]
+    async fn test_group_scopes_the_check() {
+        let report = verify_bindle(
+            &damaged_server(),
+            &test_bindle_id(),
+            &options(VerifyPolicy::Always, Some("assets")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, report.checked);
+        assert!(report.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_group_lists_available_groups() {
+        let err = verify_bindle(
+            &damaged_server(),
+            &test_bindle_id(),
+            &options(VerifyPolicy::MissingOnly, Some("nope")),
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("assets"), "lists groups: '{}'", err);
+    }
+}
@@ -1,19 +1,99 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 
 use bindle::{Invoice, Parcel};
 
 use crate::bindle_utils::ParcelHelpers;
+use crate::parcel_store::ParcelStore;
+use crate::path_mapping;
+
+/// The file which marks a bindle directory as a complete export. It holds the
+/// bindle id, and is written after everything else, so a directory whose export
+/// was interrupted never has it.
+pub const EXPORT_MARKER: &str = ".hippo-export-complete";
+
+/// What to do with anything already in the destination directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverwritePolicy {
+    /// Write regardless, leaving anything else alone. This is only for
+    /// Hippo's own staging directory.
+    Always,
+    /// Refuse a directory which holds anything but exports of the same
+    /// bindle name, complete or from before exports were marked complete. An
+    /// export of the same bindle is updated in place, removing parcels it no
+    /// longer has.
+    PreviousExportOnly,
+    /// Delete anything which `PreviousExportOnly` would refuse, listing it
+    /// first.
+    Force,
+    /// Empty the directory first, listing what it deletes.
+    Clean,
+}
+
+/// The error for a destination directory which holds things that are not
+/// previous exports of the bindle.
+#[derive(Debug)]
+pub struct DestinationNotEmpty {
+    pub dir: PathBuf,
+    /// Files and directories which have nothing to do with the bindle.
+    pub unrelated: Vec<PathBuf>,
+    /// Bindle directories whose export didn't finish.
+    pub incomplete: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for DestinationNotEmpty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} holds files which are not a previous export of this bindle:",
+            self.dir.to_string_lossy()
+        )?;
+        for path in &self.unrelated {
+            writeln!(f, "  {}", path.to_string_lossy())?;
+        }
+        for path in &self.incomplete {
+            writeln!(f, "  {} (an incomplete export)", path.to_string_lossy())?;
+        }
+        write!(
+            f,
+            "Use --force to delete them, or --clean to empty the directory first"
+        )
+    }
+}
+
+impl std::error::Error for DestinationNotEmpty {}
 
 pub struct BindleWriter {
     source_base_path: PathBuf,
     dest_base_path: PathBuf,
+    parcel_store: Option<ParcelStore>,
+    overwrite_policy: OverwritePolicy,
 }
 
 impl BindleWriter {
     pub fn new(source_base_path: impl AsRef<Path>, dest_base_path: impl AsRef<Path>) -> Self {
         Self {
-            source_base_path: source_base_path.as_ref().to_path_buf(),
-            dest_base_path: dest_base_path.as_ref().to_path_buf(),
+            source_base_path: path_mapping::staging_path(source_base_path.as_ref()),
+            dest_base_path: path_mapping::staging_path(dest_base_path.as_ref()),
+            parcel_store: None,
+            overwrite_policy: OverwritePolicy::Always,
+        }
+    }
+
+    /// Stages parcels by linking to a shared parcel store, adding them to the
+    /// store if they are not already there.
+    pub fn with_parcel_store(self, parcel_store: ParcelStore) -> Self {
+        Self {
+            parcel_store: Some(parcel_store),
+            ..self
+        }
+    }
+
+    pub fn with_overwrite_policy(self, overwrite_policy: OverwritePolicy) -> Self {
+        Self {
+            overwrite_policy,
+            ..self
         }
     }
 
@@ -22,10 +102,84 @@ impl BindleWriter {
         let bindle_id_hash = invoice.bindle.id.sha();
         let bindle_dir = self.dest_base_path.join(bindle_id_hash);
         let parcels_dir = bindle_dir.join("parcels");
+        self.prepare_destination(invoice)?;
+        // Until the export is complete again, it mustn't look complete.
+        remove_if_exists(&bindle_dir.join(EXPORT_MARKER))?;
         tokio::fs::create_dir_all(&parcels_dir).await?;
 
+        // Write the invoice last, so that if staging is interrupted, the stage
+        // directory never holds an invoice whose parcels are not all there.
+        {
+            let _store_lock = match &self.parcel_store {
+                Some(store) => Some(store.lock_shared()?),
+                None => None,
+            };
+            self.write_parcel_files(invoice, &parcels_dir).await?;
+        }
         self.write_invoice_file(invoice, &bindle_dir).await?;
-        self.write_parcel_files(invoice, &parcels_dir).await?;
+        if self.overwrite_policy != OverwritePolicy::Always {
+            remove_unreferenced_parcels(invoice, &parcels_dir)?;
+        }
+        tokio::fs::write(
+            bindle_dir.join(EXPORT_MARKER),
+            invoice.bindle.id.to_string(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    fn prepare_destination(&self, invoice: &Invoice) -> anyhow::Result<()> {
+        let dir = &self.dest_base_path;
+        if self.overwrite_policy == OverwritePolicy::Always || !dir.is_dir() {
+            return Ok(());
+        }
+        if self.overwrite_policy == OverwritePolicy::Clean {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                eprintln!("deleting {}", path.to_string_lossy());
+                remove_path(&path)?;
+            }
+            return Ok(());
+        }
+
+        let mut unrelated = vec![];
+        let mut incomplete = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            match exported_bindle_name(&path) {
+                Some(name) if name == invoice.bindle.id.name() => (),
+                // Exporting again rewrites every parcel and the invoice, so a
+                // directory whose invoice names the bindle is safe to update.
+                None if legacy_export_name(&path).as_deref() == Some(invoice.bindle.id.name()) => {
+                    eprintln!(
+                        "warning: {} has no {} file, so was staged by an earlier version of Hippo or not finished; updating it in place",
+                        path.to_string_lossy(),
+                        EXPORT_MARKER
+                    );
+                }
+                None if path.join("invoice.toml").exists() || path.join("parcels").exists() => {
+                    incomplete.push(path)
+                }
+                _ => unrelated.push(path),
+            }
+        }
+        if unrelated.is_empty() && incomplete.is_empty() {
+            return Ok(());
+        }
+        if self.overwrite_policy == OverwritePolicy::PreviousExportOnly {
+            return Err(DestinationNotEmpty {
+                dir: dir.clone(),
+                unrelated,
+                incomplete,
+            }
+            .into());
+        }
+        for path in unrelated.iter().chain(&incomplete) {
+            eprintln!("deleting {}", path.to_string_lossy());
+        }
+        for path in unrelated.iter().chain(&incomplete) {
+            remove_path(path)?;
+        }
         Ok(())
     }
 
@@ -36,7 +190,9 @@ impl BindleWriter {
     ) -> anyhow::Result<()> {
         let invoice_text = toml::to_string_pretty(&invoice)?;
         let invoice_file = bindle_dir.join("invoice.toml");
-        tokio::fs::write(&invoice_file, &invoice_text).await?;
+        let partial_file = bindle_dir.join("invoice.toml.partial");
+        tokio::fs::write(&partial_file, &invoice_text).await?;
+        tokio::fs::rename(&partial_file, &invoice_file).await?;
         Ok(())
     }
 
@@ -64,10 +220,274 @@ impl BindleWriter {
         if parcel.has_annotation("hippos_do_not_stage") {
             return Ok(());
         }
-        let source_file = self.source_base_path.join(&parcel.label.name);
+        let source_file = path_mapping::parcel_path(&self.source_base_path, &parcel.label.name);
         let hash = &parcel.label.sha256;
         let dest_file = parcels_dir.join(format!("{}.dat", hash));
-        tokio::fs::copy(&source_file, &dest_file).await?;
+        if let Some(store) = &self.parcel_store {
+            return store.stage(hash, &source_file, &dest_file);
+        }
+        // A previous prepare may have linked this file into the parcel store, so
+        // replace it rather than writing through the link.
+        if dest_file.exists() {
+            tokio::fs::remove_file(&dest_file).await?;
+        }
+        // Copy under a temporary name so that an interrupted copy never leaves
+        // a truncated parcel.
+        let partial_file = parcels_dir.join(format!("{}.dat.partial", hash));
+        tokio::fs::copy(&source_file, &partial_file).await?;
+        tokio::fs::rename(&partial_file, &dest_file).await?;
         Ok(())
     }
 }
+
+/// The name of the bindle exported to `dir`, if it holds a complete export.
+fn exported_bindle_name(dir: &Path) -> Option<String> {
+    let marker = std::fs::read_to_string(dir.join(EXPORT_MARKER)).ok()?;
+    let id = bindle::Id::try_from(marker.trim()).ok()?;
+    Some(id.name().to_owned())
+}
+
+/// The name of the bindle in `dir`, if it holds an invoice but no export
+/// marker, as directories staged before the marker was added do.
+fn legacy_export_name(dir: &Path) -> Option<String> {
+    let text = std::fs::read_to_string(dir.join("invoice.toml")).ok()?;
+    let invoice: Invoice = toml::from_str(&text).ok()?;
+    Some(invoice.bindle.id.name().to_owned())
+}
+
+/// Removes the parcel files, and any partial files, which the invoice doesn't
+/// stage, so that updating an export doesn't accumulate parcels.
+fn remove_unreferenced_parcels(invoice: &Invoice, parcels_dir: &Path) -> anyhow::Result<()> {
+    let staged: HashSet<_> = invoice
+        .parcel
+        .iter()
+        .flatten()
+        .filter(|p| !p.has_annotation("hippos_do_not_stage"))
+        .map(|p| format!("{}.dat", p.label.sha256))
+        .collect();
+    for entry in std::fs::read_dir(parcels_dir)? {
+        let entry = entry?;
+        if !staged.contains(entry.file_name().to_string_lossy().as_ref()) {
+            remove_path(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let result = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    result.map_err(|e| anyhow::anyhow!("Error deleting {}: {}", path.to_string_lossy(), e))
+}
+
+fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, sha256_of, test_invoice};
+    use std::str::FromStr;
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("one.txt", b"one"),
+        ("two.txt", b"two"),
+        ("three.txt", b"three"),
+    ];
+
+    fn source_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in FILES {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+        dir
+    }
+
+    fn invoice_of(id: &str, names: &[&str]) -> Invoice {
+        let parcels = FILES
+            .iter()
+            .filter(|(name, _)| names.contains(name))
+            .map(|(name, content)| parcel_for(name, content))
+            .collect();
+        test_invoice(&bindle::Id::from_str(id).unwrap(), parcels)
+    }
+
+    fn parcel_file(dest: &Path, invoice: &Invoice, content: &[u8]) -> PathBuf {
+        dest.join(invoice.bindle.id.sha())
+            .join("parcels")
+            .join(format!("{}.dat", sha256_of(content)))
+    }
+
+    async fn export(
+        source: &Path,
+        dest: &Path,
+        invoice: &Invoice,
+        policy: OverwritePolicy,
+    ) -> anyhow::Result<()> {
+        BindleWriter::new(source, dest)
+            .with_overwrite_policy(policy)
+            .write(invoice)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_previous_export_is_updated_in_place() {
+        let source = source_dir();
+        let dest = tempfile::tempdir().unwrap();
+        let first = invoice_of("export/me/1.0.0", &["one.txt", "two.txt"]);
+        export(
+            source.path(),
+            dest.path(),
+            &first,
+            OverwritePolicy::PreviousExportOnly,
+        )
+        .await
+        .unwrap();
+
+        let second = invoice_of("export/me/1.0.0", &["one.txt", "three.txt"]);
+        export(
+            source.path(),
+            dest.path(),
+            &second,
+            OverwritePolicy::PreviousExportOnly,
+        )
+        .await
+        .unwrap();
+
+        assert!(parcel_file(dest.path(), &second, b"one").is_file());
+        assert!(parcel_file(dest.path(), &second, b"three").is_file());
+        assert!(!parcel_file(dest.path(), &second, b"two").exists());
+        let bindle_dir = dest.path().join(second.bindle.id.sha());
+        assert_eq!(
+            "export/me/1.0.0",
+            std::fs::read_to_string(bindle_dir.join(EXPORT_MARKER)).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unrelated_files_are_refused_unless_forced() {
+        let source = source_dir();
+        let dest = tempfile::tempdir().unwrap();
+        let notes = dest.path().join("notes.txt");
+        std::fs::write(&notes, "mine").unwrap();
+        let other_bindle = invoice_of("someone/else/1.0.0", &["one.txt"]);
+        export(
+            source.path(),
+            dest.path(),
+            &other_bindle,
+            OverwritePolicy::Always,
+        )
+        .await
+        .unwrap();
+        let other_dir = dest.path().join(other_bindle.bindle.id.sha());
+
+        let invoice = invoice_of("export/me/1.0.0", &["one.txt"]);
+        let err = export(
+            source.path(),
+            dest.path(),
+            &invoice,
+            OverwritePolicy::PreviousExportOnly,
+        )
+        .await
+        .unwrap_err();
+        let err = err.downcast_ref::<DestinationNotEmpty>().unwrap();
+        let mut unrelated = err.unrelated.clone();
+        unrelated.sort();
+        let mut expected = vec![notes.clone(), other_dir.clone()];
+        expected.sort();
+        assert_eq!(expected, unrelated);
+        assert!(notes.is_file());
+        assert!(!dest.path().join(invoice.bindle.id.sha()).exists());
+
+        export(source.path(), dest.path(), &invoice, OverwritePolicy::Force)
+            .await
+            .unwrap();
+        assert!(!notes.exists());
+        assert!(!other_dir.exists());
+        assert!(parcel_file(dest.path(), &invoice, b"one").is_file());
+    }
+
+    #[tokio::test]
+    async fn test_unmarked_export_of_the_bindle_is_updated_in_place() {
+        let source = source_dir();
+        let dest = tempfile::tempdir().unwrap();
+        let first = invoice_of("export/me/1.0.0", &["one.txt", "two.txt"]);
+        export(source.path(), dest.path(), &first, OverwritePolicy::Always)
+            .await
+            .unwrap();
+        // As staged by a version of Hippo which didn't mark complete exports
+        let bindle_dir = dest.path().join(first.bindle.id.sha());
+        std::fs::remove_file(bindle_dir.join(EXPORT_MARKER)).unwrap();
+
+        let second = invoice_of("export/me/1.0.0", &["one.txt", "three.txt"]);
+        export(
+            source.path(),
+            dest.path(),
+            &second,
+            OverwritePolicy::PreviousExportOnly,
+        )
+        .await
+        .unwrap();
+        assert!(parcel_file(dest.path(), &second, b"three").is_file());
+        assert!(!parcel_file(dest.path(), &second, b"two").exists());
+        assert!(bindle_dir.join(EXPORT_MARKER).is_file());
+
+        // An unmarked export of another bindle is still refused
+        let other = invoice_of("someone/else/1.0.0", &["one.txt"]);
+        std::fs::remove_file(bindle_dir.join(EXPORT_MARKER)).unwrap();
+        let err = export(
+            source.path(),
+            dest.path(),
+            &other,
+            OverwritePolicy::PreviousExportOnly,
+        )
+        .await
+        .unwrap_err();
+        let err = err.downcast_ref::<DestinationNotEmpty>().unwrap();
+        assert_eq!(vec![bindle_dir], err.incomplete);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_export_is_not_a_previous_export() {
+        let source = source_dir();
+        let dest = tempfile::tempdir().unwrap();
+        let invoice = invoice_of("export/me/1.0.0", &["one.txt", "two.txt"]);
+        export(
+            source.path(),
+            dest.path(),
+            &invoice,
+            OverwritePolicy::PreviousExportOnly,
+        )
+        .await
+        .unwrap();
+        // An export which stopped part way through writing the parcels
+        let bindle_dir = dest.path().join(invoice.bindle.id.sha());
+        std::fs::remove_file(bindle_dir.join(EXPORT_MARKER)).unwrap();
+        std::fs::remove_file(bindle_dir.join("invoice.toml")).unwrap();
+
+        let err = export(
+            source.path(),
+            dest.path(),
+            &invoice,
+            OverwritePolicy::PreviousExportOnly,
+        )
+        .await
+        .unwrap_err();
+        let err = err.downcast_ref::<DestinationNotEmpty>().unwrap();
+        assert_eq!(vec![bindle_dir.clone()], err.incomplete);
+        assert!(err.to_string().contains("incomplete export"));
+
+        export(source.path(), dest.path(), &invoice, OverwritePolicy::Clean)
+            .await
+            .unwrap();
+        assert!(bindle_dir.join(EXPORT_MARKER).is_file());
+        assert!(bindle_dir.join("invoice.toml").is_file());
+    }
+}
@@ -0,0 +1,226 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use futures::StreamExt;
+
+use crate::hippofacts::HippoFacts;
+
+/// The number of lines of a failed build's error output to show.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Whether and how to run build commands before building a bindle.
+pub struct BuildOptions {
+    pub skip_build: bool,
+    /// Run the build commands, but don't build a bindle.
+    pub build_only: bool,
+    /// The most build commands to run at once.
+    pub jobs: usize,
+}
+
+pub struct BuildHook {
+    pub handler_name: String,
+    pub command: String,
+}
+
+/// The build commands for the handlers in a HIPPOFACTS file, in file order.
+pub fn build_hooks(hippofacts: &HippoFacts) -> Vec<BuildHook> {
+    hippofacts
+        .entries
+        .iter()
+        .filter_map(|e| {
+            e.build().map(|b| BuildHook {
+                handler_name: e.handler_name(),
+                command: b.command.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Runs build commands in `working_dir`, up to `jobs` at a time. This stops
+/// starting new commands after one fails, and reports the failure.
+pub async fn run_build_hooks(
+    hooks: Vec<BuildHook>,
+    working_dir: &Path,
+    jobs: usize,
+) -> anyhow::Result<()> {
+    let mut results = futures::stream::iter(hooks)
+        .map(|hook| {
+            let working_dir = working_dir.to_path_buf();
+            tokio::task::spawn_blocking(move || run_build_hook(&hook, &working_dir))
+        })
+        .buffered(jobs.max(1));
+    while let Some(result) = results.next().await {
+        result??;
+    }
+    Ok(())
+}
+
+fn run_build_hook(hook: &BuildHook, working_dir: &Path) -> anyhow::Result<()> {
+    eprintln!("building {}: {}", hook.handler_name, hook.command);
+    let output = shell_command(&hook.command)
+        .current_dir(working_dir)
+        .env("HIPPO_HANDLER_NAME", &hook.handler_name)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Could not run build command for {}: {}",
+                hook.handler_name,
+                e
+            )
+        })?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        eprint!("{}", stderr);
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Build command for {} failed ({}):\n{}",
+            hook.handler_name,
+            output.status,
+            tail(&stderr, STDERR_TAIL_LINES)
+        ))
+    }
+}
+
+/// Runs `command` with `input` on its standard input, and returns what it
+/// writes to standard output.
+pub(crate) fn pipe_through(command: &str, input: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let mut child = shell_command(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Could not run '{}': {}", command, e))?;
+    // Write from another thread so that a command which fills its output
+    // before reading all its input can't deadlock.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    let written = writer.join().unwrap();
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "'{}' failed ({}):\n{}",
+            command,
+            output.status,
+            tail(&String::from_utf8_lossy(&output.stderr), STDERR_TAIL_LINES)
+        ));
+    }
+    written?;
+    Ok(output.stdout)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(target_os = "windows")]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+fn tail(text: &str, lines: usize) -> String {
+    let all_lines: Vec<_> = text.trim_end().lines().collect();
+    let start = all_lines.len().saturating_sub(lines);
+    all_lines[start..].join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(not(target_os = "windows"))]
+    const RECORD_ENVIRONMENT: &str =
+        "echo \"$HIPPO_HANDLER_NAME $HIPPO_BUILD_HOOK_TEST\" > \"built-$HIPPO_HANDLER_NAME.txt\"";
+    #[cfg(target_os = "windows")]
+    const RECORD_ENVIRONMENT: &str =
+        "echo %HIPPO_HANDLER_NAME% %HIPPO_BUILD_HOOK_TEST%> built-%HIPPO_HANDLER_NAME%.txt";
+
+    #[cfg(not(target_os = "windows"))]
+    const FAIL: &str = "echo warming up >&2; echo error: mismatched types >&2; exit 3";
+    #[cfg(target_os = "windows")]
+    const FAIL: &str = "(echo warming up& echo error: mismatched types) 1>&2 & exit /b 3";
+
+    fn hook(handler_name: &str, command: &str) -> BuildHook {
+        BuildHook {
+            handler_name: handler_name.to_owned(),
+            command: command.to_owned(),
+        }
+    }
+
+    fn read_trimmed(path: impl AsRef<Path>) -> String {
+        std::fs::read_to_string(path).unwrap().trim().to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_build_hooks_run_in_dir_with_handler_name_and_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_var("HIPPO_BUILD_HOOK_TEST", "passed-through");
+
+        let hooks = vec![
+            hook("penguin", RECORD_ENVIRONMENT),
+            hook("cassowary", RECORD_ENVIRONMENT),
+        ];
+        run_build_hooks(hooks, dir.path(), 2).await.unwrap();
+
+        assert_eq!(
+            "penguin passed-through",
+            read_trimmed(dir.path().join("built-penguin.txt"))
+        );
+        assert_eq!(
+            "cassowary passed-through",
+            read_trimmed(dir.path().join("built-cassowary.txt"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_build_hook_reports_stderr_and_stops_later_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let hooks = vec![hook("penguin", FAIL), hook("cassowary", RECORD_ENVIRONMENT)];
+        let err = run_build_hooks(hooks, dir.path(), 1)
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("penguin"), "names the handler: '{}'", err);
+        assert!(
+            err.contains("error: mismatched types"),
+            "includes stderr: '{}'",
+            err
+        );
+        assert!(!dir.path().join("built-cassowary.txt").exists());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_pipe_through_returns_command_output() {
+        let output = pipe_through("tr a-z A-Z", b"penguin".to_vec()).unwrap();
+        assert_eq!(b"PENGUIN", &output[..]);
+    }
+
+    #[test]
+    fn test_failed_pipe_through_reports_stderr() {
+        let err = pipe_through(FAIL, b"penguin".to_vec())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("error: mismatched types"),
+            "includes stderr: '{}'",
+            err
+        );
+    }
+
+    #[test]
+    fn test_tail_keeps_last_lines() {
+        assert_eq!("three\nfour", tail("one\ntwo\nthree\nfour\n", 2));
+        assert_eq!("one", tail("one", 5));
+    }
+}
@@ -0,0 +1,253 @@
+//! Pinning the Bindle server's certificate, so that a certificate issued by a
+//! compromised CA is refused even though the system trusts it. A pin is the
+//! SHA-256 digest of the certificate's DER-encoded SubjectPublicKeyInfo, in
+//! hex, as printed by e.g.
+//!
+//! ```text
+//! openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | sha256sum
+//! ```
+
+use sha2::{Digest, Sha256};
+
+/// Checks a pin's format, returning it in the form `spki_sha256` produces.
+/// Colons between the hex digit pairs are allowed.
+pub fn parse_pin(text: &str) -> anyhow::Result<String> {
+    let pin = text.replace(':', "").to_lowercase();
+    if pin.len() == 64 && pin.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(pin)
+    } else {
+        Err(anyhow::anyhow!(
+            "Invalid certificate pin '{}': it must be the 64 hex digits of a SHA-256 digest",
+            text
+        ))
+    }
+}
+
+/// A TLS configuration which accepts the server's certificate only if it has
+/// one of the pinned keys. The certificate must also pass the usual validation
+/// against the system's trusted roots. The check is made during each
+/// handshake, so a client with this configuration can't reach a server whose
+/// key is not pinned.
+pub fn pinned_tls_config(pins: &[String]) -> anyhow::Result<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    let certificates = rustls_native_certs::load_native_certs()
+        .map_err(|e| anyhow::anyhow!("Error loading the system's trusted certificates: {}", e))?;
+    for certificate in certificates {
+        // A root which can't be parsed can't be the one which issued the
+        // server's certificate, so it is skipped rather than failing.
+        let _ = roots.add(&rustls::Certificate(certificate.0));
+    }
+    Ok(tls_config(pins, roots))
+}
+
+fn tls_config(pins: &[String], roots: rustls::RootCertStore) -> rustls::ClientConfig {
+    let verifier = PinningVerifier {
+        pins: pins.to_vec(),
+        inner: rustls::client::WebPkiVerifier::new(roots, None),
+    };
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(std::sync::Arc::new(verifier))
+        .with_no_client_auth()
+}
+
+struct PinningVerifier {
+    pins: Vec<String>,
+    inner: rustls::client::WebPkiVerifier,
+}
+
+impl rustls::client::ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        // The pin is checked first, so that a mismatch is reported as such
+        // even if the certificate would fail validation too.
+        let observed =
+            spki_sha256(&end_entity.0).map_err(|e| rustls::Error::General(e.to_string()))?;
+        if !self.pins.contains(&observed) {
+            return Err(rustls::Error::General(format!(
+                "Certificate pin mismatch: the server's key has SPKI SHA-256 {}, which is not pinned",
+                observed
+            )));
+        }
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// The SHA-256 digest, in hex, of the SubjectPublicKeyInfo of a DER-encoded
+/// X.509 certificate.
+pub fn spki_sha256(certificate_der: &[u8]) -> anyhow::Result<String> {
+    let spki = subject_public_key_info(certificate_der)
+        .ok_or_else(|| anyhow::anyhow!("The server's certificate could not be parsed"))?;
+    Ok(format!("{:x}", Sha256::digest(spki)))
+}
+
+// Certificate ::= SEQUENCE { tbsCertificate, ... }
+// TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature,
+//     issuer, validity, subject, subjectPublicKeyInfo, ... }
+fn subject_public_key_info(certificate_der: &[u8]) -> Option<&[u8]> {
+    const CONTEXT_0: u8 = 0xa0;
+    let certificate = read_element(certificate_der)?;
+    let tbs_certificate = read_element(certificate.content)?;
+    let mut fields = tbs_certificate.content;
+    let first = read_element(fields)?;
+    if first.tag == CONTEXT_0 {
+        fields = first.rest;
+    }
+    for _ in 0..5 {
+        fields = read_element(fields)?.rest;
+    }
+    Some(read_element(fields)?.whole)
+}
+
+struct DerElement<'a> {
+    tag: u8,
+    content: &'a [u8],
+    /// The element including its tag and length.
+    whole: &'a [u8],
+    rest: &'a [u8],
+}
+
+fn read_element(data: &[u8]) -> Option<DerElement<'_>> {
+    let tag = *data.first()?;
+    let first_length_byte = *data.get(1)? as usize;
+    let (length, header_length) = if first_length_byte < 0x80 {
+        (first_length_byte, 2)
+    } else {
+        let count = first_length_byte & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let length = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | *b as usize);
+        (length, 2 + count)
+    };
+    let end = header_length.checked_add(length)?;
+    Some(DerElement {
+        tag,
+        content: data.get(header_length..end)?,
+        whole: data.get(..end)?,
+        rest: data.get(end..)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{http_response, serve_tls, TLS_CERTIFICATE, TLS_CERTIFICATE_PIN};
+
+    /// A client which trusts the test server's self-signed certificate, and
+    /// accepts the given pins.
+    fn client_trusting_test_server(pins: &[String]) -> reqwest::Client {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(TLS_CERTIFICATE.to_vec()))
+            .unwrap();
+        reqwest::Client::builder()
+            .use_preconfigured_tls(tls_config(pins, roots))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_spki_digest_matches_openssl() {
+        assert_eq!(TLS_CERTIFICATE_PIN, spki_sha256(TLS_CERTIFICATE).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_certificate_is_an_error() {
+        assert!(spki_sha256(&TLS_CERTIFICATE[..100]).is_err());
+        assert!(spki_sha256(&[]).is_err());
+    }
+
+    #[test]
+    fn test_pins_are_normalised() {
+        let with_colons = TLS_CERTIFICATE_PIN
+            .to_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(TLS_CERTIFICATE_PIN, parse_pin(&with_colons).unwrap());
+        assert!(parse_pin("269d1cae").is_err());
+        assert!(parse_pin(&TLS_CERTIFICATE_PIN.replace('a', "g")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_with_pinned_key_is_accepted() {
+        let url = serve_tls(http_response("200 OK", "")).await;
+        let client = client_trusting_test_server(&[TLS_CERTIFICATE_PIN.to_owned()]);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    async fn test_server_without_pinned_key_is_refused_with_its_digest() {
+        let url = serve_tls(http_response("200 OK", "")).await;
+        let client = client_trusting_test_server(&["00".repeat(32)]);
+        let error = client.get(&url).send().await.unwrap_err();
+        let message = format!("{:?}", anyhow::Error::new(error));
+        assert!(message.contains("pin mismatch"), "{}", message);
+        assert!(message.contains(TLS_CERTIFICATE_PIN), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_key_does_not_replace_validation() {
+        // The pin matches, but nothing the client trusts issued the certificate.
+        let url = serve_tls(http_response("200 OK", "")).await;
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls(tls_config(
+                &[TLS_CERTIFICATE_PIN.to_owned()],
+                rustls::RootCertStore::empty(),
+            ))
+            .build()
+            .unwrap();
+        let error = client.get(&url).send().await.unwrap_err();
+        let message = format!("{:?}", anyhow::Error::new(error));
+        assert!(!message.contains("pin mismatch"), "{}", message);
+        assert!(message.contains("UnknownIssuer"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_every_connection_to_the_server_checks_the_pins() {
+        use crate::bindle_events::EventSource;
+
+        let url = serve_tls(http_response("200 OK", "")).await;
+        let connection = crate::bindle_utils::BindleConnectionInfo::new(&url, false, None, None)
+            .with_pinned_certificates(vec!["00".repeat(32)])
+            .unwrap();
+        let id: bindle::Id = "birds/1.0.0".parse().unwrap();
+
+        let error = connection
+            .client()
+            .unwrap()
+            .get_invoice(&id)
+            .await
+            .unwrap_err();
+        let message = format!("{:?}", anyhow::Error::new(error));
+        assert!(message.contains("pin mismatch"), "{}", message);
+
+        let error = match connection.event_source().unwrap().connect().await {
+            Ok(_) => panic!("expected the event stream to be refused"),
+            Err(e) => e,
+        };
+        let message = format!("{:?}", error);
+        assert!(message.contains("pin mismatch"), "{}", message);
+    }
+}
@@ -0,0 +1,133 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use std::convert::TryFrom;
+
+use crate::bindle_utils::{BindleConnectionInfo, BindleSource};
+use crate::parcel_layout::ParcelLayout;
+use crate::parcel_store::{parse_size, DeltaReport, ParcelStore};
+use crate::report_format::{render, ReportFormat, REPORT_FORMATS};
+
+pub(crate) const CMD_CACHE: &str = "cache";
+pub(crate) const CMD_CACHE_GC: &str = "gc";
+pub(crate) const CMD_CACHE_DELTA: &str = "delta";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_MAX_SIZE: &str = "max_size";
+const ARG_CACHE_LAYOUT: &str = "cache_layout";
+const ARG_OUTPUT: &str = "output";
+
+/// The top-level subcommand for `hippo cache`
+pub(crate) struct CacheSubcommand;
+
+#[async_trait]
+impl super::CommandRunner for CacheSubcommand {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_CACHE)
+            .about("Manage the shared parcel cache used by --parcel-cache")
+            .subcommand(CacheGc::app())
+            .subcommand(CacheDelta::app())
+    }
+
+    async fn run(&self, matches: &ArgMatches) -> anyhow::Result<()> {
+        match matches.subcommand() {
+            Some((CMD_CACHE_GC, args)) => {
+                let cmd = CacheGc;
+                cmd.run(args).await
+            }
+            Some((CMD_CACHE_DELTA, args)) => {
+                let cmd = CacheDelta;
+                cmd.run(args).await
+            }
+            Some((cmd, _)) => anyhow::bail!("Unknown subcommand: {}", cmd),
+            None => anyhow::bail!(
+                "Use one of the subcommands, such as 'hippo cache gc'. Try 'hippo cache --help'."
+            ),
+        }
+    }
+}
+
+/// The subcommand for `hippo cache gc`
+pub(crate) struct CacheGc;
+
+#[async_trait]
+impl super::CommandRunner for CacheGc {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_CACHE_GC)
+            .about("Removes the least recently used parcels from the cache")
+            .arg(
+                Arg::new(ARG_MAX_SIZE)
+                    .takes_value(true)
+                    .value_name("SIZE")
+                    .long("max-size")
+                    .required(true)
+                    .about("The size to reduce the cache to, e.g. '5G' or '500M'"),
+            )
+            .arg(cache_layout_arg())
+            .arg(output_arg())
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let max_size = parse_size(args.value_of(ARG_MAX_SIZE).unwrap())?;
+        let layout = ParcelLayout::parse(args.value_of(ARG_CACHE_LAYOUT).unwrap())?;
+        let output_format = ReportFormat::parse(args.value_of(ARG_OUTPUT).unwrap())?;
+        let store = ParcelStore::open_default()?.with_layout(layout);
+        let report = store.gc(max_size)?;
+        println!("{}", render(&report, output_format)?);
+        Ok(())
+    }
+}
+
+/// The subcommand for `hippo cache delta`
+pub(crate) struct CacheDelta;
+
+#[async_trait]
+impl super::CommandRunner for CacheDelta {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_CACHE_DELTA)
+            .about("Lists the parcels of a bindle on the Bindle server which are not in the cache, i.e. what upgrading to it would download")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required(true)
+                    .index(1)
+                    .about("The bindle to compare with the cache, e.g. 'myapp/2.0.0'"),
+            )
+            .arg(cache_layout_arg())
+            .arg(output_arg())
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let layout = ParcelLayout::parse(args.value_of(ARG_CACHE_LAYOUT).unwrap())?;
+        let output_format = ReportFormat::parse(args.value_of(ARG_OUTPUT).unwrap())?;
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+        let invoice = client.fetch_invoice(&bindle_id).await?;
+        let store = ParcelStore::open_default()?.with_layout(layout);
+        let report = DeltaReport::new(&invoice, &store);
+        println!("{}", render(&report, output_format)?);
+        Ok(())
+    }
+}
+
+fn cache_layout_arg<'a>() -> Arg<'a> {
+    Arg::new(ARG_CACHE_LAYOUT)
+        .long("cache-layout")
+        .env("HIPPO_CACHE_LAYOUT")
+        .takes_value(true)
+        .value_name("LAYOUT")
+        .default_value("nested")
+        .about(
+            "How files are laid out in the parcel cache, as given to --cache-layout when staging",
+        )
+}
+
+fn output_arg<'a>() -> Arg<'a> {
+    Arg::new(ARG_OUTPUT)
+        .possible_values(REPORT_FORMATS)
+        .default_value("text")
+        .short('o')
+        .long("output")
+        .about("How to print the report")
+}
@@ -0,0 +1,92 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use std::convert::TryFrom;
+
+use crate::bindle_utils::BindleConnectionInfo;
+use crate::report_format::{render, ReportFormat, REPORT_FORMATS};
+use crate::standalone::{compare_standalone, StandaloneBindle};
+
+pub(crate) const CMD_COMPARE: &str = "compare";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_DIR: &str = "dir";
+const ARG_CLOSURE_ONLY: &str = "closure_only";
+const ARG_OUTPUT: &str = "output";
+
+/// The subcommand for `hippo bindle compare`
+pub(crate) struct BindleCompare;
+
+#[async_trait]
+impl super::CommandRunner for BindleCompare {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_COMPARE)
+            .about("Checks that a bindle on disk, such as one written by 'hippo prepare', matches the copy on the Bindle server")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required(true)
+                    .index(1)
+                    .about("The bindle to compare, e.g. 'myapp/1.0.0'"),
+            )
+            .arg(
+                Arg::new(ARG_DIR)
+                    .required(true)
+                    .short('d')
+                    .long("dir")
+                    .takes_value(true)
+                    .about("The directory holding the bindle, in the standalone layout"),
+            )
+            .arg(
+                Arg::new(ARG_CLOSURE_ONLY)
+                    .long("closure-only")
+                    .about("Only fail if the bindle on disk needs different parcels, or has corrupt ones, ignoring parcel names and optional parcels, e.g. to check that a rebuild produced the same application"),
+            )
+            .arg(
+                Arg::new(ARG_OUTPUT)
+                    .possible_values(REPORT_FORMATS)
+                    .default_value("text")
+                    .short('o')
+                    .long("output")
+                    .about("How to print the report"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let output_format = ReportFormat::parse(args.value_of(ARG_OUTPUT).unwrap())?;
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+
+        let local = StandaloneBindle::read(args.value_of(ARG_DIR).unwrap(), &bindle_id).await?;
+        let report = compare_standalone(&local, &client, &bindle_id).await?;
+        println!("{}", render(&report, output_format)?);
+
+        if args.is_present(ARG_CLOSURE_ONLY) {
+            if report.is_closure_match() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!(
+                    "Bindle {} on disk is not the same application as the server's copy: {} parcels corrupt{}",
+                    bindle_id,
+                    report.corrupt.len(),
+                    if report.closure_matches {
+                        ""
+                    } else {
+                        ", and it needs different parcels"
+                    }
+                ))
+            }
+        } else if report.is_match() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Bindle {} on disk does not match the server's copy: {} parcels missing, {} extra, {} corrupt",
+                bindle_id,
+                report.missing.len(),
+                report.extra.len(),
+                report.corrupt.len()
+            ))
+        }
+    }
+}
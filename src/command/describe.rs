@@ -0,0 +1,215 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use itertools::Itertools;
+use std::convert::TryFrom;
+
+use crate::bindle_utils::{combined_closure, BindleConnectionInfo, BindleSource, InvoiceHelpers};
+use crate::invoice_summary::summarize_invoice;
+
+pub(crate) const CMD_DESCRIBE: &str = "describe";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_HOST_VERSION: &str = "host_version";
+const ARG_ACTIVATION_FOR: &str = "activation_for";
+const ARG_DEPLOYED_WITH: &str = "deployed_with";
+
+/// The subcommand for `hippo bindle describe`
+pub(crate) struct BindleDescribe;
+
+#[async_trait]
+impl super::CommandRunner for BindleDescribe {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_DESCRIBE)
+            .about("Summarises a bindle on the Bindle server: its parcels, groups and entrypoint")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required(true)
+                    .index(1)
+                    .about("The bindle to describe, e.g. 'myapp/1.0.0'"),
+            )
+            .arg(
+                Arg::new(ARG_HOST_VERSION)
+                    .long("host-version")
+                    .takes_value(true)
+                    .value_name("VERSION")
+                    .about("Fail if the bindle can't run on this version of the host, e.g. before deploying it"),
+            )
+            .arg(
+                Arg::new(ARG_ACTIVATION_FOR)
+                    .long("activation-for")
+                    .takes_value(true)
+                    .value_name("PARCEL")
+                    .about("Also show the fewest groups to activate for the bindle to include this parcel, by name"),
+            )
+            .arg(
+                Arg::new(ARG_DEPLOYED_WITH)
+                    .long("deployed-with")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .number_of_values(1)
+                    .value_name("BINDLE_ID")
+                    .about("Also show what deploying the bindle together with this one would download, counting shared parcels once"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let host_version = args
+            .value_of(ARG_HOST_VERSION)
+            .map(|v| {
+                semver::Version::parse(v)
+                    .map_err(|e| anyhow::anyhow!("Invalid --host-version '{}': {}", v, e))
+            })
+            .transpose()?;
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+
+        let invoice = client.fetch_invoice(&bindle_id).await?;
+        println!("{}", summarize_invoice(&invoice));
+        if let Some(name) = args.value_of(ARG_ACTIVATION_FOR) {
+            println!("{}", activation_for(&invoice, name)?);
+        }
+        if let Some(others) = args.values_of(ARG_DEPLOYED_WITH) {
+            let mut invoices = vec![invoice.clone()];
+            for other in others {
+                let other = bindle::Id::try_from(other)?;
+                invoices.push(client.fetch_invoice(&other).await?);
+            }
+            println!("{}", deployment_of(&invoices.iter().collect_vec()));
+        }
+
+        match host_version {
+            Some(host_version) if !invoice.is_compatible_with(&host_version) => {
+                Err(anyhow::anyhow!(
+                    "Bindle {} can't run on host version {}: it needs {}",
+                    bindle_id,
+                    host_version,
+                    invoice
+                        .host_requirement()
+                        .map(|r| r.to_string())
+                        .unwrap_or_else(|| "a valid host version requirement".to_owned())
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Describes the groups to activate for `invoice` to include the parcel named
+/// `name`.
+fn activation_for(invoice: &bindle::Invoice, name: &str) -> anyhow::Result<String> {
+    let parcel = invoice
+        .parcel
+        .iter()
+        .flatten()
+        .find(|p| p.label.name == name)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Bindle {} has no parcel named '{}'",
+                invoice.bindle.id,
+                name
+            )
+        })?;
+    let groups = invoice.minimal_activation_for(parcel)?;
+    Ok(match groups.split_first() {
+        None => format!("{} is always included", name),
+        Some((group, [])) => format!("to include {}, activate the group '{}'", name, group),
+        Some((group, activated)) => format!(
+            "to include {}, activate the group '{}', which activates {}",
+            name,
+            group,
+            activated.join(", ")
+        ),
+    })
+}
+
+/// Describes what deploying `invoices` together would download.
+fn deployment_of(invoices: &[&bindle::Invoice]) -> String {
+    let combined = combined_closure(invoices);
+    let combined_bytes: u64 = combined.iter().map(|p| p.label.size).sum();
+    let separate_bytes: u64 = invoices
+        .iter()
+        .flat_map(|invoice| invoice.required_closure())
+        .map(|p| p.label.size)
+        .sum();
+    format!(
+        "deployed with {}: {} parcels ({} bytes), saving {} bytes of shared parcels",
+        invoices[1..].iter().map(|i| &i.bindle.id).join(", "),
+        combined.len(),
+        combined_bytes,
+        separate_bytes - combined_bytes
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, test_invoice};
+    use std::str::FromStr;
+
+    fn in_group(name: &str, member_of: &str, requires: Option<&str>) -> bindle::Parcel {
+        let mut parcel = parcel_for(name, name.as_bytes());
+        parcel.conditions = Some(bindle::Condition {
+            member_of: Some(vec![member_of.to_owned()]),
+            requires: requires.map(|r| vec![r.to_owned()]),
+        });
+        parcel
+    }
+
+    #[test]
+    fn test_activation_for_names_the_group_to_choose_first() {
+        let id = bindle::Id::from_str("nested/1.0.0").unwrap();
+        let mut invoice = test_invoice(
+            &id,
+            vec![
+                parcel_for("main.wasm", b"main"),
+                in_group("gate.dat", "outer", Some("inner")),
+                in_group("deep.dat", "inner", None),
+            ],
+        );
+        invoice.group = Some(vec![bindle::Group {
+            name: "outer".to_owned(),
+            required: None,
+            satisfied_by: None,
+        }]);
+
+        assert_eq!(
+            "to include deep.dat, activate the group 'outer', which activates inner",
+            activation_for(&invoice, "deep.dat").unwrap()
+        );
+        assert_eq!(
+            "to include gate.dat, activate the group 'outer'",
+            activation_for(&invoice, "gate.dat").unwrap()
+        );
+        assert_eq!(
+            "main.wasm is always included",
+            activation_for(&invoice, "main.wasm").unwrap()
+        );
+        assert!(activation_for(&invoice, "lost.dat").is_err());
+    }
+
+    #[test]
+    fn test_deployment_counts_shared_parcels_once() {
+        let first = test_invoice(
+            &bindle::Id::from_str("first/1.0.0").unwrap(),
+            vec![
+                parcel_for("first.wasm", b"first"),
+                parcel_for("shared.css", b"shared"),
+            ],
+        );
+        let second = test_invoice(
+            &bindle::Id::from_str("second/1.0.0").unwrap(),
+            vec![
+                parcel_for("second.wasm", b"second"),
+                parcel_for("style.css", b"shared"),
+            ],
+        );
+
+        assert_eq!(
+            "deployed with second/1.0.0: 3 parcels (17 bytes), saving 6 bytes of shared parcels",
+            deployment_of(&[&first, &second])
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use std::convert::TryFrom;
+use std::path::Path;
+
+use crate::bindle_exporter::export_closure;
+use crate::bindle_fetcher::FetchOptions;
+use crate::bindle_utils::BindleConnectionInfo;
+use crate::parcel_layout::ParcelLayout;
+
+pub(crate) const CMD_EXPORT: &str = "export";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_TO: &str = "to";
+const ARG_LAYOUT: &str = "layout";
+
+/// The subcommand for `hippo bindle export`
+pub(crate) struct BindleExport;
+
+#[async_trait]
+impl super::CommandRunner for BindleExport {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_EXPORT)
+            .about("Writes a bindle on the Bindle server, and the parcels it always needs, to a tar archive")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required(true)
+                    .index(1)
+                    .about("The bindle to export, e.g. 'myapp/1.0.0'"),
+            )
+            .arg(
+                Arg::new(ARG_TO)
+                    .required(true)
+                    .long("to")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .about("The tar archive to write"),
+            )
+            .arg(
+                Arg::new(ARG_LAYOUT)
+                    .long("layout")
+                    .takes_value(true)
+                    .value_name("LAYOUT")
+                    .default_value("flat")
+                    .about("How parcels are laid out under 'parcels/' in the archive: 'flat', 'nested', or a template such as '{shard1}/{sha256}.bin'"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let layout = ParcelLayout::parse(args.value_of(ARG_LAYOUT).unwrap())?;
+        let to = args.value_of(ARG_TO).unwrap();
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+
+        // Write under a temporary name so that a failed export never leaves
+        // an archive which looks complete.
+        let partial = format!("{}.partial", to);
+        let file = std::fs::File::create(&partial)
+            .map_err(|e| anyhow::anyhow!("Error creating {}: {}", partial, e))?;
+        let scratch_dir = format!("{}.parcels", partial);
+        std::fs::create_dir_all(&scratch_dir)?;
+        let exported = export_closure(
+            &client,
+            &bindle_id,
+            &FetchOptions::default(),
+            &layout,
+            file,
+            Path::new(&scratch_dir),
+        )
+        .await;
+        let _ = std::fs::remove_dir_all(&scratch_dir);
+        let summary = match exported {
+            Ok(summary) => summary,
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+        std::fs::rename(&partial, to)?;
+
+        println!(
+            "exported: {} ({} parcels, {} distinct bodies) to {}",
+            bindle_id,
+            summary.parcel_references.len(),
+            summary.bodies_written.len(),
+            to
+        );
+        Ok(())
+    }
+}
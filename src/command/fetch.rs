@@ -0,0 +1,210 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use colored::Colorize;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bindle_fetcher::{BatchStrategy, FetchOptions, ParcelTransforms, VerifyPolicy};
+use crate::bindle_utils::{BindleConnectionInfo, BindleSource};
+use crate::download_session::DownloadSession;
+use crate::parcel_store::parse_size;
+use crate::report_format::{render, ReportFormat, REPORT_FORMATS};
+
+pub(crate) const CMD_FETCH: &str = "fetch";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_DIR: &str = "dir";
+const ARG_HANDLER: &str = "handler";
+const ARG_GROUP: &str = "group";
+const ARG_PROFILE: &str = "profile";
+const ARG_VERIFY: &str = "verify";
+const ARG_TRANSFORM: &str = "transform";
+const ARG_BATCH_SIZE: &str = "batch_size";
+const ARG_BATCH_BYTES: &str = "batch_bytes";
+const ARG_BATCH_PAUSE: &str = "batch_pause";
+const ARG_OUTPUT: &str = "output";
+
+/// The subcommand for `hippo bindle fetch`
+pub(crate) struct BindleFetch;
+
+#[async_trait]
+impl super::CommandRunner for BindleFetch {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_FETCH)
+            .about("Downloads the parcels which a bindle on the Bindle server always needs into a directory, resuming an interrupted download")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required(true)
+                    .index(1)
+                    .about("The bindle to download, e.g. 'myapp/1.0.0'"),
+            )
+            .arg(
+                Arg::new(ARG_DIR)
+                    .required(true)
+                    .short('d')
+                    .long("dir")
+                    .takes_value(true)
+                    .about("The directory to download the parcels to, each as <sha256>.dat"),
+            )
+            .arg(
+                Arg::new(ARG_HANDLER)
+                    .long("handler")
+                    .takes_value(true)
+                    .about("Download only this handler and the parcels it requires, e.g. to preview it"),
+            )
+            .arg(
+                Arg::new(ARG_GROUP)
+                    .long("group")
+                    .takes_value(true)
+                    .conflicts_with_all(&[ARG_HANDLER, ARG_PROFILE])
+                    .about("Download only the parcels in this group and the parcels they require"),
+            )
+            .arg(
+                Arg::new(ARG_PROFILE)
+                    .long("profile")
+                    .takes_value(true)
+                    .conflicts_with_all(&[ARG_HANDLER, ARG_GROUP])
+                    .about("Also download the groups which this activation profile, from the bindle's hippo_profile_<name> annotation, activates"),
+            )
+            .arg(
+                Arg::new(ARG_VERIFY)
+                    .long("verify")
+                    .possible_values(&["always", "missing-only", "never"])
+                    .default_value("always")
+                    .about("When to check parcel files against their digests: 'missing-only' trusts files already downloaded if their size is right, and 'never' is for emergencies"),
+            )
+            .arg(
+                Arg::new(ARG_TRANSFORM)
+                    .long("transform")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .number_of_values(1)
+                    .value_name("MEDIA_TYPE=COMMAND")
+                    .about("Pipe parcels of this media type through a shell command once they are verified, e.g. 'application/x-brotli=brotli -d'"),
+            )
+            .arg(
+                Arg::new(ARG_BATCH_SIZE)
+                    .long("batch-size")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .conflicts_with_all(&[ARG_BATCH_BYTES, ARG_HANDLER, ARG_GROUP, ARG_PROFILE])
+                    .about("Download the parcels this many at a time, pausing between batches, for servers which limit how fast parcels may be fetched"),
+            )
+            .arg(
+                Arg::new(ARG_BATCH_BYTES)
+                    .long("batch-bytes")
+                    .takes_value(true)
+                    .value_name("SIZE")
+                    .conflicts_with_all(&[ARG_BATCH_SIZE, ARG_HANDLER, ARG_GROUP, ARG_PROFILE])
+                    .about("Download at most this many bytes of parcels at a time, e.g. '50M', pausing between batches"),
+            )
+            .arg(
+                Arg::new(ARG_BATCH_PAUSE)
+                    .long("batch-pause")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .default_value("1")
+                    .about("How long to pause between batches, with --batch-size or --batch-bytes"),
+            )
+            .arg(
+                Arg::new(ARG_OUTPUT)
+                    .possible_values(REPORT_FORMATS)
+                    .default_value("text")
+                    .short('o')
+                    .long("output")
+                    .about("How to print the report"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let output_format = ReportFormat::parse(args.value_of(ARG_OUTPUT).unwrap())?;
+        let options = FetchOptions {
+            verify: VerifyPolicy::parse(args.value_of(ARG_VERIFY).unwrap())?,
+            transforms: Arc::new(transforms_from_args(args)?),
+            ..FetchOptions::default()
+        };
+        options.verify.warn_if_disabled();
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+
+        let invoice = client.fetch_invoice(&bindle_id).await?;
+        let (mut session, warnings) =
+            DownloadSession::open(args.value_of(ARG_DIR).unwrap(), &invoice)?.into();
+        for warning in warnings {
+            eprintln!("{}", format!("warning: {}", warning).yellow());
+        }
+        let report = if let Some(handler) = args.value_of(ARG_HANDLER) {
+            let report = session
+                .download_handler(&client, &invoice, handler, &options)
+                .await?;
+            render(&report, output_format)?
+        } else if let Some(group) = args.value_of(ARG_GROUP) {
+            let report = session
+                .download_group(&client, &invoice, group, &options)
+                .await?;
+            render(&report, output_format)?
+        } else if let Some(profile) = args.value_of(ARG_PROFILE) {
+            let report = session
+                .download_profile(&client, &invoice, profile, &options)
+                .await?;
+            render(&report, output_format)?
+        } else if let Some(strategy) = batch_strategy_from_args(args)? {
+            let pause = batch_pause_from_args(args)?;
+            let report = session
+                .download_in_batches(&client, &invoice, strategy, pause, &options)
+                .await?;
+            render(&report, output_format)?
+        } else {
+            let report = session.download(&client, &invoice, &options).await?;
+            render(&report, output_format)?
+        };
+        println!("{}", report);
+        Ok(())
+    }
+}
+
+fn transforms_from_args(args: &ArgMatches) -> anyhow::Result<ParcelTransforms> {
+    let mut transforms = ParcelTransforms::default();
+    for transform in args.values_of(ARG_TRANSFORM).into_iter().flatten() {
+        let (media_type, command) = match transform.split_once('=') {
+            Some((media_type, command)) if !media_type.is_empty() && !command.is_empty() => {
+                (media_type, command.to_owned())
+            }
+            _ => anyhow::bail!(
+                "Transform '{}' should be of the form MEDIA_TYPE=COMMAND",
+                transform
+            ),
+        };
+        transforms = transforms.with_transform(media_type, move |content| {
+            crate::build_hooks::pipe_through(&command, content)
+        });
+    }
+    Ok(transforms)
+}
+
+fn batch_strategy_from_args(args: &ArgMatches) -> anyhow::Result<Option<BatchStrategy>> {
+    if let Some(count) = args.value_of(ARG_BATCH_SIZE) {
+        let count = count
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| anyhow::anyhow!("--batch-size must be a positive number of parcels"))?;
+        Ok(Some(BatchStrategy::MaxCount(count)))
+    } else if let Some(size) = args.value_of(ARG_BATCH_BYTES) {
+        Ok(Some(BatchStrategy::MaxBytes(parse_size(size)?)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn batch_pause_from_args(args: &ArgMatches) -> anyhow::Result<Duration> {
+    args.value_of(ARG_BATCH_PAUSE)
+        .unwrap()
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|_| anyhow::anyhow!("--batch-pause must be a number of seconds"))
+}
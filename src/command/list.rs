@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use futures::StreamExt;
+
+use crate::bindle_search::stream_invoices;
+use crate::bindle_utils::BindleConnectionInfo;
+
+pub(crate) const CMD_LIST: &str = "list";
+
+const ARG_QUERY: &str = "query";
+
+/// The subcommand for `hippo bindle list`
+pub(crate) struct BindleList;
+
+#[async_trait]
+impl super::CommandRunner for BindleList {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_LIST)
+            .about("Lists the bindles on the Bindle server, a page at a time")
+            .arg(
+                Arg::new(ARG_QUERY)
+                    .index(1)
+                    .default_value("")
+                    .about("List only the bindles whose names contain this text"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let query = args.value_of(ARG_QUERY).unwrap();
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+
+        let mut invoices = Box::pin(stream_invoices(&client, query));
+        while let Some(summary) = invoices.next().await {
+            let summary = summary?;
+            let yanked = if summary.yanked { " [yanked]" } else { "" };
+            match summary.description {
+                Some(description) => println!(
+                    "{} ({} parcels){}: {}",
+                    summary.id, summary.parcel_count, yanked, description
+                ),
+                None => println!(
+                    "{} ({} parcels){}",
+                    summary.id, summary.parcel_count, yanked
+                ),
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,22 @@
 use async_trait::async_trait;
 use clap::{App, ArgMatches};
 
+use crate::report_format::ReportFormat;
+
+pub(crate) mod cache;
+pub(crate) mod compare;
+pub(crate) mod describe;
+pub(crate) mod export;
+pub(crate) mod fetch;
+pub(crate) mod list;
 pub(crate) mod newhippo;
+pub(crate) mod orphans;
+pub(crate) mod ping;
+pub(crate) mod register;
 pub(crate) mod upload;
+pub(crate) mod vendor;
+pub(crate) mod verify;
+pub(crate) mod watch;
 
 /// A command runner is capabile of running particular subcommand.
 ///
@@ -23,10 +37,28 @@ pub fn apps<'a>() -> Vec<App<'a>> {
         upload::Push::app(),
         upload::Bindle::app(),
         upload::Prepare::app(),
+        register::Register::app(),
         newhippo::NewSubcommand::app(),
+        cache::CacheSubcommand::app(),
     ]
 }
 
+/// The format of the command's output if it was asked for JSON or YAML, in
+/// which case a failure is reported in that format too.
+pub fn structured_output(name: &str, args: &ArgMatches) -> Option<ReportFormat> {
+    let output = match name {
+        upload::CMD_BINDLE | upload::CMD_PREPARE | upload::CMD_PUSH | register::CMD_REGISTER => {
+            args.value_of(upload::ARG_OUTPUT)
+        }
+        _ => None,
+    };
+    match output {
+        Some("json") => Some(ReportFormat::Json),
+        Some("yaml") => Some(ReportFormat::Yaml),
+        _ => None,
+    }
+}
+
 /// Execute the apps.
 pub async fn exec(name: &str, args: &ArgMatches) -> anyhow::Result<()> {
     match name {
@@ -42,6 +74,10 @@ pub async fn exec(name: &str, args: &ArgMatches) -> anyhow::Result<()> {
             let cmd = upload::Push {};
             cmd.run(args).await
         }
+        register::CMD_REGISTER => {
+            let cmd = register::Register {};
+            cmd.run(args).await
+        }
         newhippo::CMD_NEW_HIPPO => {
             let cmd = newhippo::NewHippofacts {};
             cmd.run(args).await
@@ -50,6 +86,10 @@ pub async fn exec(name: &str, args: &ArgMatches) -> anyhow::Result<()> {
             let cmd = newhippo::NewSubcommand {};
             cmd.run(args).await
         }
+        cache::CMD_CACHE => {
+            let cmd = cache::CacheSubcommand {};
+            cmd.run(args).await
+        }
         _ => anyhow::bail!("Unknown command: {}", name),
     }
 }
@@ -85,6 +85,8 @@ impl super::CommandRunner for NewHippofacts {
             route: "/".to_owned(),
             files: None,
             external: None,
+            build: None,
+            file_rules: None,
         };
 
         // if dir is a directory, join with HIPPOFACTS. Otherwise, use it as a file name.
@@ -111,6 +113,7 @@ impl super::CommandRunner for NewHippofacts {
                 description: None,
                 authors: author,
             },
+            channel: None,
             annotations: None,
             export: None,
             handler: Some(vec![handler]),
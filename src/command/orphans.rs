@@ -0,0 +1,107 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use futures::TryStreamExt;
+use std::convert::TryFrom;
+
+use crate::bindle_search::stream_full_invoices;
+use crate::bindle_utils::{orphaned_by_removal, BindleConnectionInfo, BindleSource};
+
+pub(crate) const CMD_ORPHANS: &str = "orphans";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+
+/// The subcommand for `hippo bindle orphans`
+pub(crate) struct BindleOrphans;
+
+#[async_trait]
+impl super::CommandRunner for BindleOrphans {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_ORPHANS)
+            .about("Lists the parcels which no other bindle on the Bindle server refers to, i.e. which removing this bindle would leave unused")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required(true)
+                    .index(1)
+                    .about("The bindle to be removed, e.g. 'myapp/1.0.0'"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+        println!("{}", describe_orphans(&client, &bindle_id).await?);
+        Ok(())
+    }
+}
+
+/// Describes the parcels which removing the bindle would leave unused, by
+/// comparing it with every bindle the server lists.
+async fn describe_orphans(
+    source: &(impl BindleSource + Sync),
+    bindle_id: &bindle::Id,
+) -> anyhow::Result<String> {
+    let removing = source.fetch_invoice(bindle_id).await?;
+    let all: Vec<_> = stream_full_invoices(source, "").try_collect().await?;
+    let orphaned = orphaned_by_removal(&all, &removing);
+    let size_of = |sha256: &str| {
+        removing
+            .parcel
+            .iter()
+            .flatten()
+            .find(|p| p.label.sha256 == sha256)
+            .map(|p| p.label.size)
+            .unwrap_or_default()
+    };
+    let bytes: u64 = orphaned.iter().map(|sha256| size_of(sha256)).sum();
+    let mut description = format!(
+        "removing {} would leave {} parcels ({} bytes) unused",
+        bindle_id,
+        orphaned.len(),
+        bytes
+    );
+    for sha256 in &orphaned {
+        description.push_str(&format!("\n  {}", sha256));
+    }
+    Ok(description)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, sha256_of, test_invoice, FakeBindleServer};
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_orphans_are_the_parcels_no_other_bindle_lists() {
+        let old = bindle::Id::from_str("birds/1.0.0").unwrap();
+        let new = bindle::Id::from_str("birds/2.0.0").unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(test_invoice(
+                &old,
+                vec![
+                    parcel_for("birds.wasm", b"birds v1"),
+                    parcel_for("logo.png", b"logo"),
+                ],
+            ))
+            .with_invoice(test_invoice(
+                &new,
+                vec![
+                    parcel_for("birds.wasm", b"birds v2"),
+                    parcel_for("logo.png", b"logo"),
+                ],
+            ));
+
+        let description = describe_orphans(&server, &old).await.unwrap();
+
+        assert_eq!(
+            format!(
+                "removing birds/1.0.0 would leave 1 parcels (8 bytes) unused\n  {}",
+                sha256_of(b"birds v1")
+            ),
+            description
+        );
+    }
+}
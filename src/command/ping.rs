@@ -0,0 +1,30 @@
+use async_trait::async_trait;
+use clap::{App, ArgMatches};
+
+use crate::bindle_utils::BindleConnectionInfo;
+
+pub(crate) const CMD_PING: &str = "ping";
+
+/// The subcommand for `hippo bindle ping`
+pub(crate) struct BindlePing;
+
+#[async_trait]
+impl super::CommandRunner for BindlePing {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_PING)
+            .about("Checks that the Bindle server can be reached and accepts the credentials given")
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let connection = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?;
+
+        let info = connection.ping().await?;
+        match info.version {
+            Some(version) => println!("Bindle server {} is available ({})", info.url, version),
+            None => println!("Bindle server {} is available", info.url),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use colored::Colorize;
+use std::convert::TryFrom;
+
+use crate::bindle_utils::{BindleConnectionInfo, BindleSource};
+use crate::hippofacts::ChannelSpec;
+
+use super::upload::{OutputFormat, PushOutput};
+
+pub(crate) const CMD_REGISTER: &str = "register";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_APP: &str = "app";
+const ARG_CHANNEL: &str = "channel";
+const ARG_DOMAIN: &str = "domain";
+const ARG_OUTPUT: &str = "output_format";
+
+/// Register a bindle which is already on the Bindle server with Hippo, e.g. one
+/// pushed by `hippo push --bindle-only`.
+pub(crate) struct Register;
+
+#[async_trait]
+impl super::CommandRunner for Register {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_REGISTER)
+            .about("Registers a bindle already on the Bindle server with Hippo")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .long("bindle-id")
+                    .takes_value(true)
+                    .required(true)
+                    .about("The bindle to register, e.g. 'myapp/1.0.0'"),
+            )
+            .arg(
+                Arg::new(ARG_APP)
+                    .long("app")
+                    .takes_value(true)
+                    .required(true)
+                    .about("The application to register the bindle with. Hippo identifies applications by bindle name, so this must be the bindle's name"),
+            )
+            .arg(
+                Arg::new(ARG_CHANNEL)
+                    .long("channel")
+                    .takes_value(true)
+                    .about("The channel whose domain to set, as in the HIPPOFACTS channel section"),
+            )
+            .arg(
+                Arg::new(ARG_DOMAIN)
+                    .long("domain")
+                    .takes_value(true)
+                    .requires(ARG_CHANNEL)
+                    .about("The domain to serve the channel on"),
+            )
+            .arg(
+                Arg::new(ARG_OUTPUT)
+                    .possible_values(&["id", "message", "json", "yaml", "none"])
+                    .default_value("message")
+                    .required(false)
+                    .short('o')
+                    .long("output")
+                    .about("What to print on success"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+            .args(super::upload::hippo_connection_args(false))
+            .args(super::upload::registration_args())
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        check_app_name(&bindle_id, args.value_of(ARG_APP).unwrap())?;
+        let channel = args.value_of(ARG_CHANNEL).map(|name| ChannelSpec {
+            name: name.to_owned(),
+            domain: args.value_of(ARG_DOMAIN).map(|d| d.to_owned()),
+        });
+        let output_format = OutputFormat::parse(args.value_of(ARG_OUTPUT).unwrap());
+
+        // Hippo would register a revision for a bindle that isn't there, and
+        // only fail when it came to serve it.
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+        let invoice = client.fetch_invoice(&bindle_id).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Can't register {}: it is not available on the Bindle server: {}",
+                bindle_id,
+                e
+            )
+        })?;
+
+        let hippo_connection = super::upload::hippo_connection_from_args(args)?;
+        let registration_options = super::upload::registration_options_from_args(args);
+        let (_, warnings) = crate::hippo_notifier::register(
+            &invoice,
+            channel.as_ref(),
+            &hippo_connection,
+            &registration_options,
+        )
+        .await?
+        .into();
+        for warning in &warnings {
+            eprintln!("{}", format!("warning: {}", warning).yellow());
+        }
+
+        match output_format {
+            OutputFormat::None => (),
+            OutputFormat::Id => println!("{}", &bindle_id),
+            OutputFormat::Message => println!("registered: {}", &bindle_id),
+            OutputFormat::Json | OutputFormat::Yaml => PushOutput {
+                bindle_id: bindle_id.to_string(),
+                pushed: true,
+                registered: true,
+                ..PushOutput::default()
+            }
+            .print(&output_format)?,
+        }
+        Ok(())
+    }
+}
+
+fn check_app_name(bindle_id: &bindle::Id, app: &str) -> anyhow::Result<()> {
+    if bindle_id.name() == app {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Bindle {} does not belong to app {}: Hippo identifies an app by its bindle name, {}",
+            bindle_id,
+            app,
+            bindle_id.name()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::CommandRunner;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_app_must_be_the_bindle_name() {
+        let id = bindle::Id::from_str("birds/1.4.2").unwrap();
+        assert!(check_app_name(&id, "birds").is_ok());
+        let err = check_app_name(&id, "fish").unwrap_err().to_string();
+        assert!(err.contains("fish"), "names the app: '{}'", err);
+    }
+
+    #[test]
+    fn test_domain_needs_a_channel() {
+        let argv = vec![
+            "register",
+            "--bindle-id",
+            "birds/1.4.2",
+            "--app",
+            "birds",
+            "-s",
+            "http://bindle.test",
+            "--hippo-url",
+            "http://hippo.test",
+            "--domain",
+            "birds.example.com",
+        ];
+        assert!(Register::app().try_get_matches_from(argv).is_err());
+    }
+}
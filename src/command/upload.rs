@@ -1,27 +1,93 @@
 use async_trait::async_trait;
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use colored::Colorize;
 use itertools::Itertools;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use crate::bindle_utils::BindleConnectionInfo;
-use crate::bindle_writer::BindleWriter;
+use crate::annotation_schema::{validate_annotations, AnnotationSchema};
+use crate::bindle_pusher::UploadOptions;
+use crate::bindle_search::QueryUnsupported;
+use crate::bindle_utils::{
+    empty_files, omit_empty_files, resolve_invoice, validate_group_names, BindleConnectionInfo,
+    BindleSource, InvoiceHelpers, YankedPolicy,
+};
+use crate::bindle_writer::{BindleWriter, OverwritePolicy};
+use crate::build_hooks::{build_hooks, run_build_hooks, BuildOptions};
+use crate::command::compare::{BindleCompare, CMD_COMPARE};
+use crate::command::describe::{BindleDescribe, CMD_DESCRIBE};
+use crate::command::export::{BindleExport, CMD_EXPORT};
+use crate::command::fetch::{BindleFetch, CMD_FETCH};
+use crate::command::list::{BindleList, CMD_LIST};
+use crate::command::orphans::{BindleOrphans, CMD_ORPHANS};
+use crate::command::ping::{BindlePing, CMD_PING};
+use crate::command::vendor::{BindleVendor, CMD_VENDOR};
+use crate::command::verify::{BindleVerify, CMD_VERIFY};
+use crate::command::watch::{BindleWatch, CMD_WATCH};
+use crate::credential_helper::{CredentialHelper, HelperCredentials};
 use crate::expander::{ExpansionContext, InvoiceVersioning};
-use crate::hippo_notifier::ConnectionInfo;
-use crate::hippofacts::{HippoFacts, HippoFactsEntry};
+use crate::expansion_plan::ExpansionPlan;
+use crate::hippo_notifier::{ConnectionInfo, HippoCredentials, RegistrationOptions};
+use crate::hippofacts::{HandlerSelection, HippoFacts, HippoFactsEntry};
+use crate::invoice_diff::{compare_invoices, PushConflict};
+use crate::parcel_layout::ParcelLayout;
+use crate::parcel_store::{parse_size, ParcelStore};
+use crate::path_mapping;
+use crate::profiles::{Profile, ProfileAuth, Profiles};
+use crate::report_format::{render, ReportFormat};
+use crate::retry::RetryPolicy;
+use crate::sbom::{write_sbom, SbomFormat, SbomOptions};
+use crate::templating::TemplateVariables;
 
 const ARG_HIPPOFACTS: &str = "hippofacts_path";
 const ARG_STAGING_DIR: &str = "output_dir";
-const ARG_OUTPUT: &str = "output_format";
+pub(crate) const ARG_OUTPUT: &str = "output_format";
 const ARG_VERSIONING: &str = "versioning";
 const ARG_BINDLE_URL: &str = "bindle_server";
 const ARG_BINDLE_USERNAME: &str = "bindle_username";
 const ARG_BINDLE_PASSWORD: &str = "bindle_password";
+const ARG_BINDLE_CREDENTIALS_FILE: &str = "bindle_credentials_file";
+const ARG_BINDLE_CREDENTIAL_HELPER: &str = "bindle_credential_helper";
+const ARG_BINDLE_PROFILE: &str = "bindle_profile";
+const ARG_BINDLE_PROFILES_FILE: &str = "bindle_profiles_file";
 const ARG_HIPPO_URL: &str = "hippo_url";
 const ARG_HIPPO_USERNAME: &str = "hippo_username";
 const ARG_HIPPO_PASSWORD: &str = "hippo_password";
+const ARG_HIPPO_TOKEN: &str = "hippo_token";
+const ARG_HIPPO_CREDENTIAL_HELPER: &str = "hippo_credential_helper";
 const ARG_INSECURE: &str = "insecure";
+const ARG_PIN_CERT: &str = "pin_cert_sha256";
+const ARG_RE_REGISTER: &str = "re_register";
+const ARG_NO_APPLY_DOMAIN: &str = "no_apply_domain";
+const ARG_CHECK_REMOTE_ROUTES: &str = "check_remote_routes";
+const ARG_MAX_RETRY_WAIT: &str = "max_retry_wait";
+const ARG_HANDLER: &str = "handler";
+const ARG_ONLY_ROUTE: &str = "only_route";
+const ARG_NO_PARTIAL_TAG: &str = "no_partial_tag";
+const ARG_PARCEL_CACHE: &str = "parcel_cache";
+const ARG_CACHE_LAYOUT: &str = "cache_layout";
+const ARG_SKIP_BUILD: &str = "skip_build";
+const ARG_BUILD_ONLY: &str = "build_only";
+const ARG_BUILD_JOBS: &str = "build_jobs";
+const ARG_SET: &str = "set";
+const ARG_VALUES: &str = "values";
+const ARG_TEMPLATE_BINDLE_NAME: &str = "template_bindle_name";
+const ARG_EMIT_PLAN: &str = "emit_plan";
+const ARG_FROM_PLAN: &str = "from_plan";
+const ARG_YANKED_REFERENCES: &str = "yanked_references";
+const ARG_STRICT_VERSION_ORDERING: &str = "strict_version_ordering";
+const ARG_BINDLE_ONLY: &str = "bindle_only";
+const ARG_UPLOAD_CHUNK_SIZE: &str = "upload_chunk_size";
+const ARG_ANNOTATION_SCHEMA: &str = "annotation_schema";
+const ARG_IDEMPOTENT: &str = "idempotent";
+const ARG_EMIT_SBOM: &str = "emit_sbom";
+const ARG_SBOM_FORMAT: &str = "sbom_format";
+const ARG_SKIP_EMPTY_FILES: &str = "skip_empty_files";
+const ARG_FORCE: &str = "force";
+const ARG_CLEAN: &str = "clean";
+#[cfg(feature = "negotiate-proxy")]
+const ARG_NEGOTIATE_PROXY: &str = "negotiate_proxy";
 
 pub(crate) const CMD_BINDLE: &str = "bindle";
 pub(crate) const CMD_PUSH: &str = "push";
@@ -41,7 +107,7 @@ fn common_args<'a>(cmd: &str) -> Vec<Arg<'a>> {
         _ => (false, false),
     };
 
-    vec![
+    let mut args = vec![
         Arg::new(ARG_HIPPOFACTS)
             .required(true)
             .index(1)
@@ -54,19 +120,178 @@ fn common_args<'a>(cmd: &str) -> Vec<Arg<'a>> {
             .long("invoice-version")
             .about("How to version the generated invoice"),
         Arg::new(ARG_OUTPUT)
-            .possible_values(&["id", "message", "none"])
+            .possible_values(&["id", "message", "json", "yaml", "none"])
             .default_value("message")
             .required(false)
             .short('o')
             .long("output")
             .about("What to print on success"),
-        Arg::new(ARG_BINDLE_URL)
-            .short('s')
-            .long("server")
-            .env("BINDLE_URL")
+        Arg::new(ARG_STAGING_DIR)
+            .takes_value(true)
+            .short('d')
+            .long("dir")
+            .about("The path to output the artifacts to. Required when doing a `hippo prepare`. Other commands will use a temp dir if this is not specified.")
+            .required(require_stage_dir),
+        Arg::new(ARG_HANDLER)
+            .long("handler")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .number_of_values(1)
+            .about("Build only the given handler and its files (may be repeated)"),
+        Arg::new(ARG_ONLY_ROUTE)
+            .long("only-route")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .number_of_values(1)
+            .about("Build only the handler for the given route and its files (may be repeated)"),
+        Arg::new(ARG_NO_PARTIAL_TAG)
+            .long("no-partial-tag")
+            .takes_value(false)
+            .about("Don't add a -partial tag to the version when building only some handlers"),
+        Arg::new(ARG_PARCEL_CACHE)
+            .long("parcel-cache")
+            .takes_value(false)
+            .about("Stage parcels via the shared parcel cache, so that parcels used by several applications are stored only once"),
+        Arg::new(ARG_CACHE_LAYOUT)
+            .long("cache-layout")
+            .env("HIPPO_CACHE_LAYOUT")
+            .takes_value(true)
+            .value_name("LAYOUT")
+            .default_value("nested")
+            .about("How files are laid out in the parcel cache: 'flat', 'nested', or a template such as '{shard1}/{sha256}.dat'"),
+        Arg::new(ARG_SKIP_BUILD)
+            .long("skip-build")
+            .takes_value(false)
+            .about("Don't run the handlers' build commands")
+            .conflicts_with(ARG_BUILD_ONLY),
+        Arg::new(ARG_BUILD_ONLY)
+            .long("build-only")
+            .takes_value(false)
+            .about("Run the handlers' build commands, but don't build or push a bindle"),
+        Arg::new(ARG_BUILD_JOBS)
+            .long("build-jobs")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("1")
+            .about("The number of handlers' build commands to run at once"),
+        Arg::new(ARG_SET)
+            .long("set")
+            .takes_value(true)
+            .value_name("NAME=VALUE")
+            .multiple_occurrences(true)
+            .number_of_values(1)
+            .about("A value for {{var.NAME}} in the HIPPOFACTS file (may be repeated; overrides --values)"),
+        Arg::new(ARG_VALUES)
+            .long("values")
+            .takes_value(true)
+            .value_name("FILE")
+            .about("A TOML file of values for {{var.NAME}} in the HIPPOFACTS file"),
+        Arg::new(ARG_TEMPLATE_BINDLE_NAME)
+            .long("template-bindle-name")
+            .takes_value(false)
+            .about("Substitute variables in the bindle name too"),
+        Arg::new(ARG_EMIT_PLAN)
+            .long("emit-plan")
+            .takes_value(true)
+            .value_name("FILE")
+            .about("Write the plan for the bindle to a JSON file, without hashing, staging or pushing anything")
+            .conflicts_with(ARG_FROM_PLAN),
+        Arg::new(ARG_FROM_PLAN)
+            .long("from-plan")
             .takes_value(true)
-            .about("The Bindle server to push the artifacts to")
-            .required(require_bindle_server),
+            .value_name("FILE")
+            .about("Build the bindle from a plan written by --emit-plan, instead of from the HIPPOFACTS file. Build commands are not run"),
+        Arg::new(ARG_YANKED_REFERENCES)
+            .long("yanked-references")
+            .takes_value(true)
+            .possible_values(&["reject", "warn", "allow"])
+            .default_value("reject")
+            .about("What to do if an external reference is to a yanked bindle"),
+        Arg::new(ARG_ANNOTATION_SCHEMA)
+            .long("annotation-schema")
+            .env("HIPPO_ANNOTATION_SCHEMA")
+            .takes_value(true)
+            .value_name("FILE")
+            .about("A JSON file of annotation conventions which the bindle must follow: required annotations by media type, and patterns for their values"),
+        Arg::new(ARG_EMIT_SBOM)
+            .long("emit-sbom")
+            .takes_value(true)
+            .value_name("FILE")
+            .about("Write a software bill of materials for the bindle to a file"),
+        Arg::new(ARG_SBOM_FORMAT)
+            .long("sbom-format")
+            .takes_value(true)
+            .possible_values(&["cyclonedx", "spdx"])
+            .default_value("cyclonedx")
+            .about("The format of the --emit-sbom file"),
+        Arg::new(ARG_SKIP_EMPTY_FILES)
+            .long("skip-empty-files")
+            .takes_value(false)
+            .about("Leave empty asset files out of the bindle, for Bindle servers which reject empty parcels"),
+        Arg::new(ARG_FORCE)
+            .long("force")
+            .takes_value(false)
+            .requires(ARG_STAGING_DIR)
+            .about("Delete anything in the --dir directory which is not a previous export of the bindle"),
+        Arg::new(ARG_CLEAN)
+            .long("clean")
+            .takes_value(false)
+            .requires(ARG_STAGING_DIR)
+            .conflicts_with(ARG_FORCE)
+            .about("Empty the --dir directory before writing the bindle"),
+    ];
+    if cmd != CMD_PREPARE {
+        args.push(
+            Arg::new(ARG_STRICT_VERSION_ORDERING)
+                .long("strict-version-ordering")
+                .takes_value(false)
+                .about("Fail, instead of warning, if the version is not greater than the latest version of the bindle on the server"),
+        );
+        args.push(
+            Arg::new(ARG_UPLOAD_CHUNK_SIZE)
+                .long("upload-chunk-size")
+                .env("HIPPO_UPLOAD_CHUNK_SIZE")
+                .takes_value(true)
+                .value_name("SIZE")
+                .about("Stream parcels to the Bindle server in chunks of this size, e.g. '1M', retrying any which fail. Use this if a proxy drops long uploads"),
+        );
+        args.push(
+            Arg::new(ARG_IDEMPOTENT)
+                .long("idempotent")
+                .takes_value(false)
+                .about("If the Bindle server already has the bindle, succeed if it is the same as the local one, and report the differences if not"),
+        );
+    }
+    args.extend(bindle_connection_args(require_bindle_server));
+    args
+}
+
+// Arguments necessary to connect to a Bindle server
+// - ARG_BINDLE_URL
+// - ARG_BINDLE_USERNAME
+// - ARG_BINDLE_PASSWORD
+// - ARG_BINDLE_CREDENTIALS_FILE
+// - ARG_BINDLE_CREDENTIAL_HELPER
+// - ARG_BINDLE_PROFILE
+// - ARG_BINDLE_PROFILES_FILE
+// - ARG_INSECURE
+// - ARG_PIN_CERT
+// - ARG_MAX_RETRY_WAIT, which applies to Hippo too
+pub(crate) fn bindle_connection_args<'a>(require_bindle_server: bool) -> Vec<Arg<'a>> {
+    let bindle_url = Arg::new(ARG_BINDLE_URL)
+        .short('s')
+        .long("server")
+        .env("BINDLE_URL")
+        .takes_value(true)
+        .about("The Bindle server to use");
+    // A profile can give the server instead.
+    let bindle_url = if require_bindle_server {
+        bindle_url.required_unless_present(ARG_BINDLE_PROFILE)
+    } else {
+        bindle_url
+    };
+    let mut args = vec![
+        bindle_url,
         Arg::new(ARG_BINDLE_USERNAME)
             .long("bindle-username")
             .env("BINDLE_USERNAME")
@@ -79,18 +304,137 @@ fn common_args<'a>(cmd: &str) -> Vec<Arg<'a>> {
             .takes_value(true)
             .hide_env_values(true)
             .about("The username to log into Bindle"),
+        Arg::new(ARG_BINDLE_CREDENTIALS_FILE)
+            .long("bindle-credentials-file")
+            .env("BINDLE_CREDENTIALS_FILE")
+            .takes_value(true)
+            .about("A Docker-style credentials file in which to look up the Bindle server's credentials")
+            .conflicts_with(ARG_BINDLE_USERNAME),
+        Arg::new(ARG_BINDLE_CREDENTIAL_HELPER)
+            .long("bindle-credential-helper")
+            .env("BINDLE_CREDENTIAL_HELPER")
+            .takes_value(true)
+            .value_name("COMMAND")
+            .about("A command which prints the Bindle server's credentials, run with the arguments 'get <server URL>'")
+            .conflicts_with_all(&[ARG_BINDLE_USERNAME, ARG_BINDLE_CREDENTIALS_FILE]),
+        Arg::new(ARG_BINDLE_PROFILE)
+            .long("bindle-profile")
+            .env("BINDLE_PROFILE")
+            .takes_value(true)
+            .value_name("NAME")
+            .about("A saved profile of Bindle connection settings to use. Other Bindle options override the profile's settings"),
+        Arg::new(ARG_BINDLE_PROFILES_FILE)
+            .long("bindle-profiles-file")
+            .env(crate::profiles::PROFILES_FILE_VAR)
+            .takes_value(true)
+            .about("The file of saved Bindle profiles, if not ~/.hippo/profiles.toml. Used only with --bindle-profile"),
         Arg::new(ARG_INSECURE)
             .required(false)
             .takes_value(false)
             .short('k')
             .long("insecure")
-            .about("If set, ignore server certificate errors"),
-        Arg::new(ARG_STAGING_DIR)
+            .about("If set, ignore server certificate errors. A profile which sets 'insecure' can't be made secure from the command line"),
+        Arg::new(ARG_PIN_CERT)
+            .long("pin-cert-sha256")
             .takes_value(true)
-            .short('d')
-            .long("dir")
-            .about("The path to output the artifacts to. Required when doing a `hippo prepare`. Other commands will use a temp dir if this is not specified.")
-            .required(require_stage_dir),
+            .value_name("HEX")
+            .multiple_occurrences(true)
+            .number_of_values(1)
+            .conflicts_with(ARG_INSECURE)
+            .about("Connect only if the Bindle server's certificate has a key with this SPKI SHA-256 digest (may be repeated)"),
+        Arg::new(ARG_MAX_RETRY_WAIT)
+            .long("max-retry-wait")
+            .env("HIPPO_MAX_RETRY_WAIT")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .default_value("60")
+            .about("The longest to wait when the Bindle server or Hippo is overloaded and asks us to retry later"),
+    ];
+    args.extend(negotiate_proxy_args());
+    args
+}
+
+#[cfg(feature = "negotiate-proxy")]
+fn negotiate_proxy_args<'a>() -> Vec<Arg<'a>> {
+    vec![Arg::new(ARG_NEGOTIATE_PROXY)
+        .long("negotiate-proxy")
+        .env("BINDLE_NEGOTIATE_PROXY")
+        .takes_value(true)
+        .value_name("PROXY_URL")
+        .about("Send requests to the Bindle server through the HTTP proxy at this URL, authenticating to it using Negotiate (Kerberos)")]
+}
+
+#[cfg(not(feature = "negotiate-proxy"))]
+fn negotiate_proxy_args<'a>() -> Vec<Arg<'a>> {
+    vec![]
+}
+
+// Arguments necessary to connect to Hippo. If `allow_bindle_only` is set, the
+// Hippo URL is not required when ARG_BINDLE_ONLY is present.
+// - ARG_HIPPO_URL
+// - ARG_HIPPO_USERNAME
+// - ARG_HIPPO_PASSWORD
+// - ARG_HIPPO_TOKEN
+pub(crate) fn hippo_connection_args<'a>(allow_bindle_only: bool) -> Vec<Arg<'a>> {
+    let hippo_url = Arg::new(ARG_HIPPO_URL)
+        .long("hippo-url")
+        .takes_value(true)
+        .env("HIPPO_URL")
+        .about("The Hippo service to push the artifacts to");
+    let hippo_url = if allow_bindle_only {
+        hippo_url.required_unless_present(ARG_BINDLE_ONLY)
+    } else {
+        hippo_url.required(true)
+    };
+    vec![
+        hippo_url,
+        Arg::new(ARG_HIPPO_USERNAME)
+            .long("hippo-username")
+            .takes_value(true)
+            .env("HIPPO_USERNAME")
+            .about("The username for connecting to Hippo")
+            .requires(ARG_HIPPO_PASSWORD),
+        Arg::new(ARG_HIPPO_PASSWORD)
+            .long("hippo-password")
+            .env("HIPPO_PASSWORD")
+            .about("The username for connecting to Hippo")
+            .takes_value(true)
+            .hide_env_values(true)
+            .requires(ARG_HIPPO_USERNAME),
+        Arg::new(ARG_HIPPO_TOKEN)
+            .long("hippo-token")
+            .env("HIPPO_TOKEN")
+            .about("An API token for connecting to Hippo, instead of a username and password")
+            .takes_value(true)
+            .hide_env_values(true),
+        Arg::new(ARG_HIPPO_CREDENTIAL_HELPER)
+            .long("hippo-credential-helper")
+            .env("HIPPO_CREDENTIAL_HELPER")
+            .takes_value(true)
+            .value_name("COMMAND")
+            .about("A command which prints the Hippo credentials, run with the arguments 'get <Hippo URL>'")
+            .conflicts_with_all(&[ARG_HIPPO_TOKEN, ARG_HIPPO_USERNAME]),
+    ]
+}
+
+// Arguments controlling how a revision is registered with Hippo
+// - ARG_RE_REGISTER
+// - ARG_NO_APPLY_DOMAIN
+// - ARG_CHECK_REMOTE_ROUTES
+pub(crate) fn registration_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        Arg::new(ARG_RE_REGISTER)
+            .long("re-register")
+            .takes_value(false)
+            .about("Register the revision with Hippo even if Hippo already has it"),
+        Arg::new(ARG_NO_APPLY_DOMAIN)
+            .long("no-apply-domain")
+            .takes_value(false)
+            .about("Do not set the Hippo channel's domain from the HIPPOFACTS channel section"),
+        Arg::new(ARG_CHECK_REMOTE_ROUTES)
+            .long("check-remote-routes")
+            .takes_value(false)
+            .about("Before registering, check that no other application on the channel's domain serves the same routes"),
     ]
 }
 
@@ -103,30 +447,13 @@ impl super::CommandRunner for Push {
             .about("Packages and uploads Hippo artifacts, notifying Hippo")
             .alias("ship")
             .args(common_args(CMD_PUSH))
+            .args(hippo_connection_args(true))
+            .args(registration_args())
             .arg(
-                Arg::new(ARG_HIPPO_URL)
-                    .long("hippo-url")
-                    .takes_value(true)
-                    .env("HIPPO_URL")
-                    .about("The Hippo service to push the artifacts to")
-                    .required(true),
-            )
-            .arg(
-                Arg::new(ARG_HIPPO_USERNAME)
-                    .long("hippo-username")
-                    .takes_value(true)
-                    .env("HIPPO_USERNAME")
-                    .about("The username for connecting to Hippo")
-                    .required(true),
-            )
-            .arg(
-                Arg::new(ARG_HIPPO_PASSWORD)
-                    .long("hippo-password")
-                    .env("HIPPO_PASSWORD")
-                    .about("The username for connecting to Hippo")
-                    .takes_value(true)
-                    .hide_env_values(true)
-                    .required(true),
+                Arg::new(ARG_BINDLE_ONLY)
+                    .long("bindle-only")
+                    .takes_value(false)
+                    .about("Push the bindle, but don't register it with Hippo. Use `hippo register` to register it later"),
             )
     }
     /// Package a bindle and push it to a Bindle server, notifying Hippo.
@@ -145,26 +472,19 @@ impl super::CommandRunner for Push {
 
         // Bindle configuration
         let bindle_settings = BindleSettings::Push(
-            BindleConnectionInfo::from_args(args).ok_or_else(bindle_url_is_required)?,
+            BindleConnectionInfo::from_args(args)?.ok_or_else(bindle_url_is_required)?,
         );
 
-        // Hippo configuration
-        let hippo_url = args
-            .value_of(ARG_HIPPO_URL)
-            .map(|s| s.to_owned())
-            .ok_or_else(|| {
-                anyhow::anyhow!("A Hippo url is required. Use --hippo-url or $HIPPO_URL")
-            })?;
-        let hippo_username = args.value_of(ARG_HIPPO_USERNAME);
-        let hippo_password = args.value_of(ARG_HIPPO_PASSWORD);
-
-        // Notification configuration
-        let notify_to = Some(ConnectionInfo {
-            url: hippo_url,
-            danger_accept_invalid_certs: args.is_present(ARG_INSECURE),
-            username: hippo_username.unwrap().to_owned(), // Known to be set if the URL is
-            password: hippo_password.unwrap().to_owned(),
-        });
+        // Notification configuration. With --bindle-only, Hippo credentials
+        // aren't needed, so that the build stage of a pipeline needn't hold them.
+        let notify_to = if args.is_present(ARG_BINDLE_ONLY) {
+            None
+        } else {
+            Some((
+                hippo_connection_from_args(args)?,
+                registration_options_from_args(args),
+            ))
+        };
 
         run(
             &source,
@@ -172,6 +492,7 @@ impl super::CommandRunner for Push {
             invoice_versioning,
             output_format,
             bindle_settings,
+            run_options_from_args(CMD_PUSH, args)?,
             notify_to,
         )
         .await
@@ -185,7 +506,18 @@ impl super::CommandRunner for Bindle {
     fn app<'a>() -> App<'a> {
         App::new(CMD_BINDLE)
             .about("Creates a bindle and pushes it to the Bindle server, but does not notify Hippo")
+            .setting(AppSettings::SubcommandsNegateReqs)
             .args(common_args(CMD_BINDLE))
+            .subcommand(BindleVerify::app())
+            .subcommand(BindleFetch::app())
+            .subcommand(BindleDescribe::app())
+            .subcommand(BindlePing::app())
+            .subcommand(BindleWatch::app())
+            .subcommand(BindleVendor::app())
+            .subcommand(BindleCompare::app())
+            .subcommand(BindleExport::app())
+            .subcommand(BindleList::app())
+            .subcommand(BindleOrphans::app())
     }
     /// Run the bindle command
     ///
@@ -196,6 +528,20 @@ impl super::CommandRunner for Bindle {
     /// - ARG_OUTPUT
     /// - ARG_BINDLE_URL
     async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        match args.subcommand() {
+            Some((CMD_VERIFY, verify_args)) => return BindleVerify.run(verify_args).await,
+            Some((CMD_FETCH, fetch_args)) => return BindleFetch.run(fetch_args).await,
+            Some((CMD_DESCRIBE, describe_args)) => return BindleDescribe.run(describe_args).await,
+            Some((CMD_PING, ping_args)) => return BindlePing.run(ping_args).await,
+            Some((CMD_WATCH, watch_args)) => return BindleWatch.run(watch_args).await,
+            Some((CMD_VENDOR, vendor_args)) => return BindleVendor.run(vendor_args).await,
+            Some((CMD_COMPARE, compare_args)) => return BindleCompare.run(compare_args).await,
+            Some((CMD_EXPORT, export_args)) => return BindleExport.run(export_args).await,
+            Some((CMD_LIST, list_args)) => return BindleList.run(list_args).await,
+            Some((CMD_ORPHANS, orphans_args)) => return BindleOrphans.run(orphans_args).await,
+            _ => (),
+        }
+
         let source = hippofacts_file_path_from_args(args)?;
 
         let destination = match args.value_of(ARG_STAGING_DIR) {
@@ -206,7 +552,7 @@ impl super::CommandRunner for Bindle {
         let invoice_versioning = InvoiceVersioning::parse(args.value_of(ARG_VERSIONING).unwrap())?;
         let output_format = OutputFormat::parse(args.value_of(ARG_OUTPUT).unwrap());
         let bindle_settings = BindleSettings::Push(
-            BindleConnectionInfo::from_args(args).ok_or_else(bindle_url_is_required)?,
+            BindleConnectionInfo::from_args(args)?.ok_or_else(bindle_url_is_required)?,
         );
 
         run(
@@ -215,6 +561,7 @@ impl super::CommandRunner for Bindle {
             invoice_versioning,
             output_format,
             bindle_settings,
+            run_options_from_args(CMD_BINDLE, args)?,
             None, // `bindle` never notifies.
         )
         .await
@@ -253,7 +600,7 @@ impl super::CommandRunner for Prepare {
         let output_format = OutputFormat::parse(args.value_of(ARG_OUTPUT).unwrap());
 
         // NOTE: Prepare currently does not require a Bindle URL, so this could be NoPush(None)
-        let bindle_settings = BindleSettings::NoPush(BindleConnectionInfo::from_args(args));
+        let bindle_settings = BindleSettings::NoPush(BindleConnectionInfo::from_args(args)?);
 
         run(
             &source,
@@ -261,6 +608,7 @@ impl super::CommandRunner for Prepare {
             invoice_versioning,
             output_format,
             bindle_settings,
+            run_options_from_args(CMD_PREPARE, args)?,
             None, // Prepare never notifies.
         )
         .await
@@ -277,39 +625,180 @@ async fn run(
     invoice_versioning: InvoiceVersioning,
     output_format: OutputFormat,
     bindle_settings: BindleSettings,
-    notify_to: Option<ConnectionInfo>,
+    options: RunOptions,
+    notify_to: Option<(ConnectionInfo, RegistrationOptions)>,
 ) -> anyhow::Result<()> {
-    let spec = HippoFacts::read_from(&source)?;
-
+    let RunOptions {
+        overwrite_policy,
+        handler_selection,
+        template_variables,
+        build_options,
+        parcel_store,
+        plan_options,
+        annotation_schema,
+        sbom_options,
+        skip_empty_files,
+        strict_version_ordering,
+        upload_options,
+        idempotent,
+    } = options;
     let source_dir = source
         .as_ref()
         .parent()
         .ok_or_else(|| anyhow::Error::msg("Can't establish source directory"))?
         .to_path_buf();
 
-    // Do this outside the `expand` function so `expand` is more testable
-    let external_invoices =
-        prefetch_required_invoices(&spec, bindle_settings.connection_info()).await?;
+    // Development versions are unique to the user and time, so ordering them
+    // against other users' pushes would only be noise.
+    let check_version_ordering = matches!(invoice_versioning, InvoiceVersioning::Production);
+
+    let plan = match &plan_options.from_plan {
+        Some(plan_path) => ExpansionPlan::read_from(plan_path)?,
+        None => {
+            let spec = HippoFacts::read_from(&source, &template_variables)?;
+            let spec = match &handler_selection {
+                Some(selection) => spec.select(selection)?,
+                None => spec,
+            };
+
+            // Build modules before expanding so that we hash the freshly built ones
+            if !build_options.skip_build {
+                run_build_hooks(build_hooks(&spec), &source_dir, build_options.jobs).await?;
+            }
+            if build_options.build_only {
+                return Ok(());
+            }
+
+            // Do this outside the `expand` function so `expand` is more testable
+            let external_invoices = prefetch_required_invoices(
+                &spec,
+                bindle_settings.connection_info(),
+                plan_options.yanked_policy,
+            )
+            .await?;
+
+            let expansion_context = ExpansionContext {
+                relative_to: source_dir.clone(),
+                invoice_versioning,
+                external_invoices,
+            };
+
+            let (plan, warnings) =
+                crate::expander::expand_to_plan(&spec, &expansion_context)?.into();
+            for warning in &warnings {
+                eprintln!("{}", format!("warning: {}", warning).yellow());
+            }
+            plan
+        }
+    };
+
+    crate::debug_bundle::note_plan(&plan);
+
+    if let Some(plan_path) = &plan_options.emit_plan {
+        plan.write_to(plan_path)?;
+        match output_format {
+            OutputFormat::None => (),
+            OutputFormat::Id => println!("{}", &plan.bindle_id),
+            OutputFormat::Message => println!("plan: {}", plan_path.to_string_lossy()),
+            OutputFormat::Json | OutputFormat::Yaml => PushOutput {
+                bindle_id: plan.bindle_id.clone(),
+                plan: Some(plan_path.to_string_lossy().to_string()),
+                ..PushOutput::default()
+            }
+            .print(&output_format)?,
+        }
+        return Ok(());
+    }
 
-    let expansion_context = ExpansionContext {
-        relative_to: source_dir.clone(),
-        invoice_versioning,
-        external_invoices,
+    let invoice = plan.to_invoice(&source_dir)?;
+    let invoice = if skip_empty_files {
+        let (invoice, warnings) = omit_empty_files(invoice).into();
+        for warning in &warnings {
+            eprintln!("{}", format!("warning: {}", warning).yellow());
+        }
+        invoice
+    } else {
+        invoice
     };
+    let empty_files = empty_files(&invoice);
+    if !empty_files.is_empty() {
+        eprintln!(
+            "{}",
+            format!(
+                "warning: the bindle has {} empty file(s), which some Bindle servers reject; use --skip-empty-files to leave them out",
+                empty_files.len()
+            )
+            .yellow()
+        );
+    }
+
+    if let Err(problems) = validate_group_names(&invoice) {
+        for problem in &problems {
+            eprintln!("{}", format!("warning: {}", problem).yellow());
+        }
+    }
 
-    let (invoice, warnings) = crate::expander::expand(&spec, &expansion_context)?.into();
+    if let Some(schema) = &annotation_schema {
+        if let Err(violations) = validate_annotations(&invoice, schema) {
+            return Err(anyhow::anyhow!(
+                "The bindle does not follow the annotation schema:\n{}",
+                violations.iter().map(|v| format!("  {}", v)).join("\n")
+            ));
+        }
+    }
 
-    for warning in &warnings {
-        eprintln!("{}", format!("warning: {}", warning).yellow());
+    if notify_to.is_some() {
+        if let Err(problems) = invoice.validate_for_hippo() {
+            for problem in &problems {
+                eprintln!("{}", format!("warning: {}", problem).yellow());
+            }
+        }
     }
 
-    let writer = BindleWriter::new(&source_dir, &destination);
+    let writer =
+        BindleWriter::new(&source_dir, &destination).with_overwrite_policy(overwrite_policy);
+    let writer = match parcel_store {
+        Some(store) => writer.with_parcel_store(store),
+        None => writer,
+    };
     writer.write(&invoice).await?;
 
+    if let Some(sbom_options) = &sbom_options {
+        write_sbom(&invoice, sbom_options)?;
+    }
+
     if let BindleSettings::Push(bindle_connection) = &bindle_settings {
-        crate::bindle_pusher::push_all(&destination, &invoice.bindle.id, bindle_connection).await?;
-        if let Some(hippo_connection) = &notify_to {
-            crate::hippo_notifier::register(&invoice.bindle.id, hippo_connection).await?;
+        let already_pushed = idempotent && is_already_pushed(&invoice, bindle_connection).await?;
+        if !already_pushed {
+            if check_version_ordering {
+                check_version_is_latest(
+                    &invoice.bindle.id,
+                    bindle_connection,
+                    strict_version_ordering,
+                )
+                .await?;
+            }
+            crate::bindle_pusher::push_all(
+                &destination,
+                &invoice.bindle.id,
+                bindle_connection,
+                &upload_options,
+            )
+            .await
+            .map_err(|e| explain_push_failure(e, &empty_files))?;
+        }
+        if let Some((hippo_connection, registration_options)) = &notify_to {
+            let (_, registration_warnings) = crate::hippo_notifier::register(
+                &invoice,
+                plan.channel.as_ref(),
+                hippo_connection,
+                registration_options,
+            )
+            .await?
+            .into();
+            for warning in &registration_warnings {
+                eprintln!("{}", format!("warning: {}", warning).yellow());
+            }
         }
     }
 
@@ -328,25 +817,134 @@ async fn run(
                 );
             }
         },
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let staging_dir = match &bindle_settings {
+                BindleSettings::Push(_) => None,
+                BindleSettings::NoPush(_) => Some(
+                    dunce::canonicalize(&destination)?
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+            };
+            PushOutput {
+                bindle_id: invoice.bindle.id.to_string(),
+                pushed: matches!(bindle_settings, BindleSettings::Push(_)),
+                registered: notify_to.is_some(),
+                staging_dir,
+                plan: None,
+            }
+            .print(&output_format)?
+        }
     }
 
     Ok(())
 }
 
+/// Warn, or fail if `strict`, if the server already has a version of the bindle
+/// which is not lower than the one being pushed.
+async fn check_version_is_latest(
+    id: &bindle::Id,
+    bindle_connection: &BindleConnectionInfo,
+    strict: bool,
+) -> anyhow::Result<()> {
+    let client = bindle_connection.client()?;
+    check_version_is_latest_on(&client, id, strict).await
+}
+
+/// The check of `check_version_is_latest`, against any source. If the server
+/// has no query API, the versions on it can't be listed, so this warns that
+/// the check was skipped, or fails if `strict`.
+async fn check_version_is_latest_on(
+    source: &(impl BindleSource + Sync),
+    id: &bindle::Id,
+    strict: bool,
+) -> anyhow::Result<()> {
+    let problem = match semver::Version::parse(&id.version_string()) {
+        Err(_) => return Ok(()), // The push itself will report this
+        Ok(pushing) => match crate::bindle_search::latest_version(source, id.name()).await {
+            Err(e) if e.is::<QueryUnsupported>() => {
+                let problem = format!("can't check for later versions of {}, as {}", id.name(), e);
+                if strict {
+                    // Keep the error, so that a JSON error report can classify it.
+                    return Err(e.context(format!("Not pushing {}: {}", id, problem)));
+                }
+                problem
+            }
+            Err(e) => format!("could not check for later versions of {}: {}", id.name(), e),
+            Ok(latest) => {
+                match crate::bindle_search::check_version_ordering(&pushing, latest.as_ref()) {
+                    Ok(()) => return Ok(()),
+                    Err(message) => message,
+                }
+            }
+        },
+    };
+    if strict {
+        Err(anyhow::anyhow!("Not pushing {}: {}", id, problem))
+    } else {
+        eprintln!("{}", format!("warning: {}", problem).yellow());
+        Ok(())
+    }
+}
+
+/// If the Bindle server refused a bindle with empty files, the empty files are
+/// the likely reason, as some servers reject empty parcels.
+fn explain_push_failure(e: anyhow::Error, empty_files: &[String]) -> anyhow::Error {
+    let is_refusal = e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<bindle::client::ClientError>(),
+            Some(bindle::client::ClientError::InvalidRequest { .. })
+        )
+    });
+    if empty_files.is_empty() || !is_refusal {
+        return e;
+    }
+    e.context(format!(
+        "The Bindle server refused the bindle, which has empty files ({}). If the server does not accept empty parcels, push with --skip-empty-files",
+        empty_files.join(", ")
+    ))
+}
+
+/// Whether the server already has the bindle, with the same parcels. If it has
+/// a different bindle with the same ID, this fails with a `PushConflict`,
+/// which holds the differences.
+async fn is_already_pushed(
+    invoice: &bindle::Invoice,
+    bindle_connection: &BindleConnectionInfo,
+) -> anyhow::Result<bool> {
+    let id = &invoice.bindle.id;
+    let client = bindle_connection.client()?;
+    let remote = match client.get_invoice(id).await {
+        Ok(remote) => remote,
+        Err(bindle::client::ClientError::InvoiceNotFound) => return Ok(false),
+        Err(e) => {
+            return Err(anyhow::Error::new(e).context(format!("Error checking for existing {}", id)))
+        }
+    };
+    let conflict = compare_invoices(invoice, &remote);
+    if conflict.is_empty() {
+        return Ok(true);
+    }
+    Err(PushConflict {
+        bindle_id: id.clone(),
+        conflict,
+    }
+    .into())
+}
+
 /// Pre-fetch any invoices that are referenced in the HIPPOFACTS.
 async fn prefetch_required_invoices(
     hippofacts: &HippoFacts,
     bindle_client_factory: Option<&BindleConnectionInfo>,
+    yanked_policy: YankedPolicy,
 ) -> anyhow::Result<HashMap<bindle::Id, bindle::Invoice>> {
-    let mut map = HashMap::new();
-
     let external_refs: Vec<bindle::Id> = hippofacts
         .entries
         .iter()
         .flat_map(external_bindle_id)
         .collect();
     if external_refs.is_empty() {
-        return Ok(map);
+        return Ok(HashMap::new());
     }
 
     let client = bindle_client_factory
@@ -358,14 +956,34 @@ async fn prefetch_required_invoices(
         })?
         .client()?;
 
+    resolve_external_refs(&client, external_refs, yanked_policy).await
+}
+
+/// The fetching of `prefetch_required_invoices`, from any source. An error
+/// keeps its cause, so that e.g. a yanked invoice can still be recognised.
+async fn resolve_external_refs(
+    source: &(impl BindleSource + Sync),
+    external_refs: Vec<bindle::Id>,
+    yanked_policy: YankedPolicy,
+) -> anyhow::Result<HashMap<bindle::Id, bindle::Invoice>> {
+    let mut map = HashMap::new();
     for external_ref in external_refs {
-        let invoice = client.get_yanked_invoice(&external_ref)
+        let (invoice, warnings) = resolve_invoice(source, &external_ref, yanked_policy)
             .await
-            .map_err(|e| anyhow::anyhow!("Error retrieving external reference {}: {}", external_ref, e))?;
+            .map_err(|e| {
+                e.context(format!(
+                    "Error retrieving external reference {}",
+                    external_ref
+                ))
+            })?
+            .into();
+        for warning in &warnings {
+            eprintln!("{}", format!("warning: {}", warning).yellow());
+        }
         map.insert(external_ref, invoice);
     }
-
-    Ok(map)}
+    Ok(map)
+}
 
 /// Calculate the external Bindle ID from hippofacts data.
 fn external_bindle_id(entry: &HippoFactsEntry) -> Option<bindle::Id> {
@@ -379,7 +997,9 @@ fn hippofacts_file_path_from_args(args: &ArgMatches) -> anyhow::Result<PathBuf>
 }
 
 fn hippofacts_file_path(hippofacts_arg: &str) -> anyhow::Result<PathBuf> {
+    // The glob crate can't match under a `\\?\` path, so use the plain form
     let source = std::env::current_dir()?.join(hippofacts_arg);
+    let source = path_mapping::without_verbatim_prefix(&source);
     if source.is_dir() {
         find_hippofacts_file_in(&source)
     } else if source.is_file() {
@@ -436,15 +1056,246 @@ fn find_hippofacts_file_in(source_dir: &Path) -> anyhow::Result<PathBuf> {
     }
 }
 
-fn bindle_url_is_required() -> anyhow::Error {
+pub(crate) fn hippo_connection_from_args(args: &ArgMatches) -> anyhow::Result<ConnectionInfo> {
+    let url = args
+        .value_of(ARG_HIPPO_URL)
+        .map(|s| s.to_owned())
+        .ok_or_else(|| anyhow::anyhow!("A Hippo url is required. Use --hippo-url or $HIPPO_URL"))?;
+    Ok(ConnectionInfo {
+        url,
+        danger_accept_invalid_certs: args.is_present(ARG_INSECURE),
+        credentials: hippo_credentials_from_args(args)?,
+        max_retry_wait: max_retry_wait_from_args(args)?,
+    })
+}
+
+fn max_retry_wait_from_args(args: &ArgMatches) -> anyhow::Result<std::time::Duration> {
+    args.value_of(ARG_MAX_RETRY_WAIT)
+        .unwrap()
+        .parse()
+        .map(std::time::Duration::from_secs)
+        .map_err(|_| anyhow::anyhow!("--max-retry-wait must be a number of seconds"))
+}
+
+pub(crate) fn registration_options_from_args(args: &ArgMatches) -> RegistrationOptions {
+    RegistrationOptions {
+        re_register: args.is_present(ARG_RE_REGISTER),
+        apply_domain: !args.is_present(ARG_NO_APPLY_DOMAIN),
+        check_remote_routes: args.is_present(ARG_CHECK_REMOTE_ROUTES),
+    }
+}
+
+fn hippo_credentials_from_args(args: &ArgMatches) -> anyhow::Result<HippoCredentials> {
+    let token = args.value_of(ARG_HIPPO_TOKEN);
+    let username = args.value_of(ARG_HIPPO_USERNAME);
+    let password = args.value_of(ARG_HIPPO_PASSWORD);
+    match (token, username, password) {
+        (Some(token), None, None) => Ok(HippoCredentials::Token(token.to_owned())),
+        (None, Some(username), Some(password)) => Ok(HippoCredentials::Login {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }),
+        (Some(_), _, _) => Err(anyhow::anyhow!(
+            "Specify either a Hippo token or a Hippo username and password, not both: the token was set by {}, and the username by {}",
+            arg_source(args, ARG_HIPPO_TOKEN, "--hippo-token", "$HIPPO_TOKEN"),
+            arg_source(args, ARG_HIPPO_USERNAME, "--hippo-username", "$HIPPO_USERNAME"),
+        )),
+        _ => match (
+            args.value_of(ARG_HIPPO_CREDENTIAL_HELPER),
+            args.value_of(ARG_HIPPO_URL),
+        ) {
+            (Some(helper), Some(url)) => match CredentialHelper::new(helper).get(url)? {
+                HelperCredentials::Login { username, password } => {
+                    Ok(HippoCredentials::Login { username, password })
+                }
+                HelperCredentials::Token(token) => Ok(HippoCredentials::Token(token)),
+            },
+            _ => Err(anyhow::anyhow!(
+                "Hippo credentials are required. Use --hippo-token or $HIPPO_TOKEN, --hippo-username and --hippo-password (or $HIPPO_USERNAME and $HIPPO_PASSWORD), or --hippo-credential-helper"
+            )),
+        },
+    }
+}
+
+/// Describe where an argument's value came from, for error messages.
+fn arg_source(args: &ArgMatches, arg: &str, flag: &str, env_var: &str) -> String {
+    if args.occurrences_of(arg) > 0 {
+        flag.to_owned()
+    } else {
+        env_var.to_owned()
+    }
+}
+
+/// The handlers to build, if the user asked for only some of them.
+fn handler_selection_from_args(args: &ArgMatches) -> Option<HandlerSelection> {
+    let values_of = |arg| -> Vec<String> {
+        args.values_of(arg)
+            .map(|vs| vs.map(|v| v.to_owned()).collect())
+            .unwrap_or_default()
+    };
+    let handlers = values_of(ARG_HANDLER);
+    let routes = values_of(ARG_ONLY_ROUTE);
+    if handlers.is_empty() && routes.is_empty() {
+        return None;
+    }
+    Some(HandlerSelection {
+        handlers,
+        routes,
+        tag_version: !args.is_present(ARG_NO_PARTIAL_TAG),
+    })
+}
+
+fn template_variables_from_args(args: &ArgMatches) -> anyhow::Result<TemplateVariables> {
+    let values = match args.value_of(ARG_VALUES) {
+        Some(path) => TemplateVariables::read_values_file(path)?,
+        None => Default::default(),
+    };
+    let set = args
+        .values_of(ARG_SET)
+        .into_iter()
+        .flatten()
+        .map(TemplateVariables::parse_setting)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let env = std::env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+        .collect();
+    let mut variables = TemplateVariables::new(values, set, env);
+    variables.substitute_bindle_name = args.is_present(ARG_TEMPLATE_BINDLE_NAME);
+    Ok(variables)
+}
+
+fn build_options_from_args(args: &ArgMatches) -> anyhow::Result<BuildOptions> {
+    let jobs = args
+        .value_of(ARG_BUILD_JOBS)
+        .unwrap()
+        .parse::<std::num::NonZeroUsize>()
+        .map_err(|_| anyhow::anyhow!("--build-jobs must be a positive number"))?
+        .get();
+    Ok(BuildOptions {
+        skip_build: args.is_present(ARG_SKIP_BUILD),
+        build_only: args.is_present(ARG_BUILD_ONLY),
+        jobs,
+    })
+}
+
+fn plan_options_from_args(args: &ArgMatches) -> anyhow::Result<PlanOptions> {
+    Ok(PlanOptions {
+        emit_plan: args.value_of(ARG_EMIT_PLAN).map(PathBuf::from),
+        from_plan: args.value_of(ARG_FROM_PLAN).map(PathBuf::from),
+        yanked_policy: YankedPolicy::parse(args.value_of(ARG_YANKED_REFERENCES).unwrap())?,
+    })
+}
+
+fn annotation_schema_from_args(args: &ArgMatches) -> anyhow::Result<Option<AnnotationSchema>> {
+    match args.value_of(ARG_ANNOTATION_SCHEMA) {
+        Some(path) => Ok(Some(AnnotationSchema::read_from(path)?)),
+        None => Ok(None),
+    }
+}
+
+fn sbom_options_from_args(args: &ArgMatches) -> anyhow::Result<Option<SbomOptions>> {
+    match args.value_of(ARG_EMIT_SBOM) {
+        Some(path) => Ok(Some(SbomOptions {
+            path: PathBuf::from(path),
+            format: SbomFormat::parse(args.value_of(ARG_SBOM_FORMAT).unwrap())?,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// The settings for `run` which the prepare, bindle and push commands share.
+struct RunOptions {
+    overwrite_policy: OverwritePolicy,
+    handler_selection: Option<HandlerSelection>,
+    template_variables: TemplateVariables,
+    build_options: BuildOptions,
+    parcel_store: Option<ParcelStore>,
+    plan_options: PlanOptions,
+    annotation_schema: Option<AnnotationSchema>,
+    sbom_options: Option<SbomOptions>,
+    skip_empty_files: bool,
+    /// Fail if the version is not the latest on the server.
+    strict_version_ordering: bool,
+    upload_options: UploadOptions,
+    /// Succeed if the server already has the same bindle.
+    idempotent: bool,
+}
+
+/// The settings given by `args` to the command `cmd`. The settings for pushing
+/// are read only if `cmd` pushes, as `common_args` gives `prepare` none.
+fn run_options_from_args(cmd: &str, args: &ArgMatches) -> anyhow::Result<RunOptions> {
+    let pushes = cmd != CMD_PREPARE;
+    Ok(RunOptions {
+        overwrite_policy: overwrite_policy_from_args(args),
+        handler_selection: handler_selection_from_args(args),
+        template_variables: template_variables_from_args(args)?,
+        build_options: build_options_from_args(args)?,
+        parcel_store: parcel_store_from_args(args)?,
+        plan_options: plan_options_from_args(args)?,
+        annotation_schema: annotation_schema_from_args(args)?,
+        sbom_options: sbom_options_from_args(args)?,
+        skip_empty_files: args.is_present(ARG_SKIP_EMPTY_FILES),
+        strict_version_ordering: pushes && args.is_present(ARG_STRICT_VERSION_ORDERING),
+        upload_options: if pushes {
+            upload_options_from_args(args)?
+        } else {
+            UploadOptions::default()
+        },
+        idempotent: pushes && args.is_present(ARG_IDEMPOTENT),
+    })
+}
+
+fn upload_options_from_args(args: &ArgMatches) -> anyhow::Result<UploadOptions> {
+    let chunk_size = match args.value_of(ARG_UPLOAD_CHUNK_SIZE) {
+        Some(size) => Some(parse_size(size)? as usize),
+        None => None,
+    };
+    Ok(UploadOptions {
+        chunk_size,
+        // As for the client's own requests.
+        retry_policy: RetryPolicy {
+            max_wait: max_retry_wait_from_args(args)?,
+            ..RetryPolicy::default()
+        },
+    })
+}
+
+/// Hippo's own staging directory is written regardless; a directory the user
+/// names is protected unless they say otherwise.
+fn overwrite_policy_from_args(args: &ArgMatches) -> OverwritePolicy {
+    if !args.is_present(ARG_STAGING_DIR) {
+        OverwritePolicy::Always
+    } else if args.is_present(ARG_CLEAN) {
+        OverwritePolicy::Clean
+    } else if args.is_present(ARG_FORCE) {
+        OverwritePolicy::Force
+    } else {
+        OverwritePolicy::PreviousExportOnly
+    }
+}
+
+fn parcel_store_from_args(args: &ArgMatches) -> anyhow::Result<Option<ParcelStore>> {
+    if args.is_present(ARG_PARCEL_CACHE) {
+        let layout = ParcelLayout::parse(args.value_of(ARG_CACHE_LAYOUT).unwrap())?;
+        Ok(Some(ParcelStore::open_default()?.with_layout(layout)))
+    } else {
+        Ok(None)
+    }
+}
+
+pub(crate) fn bindle_url_is_required() -> anyhow::Error {
     anyhow::anyhow!("Bindle URL is required. Use -s|--server or $BINDLE_URL")
 }
 
 /// Describe the desired output format.
-enum OutputFormat {
+pub(crate) enum OutputFormat {
     None,
     Id,
     Message,
+    /// A `PushOutput`, so that a later stage of a pipeline can read the ID.
+    Json,
+    /// A `PushOutput`, as YAML.
+    Yaml,
 }
 
 impl OutputFormat {
@@ -454,12 +1305,60 @@ impl OutputFormat {
             OutputFormat::None
         } else if text == "id" {
             OutputFormat::Id
+        } else if text == "json" {
+            OutputFormat::Json
+        } else if text == "yaml" {
+            OutputFormat::Yaml
         } else {
             OutputFormat::Message
         }
     }
 }
 
+/// What `--output json` prints. `push`, `bindle`, `prepare` and `register` all
+/// print this, so that the bindle ID can be passed from one to another.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PushOutput {
+    pub bindle_id: String,
+    /// Whether the bindle is on the Bindle server.
+    pub pushed: bool,
+    /// Whether the bindle is registered with Hippo.
+    pub registered: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staging_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan: Option<String>,
+}
+
+impl PushOutput {
+    /// Prints the output as JSON for `OutputFormat::Json`, as YAML for
+    /// `OutputFormat::Yaml` and as the bindle ID otherwise.
+    pub fn print(&self, format: &OutputFormat) -> anyhow::Result<()> {
+        let format = match format {
+            OutputFormat::Json => ReportFormat::Json,
+            OutputFormat::Yaml => ReportFormat::Yaml,
+            _ => ReportFormat::Text,
+        };
+        println!("{}", render(self, format)?);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for PushOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.bindle_id)
+    }
+}
+
+/// Whether to stop after planning the bindle, or to start from a saved plan.
+struct PlanOptions {
+    emit_plan: Option<PathBuf>,
+    from_plan: Option<PathBuf>,
+    /// What to do if an external reference is to a yanked bindle.
+    yanked_policy: YankedPolicy,
+}
+
 /// Desribe the actions to be taken viz a viz a Bindle server.
 enum BindleSettings {
     /// Do not push to a Bindle server, but still resolve local references.
@@ -479,11 +1378,381 @@ impl BindleSettings {
 }
 
 impl BindleConnectionInfo {
-    pub fn from_args(args: &ArgMatches) -> Option<Self> {
-        let allow_insecure = args.is_present(ARG_INSECURE);
-        let username = args.value_of(ARG_BINDLE_USERNAME).map(|s| s.to_owned());
-        let password = args.value_of(ARG_BINDLE_PASSWORD).map(|s| s.to_owned());
-        args.value_of(ARG_BINDLE_URL)
-            .map(|base_url| Self::new(base_url, allow_insecure, username, password))
+    pub fn from_args(args: &ArgMatches) -> anyhow::Result<Option<Self>> {
+        let given = bindle_settings_from_args(args)?;
+        let connection = match args.value_of(ARG_BINDLE_PROFILE) {
+            None if given.url.is_none() => return Ok(None),
+            None => Self::from_settings(&given)?,
+            Some(name) => {
+                let profiles = match args.value_of(ARG_BINDLE_PROFILES_FILE) {
+                    Some(path) => Profiles::load(path)?,
+                    None => Profiles::load_default()?,
+                };
+                Self::from_profile(&profiles, name, given)?
+            }
+        };
+        let connection = connection.with_max_retry_wait(max_retry_wait_from_args(args)?);
+        #[cfg(feature = "negotiate-proxy")]
+        let connection = match args.value_of(ARG_NEGOTIATE_PROXY) {
+            Some(proxy_url) => connection.with_negotiate_proxy(proxy_url)?,
+            None => connection,
+        };
+        Ok(Some(connection))
+    }
+}
+
+/// The Bindle connection settings given on the command line. Only those which
+/// were given are set, so that the rest can come from a profile.
+fn bindle_settings_from_args(args: &ArgMatches) -> anyhow::Result<Profile> {
+    let username = args.value_of(ARG_BINDLE_USERNAME);
+    let password = args.value_of(ARG_BINDLE_PASSWORD);
+    let credentials_file = args.value_of(ARG_BINDLE_CREDENTIALS_FILE);
+    let credential_helper = args.value_of(ARG_BINDLE_CREDENTIAL_HELPER);
+    let auth = match (credentials_file, credential_helper, username, password) {
+        (Some(path), _, _, _) => Some(ProfileAuth::CredentialsFile { path: path.into() }),
+        (None, Some(helper), _, _) => Some(ProfileAuth::CredentialHelper {
+            command: helper.to_owned(),
+        }),
+        (None, None, Some(username), Some(password)) => Some(ProfileAuth::Basic {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }),
+        // Rather than silently use a profile's credentials, or none.
+        (None, None, Some(_), None) | (None, None, None, Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "--bindle-username and --bindle-password (or BINDLE_USERNAME and BINDLE_PASSWORD) must be given together"
+            ))
+        }
+        _ => None,
+    };
+    Ok(Profile {
+        url: args.value_of(ARG_BINDLE_URL).map(|s| s.to_owned()),
+        // Only turns insecure on: a profile's `insecure = true` stands.
+        insecure: args.is_present(ARG_INSECURE).then_some(true),
+        auth,
+        timeout_secs: None,
+        pin_cert_sha256: args
+            .values_of(ARG_PIN_CERT)
+            .map(|pins| pins.map(|s| s.to_owned()).collect()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::CommandRunner;
+    use crate::testing::FakeBindleServer;
+    use std::str::FromStr;
+
+    fn push_args(args: &[&str]) -> ArgMatches {
+        let mut argv = vec![
+            "push",
+            ".",
+            "-s",
+            "http://bindle.test",
+            "--hippo-url",
+            "http://hippo.test",
+        ];
+        argv.extend_from_slice(args);
+        Push::app()
+            .try_get_matches_from(argv)
+            .expect("error parsing test arguments")
+    }
+
+    #[test]
+    fn test_hippo_token_is_used_as_credentials() {
+        let args = push_args(&["--hippo-token", "t0k3n"]);
+        match hippo_credentials_from_args(&args).unwrap() {
+            HippoCredentials::Token(token) => assert_eq!("t0k3n", token),
+            HippoCredentials::Login { .. } => panic!("expected token credentials"),
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_hippo_credentials_can_come_from_a_helper() {
+        let args = push_args(&[
+            "--hippo-credential-helper",
+            "sh ./testdata/credential_helper/stub.sh token",
+        ]);
+        match hippo_credentials_from_args(&args).unwrap() {
+            HippoCredentials::Token(token) => assert_eq!("helper-token", token),
+            HippoCredentials::Login { .. } => panic!("expected token credentials"),
+        }
+    }
+
+    #[test]
+    fn test_hippo_token_and_username_are_mutually_exclusive() {
+        let args = push_args(&[
+            "--hippo-token",
+            "t0k3n",
+            "--hippo-username",
+            "hippo",
+            "--hippo-password",
+            "pa55",
+        ]);
+        // The credentials have no Debug, so that they can't be printed.
+        let message = match hippo_credentials_from_args(&args) {
+            Ok(_) => panic!("expected conflicting credentials to be refused"),
+            Err(e) => e.to_string(),
+        };
+        assert!(
+            message.contains("--hippo-token"),
+            "check error names token source: '{}'",
+            message
+        );
+        assert!(
+            message.contains("--hippo-username"),
+            "check error names username source: '{}'",
+            message
+        );
+    }
+
+    #[test]
+    fn test_handler_and_route_options_are_repeatable() {
+        let args = push_args(&[
+            "--handler",
+            "lion.wasm",
+            "--handler",
+            "tiger.wasm",
+            "--only-route",
+            "/bear",
+        ]);
+        let selection = handler_selection_from_args(&args).expect("expected a selection");
+        assert_eq!(vec!["lion.wasm", "tiger.wasm"], selection.handlers);
+        assert_eq!(vec!["/bear"], selection.routes);
+        assert!(selection.tag_version);
+    }
+
+    #[test]
+    fn test_no_handler_options_means_build_everything() {
+        let args = push_args(&["--no-partial-tag"]);
+        assert!(handler_selection_from_args(&args).is_none());
+    }
+
+    #[test]
+    fn test_build_jobs_must_be_positive() {
+        let args = push_args(&["--build-jobs", "4"]);
+        assert_eq!(4, build_options_from_args(&args).unwrap().jobs);
+        let args = push_args(&["--build-jobs", "0"]);
+        assert!(build_options_from_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_a_plan_cannot_be_both_emitted_and_consumed() {
+        let argv = vec![
+            "push",
+            ".",
+            "-s",
+            "http://bindle.test",
+            "--hippo-url",
+            "http://hippo.test",
+            "--emit-plan",
+            "plan.json",
+            "--from-plan",
+            "plan.json",
+        ];
+        assert!(Push::app().try_get_matches_from(argv).is_err());
+
+        let args = push_args(&["--from-plan", "plan.json"]);
+        let options = plan_options_from_args(&args).unwrap();
+        assert_eq!(Some(PathBuf::from("plan.json")), options.from_plan);
+        assert_eq!(None, options.emit_plan);
+    }
+
+    #[test]
+    fn test_bindle_only_push_needs_no_hippo_settings() {
+        let argv = vec!["push", ".", "-s", "http://bindle.test", "--bindle-only"];
+        let args = Push::app()
+            .try_get_matches_from(argv)
+            .expect("error parsing test arguments");
+        assert!(args.is_present(ARG_BINDLE_ONLY));
+
+        let argv = vec!["push", ".", "-s", "http://bindle.test"];
+        assert!(Push::app().try_get_matches_from(argv).is_err());
+    }
+
+    #[test]
+    fn test_json_output_names_the_bindle() {
+        let output = PushOutput {
+            bindle_id: "birds/1.4.2".to_owned(),
+            pushed: true,
+            ..PushOutput::default()
+        };
+        let json: serde_json::Value = serde_json::to_value(&output).unwrap();
+        assert_eq!("birds/1.4.2", json["bindleId"]);
+        assert_eq!(true, json["pushed"]);
+        assert_eq!(false, json["registered"]);
+        assert!(json.get("stagingDir").is_none());
+    }
+
+    #[test]
+    fn test_uploads_are_chunked_only_on_request() {
+        let options = upload_options_from_args(&push_args(&[])).unwrap();
+        assert_eq!(None, options.chunk_size);
+
+        let args = push_args(&["--upload-chunk-size", "8M"]);
+        let options = upload_options_from_args(&args).unwrap();
+        assert_eq!(Some(8 * 1024 * 1024), options.chunk_size);
+    }
+
+    #[test]
+    fn test_uploads_wait_no_longer_than_max_retry_wait() {
+        let args = push_args(&["--upload-chunk-size", "8M", "--max-retry-wait", "7"]);
+        let options = upload_options_from_args(&args).unwrap();
+        assert_eq!(
+            std::time::Duration::from_secs(7),
+            options.retry_policy.max_wait
+        );
+    }
+
+    #[tokio::test]
+    async fn test_version_check_is_skipped_without_query_api() {
+        let server = FakeBindleServer::new().without_query_api();
+        let id = bindle::Id::from_str("birds/1.4.2").unwrap();
+
+        assert!(check_version_is_latest_on(&server, &id, false)
+            .await
+            .is_ok());
+
+        let err = check_version_is_latest_on(&server, &id, true)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("does not support queries"),
+            "explains why: '{}'",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_yanked_external_reference_is_reported_as_yanked() {
+        let id = bindle::Id::from_str("birds/1.4.2").unwrap();
+        let mut invoice = crate::testing::test_invoice(&id, vec![]);
+        invoice.yanked = Some(true);
+        let server = FakeBindleServer::new().with_invoice(invoice);
+
+        let err = resolve_external_refs(&server, vec![id.clone()], YankedPolicy::Reject)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            id,
+            err.downcast_ref::<crate::bindle_utils::InvoiceYanked>()
+                .unwrap()
+                .id
+        );
+        assert!(
+            format!("{:#}", err).contains("Error retrieving external reference birds/1.4.2"),
+            "{:#}",
+            err
+        );
+
+        let resolved = resolve_external_refs(&server, vec![id.clone()], YankedPolicy::Allow)
+            .await
+            .unwrap();
+        assert!(resolved.contains_key(&id));
+    }
+
+    #[test]
+    fn test_refused_push_with_empty_files_suggests_skipping_them() {
+        let refusal = || {
+            anyhow::Error::new(bindle::client::ClientError::InvalidRequest {
+                status_code: reqwest::StatusCode::BAD_REQUEST,
+                message: Some("parcel has no content".to_owned()),
+            })
+            .context("Error pushing bindle to server")
+        };
+        let empty = vec!["static/robots.txt".to_owned()];
+
+        let message = format!("{:#}", explain_push_failure(refusal(), &empty));
+        assert!(
+            message.contains("--skip-empty-files") && message.contains("static/robots.txt"),
+            "suggests the flag: '{}'",
+            message
+        );
+        assert!(
+            message.contains("parcel has no content"),
+            "keeps the server's error: '{}'",
+            message
+        );
+
+        let message = format!("{:#}", explain_push_failure(refusal(), &[]));
+        assert!(!message.contains("--skip-empty-files"), "{}", message);
+        let unrelated = anyhow::anyhow!("connection reset by peer");
+        let message = format!("{:#}", explain_push_failure(unrelated, &empty));
+        assert!(!message.contains("--skip-empty-files"), "{}", message);
+    }
+
+    #[test]
+    fn test_bindle_profile_supplies_unset_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profiles.toml");
+        std::fs::write(
+            &path,
+            "[staging]\nurl = \"https://staging.test/v1\"\ntimeout-secs = 30\n\n[other]\nurl = \"https://other.test/v1\"\n",
+        )
+        .unwrap();
+        let profile_args = |extra: &[&str]| {
+            let mut argv = vec![
+                "push",
+                ".",
+                "--hippo-url",
+                "http://hippo.test",
+                "--bindle-profile",
+                "staging",
+                "--bindle-profiles-file",
+                path.to_str().unwrap(),
+            ];
+            argv.extend_from_slice(extra);
+            Push::app()
+                .try_get_matches_from(argv)
+                .expect("error parsing test arguments")
+        };
+
+        let connection = BindleConnectionInfo::from_args(&profile_args(&[]))
+            .unwrap()
+            .unwrap();
+        assert_eq!("https://staging.test/v1", connection.base_url());
+        assert_eq!(
+            Some(std::time::Duration::from_secs(30)),
+            connection.timeout()
+        );
+
+        let connection =
+            BindleConnectionInfo::from_args(&profile_args(&["-s", "http://bindle.test"]))
+                .unwrap()
+                .unwrap();
+        assert_eq!("http://bindle.test", connection.base_url());
+        assert_eq!(
+            Some(std::time::Duration::from_secs(30)),
+            connection.timeout()
+        );
+    }
+
+    #[test]
+    fn test_bindle_password_without_username_is_refused() {
+        let args = push_args(&["--bindle-password", "secret"]);
+        let err = BindleConnectionInfo::from_args(&args).err().unwrap();
+        assert!(err.to_string().contains("--bindle-username"), "{}", err);
+    }
+
+    #[test]
+    fn test_pinning_is_refused_with_insecure() {
+        let pin = "2601d2a56f7a0b6da4b30dbe15d8bd1e475f8a6f9c203bf4db828ee394bdb768";
+        let args = push_args(&["--pin-cert-sha256", pin]);
+        assert!(BindleConnectionInfo::from_args(&args).is_ok());
+
+        let argv = vec![
+            "push",
+            ".",
+            "-s",
+            "https://bindle.test",
+            "--bindle-only",
+            "-k",
+            "--pin-cert-sha256",
+            pin,
+        ];
+        assert!(Push::app().try_get_matches_from(argv).is_err());
     }
 }
@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use std::convert::TryFrom;
+use std::path::Path;
+
+use crate::bindle_fetcher::{FetchOptions, VerifyPolicy};
+use crate::bindle_utils::BindleConnectionInfo;
+use crate::vendor::{restore, vendor, Lockfile, LOCKFILE_NAME};
+
+pub(crate) const CMD_VENDOR: &str = "vendor";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_DIR: &str = "dir";
+const ARG_CHECK: &str = "check";
+const ARG_VERIFY: &str = "verify";
+
+/// The subcommand for `hippo bindle vendor`
+pub(crate) struct BindleVendor;
+
+#[async_trait]
+impl super::CommandRunner for BindleVendor {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_VENDOR)
+            .about("Downloads a bindle from the Bindle server into a directory, with a lockfile, for use without the server")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required_unless_present(ARG_CHECK)
+                    .index(1)
+                    .about("The bindle to vendor, e.g. 'myapp/1.0.0'"),
+            )
+            .arg(
+                Arg::new(ARG_DIR)
+                    .required(true)
+                    .short('d')
+                    .long("dir")
+                    .takes_value(true)
+                    .about("The directory to vendor the bindle into"),
+            )
+            .arg(
+                Arg::new(ARG_CHECK)
+                    .long("check")
+                    .takes_value(false)
+                    .conflicts_with(ARG_BINDLE_ID)
+                    .about("Check the bindle already vendored in the directory against its lockfile, without contacting the server"),
+            )
+            .arg(
+                Arg::new(ARG_VERIFY)
+                    .long("verify")
+                    .possible_values(&["always", "missing-only", "never"])
+                    .default_value("always")
+                    .conflicts_with(ARG_CHECK)
+                    .about("When to check parcel files against their digests: 'missing-only' trusts files already vendored if their size is right, and 'never' is for emergencies"),
+            )
+            .args(super::upload::bindle_connection_args(false))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let dir = Path::new(args.value_of(ARG_DIR).unwrap());
+
+        if args.is_present(ARG_CHECK) {
+            let lockfile = Lockfile::read_from(dir.join(LOCKFILE_NAME))?;
+            restore(&lockfile, dir)?;
+            println!(
+                "verified: {} ({} parcels)",
+                lockfile.bindle_id,
+                lockfile.parcels.len()
+            );
+            return Ok(());
+        }
+
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let options = FetchOptions {
+            verify: VerifyPolicy::parse(args.value_of(ARG_VERIFY).unwrap())?,
+            ..FetchOptions::default()
+        };
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+
+        let lockfile = vendor(&client, &bindle_id, dir, &options).await?;
+        println!(
+            "vendored: {} ({} parcels) to {}",
+            lockfile.bindle_id,
+            lockfile.parcels.len(),
+            dir.to_string_lossy()
+        );
+        Ok(())
+    }
+}
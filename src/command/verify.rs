@@ -0,0 +1,180 @@
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use colored::Colorize;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::bindle_fetcher::VerifyPolicy;
+use crate::bindle_utils::{BindleConnectionInfo, BindleSource};
+use crate::bindle_verifier::{verify_bindle, VerifyMode, VerifyOptions};
+use crate::digest_backend::Sha2Backend;
+use crate::invoice_signatures::{read_keyring, verify_invoice_signatures};
+use crate::report_format::{render, ReportFormat, REPORT_FORMATS};
+
+pub(crate) const CMD_VERIFY: &str = "verify";
+
+const ARG_BINDLE_ID: &str = "bindle_id";
+const ARG_DEEP: &str = "deep";
+const ARG_VERIFY: &str = "verify";
+const ARG_GROUP: &str = "group";
+const ARG_CONCURRENCY: &str = "concurrency";
+const ARG_FAIL_FAST: &str = "fail_fast";
+const ARG_OUTPUT: &str = "output";
+const ARG_KEYRING: &str = "keyring";
+const ARG_REQUIRE_ROLE: &str = "require_role";
+
+/// The subcommand for `hippo bindle verify`
+pub(crate) struct BindleVerify;
+
+#[async_trait]
+impl super::CommandRunner for BindleVerify {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_VERIFY)
+            .about("Checks that every parcel of a bindle on the Bindle server can be retrieved")
+            .arg(
+                Arg::new(ARG_BINDLE_ID)
+                    .required(true)
+                    .index(1)
+                    .about("The bindle to check, e.g. 'myapp/1.0.0'"),
+            )
+            .arg(
+                Arg::new(ARG_DEEP)
+                    .long("deep")
+                    .takes_value(false)
+                    .about("Download every parcel and check it against its digest, instead of only checking that it exists. The same as '--verify always'"),
+            )
+            .arg(
+                Arg::new(ARG_VERIFY)
+                    .long("verify")
+                    .possible_values(&["always", "missing-only", "never"])
+                    .default_value("missing-only")
+                    .conflicts_with(ARG_DEEP)
+                    .about("How to check parcels: 'always' downloads each and checks it against its digest, 'missing-only' checks only that the server has it, and 'never' is for emergencies"),
+            )
+            .arg(
+                Arg::new(ARG_GROUP)
+                    .long("group")
+                    .takes_value(true)
+                    .about("Check only the parcels in this group"),
+            )
+            .arg(
+                Arg::new(ARG_CONCURRENCY)
+                    .long("concurrency")
+                    .takes_value(true)
+                    .default_value("8")
+                    .about("The most parcels to check at once"),
+            )
+            .arg(
+                Arg::new(ARG_FAIL_FAST)
+                    .long("fail-fast")
+                    .takes_value(false)
+                    .about("Stop at the first missing or corrupt parcel, instead of checking them all"),
+            )
+            .arg(
+                Arg::new(ARG_OUTPUT)
+                    .possible_values(REPORT_FORMATS)
+                    .default_value("text")
+                    .short('o')
+                    .long("output")
+                    .about("How to print the report"),
+            )
+            .arg(
+                Arg::new(ARG_KEYRING)
+                    .long("keyring")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .requires(ARG_REQUIRE_ROLE)
+                    .about("The Bindle key ring holding the keys trusted to sign the invoice"),
+            )
+            .arg(
+                Arg::new(ARG_REQUIRE_ROLE)
+                    .long("require-role")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .number_of_values(1)
+                    .possible_values(&["creator", "approver", "host", "proxy"])
+                    .requires(ARG_KEYRING)
+                    .about("Fail unless the invoice has a valid signature in this role by a key in the key ring (may be repeated)"),
+            )
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let bindle_id = bindle::Id::try_from(args.value_of(ARG_BINDLE_ID).unwrap())?;
+        let output_format = ReportFormat::parse(args.value_of(ARG_OUTPUT).unwrap())?;
+        let max_concurrency = args
+            .value_of(ARG_CONCURRENCY)
+            .unwrap()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("--concurrency must be a positive number"))?;
+        let verify = if args.is_present(ARG_DEEP) {
+            VerifyPolicy::Always
+        } else {
+            VerifyPolicy::parse(args.value_of(ARG_VERIFY).unwrap())?
+        };
+        verify.warn_if_disabled();
+        let options = VerifyOptions {
+            verify,
+            group: args.value_of(ARG_GROUP).map(|g| g.to_owned()),
+            max_concurrency,
+            mode: if args.is_present(ARG_FAIL_FAST) {
+                VerifyMode::FailFast
+            } else {
+                VerifyMode::ReportAll
+            },
+            digest: Arc::new(Sha2Backend),
+        };
+        let client = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .client()?;
+
+        if let Some(keyring) = args.value_of(ARG_KEYRING) {
+            let keyring = read_keyring(keyring)?;
+            let roles = args
+                .values_of(ARG_REQUIRE_ROLE)
+                .into_iter()
+                .flatten()
+                .map(|r| bindle::SignatureRole::from_str(r).map_err(|e| anyhow::anyhow!(e)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let invoice = client.fetch_invoice(&bindle_id).await?;
+            verify_invoice_signatures(&invoice, &roles, &keyring)?;
+        }
+
+        let report = verify_bindle(&client, &bindle_id, &options).await?;
+
+        match output_format {
+            ReportFormat::Text => {
+                for (name, sha256) in &report.missing {
+                    eprintln!("{}", format!("missing: {} ({})", name, sha256).red());
+                }
+                for (name, sha256) in &report.corrupt {
+                    eprintln!("{}", format!("corrupt: {} ({})", name, sha256).red());
+                }
+                for (name, sha256, error) in &report.errors {
+                    eprintln!(
+                        "{}",
+                        format!("error: {} ({}): {}", name, sha256, error).red()
+                    );
+                }
+                if report.is_ok() {
+                    println!("verified: {} ({} parcels)", bindle_id, report.checked);
+                }
+            }
+            _ => println!("{}", render(&report, output_format)?),
+        }
+
+        if report.is_ok() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Bindle {} failed verification: of {} parcels, {} missing, {} corrupt, {} could not be checked",
+                bindle_id,
+                report.checked,
+                report.missing.len(),
+                report.corrupt.len(),
+                report.errors.len()
+            ))
+        }
+    }
+}
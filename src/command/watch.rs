@@ -0,0 +1,37 @@
+use async_trait::async_trait;
+use clap::{App, ArgMatches};
+use futures::StreamExt;
+
+use crate::bindle_events::{subscribe_events, RegistryEvent};
+use crate::bindle_utils::BindleConnectionInfo;
+use crate::retry::RetryPolicy;
+
+pub(crate) const CMD_WATCH: &str = "watch";
+
+/// The subcommand for `hippo bindle watch`
+pub(crate) struct BindleWatch;
+
+#[async_trait]
+impl super::CommandRunner for BindleWatch {
+    fn app<'a>() -> App<'a> {
+        App::new(CMD_WATCH)
+            .about("Prints each bindle published to or yanked from the Bindle server, as it happens, if the server sends events")
+            .args(super::upload::bindle_connection_args(true))
+    }
+
+    async fn run(&self, args: &ArgMatches) -> anyhow::Result<()> {
+        let source = BindleConnectionInfo::from_args(args)?
+            .ok_or_else(super::upload::bindle_url_is_required)?
+            .event_source()?;
+        let policy = RetryPolicy::default();
+
+        let mut events = Box::pin(subscribe_events(&source, &policy));
+        while let Some(event) = events.next().await {
+            match event? {
+                RegistryEvent::Published(id) => println!("published: {}", id),
+                RegistryEvent::Yanked(id) => println!("yanked: {}", id),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+//! Getting credentials from an external command, so that they can stay in a
+//! secret store such as Vault rather than in the environment or on disk.
+//!
+//! A helper is run much as git runs its credential helpers. The setting is a
+//! shell command, optionally prefixed with `!`, which is run with the arguments
+//! `get <server URL>`. Its standard input has git's description of the server:
+//!
+//! ```text
+//! protocol=https
+//! host=bindle.example.com
+//! path=v1
+//! ```
+//!
+//! The helper prints either a JSON document, `{"username": ..., "password": ...}`
+//! or `{"token": ...}`, or git's `username=...` and `password=...` lines, so that
+//! an existing git helper can be reused. The credentials are only held in memory.
+
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// How long to wait for a helper if the caller doesn't say.
+pub const DEFAULT_HELPER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The environment variable in which a helper run by `cmd` is given the URL.
+#[cfg(windows)]
+const URL_VAR: &str = "HIPPO_CREDENTIAL_HELPER_URL";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HelperCredentials {
+    Login { username: String, password: String },
+    Token(String),
+}
+
+/// The error for a helper which could not be run, failed, or did not print
+/// credentials.
+#[derive(Debug)]
+pub struct CredentialHelperError {
+    pub command: String,
+    pub problem: String,
+}
+
+impl std::fmt::Display for CredentialHelperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Credential helper '{}' {}", self.command, self.problem)
+    }
+}
+
+impl std::error::Error for CredentialHelperError {}
+
+pub struct CredentialHelper {
+    command: String,
+    timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct HelperOutput {
+    username: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+}
+
+impl CredentialHelper {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            timeout: DEFAULT_HELPER_TIMEOUT,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Runs the helper to get the credentials for the server at `server_url`.
+    /// The helper, and anything it started, is killed if it has not finished
+    /// within the timeout.
+    pub fn get(&self, server_url: &str) -> Result<HelperCredentials, CredentialHelperError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut child = self
+            .shell_command(server_url)?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| self.error(format!("could not be run: {}", e)))?;
+
+        // The input is written and the output read while the helper runs, so
+        // that a helper can't block on a full pipe while we wait for it.
+        let input = git_description(server_url);
+        let stdin = child.stdin.take();
+        std::thread::spawn(move || {
+            if let Some(mut stdin) = stdin {
+                // A helper doesn't have to read its input, and may exit before
+                // we have written it.
+                let _ = stdin.write_all(input.as_bytes());
+            }
+        });
+        let (sender, output) = mpsc::channel();
+        let stdout = child.stdout.take();
+        std::thread::spawn(move || {
+            let mut text = String::new();
+            let read = match stdout {
+                Some(mut stdout) => stdout.read_to_string(&mut text).map(|_| text),
+                None => Ok(text),
+            };
+            let _ = sender.send(read);
+        });
+
+        let status = self.wait(&mut child, deadline)?;
+        // Something the helper started may still hold its output open.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let stdout = match output.recv_timeout(remaining) {
+            Ok(read) => {
+                read.map_err(|e| self.error(format!("printed unreadable output: {}", e)))?
+            }
+            Err(_) => return Err(self.timed_out()),
+        };
+        if !status.success() {
+            return Err(self.error(format!("failed ({})", status)));
+        }
+        parse_output(&stdout).map_err(|e| self.error(format!("printed invalid credentials: {}", e)))
+    }
+
+    fn wait(
+        &self,
+        child: &mut Child,
+        deadline: Instant,
+    ) -> Result<std::process::ExitStatus, CredentialHelperError> {
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return Ok(status),
+                Ok(None) if Instant::now() >= deadline => {
+                    kill(child);
+                    let _ = child.wait();
+                    return Err(self.timed_out());
+                }
+                Ok(None) => std::thread::sleep(Duration::from_millis(20)),
+                Err(e) => return Err(self.error(format!("could not be waited for: {}", e))),
+            }
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn shell_command(&self, server_url: &str) -> Result<Command, CredentialHelperError> {
+        use std::os::unix::process::CommandExt;
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{} \"$@\"", self.script()))
+            .arg(self.script())
+            .args(["get", server_url])
+            // So that `kill` can kill whatever the shell starts, too.
+            .process_group(0);
+        Ok(command)
+    }
+
+    #[cfg(windows)]
+    fn shell_command(&self, server_url: &str) -> Result<Command, CredentialHelperError> {
+        use std::os::windows::process::CommandExt;
+
+        // cmd doesn't treat `&`, `|` and the like as special inside quotes,
+        // and doesn't expand `%` in the value of a variable, so the URL is
+        // passed in one to keep cmd from interpreting any of it.
+        if server_url.contains('"') {
+            return Err(self.error(format!(
+                "can't be given the URL '{}', as it contains a quote",
+                server_url
+            )));
+        }
+        let mut command = Command::new("cmd");
+        command.env(URL_VAR, server_url).raw_arg(format!(
+            "/C {} get \"%{}%\"",
+            self.script(),
+            URL_VAR
+        ));
+        Ok(command)
+    }
+
+    /// The command without git's `!` prefix for shell commands.
+    fn script(&self) -> &str {
+        self.command.strip_prefix('!').unwrap_or(&self.command)
+    }
+
+    fn timed_out(&self) -> CredentialHelperError {
+        self.error(format!(
+            "did not finish within {}s",
+            self.timeout.as_secs_f32()
+        ))
+    }
+
+    fn error(&self, problem: String) -> CredentialHelperError {
+        CredentialHelperError {
+            command: self.command.clone(),
+            problem,
+        }
+    }
+}
+
+/// Kills the helper and, where the platform allows, anything it started.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // The helper leads its own process group, which has its process ID.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+fn git_description(server_url: &str) -> String {
+    let url = match reqwest::Url::parse(server_url) {
+        Ok(url) => url,
+        Err(_) => return format!("url={}\n\n", server_url),
+    };
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => "".to_owned(),
+    };
+    let mut description = format!("protocol={}\nhost={}\n", url.scheme(), host);
+    let path = url.path().trim_matches('/');
+    if !path.is_empty() {
+        description.push_str(&format!("path={}\n", path));
+    }
+    description.push('\n');
+    description
+}
+
+fn parse_output(stdout: &str) -> anyhow::Result<HelperCredentials> {
+    let output = if stdout.trim_start().starts_with('{') {
+        serde_json::from_str(stdout)?
+    } else {
+        // git's format: one key=value per line, ignoring keys it doesn't know
+        let value = |key: &str| {
+            stdout
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(k, _)| k.trim() == key)
+                .map(|(_, v)| v.to_owned())
+        };
+        HelperOutput {
+            username: value("username"),
+            password: value("password"),
+            token: value("token"),
+        }
+    };
+    match output {
+        HelperOutput {
+            token: Some(token),
+            username: None,
+            ..
+        } => Ok(HelperCredentials::Token(token)),
+        HelperOutput {
+            username: Some(username),
+            password: Some(password),
+            token: None,
+        } => Ok(HelperCredentials::Login { username, password }),
+        HelperOutput {
+            token: Some(_),
+            username: Some(_),
+            ..
+        } => Err(anyhow::anyhow!(
+            "expected a token or a username and password, not both"
+        )),
+        _ => Err(anyhow::anyhow!(
+            "expected 'token', or 'username' and 'password'"
+        )),
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod test {
+    use super::*;
+
+    const STUB: &str = "sh testdata/credential_helper/stub.sh";
+
+    fn stub(mode: &str) -> CredentialHelper {
+        CredentialHelper::new(format!("{} {}", STUB, mode))
+    }
+
+    #[test]
+    fn test_json_login_is_read() {
+        let credentials = stub("login").get("https://bindle.example.com/v1").unwrap();
+        assert_eq!(
+            HelperCredentials::Login {
+                username: "helper-user".to_owned(),
+                password: "secret for https://bindle.example.com/v1".to_owned(),
+            },
+            credentials
+        );
+    }
+
+    #[test]
+    fn test_json_token_is_read() {
+        let credentials = stub("token").get("https://hippo.example.com").unwrap();
+        assert_eq!(
+            HelperCredentials::Token("helper-token".to_owned()),
+            credentials
+        );
+    }
+
+    #[test]
+    fn test_git_style_helper_is_given_the_server_on_stdin() {
+        let credentials = CredentialHelper::new(format!("!{} git", STUB))
+            .get("https://bindle.example.com:8080/v1/")
+            .unwrap();
+        assert_eq!(
+            HelperCredentials::Login {
+                username: "user@bindle.example.com:8080".to_owned(),
+                password: "path v1".to_owned(),
+            },
+            credentials
+        );
+    }
+
+    #[test]
+    fn test_failing_helper_is_an_error_naming_it() {
+        let err = stub("fail").get("https://bindle.example.com").unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("stub.sh fail") && message.contains("failed"),
+            "names the helper: '{}'",
+            message
+        );
+    }
+
+    #[test]
+    fn test_malformed_output_is_an_error_naming_it() {
+        for mode in &["malformed", "nothing"] {
+            let message = stub(mode)
+                .get("https://bindle.example.com")
+                .unwrap_err()
+                .to_string();
+            assert!(
+                message.contains(&format!("stub.sh {}", mode))
+                    && message.contains("invalid credentials"),
+                "names the helper: '{}'",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_helper_which_prints_more_than_a_pipe_holds_is_read() {
+        let credentials = stub("verbose").get("https://bindle.example.com").unwrap();
+        assert_eq!(HelperCredentials::Token("x".repeat(200_000)), credentials);
+    }
+
+    #[test]
+    fn test_timed_out_helper_is_killed_with_what_it_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("orphan-ran");
+        let started = Instant::now();
+        let err = CredentialHelper::new(format!("{} orphan {}", STUB, marker.display()))
+            .with_timeout(Duration::from_millis(200))
+            .get("https://bindle.example.com")
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1), "{}", err);
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists(), "the helper's background command ran on");
+    }
+
+    #[test]
+    fn test_slow_helper_times_out() {
+        let started = Instant::now();
+        let message = stub("slow")
+            .with_timeout(Duration::from_millis(200))
+            .get("https://bindle.example.com")
+            .unwrap_err()
+            .to_string();
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert!(
+            message.contains("stub.sh slow") && message.contains("did not finish"),
+            "names the helper: '{}'",
+            message
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Docker-style credentials files, which map server hosts to credentials, e.g.
+//!
+//! ```json
+//! { "auths": { "bindle.example.com:8080": { "auth": "<base64 of username:password>" } } }
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct CredentialsFile {
+    #[serde(default)]
+    auths: HashMap<String, CredentialsEntry>,
+}
+
+#[derive(Deserialize)]
+struct CredentialsEntry {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Looks up the credentials for the server at `base_url`. An entry which names a
+/// port is preferred to one which gives only the host; the latter matches any port.
+pub fn find_credentials(
+    path: impl AsRef<Path>,
+    base_url: &str,
+) -> anyhow::Result<Option<Credentials>> {
+    // Immediate-call closure lets us use the try operator
+    let result = (|| {
+        let content = std::fs::read_to_string(&path)?;
+        let file: CredentialsFile = serde_json::from_str(&content)?;
+        lookup(&file, base_url)
+    })();
+    result.map_err(|e: anyhow::Error| {
+        anyhow::anyhow!(
+            "Error reading credentials file {}: {}",
+            path.as_ref().to_string_lossy(),
+            e
+        )
+    })
+}
+
+fn lookup(file: &CredentialsFile, base_url: &str) -> anyhow::Result<Option<Credentials>> {
+    let url = reqwest::Url::parse(base_url)?;
+    let host = match url.host_str() {
+        Some(host) => host.to_lowercase(),
+        None => return Ok(None),
+    };
+    let port = url.port_or_known_default();
+
+    let entries: Vec<_> = file
+        .auths
+        .iter()
+        .flat_map(|(key, entry)| parse_key(key).map(|k| (k, entry)))
+        .filter(|((h, _), _)| h == &host)
+        .collect();
+    let exact = entries.iter().find(|((_, p), _)| p.is_some() && p == &port);
+    let any_port = entries.iter().find(|((_, p), _)| p.is_none());
+
+    match exact.or(any_port) {
+        None => Ok(None),
+        Some(((h, _), entry)) => credentials_of(entry)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Invalid entry for {}: {}", h, e)),
+    }
+}
+
+/// Splits a key into a host and, if the key specifies one, a port. Keys written
+/// as URLs always have a port, since the scheme implies one.
+fn parse_key(key: &str) -> Option<(String, Option<u16>)> {
+    if key.contains("://") {
+        let url = reqwest::Url::parse(key).ok()?;
+        let host = url.host_str()?.to_lowercase();
+        return Some((host, url.port_or_known_default()));
+    }
+    let host_port = key.split('/').next()?.to_lowercase();
+    match host_port.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => Some((host.to_owned(), Some(port))),
+            Err(_) => Some((host_port.clone(), None)),
+        },
+        None => Some((host_port, None)),
+    }
+}
+
+fn credentials_of(entry: &CredentialsEntry) -> anyhow::Result<Credentials> {
+    match (&entry.auth, &entry.username, &entry.password) {
+        (_, Some(username), Some(password)) => Ok(Credentials {
+            username: username.clone(),
+            password: password.clone(),
+        }),
+        (Some(auth), _, _) => {
+            let decoded = String::from_utf8(base64::decode(auth)?)?;
+            let (username, password) = decoded
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("'auth' must encode 'username:password'"))?;
+            Ok(Credentials {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            })
+        }
+        _ => Err(anyhow::anyhow!(
+            "expected 'auth', or 'username' and 'password'"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIXTURE: &str = "./testdata/credentials/credentials.json";
+
+    fn credentials(username: &str, password: &str) -> Option<Credentials> {
+        Some(Credentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+
+    #[test]
+    fn test_host_entry_matches_any_port() {
+        let found = find_credentials(FIXTURE, "http://bindle.example.com:8080/v1").unwrap();
+        assert_eq!(credentials("anyport", "anyport-password"), found);
+    }
+
+    #[test]
+    fn test_port_entry_is_preferred_to_host_entry() {
+        let found = find_credentials(FIXTURE, "https://bindle.example.com:8443/v1").unwrap();
+        assert_eq!(credentials("port8443", "port8443-password"), found);
+    }
+
+    #[test]
+    fn test_url_entry_matches_default_port() {
+        let found = find_credentials(FIXTURE, "https://SECURE.example.com/v1").unwrap();
+        assert_eq!(credentials("secure", "secure-password"), found);
+
+        let found = find_credentials(FIXTURE, "https://secure.example.com:8443/v1").unwrap();
+        assert_eq!(None, found);
+    }
+
+    #[test]
+    fn test_unknown_host_has_no_credentials() {
+        let found = find_credentials(FIXTURE, "http://localhost:8080/v1").unwrap();
+        assert_eq!(None, found);
+    }
+
+    #[test]
+    fn test_missing_file_is_an_error() {
+        let err = find_credentials("./testdata/credentials/nope.json", "http://localhost/v1")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("nope.json"), "error names file: '{}'", err);
+    }
+}
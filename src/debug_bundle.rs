@@ -0,0 +1,749 @@
+//! Capturing what a command did, for attaching to a bug report. With
+//! `--debug-bundle <path.zip>`, the command records each HTTP request it makes
+//! and writes a zip file holding:
+//!
+//! - `http.json`: each request and its response, with truncated bodies. The
+//!   bodies of parcel downloads and of the event stream are not recorded.
+//! - `config.json`: the command line and the `HIPPO_` and `BINDLE_`
+//!   environment variables.
+//! - `plan.json`: a summary of the expansion plan, if the command made one.
+//! - `environment.json`: the Hippo version, the OS, and what the servers were
+//!   found not to support.
+//! - `error.txt`: the error, if the command failed.
+//!
+//! Nothing secret goes into the bundle. Authorization headers, the passwords
+//! in URLs, and query parameters, JSON fields and environment variables whose
+//! names suggest secrets are redacted when recorded; and every credential the
+//! command used is registered with `add_secret`, and scrubbed from everything
+//! in the bundle, wherever it appears, when the bundle is written.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::expansion_plan::{ExpansionPlan, PlannedSource};
+
+const REDACTED: &str = "REDACTED";
+/// The most of each body which goes into the bundle.
+const MAX_BODY_LENGTH: usize = 4096;
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+/// Parameters, fields and variables whose names contain any of these hold
+/// secrets.
+const SENSITIVE_NAMES: &[&str] = &["password", "token", "secret", "key", "auth", "credential"];
+
+static ACTIVE: OnceCell<DebugTrace> = OnceCell::new();
+
+/// Starts capturing for the rest of the process.
+pub fn start() -> &'static DebugTrace {
+    ACTIVE.get_or_init(DebugTrace::default)
+}
+
+/// The capture in progress, if `start` was called.
+pub fn active() -> Option<&'static DebugTrace> {
+    ACTIVE.get()
+}
+
+/// Registers a credential to scrub from the bundle, if one is being captured.
+pub fn add_secret(secret: &str) {
+    if let Some(trace) = active() {
+        trace.add_secret(secret);
+    }
+}
+
+/// Registers the password in a URL's user info, if it has one, to scrub from
+/// the bundle.
+pub fn add_url_secret(url: &str) {
+    if let Some(password) = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.password().map(|p| p.to_owned()))
+    {
+        add_secret(&password);
+        add_secret(&percent_decode(&password));
+    }
+}
+
+/// How much of a response to record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseRecording {
+    /// The status, headers and body.
+    Whole,
+    /// The status and headers, for a response whose body the caller streams.
+    HeadersOnly,
+}
+
+/// Sends a request with `send`, recording it and its response, or the error,
+/// if a bundle is being captured.
+pub async fn send_traced<F, Fut, E>(
+    subsystem: &str,
+    request: reqwest::RequestBuilder,
+    recording: ResponseRecording,
+    send: F,
+) -> Result<reqwest::Response, E>
+where
+    F: FnOnce(reqwest::RequestBuilder) -> Fut,
+    Fut: std::future::Future<Output = Result<reqwest::Response, E>>,
+    E: std::fmt::Debug + From<reqwest::Error>,
+{
+    let trace = match active() {
+        Some(trace) => trace,
+        None => return send(request).await,
+    };
+    let exchange = HttpExchange::for_request(subsystem, &request);
+    match send(request).await {
+        Ok(response) => match recording {
+            ResponseRecording::Whole => Ok(trace.record_response(exchange, response).await?),
+            ResponseRecording::HeadersOnly => {
+                trace.record(HttpExchange {
+                    status: Some(response.status().as_u16()),
+                    response_headers: redact_headers(response.headers()),
+                    ..exchange
+                });
+                Ok(response)
+            }
+        },
+        Err(e) => {
+            trace.record(HttpExchange {
+                error: Some(format!("{:?}", e)),
+                ..exchange
+            });
+            Err(e)
+        }
+    }
+}
+
+/// Records something a server was found not to support, if a bundle is being
+/// captured.
+pub fn note_capability(name: &str, value: impl Into<String>) {
+    if let Some(trace) = active() {
+        trace
+            .lock()
+            .capabilities
+            .insert(name.to_owned(), value.into());
+    }
+}
+
+/// Records a summary of the expansion plan, if a bundle is being captured.
+pub fn note_plan(plan: &ExpansionPlan) {
+    if let Some(trace) = active() {
+        trace.lock().plan = Some(PlanSummary::of(plan));
+    }
+}
+
+#[derive(Default)]
+pub struct DebugTrace {
+    inner: Mutex<TraceContents>,
+}
+
+#[derive(Default)]
+struct TraceContents {
+    exchanges: Vec<HttpExchange>,
+    secrets: Vec<String>,
+    capabilities: BTreeMap<String, String>,
+    plan: Option<PlanSummary>,
+}
+
+/// One request, and its response if it was seen.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpExchange {
+    pub subsystem: String,
+    pub method: String,
+    pub url: String,
+    pub request_headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub response_headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlanSummary {
+    bindle_id: String,
+    groups: Vec<String>,
+    parcels: Vec<PlannedParcelSummary>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlannedParcelSummary {
+    name: String,
+    source: String,
+    member_of: Vec<String>,
+    requires: Vec<String>,
+}
+
+impl PlanSummary {
+    fn of(plan: &ExpansionPlan) -> Self {
+        Self {
+            bindle_id: plan.bindle_id.clone(),
+            groups: plan.groups.clone(),
+            parcels: plan
+                .parcels
+                .iter()
+                .map(|p| PlannedParcelSummary {
+                    name: p.parcel_name.clone(),
+                    source: match &p.source {
+                        PlannedSource::File { .. } => "file".to_owned(),
+                        PlannedSource::External { .. } => "external".to_owned(),
+                    },
+                    member_of: p.member_of.clone(),
+                    requires: p.requires.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl HttpExchange {
+    /// The request part of an exchange, redacted. A request with a streamed
+    /// body, such as a parcel upload, can't be inspected without sending it, so
+    /// is recorded without its details.
+    pub fn for_request(subsystem: &str, request: &reqwest::RequestBuilder) -> Self {
+        let request = match request.try_clone().map(|r| r.build()) {
+            Some(Ok(request)) => request,
+            _ => {
+                return Self {
+                    subsystem: subsystem.to_owned(),
+                    url: "(a request with a streamed body)".to_owned(),
+                    ..Self::default()
+                }
+            }
+        };
+        Self {
+            subsystem: subsystem.to_owned(),
+            method: request.method().to_string(),
+            url: redact_url(request.url().as_str()),
+            request_headers: redact_headers(request.headers()),
+            request_body: request.body().and_then(|b| b.as_bytes()).map(redact_body),
+            ..Self::default()
+        }
+    }
+}
+
+impl DebugTrace {
+    fn lock(&self) -> std::sync::MutexGuard<'_, TraceContents> {
+        // A panic while holding the lock leaves nothing half-recorded that
+        // matters, so carry on with what is there.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn add_secret(&self, secret: &str) {
+        if !secret.is_empty() {
+            self.lock().secrets.push(secret.to_owned());
+        }
+    }
+
+    pub fn record(&self, exchange: HttpExchange) {
+        self.lock().exchanges.push(exchange);
+    }
+
+    /// Records a response to the request in `exchange`, returning a response
+    /// with the same status, headers and body for the caller to use.
+    pub async fn record_response(
+        &self,
+        mut exchange: HttpExchange,
+        response: reqwest::Response,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        exchange.status = Some(status.as_u16());
+        exchange.response_headers = redact_headers(&headers);
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                exchange.error = Some(e.to_string());
+                self.record(exchange);
+                return Err(e);
+            }
+        };
+        exchange.response_body = Some(redact_body(&body));
+        self.record(exchange);
+
+        let mut rebuilt = http::Response::new(body);
+        *rebuilt.status_mut() = status;
+        *rebuilt.headers_mut() = headers;
+        Ok(reqwest::Response::from(rebuilt))
+    }
+
+    /// Writes the bundle to `path`, with the error the command failed with,
+    /// if it did.
+    pub fn write_bundle(
+        &self,
+        path: impl AsRef<Path>,
+        error: Option<&anyhow::Error>,
+    ) -> anyhow::Result<()> {
+        let contents = self.lock();
+        let scrubber = Scrubber::new(&contents.secrets);
+        let environment = serde_json::json!({
+            "hippoVersion": env!("CARGO_PKG_VERSION"),
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+            "serverCapabilities": contents.capabilities,
+        });
+        let mut entries = vec![
+            ("http.json", scrubber.json(&contents.exchanges)?),
+            (
+                "config.json",
+                scrubber.json(&configuration(std::env::args(), std::env::vars()))?,
+            ),
+            ("environment.json", scrubber.json(&environment)?),
+        ];
+        if let Some(plan) = &contents.plan {
+            entries.push(("plan.json", scrubber.json(plan)?));
+        }
+        if let Some(error) = error {
+            entries.push(("error.txt", scrubber.text(&format!("{:?}", error))));
+        }
+
+        let file = std::fs::File::create(path.as_ref()).map_err(|e| {
+            anyhow::anyhow!(
+                "Error creating debug bundle {}: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })?;
+        let mut zip = zip::ZipWriter::new(file);
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, text) in entries {
+            zip.start_file(name, options)?;
+            zip.write_all(text.as_bytes())?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+/// Replaces registered secrets, in the forms in which they might appear, with
+/// `REDACTED`.
+struct Scrubber {
+    secrets: Vec<String>,
+}
+
+impl Scrubber {
+    fn new(secrets: &[String]) -> Self {
+        let mut forms: Vec<_> = secrets
+            .iter()
+            .flat_map(|s| vec![s.clone(), percent_encode(s), base64::encode(s)])
+            .collect();
+        // Scrub longer forms first, so that one secret inside another doesn't
+        // leave the rest of the longer one behind.
+        forms.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        forms.dedup();
+        Self { secrets: forms }
+    }
+
+    fn text(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_owned(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+    }
+
+    /// Serializes a value, scrubbing every string in it, and shortening bodies
+    /// only once they have been scrubbed, so that a secret cut short by the
+    /// truncation can't escape.
+    fn json(&self, value: &impl Serialize) -> anyhow::Result<String> {
+        let mut value = serde_json::to_value(value)?;
+        self.scrub_value(&mut value, None);
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+
+    fn scrub_value(&self, value: &mut serde_json::Value, key: Option<&str>) {
+        match value {
+            serde_json::Value::String(text) => {
+                let scrubbed = self.text(text);
+                *text = match key {
+                    Some("requestBody") | Some("responseBody") => truncate(scrubbed),
+                    _ => scrubbed,
+                };
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    self.scrub_value(value, None);
+                }
+            }
+            serde_json::Value::Object(map) => {
+                let scrubbed: serde_json::Map<_, _> = std::mem::take(map)
+                    .into_iter()
+                    .map(|(k, mut v)| {
+                        self.scrub_value(&mut v, Some(&k));
+                        (self.text(&k), v)
+                    })
+                    .collect();
+                *map = scrubbed;
+            }
+            _ => (),
+        }
+    }
+}
+
+fn truncate(text: String) -> String {
+    if text.len() <= MAX_BODY_LENGTH {
+        return text;
+    }
+    let mut end = MAX_BODY_LENGTH;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes in all)", &text[..end], text.len())
+}
+
+fn is_sensitive_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SENSITIVE_NAMES.iter().any(|s| name.contains(s))
+}
+
+/// A URL without the password of its user info, or the values of query
+/// parameters whose names suggest secrets.
+fn redact_url(text: &str) -> String {
+    let mut url = match reqwest::Url::parse(text) {
+        Ok(url) => url,
+        Err(_) => return text.to_owned(),
+    };
+    if url.password().is_some() {
+        let _ = url.set_password(Some(REDACTED));
+    }
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(k, v)| {
+                let v = if is_sensitive_name(&k) {
+                    REDACTED.to_owned()
+                } else {
+                    v.into_owned()
+                };
+                (k.into_owned(), v)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+fn redact_headers(headers: &reqwest::header::HeaderMap) -> BTreeMap<String, String> {
+    let mut redacted = BTreeMap::<String, String>::new();
+    for (name, value) in headers {
+        let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+            REDACTED
+        } else {
+            value.to_str().unwrap_or("(not text)")
+        };
+        redacted
+            .entry(name.to_string())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(value)
+            })
+            .or_insert_with(|| value.to_owned());
+    }
+    redacted
+}
+
+/// A body as text, with the values of JSON fields whose names suggest secrets
+/// redacted.
+fn redact_body(body: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(mut value) => {
+            redact_json_fields(&mut value);
+            value.to_string()
+        }
+        Err(_) => match std::str::from_utf8(body) {
+            Ok(text) => text.to_owned(),
+            Err(_) => format!("({} bytes of binary)", body.len()),
+        },
+    }
+}
+
+fn redact_json_fields(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_json_fields),
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive_name(key) && !value.is_null() {
+                    *value = serde_json::Value::String(REDACTED.to_owned());
+                } else {
+                    redact_json_fields(value);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// The command line and the environment variables which configure Hippo and
+/// Bindle, with the values of options and variables whose names suggest
+/// secrets masked.
+fn configuration(
+    args: impl IntoIterator<Item = String>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> serde_json::Value {
+    let mut arguments = vec![];
+    let mut mask_next = false;
+    for arg in args {
+        if mask_next {
+            arguments.push(REDACTED.to_owned());
+            mask_next = false;
+            continue;
+        }
+        match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                let value = if is_sensitive_name(name) {
+                    REDACTED.to_owned()
+                } else {
+                    redact_url(value)
+                };
+                arguments.push(format!("{}={}", name, value));
+            }
+            None if arg.starts_with("--") && is_sensitive_name(&arg) => {
+                mask_next = true;
+                arguments.push(arg);
+            }
+            _ => arguments.push(redact_url(&arg)),
+        }
+    }
+    let environment: BTreeMap<_, _> = vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with("HIPPO_") || name.starts_with("BINDLE_"))
+        .map(|(name, value)| {
+            let value = if is_sensitive_name(&name) {
+                REDACTED.to_owned()
+            } else {
+                redact_url(&value)
+            };
+            (name, value)
+        })
+        .collect();
+    serde_json::json!({
+        "arguments": arguments,
+        "environment": environment,
+    })
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_utils::BindleConnectionInfo;
+    use crate::testing::{http_response, serve_once};
+    use itertools::Itertools;
+    use std::io::Read;
+
+    const PASSWORD: &str = "hunter2!pass";
+    const TOKEN: &str = "tok-5up3r-s3cr3t";
+
+    /// Every file in a bundle, concatenated.
+    fn bundle_text(trace: &DebugTrace, error: Option<&anyhow::Error>) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.zip");
+        trace.write_bundle(&path, error).unwrap();
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let mut text = String::new();
+        for i in 0..zip.len() {
+            zip.by_index(i).unwrap().read_to_string(&mut text).unwrap();
+        }
+        text
+    }
+
+    fn assert_has_no_secrets(text: &str) {
+        for secret in &[PASSWORD, TOKEN] {
+            assert!(!text.contains(secret), "contains {}: {}", secret, text);
+            assert!(
+                !text.contains(&percent_encode(secret)),
+                "contains {}: {}",
+                secret,
+                text
+            );
+        }
+        let basic = base64::encode(format!("admin:{}", PASSWORD));
+        assert!(!text.contains(&basic), "contains basic auth: {}", text);
+    }
+
+    #[test]
+    fn test_urls_lose_embedded_credentials() {
+        let url = redact_url(&format!(
+            "https://admin:{}@bindle.example.com/v1/_q?access_token={}&limit=5",
+            percent_encode(PASSWORD),
+            TOKEN
+        ));
+        assert_has_no_secrets(&url);
+        assert!(url.contains("admin:REDACTED@bindle.example.com"), "{}", url);
+        assert!(url.contains("limit=5"), "{}", url);
+    }
+
+    #[test]
+    fn test_bundle_redacts_authorization_and_json_fields() {
+        let trace = DebugTrace::default();
+        let client = reqwest::Client::new();
+        let login = client
+            .post("https://hippo.example.com/account/createtoken")
+            .json(&serde_json::json!({"userName": "admin", "password": PASSWORD}));
+        trace.record(HttpExchange::for_request("hippo", &login));
+        let push = client
+            .get("https://bindle.example.com/v1/_i/app/1.0.0")
+            .basic_auth("admin", Some(PASSWORD))
+            .header("x-request-id", "abc");
+        let mut exchange = HttpExchange::for_request("bindle", &push);
+        exchange.status = Some(401);
+        exchange.response_body = Some(redact_body(
+            format!("{{\"token\": \"{}\", \"detail\": \"denied\"}}", TOKEN).as_bytes(),
+        ));
+        trace.record(exchange);
+
+        let text = bundle_text(&trace, None);
+        assert_has_no_secrets(&text);
+        assert!(text.contains("\"x-request-id\": \"abc\""), "{}", text);
+        assert!(text.contains("denied"), "{}", text);
+        assert!(text.contains("401"), "{}", text);
+    }
+
+    #[test]
+    fn test_registered_secrets_are_scrubbed_everywhere() {
+        let trace = DebugTrace::default();
+        trace.add_secret(PASSWORD);
+        trace.add_secret(TOKEN);
+        let request = reqwest::Client::new()
+            .get(format!("https://hippo.example.com/api/{}/revisions", TOKEN))
+            .header("x-echo", PASSWORD);
+        let mut exchange = HttpExchange::for_request("hippo", &request);
+        exchange.response_body = Some(format!("{}{}", "x".repeat(MAX_BODY_LENGTH - 4), TOKEN));
+        trace.record(exchange);
+        let error = anyhow::anyhow!("login as admin with {} failed", PASSWORD);
+
+        let text = bundle_text(&trace, Some(&error));
+        assert_has_no_secrets(&text);
+        assert!(
+            text.contains("login as admin with REDACTED failed"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_configuration_masks_secret_options_and_variables() {
+        let args = vec![
+            "hippo",
+            "push",
+            "--hippo-password",
+            PASSWORD,
+            &format!("--bindle-password={}", PASSWORD),
+            &format!("--hippo-url=https://admin:{}@hippo.example.com", TOKEN),
+            ".",
+        ]
+        .into_iter()
+        .map(|s| s.to_owned())
+        .collect_vec();
+        let vars = vec![
+            ("HIPPO_TOKEN".to_owned(), TOKEN.to_owned()),
+            (
+                "BINDLE_URL".to_owned(),
+                "https://bindle.example.com/v1".to_owned(),
+            ),
+            ("HOME".to_owned(), "/home/admin".to_owned()),
+        ];
+
+        let text = configuration(args, vars).to_string();
+        assert_has_no_secrets(&text);
+        assert!(text.contains("https://bindle.example.com/v1"), "{}", text);
+        assert!(text.contains("--hippo-password"), "{}", text);
+        assert!(!text.contains("HOME"), "{}", text);
+    }
+
+    #[test]
+    fn test_percent_decoding_reverses_encoding() {
+        assert_eq!(PASSWORD, percent_decode(&percent_encode(PASSWORD)));
+        assert_eq!("100%", percent_decode("100%"));
+    }
+
+    #[tokio::test]
+    async fn test_bundle_of_bindle_requests_has_no_credentials() {
+        const URL_PASSWORD: &str = "url-pa55:word";
+        const USER_PASSWORD: &str = "bundle-test-pa55word";
+        let trace = start();
+
+        let (url, _server) = serve_once(http_response(
+            "401 Unauthorized",
+            &format!("error = \"bad password {}\"\n", USER_PASSWORD),
+        ));
+        let url = url.replace(
+            "http://",
+            &format!("http://admin:{}@", percent_encode(URL_PASSWORD)),
+        );
+        let connection = BindleConnectionInfo::new(
+            &url,
+            false,
+            Some("admin".to_owned()),
+            Some(USER_PASSWORD.to_owned()),
+        );
+        let id = "bundle-test/1.0.0".parse().unwrap();
+        let error = connection
+            .client()
+            .unwrap()
+            .get_invoice(&id)
+            .await
+            .unwrap_err();
+
+        let text = bundle_text(trace, Some(&anyhow::Error::new(error)));
+        assert!(text.contains("/v1/_i/bundle-test/1.0.0"), "{}", text);
+        assert!(text.contains("\"status\": 401"), "{}", text);
+        assert!(text.contains("bad password REDACTED"), "{}", text);
+        for secret in &[URL_PASSWORD, USER_PASSWORD] {
+            assert!(!text.contains(secret), "contains {}: {}", secret, text);
+            assert!(
+                !text.contains(&percent_encode(secret)),
+                "contains {}: {}",
+                secret,
+                text
+            );
+        }
+    }
+}
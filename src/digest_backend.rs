@@ -0,0 +1,42 @@
+//! Computing the SHA-256 digests against which parcels are checked. Deep
+//! verification of a large bindle spends most of its time hashing, so the
+//! computation is behind a trait, and a deployment which has a faster
+//! implementation (hardware-accelerated, or hashing on other threads) can
+//! plug it in. `Sha2Backend` is the default.
+
+use sha2::{Digest, Sha256};
+
+/// A source of SHA-256 hashers. A backend must give the same digests as
+/// `Sha2Backend`: it is only a different way of computing them.
+pub trait DigestBackend: Send + Sync {
+    fn hasher(&self) -> Box<dyn DigestHasher>;
+}
+
+/// An incremental SHA-256 computation.
+pub trait DigestHasher: Send {
+    fn update(&mut self, data: &[u8]);
+    /// The digest of all the data passed to `update`, as lower-case hex.
+    fn finish(self: Box<Self>) -> String;
+}
+
+/// The default backend, using the sha2 crate.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha2Backend;
+
+impl DigestBackend for Sha2Backend {
+    fn hasher(&self) -> Box<dyn DigestHasher> {
+        Box::new(Sha2Hasher(Sha256::new()))
+    }
+}
+
+struct Sha2Hasher(Sha256);
+
+impl DigestHasher for Sha2Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.0.finalize())
+    }
+}
@@ -0,0 +1,784 @@
+//! Downloading the parcels a bindle always needs, its required closure, into a
+//! directory in a way that can be interrupted and resumed. The session records
+//! each parcel in a manifest in the directory as soon as it is complete, so
+//! that running the download again fetches only the parcels which are not. A
+//! session can also fetch just one handler or group and the parcels it
+//! requires, for previewing a handler without downloading the rest of a large
+//! bindle.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bindle_fetcher::{
+    batch_closure, fetch_parcel_to_file, BatchStrategy, FetchOptions, FetchedParcel, VerifyPolicy,
+};
+use crate::bindle_utils::{BindleSource, InvoiceHelpers};
+use crate::warnings::Warned;
+
+/// The name of the manifest which a session keeps in its directory.
+pub const MANIFEST_NAME: &str = ".hippo-download.json";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionManifest {
+    bindle_id: String,
+    /// The sha256 of the invoice being downloaded, so that a session for an
+    /// invoice which has since changed is not resumed.
+    invoice_sha256: String,
+    /// The sha256 of each parcel which has been downloaded in full.
+    completed: BTreeSet<String>,
+}
+
+/// A resumable download of the parcels of one invoice into a directory, each
+/// as `<sha256>.dat`.
+pub struct DownloadSession {
+    dir: PathBuf,
+    manifest: SessionManifest,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadReport {
+    /// The number of parcels downloaded by this run.
+    pub downloaded: usize,
+    /// The number of parcels which an earlier run had already downloaded.
+    pub resumed: usize,
+}
+
+impl std::fmt::Display for DownloadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "downloaded {} parcels; {} already downloaded",
+            self.downloaded, self.resumed
+        )
+    }
+}
+
+/// What a session fetched for part of a bindle, such as one handler, compared
+/// with the whole bindle.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PartialDownloadReport {
+    pub download: DownloadReport,
+    /// What was fetched, e.g. "the handler 'main.wasm'".
+    pub selection: String,
+    /// The number of distinct parcels the selection needs.
+    pub parcels: usize,
+    /// The number of distinct parcels in the bindle's required closure and
+    /// the selection.
+    pub total_parcels: usize,
+    /// The size of the parcels the selection needs.
+    pub bytes: u64,
+    /// The size of all the parcels in the bindle's required closure and the
+    /// selection.
+    pub total_bytes: u64,
+}
+
+impl PartialDownloadReport {
+    /// The fraction of the bindle's bytes which were not needed.
+    pub fn skipped_fraction(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            (self.total_bytes - self.bytes) as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+impl std::fmt::Display for PartialDownloadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}; {} needs {} of {} parcels, skipping {:.1}% of the bindle",
+            self.download,
+            self.selection,
+            self.parcels,
+            self.total_parcels,
+            self.skipped_fraction() * 100.0
+        )
+    }
+}
+
+impl DownloadSession {
+    /// Opens the session for downloading `invoice` into `dir`, resuming an
+    /// earlier one if there is one for the same invoice. If the invoice has
+    /// changed since, the earlier session is abandoned, with a warning, and
+    /// this one starts from scratch.
+    pub fn open(dir: impl AsRef<Path>, invoice: &bindle::Invoice) -> anyhow::Result<Warned<Self>> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let fresh = SessionManifest {
+            bindle_id: invoice.bindle.id.to_string(),
+            invoice_sha256: invoice_sha256(invoice)?,
+            completed: BTreeSet::new(),
+        };
+        Ok(match read_manifest(&dir.join(MANIFEST_NAME))? {
+            Some(earlier) if earlier.invoice_sha256 == fresh.invoice_sha256 => Self {
+                dir,
+                manifest: earlier,
+            }
+            .into(),
+            Some(earlier) => (
+                Self {
+                    dir,
+                    manifest: fresh,
+                },
+                format!(
+                    "The invoice for {} has changed since the last download: starting again",
+                    earlier.bindle_id
+                ),
+            )
+                .into(),
+            None => Self {
+                dir,
+                manifest: fresh,
+            }
+            .into(),
+        })
+    }
+
+    /// The path at which the session puts a parcel.
+    pub fn parcel_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(format!("{}.dat", sha256))
+    }
+
+    /// Downloads each parcel of the invoice's required closure which the
+    /// session has not already completed, recording each in the manifest as
+    /// it finishes. A parcel which the manifest lists but whose file has gone
+    /// or is the wrong size is downloaded again.
+    pub async fn download(
+        &mut self,
+        source: &impl BindleSource,
+        invoice: &bindle::Invoice,
+        options: &FetchOptions,
+    ) -> anyhow::Result<DownloadReport> {
+        let closure = invoice.required_closure();
+        self.download_parcels(source, invoice, closure.iter().collect(), options)
+            .await
+    }
+
+    /// Downloads the same parcels as `download`, but a batch at a time, waiting
+    /// `pause` after each batch which downloaded anything, for servers which
+    /// limit how fast they may be asked for parcels.
+    pub async fn download_in_batches(
+        &mut self,
+        source: &impl BindleSource,
+        invoice: &bindle::Invoice,
+        strategy: BatchStrategy,
+        pause: Duration,
+        options: &FetchOptions,
+    ) -> anyhow::Result<DownloadReport> {
+        let mut report = DownloadReport::default();
+        let mut batches = batch_closure(invoice, strategy).into_iter().peekable();
+        while let Some(batch) = batches.next() {
+            let batch_report = self
+                .download_parcels(source, invoice, batch.iter().collect(), options)
+                .await?;
+            report.downloaded += batch_report.downloaded;
+            report.resumed += batch_report.resumed;
+            if batch_report.downloaded > 0 && batches.peek().is_some() {
+                tokio::time::sleep(pause).await;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Downloads only the named handler and the parcels it requires, directly
+    /// or indirectly. The handler is the parcel with that name, or with that
+    /// `wagi_handler_id` annotation; it is an error if this matches more than
+    /// one parcel.
+    pub async fn download_handler(
+        &mut self,
+        source: &impl BindleSource,
+        invoice: &bindle::Invoice,
+        handler: &str,
+        options: &FetchOptions,
+    ) -> anyhow::Result<PartialDownloadReport> {
+        let parcels = handler_closure(invoice, handler)?;
+        let selection = format!("the handler '{}'", handler);
+        self.download_selection(source, invoice, selection, parcels, options)
+            .await
+    }
+
+    /// Downloads only the parcels in the named group and the parcels they
+    /// require, directly or indirectly. It is an error if the invoice neither
+    /// declares the group nor has any parcels in it.
+    pub async fn download_group(
+        &mut self,
+        source: &impl BindleSource,
+        invoice: &bindle::Invoice,
+        group: &str,
+        options: &FetchOptions,
+    ) -> anyhow::Result<PartialDownloadReport> {
+        let closure = invoice.closure_of_group(group);
+        if let Some(unknown) = closure.unknown_group {
+            anyhow::bail!(
+                "Bindle {} has no group named '{}'. Available groups are: {}",
+                invoice.bindle.id,
+                unknown,
+                invoice.group.iter().flatten().map(|g| &g.name).join(", ")
+            );
+        }
+        let selection = format!("the group '{}'", group);
+        self.download_selection(source, invoice, selection, closure.parcels, options)
+            .await
+    }
+
+    /// Downloads the parcels which the bindle needs with the groups of the
+    /// named activation profile activated: its required closure, and the
+    /// profile's closure.
+    pub async fn download_profile(
+        &mut self,
+        source: &impl BindleSource,
+        invoice: &bindle::Invoice,
+        profile: &str,
+        options: &FetchOptions,
+    ) -> anyhow::Result<PartialDownloadReport> {
+        let parcels = invoice
+            .required_closure()
+            .into_iter()
+            .chain(invoice.profile_closure(profile)?)
+            .unique_by(|p| p.label.sha256.clone())
+            .collect();
+        let selection = format!("the profile '{}'", profile);
+        self.download_selection(source, invoice, selection, parcels, options)
+            .await
+    }
+
+    async fn download_selection(
+        &mut self,
+        source: &impl BindleSource,
+        invoice: &bindle::Invoice,
+        selection: String,
+        parcels: Vec<bindle::Parcel>,
+        options: &FetchOptions,
+    ) -> anyhow::Result<PartialDownloadReport> {
+        let all = invoice
+            .required_closure()
+            .into_iter()
+            .chain(parcels.iter().cloned())
+            .unique_by(|p| p.label.sha256.clone())
+            .collect_vec();
+        let mut report = PartialDownloadReport {
+            selection,
+            parcels: parcels.len(),
+            total_parcels: all.len(),
+            bytes: parcels.iter().map(|p| p.label.size).sum(),
+            total_bytes: all.iter().map(|p| p.label.size).sum(),
+            ..PartialDownloadReport::default()
+        };
+        report.download = self
+            .download_parcels(source, invoice, parcels.iter().collect(), options)
+            .await?;
+        Ok(report)
+    }
+
+    async fn download_parcels(
+        &mut self,
+        source: &impl BindleSource,
+        invoice: &bindle::Invoice,
+        parcels: Vec<&bindle::Parcel>,
+        options: &FetchOptions,
+    ) -> anyhow::Result<DownloadReport> {
+        let id = &invoice.bindle.id;
+        let mut report = DownloadReport::default();
+        for parcel in parcels {
+            let sha256 = &parcel.label.sha256;
+            let path = self.parcel_path(sha256);
+            // Unless every file is to be re-hashed, the manifest is enough
+            // to trust a file which is the right size.
+            if options.verify != VerifyPolicy::Always
+                && self.manifest.completed.contains(sha256)
+                && has_size(&path, parcel.label.size)
+            {
+                report.resumed += 1;
+                continue;
+            }
+            match fetch_parcel_to_file(source, id, parcel, &path, options).await? {
+                FetchedParcel::AlreadyPresent => report.resumed += 1,
+                FetchedParcel::Downloaded => report.downloaded += 1,
+            }
+            self.manifest.completed.insert(sha256.clone());
+            self.save()?;
+        }
+        Ok(report)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let path = self.dir.join(MANIFEST_NAME);
+        let partial_path = self.dir.join(format!("{}.partial", MANIFEST_NAME));
+        let text = serde_json::to_string_pretty(&self.manifest)?;
+        std::fs::write(&partial_path, text)?;
+        std::fs::rename(&partial_path, &path).map_err(|e| {
+            anyhow::anyhow!(
+                "Error saving download manifest {}: {}",
+                path.to_string_lossy(),
+                e
+            )
+        })
+    }
+}
+
+/// The handler parcel named `handler`, followed by the parcels it requires,
+/// each once.
+fn handler_closure(
+    invoice: &bindle::Invoice,
+    handler: &str,
+) -> anyhow::Result<Vec<bindle::Parcel>> {
+    let parcels = invoice.parcel.as_ref().map(|v| &v[..]).unwrap_or_default();
+    let candidates = parcels
+        .iter()
+        .filter(|p| p.label.name == handler || handler_id(p) == Some(handler))
+        .unique_by(|p| &p.label.sha256)
+        .collect_vec();
+    let found = match candidates[..] {
+        [] => anyhow::bail!("Bindle {} has no handler '{}'", invoice.bindle.id, handler),
+        [found] => found,
+        _ => anyhow::bail!(
+            "More than one parcel in bindle {} matches handler '{}': {}",
+            invoice.bindle.id,
+            handler,
+            candidates
+                .iter()
+                .map(|p| format!("{} ({})", p.label.name, p.label.sha256))
+                .join(", ")
+        ),
+    };
+    Ok(std::iter::once(found.clone())
+        .chain(invoice.parcels_required_by(found))
+        .unique_by(|p| p.label.sha256.clone())
+        .collect())
+}
+
+fn handler_id(parcel: &bindle::Parcel) -> Option<&str> {
+    parcel
+        .label
+        .annotations
+        .as_ref()?
+        .get("wagi_handler_id")
+        .map(|s| s.as_str())
+}
+
+fn read_manifest(path: &Path) -> anyhow::Result<Option<SessionManifest>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // A manifest which can't be read is no worse than having none.
+    Ok(serde_json::from_str(&text).ok())
+}
+
+fn invoice_sha256(invoice: &bindle::Invoice) -> anyhow::Result<String> {
+    let text = toml::to_string(invoice)?;
+    Ok(format!("{:x}", Sha256::digest(text.as_bytes())))
+}
+
+fn has_size(path: &Path, size: u64) -> bool {
+    matches!(std::fs::metadata(path), Ok(m) if m.is_file() && m.len() == size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, sha256_of, test_invoice, FakeBindleServer};
+    use std::str::FromStr;
+
+    fn big_invoice() -> bindle::Invoice {
+        let id = bindle::Id::from_str("closure/1.0.0").unwrap();
+        test_invoice(
+            &id,
+            vec![
+                parcel_for("one.wasm", b"first parcel"),
+                parcel_for("two.wasm", b"second parcel"),
+                parcel_for("three.wasm", b"third parcel"),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_restarting_skips_downloaded_parcels() {
+        let invoice = big_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        // The server loses the third parcel, so the first run stops there.
+        let mut server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"first parcel")
+            .with_parcel(b"second parcel");
+
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        assert!(session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .is_err());
+
+        server = server.with_parcel(b"third parcel");
+        let served_before = server.chunks_served();
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let report = session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            DownloadReport {
+                downloaded: 1,
+                resumed: 2
+            },
+            report
+        );
+        let third_parcel_chunks = b"third parcel".len().div_ceil(4);
+        assert_eq!(third_parcel_chunks, server.chunks_served() - served_before);
+        let path = session.parcel_path(&sha256_of(b"third parcel"));
+        assert_eq!(b"third parcel".to_vec(), std::fs::read(path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_batched_download_pauses_between_batches() {
+        let invoice = big_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"first parcel")
+            .with_parcel(b"second parcel")
+            .with_parcel(b"third parcel");
+        let pause = Duration::from_millis(100);
+
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let start = std::time::Instant::now();
+        let report = session
+            .download_in_batches(
+                &server,
+                &invoice,
+                BatchStrategy::MaxCount(2),
+                pause,
+                &FetchOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            DownloadReport {
+                downloaded: 3,
+                resumed: 0
+            },
+            report
+        );
+        // Two batches, so one pause.
+        assert!(start.elapsed() >= pause);
+        for content in [&b"first parcel"[..], b"second parcel", b"third parcel"] {
+            let path = session.parcel_path(&sha256_of(content));
+            assert_eq!(content.to_vec(), std::fs::read(path).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_download_does_not_pause_for_resumed_batches() {
+        let invoice = big_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"first parcel")
+            .with_parcel(b"second parcel")
+            .with_parcel(b"third parcel");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+
+        let options = FetchOptions {
+            verify: VerifyPolicy::MissingOnly,
+            ..FetchOptions::default()
+        };
+        let batched = session.download_in_batches(
+            &server,
+            &invoice,
+            BatchStrategy::MaxCount(1),
+            Duration::from_secs(60),
+            &options,
+        );
+        let report = tokio::time::timeout(Duration::from_secs(5), batched)
+            .await
+            .expect("downloading resumed batches should not pause")
+            .unwrap();
+
+        assert_eq!(
+            DownloadReport {
+                downloaded: 0,
+                resumed: 3
+            },
+            report
+        );
+    }
+
+    #[tokio::test]
+    async fn test_changed_invoice_invalidates_session() {
+        let invoice = big_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"first parcel")
+            .with_parcel(b"second parcel")
+            .with_parcel(b"third parcel");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+
+        let mut changed = invoice.clone();
+        changed.parcel.as_mut().unwrap().pop();
+        let (session, warnings) = DownloadSession::open(dir.path(), &changed).unwrap().into();
+        assert!(session.manifest.completed.is_empty());
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("has changed"), "{}", warnings[0]);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_parcel_is_downloaded_again() {
+        let invoice = big_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"first parcel")
+            .with_parcel(b"second parcel")
+            .with_parcel(b"third parcel");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+        std::fs::remove_file(session.parcel_path(&sha256_of(b"second parcel"))).unwrap();
+
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let report = session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, report.downloaded);
+        assert_eq!(2, report.resumed);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_parcel_is_trusted_only_if_the_policy_allows() {
+        let invoice = big_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"first parcel")
+            .with_parcel(b"second parcel")
+            .with_parcel(b"third parcel");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+        let path = session.parcel_path(&sha256_of(b"second parcel"));
+        std::fs::write(&path, b"second parceL").unwrap();
+
+        let missing_only = FetchOptions {
+            verify: VerifyPolicy::MissingOnly,
+            ..FetchOptions::default()
+        };
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let report = session
+            .download(&server, &invoice, &missing_only)
+            .await
+            .unwrap();
+        assert_eq!(0, report.downloaded);
+        assert_eq!(b"second parceL".to_vec(), std::fs::read(&path).unwrap());
+
+        let report = session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, report.downloaded);
+        assert_eq!(2, report.resumed);
+        assert_eq!(b"second parcel".to_vec(), std::fs::read(&path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_download_fetches_only_the_required_closure() {
+        let id = bindle::Id::from_str("optional/1.0.0").unwrap();
+        let invoice = test_invoice(
+            &id,
+            vec![
+                parcel_for("main.wasm", b"main"),
+                in_group(parcel_for("extra.dat", b"extra"), "extras", ""),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_parcel(b"main")
+            .with_parcel(b"extra");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let report = session
+            .download(&server, &invoice, &FetchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(1, report.downloaded);
+        assert!(!session.parcel_path(&sha256_of(b"extra")).exists());
+    }
+
+    fn in_group(mut parcel: bindle::Parcel, member_of: &str, requires: &str) -> bindle::Parcel {
+        let nonempty = |s: &str| (!s.is_empty()).then(|| vec![s.to_owned()]);
+        parcel.conditions = Some(bindle::Condition {
+            member_of: nonempty(member_of),
+            requires: nonempty(requires),
+        });
+        parcel
+    }
+
+    fn handlers_invoice() -> bindle::Invoice {
+        let id = bindle::Id::from_str("handlers/1.0.0").unwrap();
+        let mut library = in_group(parcel_for("library.wasm", b"library"), "", "lib-deps");
+        library.label.annotations = Some(
+            vec![("wagi_handler_id".to_owned(), "lib".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        test_invoice(
+            &id,
+            vec![
+                in_group(parcel_for("main.wasm", b"main"), "", "main-deps"),
+                in_group(parcel_for("helper.dat", b"helper"), "main-deps", "assets"),
+                in_group(parcel_for("asset.dat", b"asset"), "assets", ""),
+                library,
+                in_group(
+                    parcel_for("huge.dat", b"huge unrelated asset"),
+                    "lib-deps",
+                    "",
+                ),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_download_handler_fetches_only_its_closure() {
+        let invoice = handlers_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"main")
+            .with_parcel(b"helper")
+            .with_parcel(b"asset");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let report = session
+            .download_handler(&server, &invoice, "main.wasm", &FetchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(3, report.download.downloaded);
+        assert_eq!((3, 5), (report.parcels, report.total_parcels));
+        assert_eq!(b"mainhelperasset".len() as u64, report.bytes);
+        assert!(report.skipped_fraction() > 0.5);
+        assert!(!session.parcel_path(&sha256_of(b"library")).exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_group_fetches_only_its_closure() {
+        let invoice = handlers_invoice();
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"helper")
+            .with_parcel(b"asset");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let report = session
+            .download_group(&server, &invoice, "main-deps", &FetchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(2, report.download.downloaded);
+        assert_eq!((2, 5), (report.parcels, report.total_parcels));
+        assert!(report
+            .to_string()
+            .contains("the group 'main-deps' needs 2 of 5"));
+        assert!(!session.parcel_path(&sha256_of(b"main")).exists());
+
+        let message = session
+            .download_group(&server, &invoice, "main-dpes", &FetchOptions::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.contains("no group named 'main-dpes'"),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_profile_adds_its_groups_to_the_required_closure() {
+        let mut invoice = big_invoice();
+        let mut extra = parcel_for("extra.wasm", b"extra parcel");
+        extra.conditions = Some(bindle::Condition {
+            member_of: Some(vec!["extras".to_owned()]),
+            requires: None,
+        });
+        invoice.parcel.as_mut().unwrap().push(extra);
+        invoice.annotations = Some(
+            vec![("hippo_profile_full".to_owned(), "extras".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice.clone())
+            .with_parcel(b"first parcel")
+            .with_parcel(b"second parcel")
+            .with_parcel(b"third parcel")
+            .with_parcel(b"extra parcel");
+        let mut session = DownloadSession::open(dir.path(), &invoice).unwrap().value;
+        let report = session
+            .download_profile(&server, &invoice, "full", &FetchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(4, report.download.downloaded);
+        assert_eq!((4, 4), (report.parcels, report.total_parcels));
+        assert!(session.parcel_path(&sha256_of(b"extra parcel")).exists());
+        assert!(session
+            .download_profile(&server, &invoice, "minimal", &FetchOptions::default())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_handler_can_be_found_by_handler_id() {
+        let invoice = handlers_invoice();
+        let names = handler_closure(&invoice, "lib")
+            .unwrap()
+            .iter()
+            .map(|p| p.label.name.clone())
+            .collect_vec();
+        assert_eq!(vec!["library.wasm", "huge.dat"], names);
+    }
+
+    #[test]
+    fn test_ambiguous_handler_lists_candidates() {
+        let mut invoice = handlers_invoice();
+        let mut imposter = parcel_for("imposter.wasm", b"imposter");
+        imposter.label.annotations = Some(
+            vec![("wagi_handler_id".to_owned(), "main.wasm".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        invoice.parcel.as_mut().unwrap().push(imposter);
+
+        let message = handler_closure(&invoice, "main.wasm")
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("main.wasm ("), "{}", message);
+        assert!(message.contains("imposter.wasm ("), "{}", message);
+        assert!(handler_closure(&invoice, "nothing.wasm").is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! Reporting a failed command as JSON or YAML, so that a tool which runs
+//! `hippo` can tell failures apart without matching on the text of the error.
+
+use bindle::client::ClientError;
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::bindle_fetcher::SizeMismatch;
+use crate::bindle_pusher::PushIncomplete;
+use crate::bindle_search::QueryUnsupported;
+use crate::hippo_client::HippoError;
+use crate::invoice_diff::{InvoiceConflict, PushConflict};
+use crate::report_format::{render, ReportFormat};
+
+/// The exit code when `--idempotent` finds a different bindle with the same ID
+/// already on the server. Other failures exit with 1.
+pub const EXIT_PUSH_CONFLICT: i32 = 3;
+const EXIT_FAILURE: i32 = 1;
+
+/// What sort of failure an error is. Each kind has one exit code, so that a
+/// caller which only sees the exit code can still tell some kinds apart.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The server has a different bindle with the same ID.
+    PushConflict,
+    /// The server did not accept the credentials, or needed some.
+    Unauthorized,
+    NotFound,
+    /// The Bindle server can't list versions, which a check needed.
+    QueryUnsupported,
+    /// A parcel fetched from the server was not the size its label gives.
+    SizeMismatch,
+    /// The server could not be reached.
+    Connection,
+    /// The server refused or failed a request.
+    Server,
+    /// A local file could not be read or written.
+    Io,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::PushConflict => EXIT_PUSH_CONFLICT,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+/// Which part of the system an error came from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subsystem {
+    Bindle,
+    Hippo,
+    Local,
+}
+
+/// The object which is printed when a command fails with `--json-errors` or
+/// `--output json`, or as YAML with `--output yaml`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorOutput {
+    pub error: ErrorReport,
+    /// How far a push got before it failed, if it got part way.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<PushProgress>,
+    /// How the bindle differs from the one with the same ID on the server, if
+    /// the push failed because of a conflict.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<InvoiceConflict>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    /// The error and its causes, on one line.
+    pub message: String,
+    pub subsystem: Subsystem,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    /// What the user might do about the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+/// The parcels, by sha256, which a failed push did and did not upload.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushProgress {
+    pub bindle_id: String,
+    pub parcels_uploaded: Vec<String>,
+    pub parcels_remaining: Vec<String>,
+}
+
+impl ErrorOutput {
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        let (kind, subsystem, status) = classify(e);
+        let progress = e.downcast_ref::<PushIncomplete>().map(|p| PushProgress {
+            bindle_id: p.bindle_id.to_string(),
+            parcels_uploaded: p.parcels_uploaded.clone(),
+            parcels_remaining: p.parcels_remaining.clone(),
+        });
+        let conflict = find::<PushConflict>(e).map(|c| c.conflict.clone());
+        let hint = hint(kind, subsystem, progress.is_some());
+        Self {
+            error: ErrorReport {
+                kind,
+                message: one_line(e),
+                subsystem,
+                http_status: status.map(|s| s.as_u16()),
+                hint: hint.map(|h| h.to_owned()),
+            },
+            progress,
+            conflict,
+        }
+    }
+
+    pub fn print(&self, format: ReportFormat) -> anyhow::Result<()> {
+        println!("{}", render(self, format)?);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ErrorOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error.message)
+    }
+}
+
+fn classify(e: &anyhow::Error) -> (ErrorKind, Subsystem, Option<StatusCode>) {
+    if find::<PushConflict>(e).is_some() {
+        return (ErrorKind::PushConflict, Subsystem::Bindle, None);
+    }
+    if find::<QueryUnsupported>(e).is_some() {
+        return (ErrorKind::QueryUnsupported, Subsystem::Bindle, None);
+    }
+    if find::<SizeMismatch>(e).is_some() {
+        return (ErrorKind::SizeMismatch, Subsystem::Bindle, None);
+    }
+    if let Some(hippo_error) = find::<HippoError>(e) {
+        let (kind, status) = match hippo_error {
+            HippoError::InvalidRequest { status_code, .. } => {
+                (kind_for_status(*status_code), Some(*status_code))
+            }
+            HippoError::Http(e) => (kind_for_http(e), e.status()),
+            HippoError::Overloaded { .. } => (ErrorKind::Server, None),
+        };
+        return (kind, Subsystem::Hippo, status);
+    }
+    if let Some(client_error) = find::<ClientError>(e) {
+        let (kind, status) = match client_error {
+            ClientError::InvalidRequest { status_code, .. } => {
+                (kind_for_status(*status_code), Some(*status_code))
+            }
+            ClientError::Unauthorized => (ErrorKind::Unauthorized, Some(StatusCode::UNAUTHORIZED)),
+            ClientError::InvoiceNotFound
+            | ClientError::ParcelNotFound
+            | ClientError::ResourceNotFound => (ErrorKind::NotFound, Some(StatusCode::NOT_FOUND)),
+            _ => match find::<reqwest::Error>(e) {
+                Some(e) => (kind_for_http(e), e.status()),
+                None => (ErrorKind::Server, None),
+            },
+        };
+        return (kind, Subsystem::Bindle, status);
+    }
+    if let Some(http_error) = find::<reqwest::Error>(e) {
+        return (
+            kind_for_http(http_error),
+            Subsystem::Bindle,
+            http_error.status(),
+        );
+    }
+    if find::<std::io::Error>(e).is_some() {
+        return (ErrorKind::Io, Subsystem::Local, None);
+    }
+    if e.downcast_ref::<PushIncomplete>().is_some() {
+        return (ErrorKind::Server, Subsystem::Bindle, None);
+    }
+    (ErrorKind::Other, Subsystem::Local, None)
+}
+
+/// The error and its causes, leaving out any cause which an outer message
+/// already quotes. A push conflict is given by its summary, as its differences
+/// are reported under `conflict`.
+fn one_line(e: &anyhow::Error) -> String {
+    let mut message = String::new();
+    for cause in e.chain() {
+        let text = match cause.downcast_ref::<PushConflict>() {
+            Some(conflict) => conflict.summary(),
+            None => cause.to_string(),
+        };
+        if message.contains(&text) {
+            continue;
+        }
+        if !message.is_empty() {
+            message.push_str(": ");
+        }
+        message.push_str(&text);
+    }
+    message
+}
+
+/// The first error of type `T` in `e`, whether it is the error itself,
+/// context added to it, or one of its causes.
+fn find<T: std::error::Error + Send + Sync + 'static>(e: &anyhow::Error) -> Option<&T> {
+    e.downcast_ref::<T>()
+        .or_else(|| e.chain().find_map(|cause| cause.downcast_ref::<T>()))
+}
+
+fn kind_for_status(status: StatusCode) -> ErrorKind {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Unauthorized,
+        StatusCode::NOT_FOUND => ErrorKind::NotFound,
+        _ => ErrorKind::Server,
+    }
+}
+
+fn kind_for_http(e: &reqwest::Error) -> ErrorKind {
+    match e.status() {
+        Some(status) => kind_for_status(status),
+        None if e.is_connect() || e.is_timeout() => ErrorKind::Connection,
+        None => ErrorKind::Server,
+    }
+}
+
+fn hint(kind: ErrorKind, subsystem: Subsystem, resumable: bool) -> Option<&'static str> {
+    match (kind, subsystem) {
+        (ErrorKind::PushConflict, _) => {
+            Some("Change the bindle version: the server's bindle with this ID can't be replaced")
+        }
+        (ErrorKind::Unauthorized, Subsystem::Hippo) => Some(
+            "Login failed: please check your credentials (--hippo-username and --hippo-password, or --hippo-token)",
+        ),
+        (ErrorKind::Unauthorized, _) => Some(
+            "Please check your Bindle credentials (--bindle-username and --bindle-password, or --bindle-credentials-file)",
+        ),
+        (ErrorKind::NotFound, Subsystem::Hippo) => {
+            Some("Check that you have access to a Hippo application which uses this bindle ID")
+        }
+        (ErrorKind::QueryUnsupported, _) => Some(
+            "Use a Bindle server with the query API, or push without --strict-version-ordering",
+        ),
+        (ErrorKind::SizeMismatch, _) => {
+            Some("The server's copy of the parcel may be damaged: push the bindle again")
+        }
+        (ErrorKind::Connection, _) | (ErrorKind::Server, _) if resumable => {
+            Some("Push again: parcels which were uploaded will not be uploaded again")
+        }
+        (ErrorKind::Connection, Subsystem::Hippo) => {
+            Some("Check that the Hippo URL is right and that Hippo is running")
+        }
+        (ErrorKind::Connection, _) => {
+            Some("Check that the Bindle server URL is right and that the server is running")
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::str::FromStr;
+
+    fn report(e: anyhow::Error) -> serde_json::Value {
+        serde_json::to_value(ErrorOutput::from_error(&e)).unwrap()
+    }
+
+    #[test]
+    fn test_push_conflict_report() {
+        let e = anyhow::Error::new(PushConflict {
+            bindle_id: bindle::Id::from_str("conflicted/1.0.0").unwrap(),
+            conflict: InvoiceConflict {
+                fields: vec!["description".to_owned()],
+                ..InvoiceConflict::default()
+            },
+        });
+        assert_eq!(
+            EXIT_PUSH_CONFLICT,
+            ErrorOutput::from_error(&e).error.kind.exit_code()
+        );
+        assert_eq!(
+            json!({
+                "error": {
+                    "kind": "push-conflict",
+                    "message": "The Bindle server already has a different conflicted/1.0.0",
+                    "subsystem": "bindle",
+                    "hint": "Change the bindle version: the server's bindle with this ID can't be replaced",
+                },
+                "conflict": {
+                    "fields": ["description"],
+                    "onlyLocal": [],
+                    "onlyRemote": [],
+                    "changed": [],
+                }
+            }),
+            report(e)
+        );
+    }
+
+    #[test]
+    fn test_push_conflict_message_is_one_line_under_context() {
+        let e = anyhow::Error::new(PushConflict {
+            bindle_id: bindle::Id::from_str("conflicted/1.0.0").unwrap(),
+            conflict: InvoiceConflict {
+                fields: vec!["description".to_owned()],
+                ..InvoiceConflict::default()
+            },
+        })
+        .context("Error pushing conflicted/1.0.0");
+        let output = ErrorOutput::from_error(&e);
+        assert_eq!(
+            "Error pushing conflicted/1.0.0: The Bindle server already has a different conflicted/1.0.0",
+            output.error.message
+        );
+        assert_eq!(
+            Some(vec!["description".to_owned()]),
+            output.conflict.map(|c| c.fields)
+        );
+    }
+
+    #[test]
+    fn test_report_has_the_same_fields_as_yaml() {
+        let e = anyhow::Error::new(PushConflict {
+            bindle_id: bindle::Id::from_str("conflicted/1.0.0").unwrap(),
+            conflict: InvoiceConflict::default(),
+        });
+        let yaml = render(&ErrorOutput::from_error(&e), ReportFormat::Yaml).unwrap();
+        let parsed: serde_json::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(report(e), parsed);
+    }
+
+    #[test]
+    fn test_hippo_unauthorized_report() {
+        let e = anyhow::Error::new(HippoError::InvalidRequest {
+            status_code: StatusCode::UNAUTHORIZED,
+            message: None,
+        })
+        .context("Error registering revision");
+        assert_eq!(
+            json!({
+                "error": {
+                    "kind": "unauthorized",
+                    "message": "Error registering revision: Hippo returned 401 Unauthorized",
+                    "subsystem": "hippo",
+                    "httpStatus": 401,
+                    "hint": "Login failed: please check your credentials (--hippo-username and --hippo-password, or --hippo-token)",
+                }
+            }),
+            report(e)
+        );
+    }
+
+    #[test]
+    fn test_bindle_server_error_report() {
+        let e = anyhow::Error::new(ClientError::InvalidRequest {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            message: None,
+        })
+        .context("Error pushing bindle to server");
+        let output = ErrorOutput::from_error(&e);
+        assert_eq!(ErrorKind::Server, output.error.kind);
+        assert_eq!(Subsystem::Bindle, output.error.subsystem);
+        assert_eq!(Some(500), output.error.http_status);
+        assert_eq!(None, output.error.hint);
+        assert_eq!(1, output.error.kind.exit_code());
+    }
+
+    #[test]
+    fn test_partial_push_report_includes_progress() {
+        let e = anyhow::anyhow!("Gave up uploading big.wasm after 3 attempts: 502 Bad Gateway")
+            .context(PushIncomplete {
+                bindle_id: bindle::Id::from_str("partial/1.0.0").unwrap(),
+                parcels_uploaded: vec!["aaa".to_owned()],
+                parcels_remaining: vec!["bbb".to_owned(), "ccc".to_owned()],
+            });
+        assert_eq!(
+            json!({
+                "error": {
+                    "kind": "server",
+                    "message": "Push of partial/1.0.0 stopped with 1 of 3 parcels uploaded: Gave up uploading big.wasm after 3 attempts: 502 Bad Gateway",
+                    "subsystem": "bindle",
+                    "hint": "Push again: parcels which were uploaded will not be uploaded again",
+                },
+                "progress": {
+                    "bindleId": "partial/1.0.0",
+                    "parcelsUploaded": ["aaa"],
+                    "parcelsRemaining": ["bbb", "ccc"],
+                }
+            }),
+            report(e)
+        );
+    }
+
+    #[test]
+    fn test_query_unsupported_report() {
+        let e = anyhow::Error::new(QueryUnsupported).context(
+            "Not pushing checked/1.0.0: can't check for later versions of checked, as the Bindle server does not support queries",
+        );
+        assert_eq!(
+            json!({
+                "error": {
+                    "kind": "query-unsupported",
+                    "message": "Not pushing checked/1.0.0: can't check for later versions of checked, as the Bindle server does not support queries",
+                    "subsystem": "bindle",
+                    "hint": "Use a Bindle server with the query API, or push without --strict-version-ordering",
+                }
+            }),
+            report(e)
+        );
+    }
+
+    #[test]
+    fn test_local_file_error_report() {
+        let e = anyhow::Error::new(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No such file or directory",
+        ))
+        .context("Error reading HIPPOFACTS");
+        assert_eq!(
+            json!({
+                "error": {
+                    "kind": "io",
+                    "message": "Error reading HIPPOFACTS: No such file or directory",
+                    "subsystem": "local",
+                }
+            }),
+            report(e)
+        );
+    }
+}
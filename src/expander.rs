@@ -3,13 +3,14 @@ use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 
-use bindle::{AnnotationMap, BindleSpec, Condition, Group, Invoice, Label, Parcel};
+use bindle::{AnnotationMap, Group, Invoice, Parcel};
 use glob::GlobError;
 use itertools::Itertools;
-use sha2::{Digest, Sha256};
 
 use crate::bindle_utils::InvoiceHelpers;
+use crate::expansion_plan::{ExpansionPlan, PlannedParcel, PlannedSource};
 use crate::hippofacts::{Export, ExternalHandler, ExternalRef, HippoFacts, HippoFactsEntry, LocalHandler};
+use crate::path_mapping;
 use crate::warnings::{Unwarn, WarnContext, Warned};
 
 pub struct ExpansionContext {
@@ -24,14 +25,13 @@ impl ExpansionContext {
         absolute.to_string_lossy().to_string()
     }
 
+    pub fn to_glob_pattern(&self, pattern: &str) -> String {
+        path_mapping::glob_pattern(&self.relative_to, pattern)
+    }
+
     pub fn to_relative(&self, path: impl AsRef<Path>) -> anyhow::Result<String> {
         let relative_path = path.as_ref().strip_prefix(&self.relative_to)?;
-        let relative_path_string = relative_path
-            .to_str()
-            .ok_or_else(|| anyhow::Error::msg("Can't convert back to relative path"))?
-            .to_owned()
-            .replace("\\", "/"); // TODO: a better way
-        Ok(relative_path_string)
+        path_mapping::parcel_name(relative_path)
     }
 
     pub fn mangle_version(&self, version: &str) -> String {
@@ -67,10 +67,12 @@ impl InvoiceVersioning {
     }
 }
 
-pub fn expand(
+/// Works out the parcels and groups of the bindle for a HIPPOFACTS file, without
+/// hashing or staging any files.
+pub fn expand_to_plan(
     hippofacts: &HippoFacts,
     expansion_context: &ExpansionContext,
-) -> anyhow::Result<Warned<Invoice>> {
+) -> anyhow::Result<Warned<ExpansionPlan>> {
     WarnContext::run(|wc| {
         let groups = expand_all_entries_to_groups(hippofacts)?;
         let handler_parcels = expand_module_entries_to_parcels(hippofacts, expansion_context)?;
@@ -84,22 +86,17 @@ pub fn expand(
             .chain(file_parcels)
             .collect();
 
-        let invoice = Invoice {
-            bindle_version: "1.0.0".to_owned(),
-            yanked: None,
-            yanked_signature: None,
-            bindle: BindleSpec {
-                id: expand_id(&hippofacts.bindle, expansion_context)?,
-                description: hippofacts.bindle.description.clone(),
-                authors: hippofacts.bindle.authors.clone(),
-            },
+        let plan = ExpansionPlan {
+            bindle_id: expand_id(&hippofacts.bindle, expansion_context)?.to_string(),
+            description: hippofacts.bindle.description.clone(),
+            authors: hippofacts.bindle.authors.clone(),
             annotations: hippofacts.annotations.clone(),
-            parcel: Some(parcels),
-            group: Some(groups),
-            signature: None,
+            channel: hippofacts.channel.clone(),
+            groups: groups.into_iter().map(|g| g.name).collect(),
+            parcels,
         };
 
-        Ok(invoice)
+        Ok(plan)
     })
 }
 
@@ -140,7 +137,7 @@ fn group_name(entry: &HippoFactsEntry) -> String {
 fn expand_module_entries_to_parcels(
     hippofacts: &HippoFacts,
     expansion_context: &ExpansionContext,
-) -> anyhow::Result<Vec<Parcel>> {
+) -> anyhow::Result<Vec<PlannedParcel>> {
     let parcels = hippofacts
         .entries
         .iter()
@@ -151,7 +148,7 @@ fn expand_module_entries_to_parcels(
 fn expand_one_module_entry_to_parcel(
     entry: &HippoFactsEntry,
     expansion_context: &ExpansionContext,
-) -> anyhow::Result<Parcel> {
+) -> anyhow::Result<PlannedParcel> {
     match &entry {
         HippoFactsEntry::LocalHandler(h) => convert_one_match_to_parcel(
             PathBuf::from(expansion_context.to_absolute(&h.name)),
@@ -182,7 +179,7 @@ fn expand_one_module_entry_to_parcel(
 fn expand_all_external_ref_dependencies_to_parcels(
     hippofacts: &HippoFacts,
     expansion_context: &ExpansionContext,
-) -> anyhow::Result<Vec<Parcel>> {
+) -> anyhow::Result<Vec<PlannedParcel>> {
     let parcel_lists = hippofacts.entries.iter().map(|handler| match &handler {
         HippoFactsEntry::ExternalHandler(e) => expand_one_external_ref_dependencies_to_parcels(
             &e.external,
@@ -199,7 +196,7 @@ fn expand_one_external_ref_dependencies_to_parcels(
     external_ref: &ExternalRef,
     expansion_context: &ExpansionContext,
     dest_group_name: &str,
-) -> anyhow::Result<Vec<Parcel>> {
+) -> anyhow::Result<Vec<PlannedParcel>> {
     let parcels = (|| {
         let invoice = expansion_context
             .external_invoices
@@ -208,15 +205,18 @@ fn expand_one_external_ref_dependencies_to_parcels(
         let main_parcel = find_handler_parcel(invoice, &external_ref.handler_id)
             .ok_or_else(|| anyhow::anyhow!("external invoice does not contain specified parcel"))?;
         let required_parcels = invoice.parcels_required_by(main_parcel);
-        let parcel_copies = required_parcels.iter().map(|p| Parcel {
-            label: Label {
-                annotations: annotation_do_not_stage_file(),
-                ..p.label.clone()
+        let parcel_copies = required_parcels.iter().map(|p| PlannedParcel {
+            parcel_name: p.label.name.clone(),
+            source: PlannedSource::External {
+                sha256: p.label.sha256.clone(),
+                size: p.label.size,
+                origin: p.label.origin.clone(),
             },
-            conditions: Some(Condition {
-                member_of: Some(vec![dest_group_name.to_owned()]),
-                requires: None,
-            }),
+            media_type: p.label.media_type.clone(),
+            member_of: vec![dest_group_name.to_owned()],
+            requires: vec![],
+            feature: p.label.feature.clone(),
+            annotations: annotation_do_not_stage_file(),
         });
         Ok(parcel_copies.collect())
     })();
@@ -233,7 +233,7 @@ fn expand_one_external_ref_dependencies_to_parcels(
 fn expand_all_files_to_parcels(
     hippofacts: &HippoFacts,
     expansion_context: &ExpansionContext,
-) -> anyhow::Result<Warned<Vec<Parcel>>> {
+) -> anyhow::Result<Warned<Vec<PlannedParcel>>> {
     WarnContext::run(|wc| {
         let parcel_lists = hippofacts
             .entries
@@ -247,13 +247,68 @@ fn expand_all_files_to_parcels(
 fn expand_files_to_parcels(
     entry: &HippoFactsEntry,
     expansion_context: &ExpansionContext,
-) -> anyhow::Result<Warned<Vec<Parcel>>> {
+) -> anyhow::Result<Warned<Vec<PlannedParcel>>> {
     WarnContext::run(|wc| {
         let patterns = entry.files();
         let parcels = patterns
             .iter()
             .map(|f| expand_file_to_parcels(f, expansion_context, &group_name(entry)).unwarn(wc));
-        flatten_or_fail(parcels)
+        let parcels = flatten_or_fail(parcels)?;
+        apply_file_rules(entry, parcels).unwarn(wc)
+    })
+}
+
+/// Adds to each of an entry's file parcels the annotations of the first of its
+/// file rules which matches the file, warning about files which more than one
+/// rule matches and about rules which match no files.
+fn apply_file_rules(
+    entry: &HippoFactsEntry,
+    parcels: Vec<PlannedParcel>,
+) -> anyhow::Result<Warned<Vec<PlannedParcel>>> {
+    let rules = entry.file_rules();
+    let patterns = rules
+        .iter()
+        .map(|r| glob::Pattern::new(&r.glob))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut warnings = vec![];
+    let mut matched_any = vec![false; rules.len()];
+    let mut annotated = vec![];
+    for mut parcel in parcels {
+        let matching = (0..rules.len())
+            .filter(|&i| patterns[i].matches(&parcel.parcel_name))
+            .collect_vec();
+        if let Some(&first) = matching.first() {
+            if matching.len() > 1 {
+                warnings.push(format!(
+                    "File {} of handler {} matches {} file rules: only the first, '{}', applies",
+                    parcel.parcel_name,
+                    entry.handler_name(),
+                    matching.len(),
+                    rules[first].glob
+                ));
+            }
+            parcel
+                .annotations
+                .get_or_insert_with(AnnotationMap::new)
+                .extend(rules[first].annotations.clone());
+        }
+        for i in matching {
+            matched_any[i] = true;
+        }
+        annotated.push(parcel);
+    }
+    for (rule, matched) in rules.iter().zip(matched_any) {
+        if !matched {
+            warnings.push(format!(
+                "File rule '{}' of handler {} matches none of its files",
+                rule.glob,
+                entry.handler_name()
+            ));
+        }
+    }
+    Ok(Warned {
+        value: annotated,
+        warnings,
     })
 }
 
@@ -261,8 +316,8 @@ fn expand_file_to_parcels(
     pattern: &str,
     expansion_context: &ExpansionContext,
     member_of: &str,
-) -> anyhow::Result<Warned<Vec<Parcel>>> {
-    let paths = glob::glob(&expansion_context.to_absolute(pattern))?;
+) -> anyhow::Result<Warned<Vec<PlannedParcel>>> {
+    let paths = glob::glob(&expansion_context.to_glob_pattern(pattern))?;
     let parcels = paths
         .into_iter()
         .map(|p| try_convert_one_match_to_parcel(p, expansion_context, member_of))
@@ -279,7 +334,7 @@ fn try_convert_one_match_to_parcel(
     path: Result<PathBuf, GlobError>,
     expansion_context: &ExpansionContext,
     member_of: &str,
-) -> anyhow::Result<Parcel> {
+) -> anyhow::Result<PlannedParcel> {
     match path {
         Err(e) => Err(anyhow::anyhow!("Couldn't expand pattern: {}", e)),
         Ok(path) => {
@@ -303,18 +358,10 @@ fn convert_one_match_to_parcel(
     wagi_annotations: Option<Vec<(&str, &str)>>,
     member_of: Option<&str>,
     requires: Option<&str>,
-) -> anyhow::Result<Parcel> {
+) -> anyhow::Result<PlannedParcel> {
     // Immediate-call closure allows us to use the try operator
     let parcel = (|| {
-        let mut file = std::fs::File::open(&path)?;
-
         let name = expansion_context.to_relative(&path)?;
-        let size = file.metadata()?.len();
-
-        let mut sha = Sha256::new();
-        std::io::copy(&mut file, &mut sha)?;
-        let digest_value = sha.finalize();
-        let digest_string = format!("{:x}", digest_value);
 
         let media_type = mime_guess::from_path(&path)
             .first_or_octet_stream()
@@ -323,20 +370,14 @@ fn convert_one_match_to_parcel(
         let annotations = wagi_annotations.map(map_of);
         let feature = Some(wagi_feature_of(wagi_features));
 
-        Ok(Parcel {
-            label: Label {
-                name,
-                sha256: digest_string,
-                media_type,
-                size,
-                feature,
-                annotations,
-                origin: None,
-            },
-            conditions: Some(Condition {
-                member_of: vector_of(member_of),
-                requires: vector_of(requires),
-            }),
+        Ok(PlannedParcel {
+            parcel_name: name.clone(),
+            source: PlannedSource::File { source_path: name },
+            media_type,
+            member_of: vector_of(member_of),
+            requires: vector_of(requires),
+            feature,
+            annotations,
         })
     })();
     parcel.map_err(|e: anyhow::Error| {
@@ -354,7 +395,7 @@ fn convert_one_ref_to_parcel(
     wagi_features: Vec<(&str, &str)>,
     member_of: Option<&str>,
     requires: Option<&str>,
-) -> anyhow::Result<Parcel> {
+) -> anyhow::Result<PlannedParcel> {
     // Immediate-call closure allows us to use the try operator
     let parcel = (|| {
         // We don't need to give the IDs in these messages because these will be prepended when
@@ -368,20 +409,18 @@ fn convert_one_ref_to_parcel(
 
         let feature = Some(wagi_feature_of(wagi_features));
 
-        Ok(Parcel {
-            label: Label {
-                name: parcel.label.name.clone(),
+        Ok(PlannedParcel {
+            parcel_name: parcel.label.name.clone(),
+            source: PlannedSource::External {
                 sha256: parcel.label.sha256.clone(),
-                media_type: parcel.label.media_type.clone(),
                 size: parcel.label.size,
-                annotations: annotation_do_not_stage_file(),
-                feature,
                 origin: None,
             },
-            conditions: Some(Condition {
-                member_of: vector_of(member_of),
-                requires: vector_of(requires),
-            }),
+            media_type: parcel.label.media_type.clone(),
+            member_of: vector_of(member_of),
+            requires: vector_of(requires),
+            feature,
+            annotations: annotation_do_not_stage_file(),
         })
     })();
     parcel.map_err(|e: anyhow::Error| {
@@ -408,51 +447,20 @@ fn has_handler_id(parcel: &Parcel, handler_id: &str) -> bool {
     }
 }
 
-fn merge_memberships(parcels: Vec<Parcel>) -> Vec<Parcel> {
+fn merge_memberships(parcels: Vec<PlannedParcel>) -> Vec<PlannedParcel> {
     parcels
         .into_iter()
-        .into_grouping_map_by(file_id)
+        .into_grouping_map_by(|p| p.merge_key())
         .fold_first(|acc, _key, val| merge_parcel_into(acc, val))
         .values()
         .cloned() // into_values is not yet stable
         .collect()
 }
 
-fn merge_parcel_into(first: Parcel, second: Parcel) -> Parcel {
-    Parcel {
-        label: first.label,
-        conditions: merge_parcel_conditions(first.conditions, second.conditions),
-    }
-}
-
-fn merge_parcel_conditions(
-    first: Option<Condition>,
-    second: Option<Condition>,
-) -> Option<Condition> {
-    match first {
-        None => second, // shouldn't happen
-        Some(first_condition) => match second {
-            None => Some(first_condition),
-            Some(second_condition) => {
-                Some(merge_condition_lists(first_condition, second_condition))
-            }
-        },
-    }
-}
-
-fn merge_condition_lists(first: Condition, second: Condition) -> Condition {
-    Condition {
-        member_of: merge_lists(first.member_of, second.member_of),
-        requires: first.requires,
-    }
-}
-
-fn merge_lists(first: Option<Vec<String>>, second: Option<Vec<String>>) -> Option<Vec<String>> {
-    match (first, second) {
-        (None, None) => None,
-        (some, None) => some,
-        (None, some) => some,
-        (Some(list1), Some(list2)) => Some(vec![list1, list2].concat()),
+fn merge_parcel_into(first: PlannedParcel, second: PlannedParcel) -> PlannedParcel {
+    PlannedParcel {
+        member_of: [first.member_of, second.member_of].concat(),
+        ..first
     }
 }
 
@@ -473,18 +481,18 @@ where
 }
 
 fn check_for_name_clashes(
-    external_dependent_parcels: &[Parcel],
-    file_parcels: &[Parcel],
+    external_dependent_parcels: &[PlannedParcel],
+    file_parcels: &[PlannedParcel],
 ) -> anyhow::Result<()> {
     let file_parcel_names: HashSet<_> = file_parcels
         .iter()
-        .map(|p| p.label.name.to_owned())
+        .map(|p| p.parcel_name.to_owned())
         .collect();
     for parcel in external_dependent_parcels {
-        if file_parcel_names.contains(&parcel.label.name) {
+        if file_parcel_names.contains(&parcel.parcel_name) {
             return Err(anyhow::anyhow!(
                 "{} occurs both as a local file and as a dependency of an external reference",
-                parcel.label.name
+                parcel.parcel_name
             ));
         }
     }
@@ -497,14 +505,8 @@ fn current_user() -> Option<String> {
         .ok()
 }
 
-fn file_id(parcel: &Parcel) -> String {
-    // Two parcels with different names could refer to the same content.  We
-    // don't want to treat them as the same parcel when deduplicating.
-    format!("{}@{}", parcel.label.sha256, parcel.label.name)
-}
-
-fn vector_of(option: Option<&str>) -> Option<Vec<String>> {
-    option.map(|val| vec![val.to_owned()])
+fn vector_of(option: Option<&str>) -> Vec<String> {
+    option.map(|val| vec![val.to_owned()]).unwrap_or_default()
 }
 
 fn wagi_feature_of(values: Vec<(&str, &str)>) -> BTreeMap<String, BTreeMap<String, String>> {
@@ -559,6 +561,8 @@ mod test {
     use std::str::FromStr;
 
     use super::*;
+    use crate::templating::TemplateVariables;
+    use bindle::{Condition, Label};
 
     fn test_dir(name: &str) -> PathBuf {
         let test_data_base = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
@@ -568,7 +572,7 @@ mod test {
     }
 
     fn read_hippofacts(path: impl AsRef<Path>) -> anyhow::Result<HippoFacts> {
-        HippoFacts::read_from(path)
+        HippoFacts::read_from(path, &TemplateVariables::default())
     }
 
     fn parcel_named<'a>(invoice: &'a Invoice, parcel_name: &str) -> &'a Parcel {
@@ -578,7 +582,7 @@ mod test {
             .unwrap()
             .iter()
             .find(|p| p.label.name == parcel_name)
-            .expect(&format!("No parcel named {}", parcel_name))
+            .unwrap_or_else(|| panic!("No parcel named {}", parcel_name))
     }
 
     fn parcel_feature_value<'a>(
@@ -629,8 +633,8 @@ mod test {
             invoice_versioning: InvoiceVersioning::Production,
             external_invoices: external_test_invoices(),
         };
-        let (invoice, _) = expand(&hippofacts, &expansion_context)?.into();
-        Ok(invoice)
+        let (plan, _) = expand_to_plan(&hippofacts, &expansion_context)?.into();
+        plan.to_invoice(&expansion_context.relative_to)
     }
 
     fn external_test_invoices() -> HashMap<bindle::Id, Invoice> {
@@ -968,6 +972,35 @@ mod test {
         assert_eq!(8, parcels.len()); // 1 local handler, 1 ext handler, 3 asset files, 2 immediate ext deps, 1 indirect ext dep
     }
 
+    #[test]
+    fn test_selected_externals_bring_along_their_dependency_chains() {
+        let dir = test_dir("external2");
+        let selection = crate::hippofacts::HandlerSelection {
+            handlers: vec!["image_gallery".to_owned()],
+            routes: vec![],
+            tag_version: true,
+        };
+        let hippofacts = read_hippofacts(dir.join("HIPPOFACTS"))
+            .unwrap()
+            .select(&selection)
+            .unwrap();
+        let expansion_context = ExpansionContext {
+            relative_to: dir,
+            invoice_versioning: InvoiceVersioning::Production,
+            external_invoices: external_test_invoices(),
+        };
+        let (plan, _) = expand_to_plan(&hippofacts, &expansion_context)
+            .unwrap()
+            .into();
+        let invoice = plan.to_invoice(&expansion_context.relative_to).unwrap();
+
+        let parcels = invoice.parcel.as_ref().unwrap();
+        assert_eq!(7, parcels.len()); // 1 ext handler, 3 asset files, 2 immediate ext deps, 1 indirect ext dep
+        assert!(parcels.iter().all(|p| p.label.name != "out/fake.wasm"));
+        assert_eq!("1.2.3-partial", invoice.bindle.id.version_string());
+        assert!(invoice.validate_for_hippo().is_ok());
+    }
+
     #[test]
     fn test_externals_cannot_clash_with_local_files() {
         let invoice = expand_test_invoice("external3");
@@ -990,7 +1023,7 @@ mod test {
         let exported_parcel = parcel_named(&invoice, "wasm/gallery.wasm");
 
         match exported_parcel.label.feature.as_ref() {
-            None => assert!(false, "No features on the exported parcel"),
+            None => panic!("No features on the exported parcel"),
             Some(map) => assert_eq!("serve_pix", map.get("wagi").unwrap().get("entrypoint").unwrap()),
         };
     }
@@ -1004,11 +1037,88 @@ mod test {
         let exported_parcel = parcel_named(&invoice, "wasm/server.wasm");
 
         match exported_parcel.label.annotations.as_ref() {
-            None => assert!(false, "No annotations on the exported parcel"),
+            None => panic!("No annotations on the exported parcel"),
             Some(map) => assert_eq!("serve_all_the_things", map.get("wagi_handler_id").unwrap()),
         };
     }
 
+    fn annotation<'a>(invoice: &'a Invoice, parcel_name: &str, key: &str) -> Option<&'a str> {
+        parcel_named(invoice, parcel_name)
+            .label
+            .annotations
+            .as_ref()
+            .and_then(|map| map.get(key))
+            .map(|v| v.as_str())
+    }
+
+    #[test]
+    fn test_file_rules_annotate_matching_files() {
+        let invoice = expand_test_invoice("file_rules").unwrap();
+        let immutable = Some("public, max-age=31536000, immutable");
+        assert_eq!(
+            immutable,
+            annotation(&invoice, "assets/app.3f9a2c.js", "cache-control")
+        );
+        assert_eq!(
+            None,
+            annotation(&invoice, "assets/index.html", "cache-control")
+        );
+        assert_eq!(None, annotation(&invoice, "out/site.wasm", "cache-control"));
+    }
+
+    #[test]
+    fn test_first_matching_file_rule_wins() {
+        let invoice = expand_test_invoice("file_rules").unwrap();
+        assert_eq!(
+            Some("public, max-age=31536000, immutable"),
+            annotation(&invoice, "assets/app.3f9a2c.css", "cache-control")
+        );
+        assert_eq!(
+            None,
+            annotation(&invoice, "assets/app.3f9a2c.css", "content-language")
+        );
+    }
+
+    #[test]
+    fn test_overlapping_and_unmatched_file_rules_warn() {
+        let dir = test_dir("file_rules");
+        let hippofacts = read_hippofacts(dir.join("HIPPOFACTS")).unwrap();
+        let expansion_context = ExpansionContext {
+            relative_to: dir,
+            invoice_versioning: InvoiceVersioning::Production,
+            external_invoices: HashMap::new(),
+        };
+        let (_, warnings) = expand_to_plan(&hippofacts, &expansion_context)
+            .unwrap()
+            .into();
+        assert_eq!(2, warnings.len(), "{:?}", warnings);
+        assert!(
+            warnings[0].contains("assets/app.3f9a2c.css") && warnings[0].contains("2 file rules"),
+            "names the file matched twice: '{}'",
+            warnings[0]
+        );
+        assert!(
+            warnings[1].contains("'fonts/*'"),
+            "names the unused rule: '{}'",
+            warnings[1]
+        );
+    }
+
+    #[test]
+    fn test_empty_files_are_included_as_empty_parcels() {
+        let invoice = expand_test_invoice("empty_files").unwrap();
+        let robots = parcel_named(&invoice, "assets/robots.txt");
+        assert_eq!(0, robots.label.size);
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            robots.label.sha256
+        );
+        assert_eq!(
+            vec!["assets/robots.txt"],
+            crate::bindle_utils::empty_files(&invoice)
+        );
+    }
+
     #[test]
     fn test_exports_bring_along_their_dependencies() {
         let invoice = expand_test_invoice("lib1").unwrap();
@@ -1029,4 +1139,35 @@ mod test {
             parcel_memberships(&invoice, "gallery/thumbnails.db")[0]
         );
     }
+
+    #[test]
+    fn test_carrying_out_a_saved_plan_gives_the_same_invoice() {
+        let dir = test_dir("external1");
+        let hippofacts = read_hippofacts(dir.join("HIPPOFACTS")).unwrap();
+        let expansion_context = ExpansionContext {
+            relative_to: dir.clone(),
+            invoice_versioning: InvoiceVersioning::Production,
+            external_invoices: external_test_invoices(),
+        };
+        let (plan, _) = expand_to_plan(&hippofacts, &expansion_context)
+            .unwrap()
+            .into();
+        let invoice = plan.to_invoice(&dir).unwrap();
+
+        let saved = serde_json::to_string(&plan).unwrap();
+        let restored: ExpansionPlan = serde_json::from_str(&saved).unwrap();
+        let restored_invoice = restored.to_invoice(&dir).unwrap();
+
+        let sorted = |invoice: &Invoice| {
+            let mut parcels = invoice.parcel.clone().unwrap();
+            parcels.sort_by(|a, b| a.label.name.cmp(&b.label.name));
+            parcels
+        };
+        assert_eq!(invoice.bindle.id, restored_invoice.bindle.id);
+        assert_eq!(sorted(&invoice), sorted(&restored_invoice));
+        assert_eq!(
+            invoice.group.unwrap().len(),
+            restored_invoice.group.unwrap().len()
+        );
+    }
 }
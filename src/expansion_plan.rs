@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::{Component, Path};
+
+use bindle::{AnnotationMap, BindleSpec, Condition, Group, Invoice, Label, Parcel};
+use serde::{Deserialize, Serialize};
+
+use crate::digest_backend::Sha2Backend;
+use crate::file_hash;
+use crate::hippofacts::ChannelSpec;
+use crate::path_mapping;
+
+type FeatureMap = BTreeMap<String, BTreeMap<String, String>>;
+
+/// What building a bindle from a HIPPOFACTS file involves, worked out without
+/// reading or copying any files. Hashing the files, staging them and pushing
+/// them are separate steps which consume the plan, so a plan can be saved and
+/// carried out later, for example in a later stage of a pipeline.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpansionPlan {
+    /// The bindle ID, with the version already mangled.
+    pub bindle_id: String,
+    pub description: Option<String>,
+    pub authors: Option<Vec<String>>,
+    pub annotations: Option<AnnotationMap>,
+    /// The Hippo channel to register the bindle with.
+    pub channel: Option<ChannelSpec>,
+    pub groups: Vec<String>,
+    pub parcels: Vec<PlannedParcel>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedParcel {
+    pub parcel_name: String,
+    pub source: PlannedSource,
+    pub media_type: String,
+    pub member_of: Vec<String>,
+    pub requires: Vec<String>,
+    pub feature: Option<FeatureMap>,
+    pub annotations: Option<AnnotationMap>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum PlannedSource {
+    /// A local file, by its path relative to the HIPPOFACTS directory. This is
+    /// hashed when the plan is carried out.
+    #[serde(rename_all = "camelCase")]
+    File { source_path: String },
+    /// A parcel of another bindle, whose digest is already known.
+    #[serde(rename_all = "camelCase")]
+    External {
+        sha256: String,
+        size: u64,
+        origin: Option<String>,
+    },
+}
+
+impl PlannedParcel {
+    /// A key which is the same for two planned parcels only if they are the
+    /// same parcel with possibly different memberships. Two parcels with
+    /// different names could refer to the same content, so the name is part
+    /// of the key.
+    pub fn merge_key(&self) -> String {
+        match &self.source {
+            PlannedSource::File { .. } => format!("file@{}", self.parcel_name),
+            PlannedSource::External { sha256, .. } => format!("{}@{}", sha256, self.parcel_name),
+        }
+    }
+
+    fn to_parcel(&self, base_dir: &Path, mmap_threshold: u64) -> anyhow::Result<Parcel> {
+        let (sha256, size, origin) = match &self.source {
+            PlannedSource::File { source_path } => {
+                let path = path_mapping::parcel_path(base_dir, source_path);
+                let (sha256, size) = file_hash::hash_file(&path, mmap_threshold, &Sha2Backend)
+                    .map_err(|e| {
+                        anyhow::anyhow!(
+                            "Could not assemble parcel for file {}: {}",
+                            path.to_string_lossy(),
+                            e
+                        )
+                    })?;
+                (sha256, size, None)
+            }
+            PlannedSource::External {
+                sha256,
+                size,
+                origin,
+            } => (sha256.clone(), *size, origin.clone()),
+        };
+        Ok(Parcel {
+            label: Label {
+                name: self.parcel_name.clone(),
+                sha256,
+                media_type: self.media_type.clone(),
+                size,
+                feature: self.feature.clone(),
+                annotations: self.annotations.clone(),
+                origin,
+            },
+            conditions: Some(Condition {
+                member_of: non_empty(&self.member_of),
+                requires: non_empty(&self.requires),
+            }),
+        })
+    }
+}
+
+impl ExpansionPlan {
+    /// Builds the invoice for the plan, hashing the local files it refers to.
+    pub fn to_invoice(&self, base_dir: impl AsRef<Path>) -> anyhow::Result<Invoice> {
+        let mmap_threshold = file_hash::mmap_threshold()?;
+        let parcels = self
+            .parcels
+            .iter()
+            .map(|p| p.to_parcel(base_dir.as_ref(), mmap_threshold))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let groups = self
+            .groups
+            .iter()
+            .map(|name| Group {
+                name: name.clone(),
+                required: None,
+                satisfied_by: None,
+            })
+            .collect();
+        Ok(Invoice {
+            bindle_version: "1.0.0".to_owned(),
+            yanked: None,
+            yanked_signature: None,
+            bindle: BindleSpec {
+                id: bindle::Id::try_from(self.bindle_id.clone())?,
+                description: self.description.clone(),
+                authors: self.authors.clone(),
+            },
+            annotations: self.annotations.clone(),
+            parcel: Some(parcels),
+            group: Some(groups),
+            signature: None,
+        })
+    }
+
+    /// Reads a saved plan. A plan may have been written elsewhere, so this
+    /// checks that every local file it names is inside the source directory.
+    pub fn read_from(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        (|| {
+            let content = std::fs::read_to_string(&path)?;
+            let plan: Self = serde_json::from_str(&content)?;
+            plan.check_source_paths()?;
+            Ok(plan)
+        })()
+        .map_err(|e: anyhow::Error| {
+            anyhow::anyhow!(
+                "Error reading expansion plan {}: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })
+    }
+
+    fn check_source_paths(&self) -> anyhow::Result<()> {
+        for parcel in &self.parcels {
+            if let PlannedSource::File { source_path } = &parcel.source {
+                if !is_inside_source_dir(source_path) {
+                    return Err(anyhow::anyhow!(
+                        "parcel '{}' has source path '{}', which is not a relative path inside the source directory",
+                        parcel.parcel_name,
+                        source_path
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_to(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        (|| {
+            let content = serde_json::to_string_pretty(self)?;
+            std::fs::write(&path, content)?;
+            Ok(())
+        })()
+        .map_err(|e: anyhow::Error| {
+            anyhow::anyhow!(
+                "Error writing expansion plan {}: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })
+    }
+}
+
+/// Whether `source_path`, as joined by `path_mapping::parcel_path`, stays
+/// inside the directory it is joined to: it has no empty, parent or root
+/// segments, and no Windows drive prefix.
+fn is_inside_source_dir(source_path: &str) -> bool {
+    source_path.split('/').all(|segment| {
+        !segment.is_empty()
+            && Path::new(segment)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    })
+}
+
+fn non_empty(values: &[String]) -> Option<Vec<String>> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::sha256_of;
+
+    fn test_plan() -> ExpansionPlan {
+        ExpansionPlan {
+            bindle_id: "planned/app/1.0.0".to_owned(),
+            description: Some("An app".to_owned()),
+            authors: None,
+            annotations: None,
+            channel: Some(ChannelSpec {
+                name: "staging".to_owned(),
+                domain: None,
+            }),
+            groups: vec!["main.wasm-files".to_owned()],
+            parcels: vec![
+                PlannedParcel {
+                    parcel_name: "static/index.html".to_owned(),
+                    source: PlannedSource::File {
+                        source_path: "static/index.html".to_owned(),
+                    },
+                    media_type: "text/html".to_owned(),
+                    member_of: vec!["main.wasm-files".to_owned()],
+                    requires: vec![],
+                    feature: None,
+                    annotations: None,
+                },
+                PlannedParcel {
+                    parcel_name: "lib.wasm".to_owned(),
+                    source: PlannedSource::External {
+                        sha256: "abc123".to_owned(),
+                        size: 42,
+                        origin: None,
+                    },
+                    media_type: "application/wasm".to_owned(),
+                    member_of: vec![],
+                    requires: vec!["main.wasm-files".to_owned()],
+                    feature: None,
+                    annotations: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_plan_survives_a_round_trip_through_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plan.json");
+        let plan = test_plan();
+
+        plan.write_to(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("\"sourcePath\": \"static/index.html\""));
+        assert!(text.contains("\"kind\": \"external\""));
+
+        assert_eq!(plan, ExpansionPlan::read_from(&path).unwrap());
+    }
+
+    #[test]
+    fn test_plan_naming_files_outside_the_source_dir_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plan.json");
+        for source_path in &[
+            "../secrets.txt",
+            "static/../../secrets.txt",
+            "/etc/passwd",
+            "",
+        ] {
+            let mut plan = test_plan();
+            plan.parcels[0].source = PlannedSource::File {
+                source_path: source_path.to_string(),
+            };
+            plan.write_to(&path).unwrap();
+            let err = ExpansionPlan::read_from(&path).unwrap_err();
+            assert!(
+                err.to_string().contains("inside the source directory"),
+                "{}",
+                err
+            );
+        }
+
+        let mut plan = test_plan();
+        plan.parcels[0].source = PlannedSource::File {
+            source_path: "./static/index.html".to_owned(),
+        };
+        plan.write_to(&path).unwrap();
+        assert!(ExpansionPlan::read_from(&path).is_ok());
+    }
+
+    #[test]
+    fn test_carrying_out_a_plan_hashes_local_files_only() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("static")).unwrap();
+        std::fs::write(dir.path().join("static/index.html"), b"<html/>").unwrap();
+
+        let invoice = test_plan().to_invoice(dir.path()).unwrap();
+
+        assert_eq!("planned/app", invoice.bindle.id.name());
+        let parcels = invoice.parcel.unwrap();
+        assert_eq!(sha256_of(b"<html/>"), parcels[0].label.sha256);
+        assert_eq!(7, parcels[0].label.size);
+        assert_eq!(
+            Some(vec!["main.wasm-files".to_owned()]),
+            parcels[0].conditions.as_ref().unwrap().member_of
+        );
+        assert_eq!(None, parcels[0].conditions.as_ref().unwrap().requires);
+        assert_eq!("abc123", parcels[1].label.sha256);
+        assert_eq!(42, parcels[1].label.size);
+    }
+
+    #[test]
+    fn test_missing_planned_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = test_plan().to_invoice(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("index.html"), "names the file: '{}'", err);
+    }
+}
@@ -0,0 +1,240 @@
+//! Hashing the local files which go into a bindle. Most are small, but hashing
+//! the few large ones is most of the work of preparing a bindle, so how a file
+//! is read depends on its size: small files are read whole, medium ones
+//! through a large buffer, and large ones are memory-mapped. Every strategy
+//! gives the same digest.
+//!
+//! This module depends only on external crates and `digest_backend`, so that
+//! the hashing benchmark can include both.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::digest_backend::DigestBackend;
+
+/// Files up to this size are read whole.
+pub const WHOLE_FILE_LIMIT: u64 = 256 * 1024;
+/// The size of the buffer through which medium files are read.
+pub const BUFFER_SIZE: usize = 1024 * 1024;
+/// Files of this size or larger are memory-mapped, unless the environment
+/// variable `MMAP_THRESHOLD_VAR` gives another size.
+pub const DEFAULT_MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+pub const MMAP_THRESHOLD_VAR: &str = "HIPPO_MMAP_THRESHOLD";
+
+thread_local! {
+    static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashStrategy {
+    /// Read the whole file into memory.
+    Whole,
+    /// Read the file through a buffer, which is reused from file to file.
+    Buffered,
+    /// Map the file into memory. If it can't be mapped, it is read through a
+    /// buffer instead.
+    Mapped,
+}
+
+impl HashStrategy {
+    pub fn for_size(size: u64, mmap_threshold: u64) -> Self {
+        if size <= WHOLE_FILE_LIMIT {
+            Self::Whole
+        } else if size < mmap_threshold {
+            Self::Buffered
+        } else {
+            Self::Mapped
+        }
+    }
+}
+
+/// The size from which files are memory-mapped: the number of bytes in
+/// `HIPPO_MMAP_THRESHOLD` if it is set, otherwise `DEFAULT_MMAP_THRESHOLD`.
+pub fn mmap_threshold() -> anyhow::Result<u64> {
+    match std::env::var(MMAP_THRESHOLD_VAR) {
+        Err(_) => Ok(DEFAULT_MMAP_THRESHOLD),
+        Ok(text) => text.trim().parse().map_err(|_| {
+            anyhow::anyhow!(
+                "{} must be a number of bytes, not '{}'",
+                MMAP_THRESHOLD_VAR,
+                text
+            )
+        }),
+    }
+}
+
+/// The sha256 of a file, as hex, and its size, as computed by `digest`. The
+/// strategy is chosen by the size of the file.
+pub fn hash_file(
+    path: &Path,
+    mmap_threshold: u64,
+    digest: &dyn DigestBackend,
+) -> std::io::Result<(String, u64)> {
+    let size = std::fs::metadata(path)?.len();
+    let strategy = HashStrategy::for_size(size, mmap_threshold);
+    let sha256 = hash_file_with(path, strategy, digest)?;
+    Ok((sha256, size))
+}
+
+/// The sha256 of a file, as hex, with the strategy chosen by its size and
+/// `mmap_threshold()`.
+pub fn file_sha256(path: &Path, digest: &dyn DigestBackend) -> anyhow::Result<String> {
+    let (sha256, _) = hash_file(path, mmap_threshold()?, digest)?;
+    Ok(sha256)
+}
+
+/// The sha256 of a file, as hex, using the given strategy whatever the size
+/// of the file.
+pub fn hash_file_with(
+    path: &Path,
+    strategy: HashStrategy,
+    digest: &dyn DigestBackend,
+) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    match strategy {
+        HashStrategy::Whole => {
+            let mut content = Vec::with_capacity(file.metadata()?.len() as usize);
+            file.read_to_end(&mut content)?;
+            Ok(digest_of(&content, digest))
+        }
+        HashStrategy::Buffered => hash_buffered(&mut file, digest),
+        HashStrategy::Mapped => match hash_mapped(&file, digest) {
+            Some(sha256) => Ok(sha256),
+            None => hash_buffered(&mut file, digest),
+        },
+    }
+}
+
+fn digest_of(content: &[u8], digest: &dyn DigestBackend) -> String {
+    let mut hasher = digest.hasher();
+    hasher.update(content);
+    hasher.finish()
+}
+
+fn hash_buffered(file: &mut File, digest: &dyn DigestBackend) -> std::io::Result<String> {
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        buffer.resize(BUFFER_SIZE, 0);
+        let mut hasher = digest.hasher();
+        loop {
+            let count = file.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buffer[..count]);
+        }
+        Ok(hasher.finish())
+    })
+}
+
+/// The sha256 of a memory-mapped file, or `None` if the file could not be
+/// mapped, e.g. because it is empty or on a filesystem which doesn't support
+/// it.
+#[cfg(any(unix, windows))]
+fn hash_mapped(file: &File, digest: &dyn DigestBackend) -> Option<String> {
+    // Safety: the map is only read, and is dropped before returning. If another
+    // process changes the file meanwhile the digest is wrong, as it would be
+    // with a buffered read, but if it truncates the file, reading the map
+    // faults; bindle content should not be changing while it is prepared.
+    let map = unsafe { memmap2::Mmap::map(file) }.ok()?;
+    Some(digest_of(&map[..], digest))
+}
+
+/// Other platforms don't reliably support mapping files, so large files are
+/// read through a buffer instead.
+#[cfg(not(any(unix, windows)))]
+fn hash_mapped(_file: &File, _digest: &dyn DigestBackend) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::digest_backend::Sha2Backend;
+    use sha2::{Digest, Sha256};
+
+    const STRATEGIES: [HashStrategy; 3] = [
+        HashStrategy::Whole,
+        HashStrategy::Buffered,
+        HashStrategy::Mapped,
+    ];
+
+    /// Content of the given size which doesn't repeat at any power of two, so
+    /// that a strategy which dropped or repeated a chunk would give a
+    /// different digest.
+    fn content(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn check_size(size: u64, mmap_threshold: u64, expected: HashStrategy) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.dat");
+        let content = content(size);
+        std::fs::write(&path, &content).unwrap();
+        let expected_sha256 = format!("{:x}", Sha256::digest(&content));
+
+        assert_eq!(
+            expected,
+            HashStrategy::for_size(size, mmap_threshold),
+            "size {}",
+            size
+        );
+        assert_eq!(
+            (expected_sha256.clone(), size),
+            hash_file(&path, mmap_threshold, &Sha2Backend).unwrap()
+        );
+        for strategy in &STRATEGIES {
+            assert_eq!(
+                expected_sha256,
+                hash_file_with(&path, *strategy, &Sha2Backend).unwrap(),
+                "size {} with {:?}",
+                size,
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_file_has_the_digest_of_nothing() {
+        check_size(0, DEFAULT_MMAP_THRESHOLD, HashStrategy::Whole);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.dat");
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            hash_file_with(&path, HashStrategy::Mapped, &Sha2Backend).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_files_either_side_of_the_whole_file_limit() {
+        check_size(
+            WHOLE_FILE_LIMIT,
+            DEFAULT_MMAP_THRESHOLD,
+            HashStrategy::Whole,
+        );
+        check_size(
+            WHOLE_FILE_LIMIT + 1,
+            DEFAULT_MMAP_THRESHOLD,
+            HashStrategy::Buffered,
+        );
+    }
+
+    #[test]
+    fn test_files_either_side_of_the_buffer_size() {
+        let buffer_size = BUFFER_SIZE as u64;
+        for size in &[buffer_size - 1, buffer_size, buffer_size + 1] {
+            check_size(*size, DEFAULT_MMAP_THRESHOLD, HashStrategy::Buffered);
+        }
+    }
+
+    #[test]
+    fn test_files_either_side_of_the_mmap_threshold() {
+        let threshold = 3 * BUFFER_SIZE as u64 + 7;
+        check_size(threshold - 1, threshold, HashStrategy::Buffered);
+        check_size(threshold, threshold, HashStrategy::Mapped);
+        check_size(threshold + 1, threshold, HashStrategy::Mapped);
+    }
+}
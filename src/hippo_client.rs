@@ -0,0 +1,549 @@
+//! The requests which `hippo` makes to the Hippo API. Hippo publishes no
+//! machine-readable description of its API, so this is the contract which the
+//! client assumes, and which the tests below check that it keeps to:
+//!
+//! | Request | Body | Used for |
+//! |---------|------|----------|
+//! | `POST /account/createtoken` | `{"userName", "password"}`, answered with `{"token"}` | logging in |
+//! | `POST /api/revision` | `{"appStorageId", "revisionNumber"}` | registering a revision |
+//! | `GET /api/revision?revisionNumber=<n>` | answered with `[{"appStorageId", "revisionNumber"}]` | checking for an existing revision |
+//! | `GET /api/channel?appStorageId=<id>&name=<n>` | answered with `[{"id", "name", "domain"}]` | finding a channel |
+//! | `POST /api/channel` | `{"appStorageId", "name", "domain"}` | creating a channel |
+//! | `PUT /api/channel/<id>/domain` | `{"domain"}` | changing a channel's domain |
+//! | `GET /api/route?domain=<d>` | answered with `[{"route", "appName", "appStorageId", "channelName"}]` | `--check-remote-routes` |
+//!
+//! Every request but the first has the token as a bearer token. The first two
+//! are the requests which the hippo-client-rust crate made, which this client
+//! replaces. The rest are not in every version of Hippo; where one is missing
+//! (404, 405 or 501), the check which needs it is skipped with a warning, but
+//! updating a channel fails.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::debug_bundle::{send_traced, ResponseRecording};
+use crate::hippo_notifier::{ConnectionInfo, HippoCredentials};
+use crate::retry::{send_with_retry, RetryError, RetryPolicy};
+
+/// A revision which Hippo already knows about.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionRecord {
+    pub app_storage_id: String,
+    pub revision_number: String,
+}
+
+/// A Hippo channel: a route through which an application serves one of its revisions.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelRecord {
+    pub id: String,
+    pub name: String,
+    pub domain: Option<String>,
+}
+
+/// A route which an application serves on a domain, through one of its channels.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteRecord {
+    pub route: String,
+    pub app_name: String,
+    pub app_storage_id: String,
+    pub channel_name: String,
+}
+
+/// The Hippo operations used by the notifier.
+#[async_trait::async_trait]
+pub trait HippoApi {
+    async fn find_revisions(
+        &self,
+        revision_number: &str,
+    ) -> Result<Vec<RevisionRecord>, HippoError>;
+    async fn register_revision(
+        &self,
+        app_storage_id: &str,
+        revision_number: &str,
+    ) -> Result<(), HippoError>;
+    async fn find_channel(
+        &self,
+        app_storage_id: &str,
+        channel_name: &str,
+    ) -> Result<Option<ChannelRecord>, HippoError>;
+    async fn create_channel(
+        &self,
+        app_storage_id: &str,
+        channel_name: &str,
+        domain: &str,
+    ) -> Result<(), HippoError>;
+    async fn set_channel_domain(&self, channel_id: &str, domain: &str) -> Result<(), HippoError>;
+    /// The routes which every application serves on a domain. Older versions of
+    /// Hippo do not have this API, and return 404 Not Found.
+    async fn list_routes(&self, domain: &str) -> Result<Vec<RouteRecord>, HippoError>;
+}
+
+#[derive(Debug)]
+pub enum HippoError {
+    InvalidRequest {
+        status_code: reqwest::StatusCode,
+        message: Option<String>,
+    },
+    Http(reqwest::Error),
+    /// Hippo asked us to wait longer before retrying than we were allowed to.
+    Overloaded {
+        requested: std::time::Duration,
+        max_wait: std::time::Duration,
+    },
+}
+
+impl std::fmt::Display for HippoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidRequest {
+                status_code,
+                message,
+            } => match message {
+                Some(m) => write!(f, "Hippo returned {}: {}", status_code, m),
+                None => write!(f, "Hippo returned {}", status_code),
+            },
+            Self::Http(e) => write!(f, "Error communicating with Hippo: {}", e),
+            Self::Overloaded {
+                requested,
+                max_wait,
+            } => write!(
+                f,
+                "Hippo is overloaded and asked us to wait {}s before retrying, which is longer than the maximum of {}s",
+                requested.as_secs(),
+                max_wait.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HippoError {}
+
+impl From<reqwest::Error> for HippoError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<RetryError> for HippoError {
+    fn from(e: RetryError) -> Self {
+        match e {
+            RetryError::Http(e) => Self::Http(e),
+            RetryError::WaitTooLong {
+                requested,
+                max_wait,
+            } => Self::Overloaded {
+                requested,
+                max_wait,
+            },
+        }
+    }
+}
+
+/// Applies Hippo credentials to outgoing requests. This has the same shape as
+/// Bindle's `TokenManager`, so that the two backends handle auth the same way.
+#[async_trait::async_trait]
+pub trait HippoTokenManager {
+    async fn apply_auth_header(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<reqwest::RequestBuilder, HippoError>;
+}
+
+/// A bearer token, either minted by Hippo as an API token or obtained by logging in.
+pub struct BearerToken {
+    token: String,
+}
+
+#[async_trait::async_trait]
+impl HippoTokenManager for BearerToken {
+    async fn apply_auth_header(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<reqwest::RequestBuilder, HippoError> {
+        Ok(builder.bearer_auth(&self.token))
+    }
+}
+
+#[derive(Clone)]
+pub struct AnyHippoAuth {
+    token_manager: Arc<Box<dyn HippoTokenManager + Send + Sync>>,
+}
+
+#[async_trait::async_trait]
+impl HippoTokenManager for AnyHippoAuth {
+    async fn apply_auth_header(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<reqwest::RequestBuilder, HippoError> {
+        self.token_manager.apply_auth_header(builder).await
+    }
+}
+
+pub struct HippoClient {
+    base_url: String,
+    http: reqwest::Client,
+    auth: AnyHippoAuth,
+    retry_policy: RetryPolicy,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateTokenRequest<'a> {
+    user_name: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+struct CreateTokenResponse {
+    token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterRevisionRequest<'a> {
+    app_storage_id: &'a str,
+    revision_number: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateChannelRequest<'a> {
+    app_storage_id: &'a str,
+    name: &'a str,
+    domain: &'a str,
+}
+
+#[derive(Serialize)]
+struct UpdateChannelDomainRequest<'a> {
+    domain: &'a str,
+}
+
+impl HippoClient {
+    /// Returns a client which makes requests with the given credentials. If these
+    /// are a username and password, this logs into Hippo to obtain a token; an
+    /// API token is used as is.
+    pub async fn new(conn_info: &ConnectionInfo) -> Result<Self, HippoError> {
+        let base_url = conn_info.url.trim_end_matches('/').to_owned();
+        crate::debug_bundle::add_url_secret(&base_url);
+        let http = reqwest::Client::builder()
+            .danger_accept_invalid_certs(conn_info.danger_accept_invalid_certs)
+            .build()?;
+        let retry_policy = RetryPolicy {
+            max_wait: conn_info.max_retry_wait,
+            ..RetryPolicy::default()
+        };
+        let token = match &conn_info.credentials {
+            HippoCredentials::Token(token) => token.clone(),
+            HippoCredentials::Login { username, password } => {
+                crate::debug_bundle::add_secret(password);
+                login(&http, &base_url, username, password, &retry_policy).await?
+            }
+        };
+        crate::debug_bundle::add_secret(&token);
+        let token_manager: Box<dyn HippoTokenManager + Send + Sync> =
+            Box::new(BearerToken { token });
+        Ok(Self {
+            base_url,
+            http,
+            auth: AnyHippoAuth {
+                token_manager: Arc::new(token_manager),
+            },
+            retry_policy,
+        })
+    }
+
+    /// Authenticates and sends a request, waiting and retrying if Hippo is overloaded.
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, HippoError> {
+        let request = self.auth.apply_auth_header(request).await?;
+        let response = send_traced("hippo", request, ResponseRecording::Whole, |request| {
+            send_with_retry(request, &self.retry_policy)
+        })
+        .await?;
+        Ok(response)
+    }
+}
+
+async fn login(
+    http: &reqwest::Client,
+    base_url: &str,
+    username: &str,
+    password: &str,
+    retry_policy: &RetryPolicy,
+) -> Result<String, HippoError> {
+    let login = CreateTokenRequest {
+        user_name: username,
+        password,
+    };
+    let request = http
+        .post(format!("{}/account/createtoken", base_url))
+        .json(&login);
+    let response = send_traced("hippo", request, ResponseRecording::Whole, |request| {
+        send_with_retry(request, retry_policy)
+    })
+    .await?;
+    let token_response: CreateTokenResponse = ensure_success(response).await?.json().await?;
+    Ok(token_response.token)
+}
+
+#[async_trait::async_trait]
+impl HippoApi for HippoClient {
+    async fn find_revisions(
+        &self,
+        revision_number: &str,
+    ) -> Result<Vec<RevisionRecord>, HippoError> {
+        let request = self
+            .http
+            .get(format!("{}/api/revision", self.base_url))
+            .query(&[("revisionNumber", revision_number)]);
+        let response = self.send(request).await?;
+        let revisions: Vec<RevisionRecord> = ensure_success(response).await?.json().await?;
+        // Don't rely on the server having applied the filter.
+        Ok(revisions
+            .into_iter()
+            .filter(|r| r.revision_number == revision_number)
+            .collect())
+    }
+
+    async fn register_revision(
+        &self,
+        app_storage_id: &str,
+        revision_number: &str,
+    ) -> Result<(), HippoError> {
+        let request = RegisterRevisionRequest {
+            app_storage_id,
+            revision_number,
+        };
+        let request = self
+            .http
+            .post(format!("{}/api/revision", self.base_url))
+            .json(&request);
+        let response = self.send(request).await?;
+        ensure_success(response).await?;
+        Ok(())
+    }
+
+    async fn find_channel(
+        &self,
+        app_storage_id: &str,
+        channel_name: &str,
+    ) -> Result<Option<ChannelRecord>, HippoError> {
+        let request = self
+            .http
+            .get(format!("{}/api/channel", self.base_url))
+            .query(&[("appStorageId", app_storage_id), ("name", channel_name)]);
+        let response = self.send(request).await?;
+        let channels: Vec<ChannelRecord> = ensure_success(response).await?.json().await?;
+        Ok(channels.into_iter().find(|c| c.name == channel_name))
+    }
+
+    async fn create_channel(
+        &self,
+        app_storage_id: &str,
+        channel_name: &str,
+        domain: &str,
+    ) -> Result<(), HippoError> {
+        let request = self
+            .http
+            .post(format!("{}/api/channel", self.base_url))
+            .json(&CreateChannelRequest {
+                app_storage_id,
+                name: channel_name,
+                domain,
+            });
+        let response = self.send(request).await?;
+        ensure_success(response).await?;
+        Ok(())
+    }
+
+    async fn set_channel_domain(&self, channel_id: &str, domain: &str) -> Result<(), HippoError> {
+        let request = self
+            .http
+            .put(format!("{}/api/channel/{}/domain", self.base_url, channel_id))
+            .json(&UpdateChannelDomainRequest { domain });
+        let response = self.send(request).await?;
+        ensure_success(response).await?;
+        Ok(())
+    }
+
+    async fn list_routes(&self, domain: &str) -> Result<Vec<RouteRecord>, HippoError> {
+        let request = self
+            .http
+            .get(format!("{}/api/route", self.base_url))
+            .query(&[("domain", domain)]);
+        let response = self.send(request).await?;
+        Ok(ensure_success(response).await?.json().await?)
+    }
+}
+
+async fn ensure_success(response: reqwest::Response) -> Result<reqwest::Response, HippoError> {
+    let status_code = response.status();
+    if status_code.is_success() {
+        return Ok(response);
+    }
+    let message = response.text().await.ok().filter(|t| !t.is_empty());
+    Err(HippoError::InvalidRequest {
+        status_code,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{http_response, serve_in_turn};
+
+    fn json_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    /// A client logged in to a fake Hippo which then gives `responses`, and a
+    /// handle which gives the requests after the login.
+    async fn client(responses: Vec<String>) -> (HippoClient, std::thread::JoinHandle<Vec<String>>) {
+        let mut all = vec![json_response(
+            r#"{"token": "t0k3n", "expiration": "2030-01-01T00:00:00Z"}"#,
+        )];
+        all.extend(responses);
+        let (url, server) = serve_in_turn(all);
+        let conn_info = ConnectionInfo {
+            url: url.trim_end_matches("/v1").to_owned(),
+            danger_accept_invalid_certs: false,
+            credentials: HippoCredentials::Login {
+                username: "admin".to_owned(),
+                password: "pa55".to_owned(),
+            },
+            max_retry_wait: std::time::Duration::from_secs(1),
+        };
+        let client = HippoClient::new(&conn_info).await.unwrap();
+        let requests = std::thread::spawn(move || {
+            let mut requests = server.join().unwrap();
+            let login = requests.remove(0);
+            assert!(login.starts_with("POST /account/createtoken "), "{}", login);
+            assert!(
+                login.ends_with(r#"{"userName":"admin","password":"pa55"}"#),
+                "{}",
+                login
+            );
+            requests
+        });
+        (client, requests)
+    }
+
+    fn assert_bearer(request: &str) {
+        assert!(
+            request
+                .to_lowercase()
+                .contains("authorization: bearer t0k3n"),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revisions_are_registered_and_listed_as_hippo_expects() {
+        let (client, requests) = client(vec![
+            json_response(r#"[{"appStorageId": "weather", "revisionNumber": "1.2.3"}]"#),
+            http_response("201 Created", ""),
+        ])
+        .await;
+        let revisions = client.find_revisions("1.2.3").await.unwrap();
+        assert_eq!("weather", revisions[0].app_storage_id);
+        client.register_revision("weather", "1.2.3").await.unwrap();
+
+        let requests = requests.join().unwrap();
+        assert!(
+            requests[0].starts_with("GET /api/revision?revisionNumber=1.2.3 "),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].starts_with("POST /api/revision "),
+            "{}",
+            requests[1]
+        );
+        assert!(
+            requests[1].ends_with(r#"{"appStorageId":"weather","revisionNumber":"1.2.3"}"#),
+            "{}",
+            requests[1]
+        );
+        requests.iter().for_each(|r| assert_bearer(r));
+    }
+
+    #[tokio::test]
+    async fn test_channels_are_found_and_changed_as_hippo_expects() {
+        let (client, requests) = client(vec![
+            json_response(r#"[{"id": "c1", "name": "production", "domain": null}]"#),
+            http_response("200 OK", ""),
+            http_response("201 Created", ""),
+        ])
+        .await;
+        let channel = client.find_channel("weather", "production").await.unwrap();
+        assert_eq!("c1", channel.unwrap().id);
+        client
+            .set_channel_domain("c1", "weather.example.com")
+            .await
+            .unwrap();
+        client
+            .create_channel("weather", "staging", "staging.example.com")
+            .await
+            .unwrap();
+
+        let requests = requests.join().unwrap();
+        assert!(
+            requests[0].starts_with("GET /api/channel?appStorageId=weather&name=production "),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].starts_with("PUT /api/channel/c1/domain "),
+            "{}",
+            requests[1]
+        );
+        assert!(
+            requests[1].ends_with(r#"{"domain":"weather.example.com"}"#),
+            "{}",
+            requests[1]
+        );
+        assert!(
+            requests[2].starts_with("POST /api/channel "),
+            "{}",
+            requests[2]
+        );
+        assert!(
+            requests[2].ends_with(
+                r#"{"appStorageId":"weather","name":"staging","domain":"staging.example.com"}"#
+            ),
+            "{}",
+            requests[2]
+        );
+        requests.iter().for_each(|r| assert_bearer(r));
+    }
+
+    #[tokio::test]
+    async fn test_routes_are_listed_as_hippo_expects() {
+        let (client, requests) = client(vec![json_response(
+            r#"[{"route": "/", "appName": "Climate", "appStorageId": "climate", "channelName": "production"}]"#,
+        )])
+        .await;
+        let routes = client.list_routes("weather.example.com").await.unwrap();
+        assert_eq!("climate", routes[0].app_storage_id);
+
+        let requests = requests.join().unwrap();
+        assert!(
+            requests[0].starts_with("GET /api/route?domain=weather.example.com "),
+            "{}",
+            requests[0]
+        );
+        assert_bearer(&requests[0]);
+    }
+}
@@ -1,54 +1,707 @@
+use itertools::Itertools;
+
+use crate::bindle_utils::{normalize_route, route_shadows, InvoiceHelpers, Route};
+use crate::hippo_client::{HippoApi, HippoClient, HippoError};
+use crate::hippofacts::ChannelSpec;
+use crate::warnings::Warned;
+
 pub struct ConnectionInfo {
     pub url: String,
     pub danger_accept_invalid_certs: bool,
-    pub username: String,
-    pub password: String,
+    pub credentials: HippoCredentials,
+    /// The longest to wait when Hippo asks us to retry later.
+    pub max_retry_wait: std::time::Duration,
+}
+
+pub enum HippoCredentials {
+    /// Log in with a username and password to obtain a token.
+    Login { username: String, password: String },
+    /// Use an API token issued by Hippo, without logging in.
+    Token(String),
+}
+
+pub struct RegistrationOptions {
+    /// Register the revision even if Hippo already has one for this bindle.
+    pub re_register: bool,
+    /// Set the channel's domain to the one declared in the HIPPOFACTS.
+    pub apply_domain: bool,
+    /// Before registering, check the bindle's routes against those which
+    /// other applications serve on the channel's domain.
+    pub check_remote_routes: bool,
+}
+
+pub async fn register(
+    invoice: &bindle::Invoice,
+    channel: Option<&ChannelSpec>,
+    conn_info: &ConnectionInfo,
+    options: &RegistrationOptions,
+) -> anyhow::Result<Warned<()>> {
+    let bindle_id = &invoice.bindle.id;
+    let hippo_client = HippoClient::new(conn_info).await?;
+    let mut warnings = vec![];
+    if options.check_remote_routes {
+        let routes = invoice.routes()?;
+        let (_, route_warnings) =
+            check_remote_routes(&hippo_client, bindle_id.name(), channel, &routes)
+                .await?
+                .into();
+        warnings.extend(route_warnings);
+    }
+    let (_, registration_warnings) = register_revision(&hippo_client, bindle_id, options)
+        .await?
+        .into();
+    warnings.extend(registration_warnings);
+    if let Some(channel) = channel.filter(|_| options.apply_domain) {
+        let (_, domain_warnings) = apply_channel_domain(&hippo_client, bindle_id.name(), channel)
+            .await?
+            .into();
+        warnings.extend(domain_warnings);
+    }
+    Ok(Warned {
+        value: (),
+        warnings,
+    })
+}
+
+/// Checks the routes of an application against those which other applications
+/// already serve on its channel's domain. It is an error for another
+/// application to serve the same route, and a warning for one route to shadow
+/// the other. If the domain is unknown, or Hippo cannot list its routes, the
+/// check is skipped with a warning.
+async fn check_remote_routes(
+    hippo_client: &impl HippoApi,
+    app_storage_id: &str,
+    channel: Option<&ChannelSpec>,
+    routes: &[Route],
+) -> anyhow::Result<Warned<()>> {
+    let domain = match channel_domain(hippo_client, app_storage_id, channel).await? {
+        Some(domain) => domain,
+        None => {
+            let warning = "The application has no channel domain, so its routes were not checked against other applications";
+            return Ok(Warned::from(((), warning)));
+        }
+    };
+    let remote_routes = match hippo_client.list_routes(&domain).await {
+        Ok(remote_routes) => remote_routes,
+        Err(HippoError::InvalidRequest { status_code, .. })
+            if is_listing_unsupported_status(status_code) =>
+        {
+            crate::debug_bundle::note_capability(
+                "hippoRouteListing",
+                format!("unsupported ({})", status_code),
+            );
+            let warning = format!(
+                "This Hippo server cannot list the routes on {}, so they were not checked against other applications",
+                domain
+            );
+            return Ok(Warned::from(((), warning)));
+        }
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context(format!("Error listing the routes served on {}", domain)))
+        }
+    };
+
+    // The application's own routes are about to be replaced.
+    let others = remote_routes
+        .iter()
+        .filter(|r| r.app_storage_id != app_storage_id)
+        .map(|r| (normalize_route(&r.route), r))
+        .collect_vec();
+    let mut conflicts = vec![];
+    let mut warnings = vec![];
+    for route in routes {
+        for (other_path, other) in &others {
+            if &route.path == other_path {
+                conflicts.push(format!(
+                    "{} is already served by application {} (channel {})",
+                    route.path, other.app_name, other.channel_name
+                ));
+            } else if route_shadows(other_path, &route.path) {
+                warnings.push(format!(
+                    "Route {} on {} is shadowed by route {} of application {} (channel {})",
+                    route.path, domain, other_path, other.app_name, other.channel_name
+                ));
+            } else if route_shadows(&route.path, other_path) {
+                warnings.push(format!(
+                    "Route {} on {} shadows route {} of application {} (channel {})",
+                    route.path, domain, other_path, other.app_name, other.channel_name
+                ));
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        anyhow::bail!(
+            "Routes on {} are already served by other applications:\n{}",
+            domain,
+            conflicts.iter().map(|c| format!("  {}", c)).join("\n")
+        );
+    }
+    Ok(Warned {
+        value: (),
+        warnings,
+    })
 }
 
-pub async fn register(bindle_id: &bindle::Id, conn_info: &ConnectionInfo) -> anyhow::Result<()> {
-    let options = hippo::ClientOptions {
-        danger_accept_invalid_certs: conn_info.danger_accept_invalid_certs,
+/// The domain the application will be served on: the one declared for the
+/// channel if there is one, otherwise the one the channel already has.
+async fn channel_domain(
+    hippo_client: &impl HippoApi,
+    app_storage_id: &str,
+    channel: Option<&ChannelSpec>,
+) -> anyhow::Result<Option<String>> {
+    let channel = match channel {
+        None => return Ok(None),
+        Some(c) => c,
     };
-    let hippo_client = hippo::Client::new_with_options(
-        &conn_info.url,
-        &conn_info.username,
-        &conn_info.password,
-        options,
-    )
-    .await?;
+    if let Some(domain) = &channel.domain {
+        return Ok(Some(domain.clone()));
+    }
+    let existing = hippo_client
+        .find_channel(app_storage_id, &channel.name)
+        .await
+        .map_err(|e| {
+            anyhow::Error::new(e).context(format!("Error looking up channel {}", channel.name))
+        })?;
+    Ok(existing.and_then(|c| c.domain))
+}
+
+fn is_listing_unsupported_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::NOT_FOUND
+        || status == reqwest::StatusCode::METHOD_NOT_ALLOWED
+        || status == reqwest::StatusCode::NOT_IMPLEMENTED
+}
+
+async fn register_revision(
+    hippo_client: &impl HippoApi,
+    bindle_id: &bindle::Id,
+    options: &RegistrationOptions,
+) -> anyhow::Result<Warned<()>> {
+    let mut warnings = vec![];
+    // A registration which timed out on our side may still have succeeded on the
+    // server, so a retry must not fail just because the revision now exists.
+    if !options.re_register {
+        let existing = match hippo_client
+            .find_revisions(&bindle_id.version_string())
+            .await
+        {
+            Ok(existing) => existing,
+            Err(HippoError::InvalidRequest { status_code, .. })
+                if is_listing_unsupported_status(status_code) =>
+            {
+                crate::debug_bundle::note_capability(
+                    "hippoRevisionListing",
+                    format!("unsupported ({})", status_code),
+                );
+                warnings.push(format!(
+                    "This Hippo server cannot list revisions ({}), so revision {} is registered without checking whether it already exists",
+                    status_code,
+                    bindle_id.version_string()
+                ));
+                vec![]
+            }
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context("Error checking for existing revisions"))
+            }
+        };
+        if let Some(revision) = existing.first() {
+            if existing
+                .iter()
+                .any(|r| r.app_storage_id == bindle_id.name())
+            {
+                return Ok(Warned {
+                    value: (),
+                    warnings,
+                });
+            }
+            anyhow::bail!(
+                "Hippo already has revision {} registered for bindle {}/{}, not {}. Use --re-register to register anyway.",
+                revision.revision_number,
+                revision.app_storage_id,
+                revision.revision_number,
+                bindle_id
+            );
+        }
+    }
+
     hippo_client
-        .register_revision_by_storage_id(bindle_id.name(), &bindle_id.version_string())
+        .register_revision(bindle_id.name(), &bindle_id.version_string())
         .await
-        .map_err(format_register_revision_error)
+        .map_err(format_register_revision_error)?;
+    Ok(Warned {
+        value: (),
+        warnings,
+    })
 }
 
-fn format_register_revision_error(e: hippo::ClientError) -> anyhow::Error {
-    let message = match &e {
-        hippo::ClientError::InvalidRequest { status_code, message } => {
+async fn apply_channel_domain(
+    hippo_client: &impl HippoApi,
+    app_storage_id: &str,
+    channel: &ChannelSpec,
+) -> anyhow::Result<Warned<()>> {
+    let domain = match &channel.domain {
+        None => return Ok(Warned::from(())),
+        Some(d) => d,
+    };
+    let existing = hippo_client
+        .find_channel(app_storage_id, &channel.name)
+        .await
+        .map_err(|e| {
+            anyhow::Error::new(e).context(format!("Error looking up channel {}", channel.name))
+        })?;
+    let result = match existing {
+        Some(c) if c.domain.as_deref() == Some(domain) => return Ok(Warned::from(())),
+        Some(c) => hippo_client.set_channel_domain(&c.id, domain).await,
+        None => {
+            hippo_client
+                .create_channel(app_storage_id, &channel.name, domain)
+                .await
+        }
+    };
+    match result {
+        Ok(()) => Ok(Warned::from(())),
+        Err(HippoError::InvalidRequest {
+            status_code: reqwest::StatusCode::CONFLICT,
+            message,
+        }) => {
+            let detail = message.map(|m| format!(" ({})", m)).unwrap_or_default();
+            let warning = format!(
+                "Hippo reports that domain {} belongs to a different application{}: channel {} was not updated",
+                domain, detail, channel.name
+            );
+            Ok(Warned::from(((), warning)))
+        }
+        Err(e) => Err(anyhow::Error::new(e)
+            .context(format!("Error setting domain for channel {}", channel.name))),
+    }
+}
+
+fn format_register_revision_error(e: HippoError) -> anyhow::Error {
+    let remediation = match &e {
+        HippoError::InvalidRequest { status_code, message } => {
             let detail_clause = match message {
                 Some(m) => format!(": error was {}", m),
                 None => "".to_owned(),
             };
-            match status_code {
-                &reqwest::StatusCode::BAD_REQUEST =>
-                    format!("Hippo couldn't understand the request{} (400 Bad Request)", detail_clause),
-                &reqwest::StatusCode::UNAUTHORIZED =>
-                    format!("Login failed: please check your credentials{} (401 Unauthorized)", detail_clause),
-                &reqwest::StatusCode::FORBIDDEN =>
-                    format!("Hippo can't register this revision{} (403 Forbidden)", detail_clause),
-                &reqwest::StatusCode::NOT_FOUND =>
-                    "You don't have access to any applications that use this bindle ID (404 Not Found)".to_owned(),
-                &reqwest::StatusCode::METHOD_NOT_ALLOWED =>
+            match *status_code {
+                reqwest::StatusCode::BAD_REQUEST =>
+                    Some(format!("Hippo couldn't understand the request{} (400 Bad Request)", detail_clause)),
+                reqwest::StatusCode::UNAUTHORIZED =>
+                    Some(format!("Login failed: please check your credentials{} (401 Unauthorized)", detail_clause)),
+                reqwest::StatusCode::FORBIDDEN =>
+                    Some(format!("Hippo can't register this revision{} (403 Forbidden)", detail_clause)),
+                reqwest::StatusCode::NOT_FOUND =>
+                    Some("You don't have access to any applications that use this bindle ID (404 Not Found)".to_owned()),
+                reqwest::StatusCode::METHOD_NOT_ALLOWED =>
                     // At one point this could be returned for TLS mismatch; not sure if we fixed that
-                    format!("Wrong HTTP method; may also indicate you need to use/turn off HTTPS{} (405 Method Not Allowed)", detail_clause),
-                &reqwest::StatusCode::CONFLICT =>
-                    format!("Revision conflicts with existing Hippo state; it might already exist{} (409 Conflict)", detail_clause),
-                &reqwest::StatusCode::IM_A_TEAPOT =>
-                    "Specified URL is for a teapot not a Hippo (418 I'm a Teapot)".to_owned(),
-                _ => e.to_string(),
+                    Some(format!("Wrong HTTP method; may also indicate you need to use/turn off HTTPS{} (405 Method Not Allowed)", detail_clause)),
+                reqwest::StatusCode::CONFLICT =>
+                    Some(format!("Revision conflicts with existing Hippo state; it might already exist{} (409 Conflict)", detail_clause)),
+                reqwest::StatusCode::IM_A_TEAPOT =>
+                    Some("Specified URL is for a teapot not a Hippo (418 I'm a Teapot)".to_owned()),
+                _ => None,
             }
         },
-        _ => e.to_string(),
+        _ => None,
+    };
+    // Keep the Hippo error as the cause, so that its status can be reported.
+    let context = match remediation {
+        Some(m) => format!("Error registering revision: {}", m),
+        None => "Error registering revision".to_owned(),
     };
-    anyhow::anyhow!("Error registering revision: {}", message)
+    anyhow::Error::new(e).context(context)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hippo_client::{ChannelRecord, RevisionRecord, RouteRecord};
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    struct FakeHippo {
+        revisions: Mutex<Vec<RevisionRecord>>,
+        register_calls: Mutex<u32>,
+        channels: Mutex<Vec<ChannelRecord>>,
+        /// Domains which belong to other applications
+        taken_domains: Vec<String>,
+        /// The routes served on each domain, or `None` if this Hippo can't
+        /// list them
+        routes: Option<Vec<(String, RouteRecord)>>,
+        revision_listing: bool,
+    }
+
+    impl FakeHippo {
+        fn with_revisions(revisions: Vec<(&str, &str)>) -> Self {
+            let revisions = revisions
+                .into_iter()
+                .map(|(app_storage_id, revision_number)| RevisionRecord {
+                    app_storage_id: app_storage_id.to_owned(),
+                    revision_number: revision_number.to_owned(),
+                })
+                .collect();
+            Self {
+                revisions: Mutex::new(revisions),
+                register_calls: Mutex::new(0),
+                channels: Mutex::new(vec![]),
+                taken_domains: vec![],
+                routes: Some(vec![]),
+                revision_listing: true,
+            }
+        }
+
+        fn with_route(mut self, domain: &str, app_storage_id: &str, route: &str) -> Self {
+            let record = RouteRecord {
+                route: route.to_owned(),
+                app_name: format!("{} app", app_storage_id),
+                app_storage_id: app_storage_id.to_owned(),
+                channel_name: "production".to_owned(),
+            };
+            self.routes
+                .get_or_insert_with(Vec::new)
+                .push((domain.to_owned(), record));
+            self
+        }
+
+        fn without_route_listing(mut self) -> Self {
+            self.routes = None;
+            self
+        }
+
+        fn without_revision_listing(mut self) -> Self {
+            self.revision_listing = false;
+            self
+        }
+
+        fn with_channel(self, name: &str, domain: Option<&str>) -> Self {
+            self.channels.lock().unwrap().push(ChannelRecord {
+                id: format!("{}-id", name),
+                name: name.to_owned(),
+                domain: domain.map(|d| d.to_owned()),
+            });
+            self
+        }
+
+        fn with_taken_domain(mut self, domain: &str) -> Self {
+            self.taken_domains.push(domain.to_owned());
+            self
+        }
+
+        fn channel_domain(&self, name: &str) -> Option<String> {
+            self.channels
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|c| c.name == name)
+                .and_then(|c| c.domain.clone())
+        }
+
+        fn check_domain(&self, domain: &str) -> Result<(), HippoError> {
+            if self.taken_domains.iter().any(|d| d == domain) {
+                Err(HippoError::InvalidRequest {
+                    status_code: reqwest::StatusCode::CONFLICT,
+                    message: Some("domain is in use by app 'climate'".to_owned()),
+                })
+            } else {
+                Ok(())
+            }
+        }
+
+        fn register_calls(&self) -> u32 {
+            *self.register_calls.lock().unwrap()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl HippoApi for FakeHippo {
+        async fn find_revisions(
+            &self,
+            revision_number: &str,
+        ) -> Result<Vec<RevisionRecord>, HippoError> {
+            if !self.revision_listing {
+                return Err(HippoError::InvalidRequest {
+                    status_code: reqwest::StatusCode::METHOD_NOT_ALLOWED,
+                    message: None,
+                });
+            }
+            Ok(self
+                .revisions
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| r.revision_number == revision_number)
+                .cloned()
+                .collect())
+        }
+
+        async fn register_revision(
+            &self,
+            app_storage_id: &str,
+            revision_number: &str,
+        ) -> Result<(), HippoError> {
+            *self.register_calls.lock().unwrap() += 1;
+            self.revisions.lock().unwrap().push(RevisionRecord {
+                app_storage_id: app_storage_id.to_owned(),
+                revision_number: revision_number.to_owned(),
+            });
+            Ok(())
+        }
+
+        async fn find_channel(
+            &self,
+            _app_storage_id: &str,
+            channel_name: &str,
+        ) -> Result<Option<ChannelRecord>, HippoError> {
+            Ok(self
+                .channels
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|c| c.name == channel_name)
+                .cloned())
+        }
+
+        async fn create_channel(
+            &self,
+            _app_storage_id: &str,
+            channel_name: &str,
+            domain: &str,
+        ) -> Result<(), HippoError> {
+            self.check_domain(domain)?;
+            self.channels.lock().unwrap().push(ChannelRecord {
+                id: format!("{}-id", channel_name),
+                name: channel_name.to_owned(),
+                domain: Some(domain.to_owned()),
+            });
+            Ok(())
+        }
+
+        async fn set_channel_domain(
+            &self,
+            channel_id: &str,
+            domain: &str,
+        ) -> Result<(), HippoError> {
+            self.check_domain(domain)?;
+            let mut channels = self.channels.lock().unwrap();
+            let channel = channels.iter_mut().find(|c| c.id == channel_id).unwrap();
+            channel.domain = Some(domain.to_owned());
+            Ok(())
+        }
+
+        async fn list_routes(&self, domain: &str) -> Result<Vec<RouteRecord>, HippoError> {
+            match &self.routes {
+                None => Err(HippoError::InvalidRequest {
+                    status_code: reqwest::StatusCode::NOT_FOUND,
+                    message: None,
+                }),
+                Some(routes) => Ok(routes
+                    .iter()
+                    .filter(|(d, _)| d == domain)
+                    .map(|(_, r)| r.clone())
+                    .collect()),
+            }
+        }
+    }
+
+    fn test_bindle_id() -> bindle::Id {
+        bindle::Id::from_str("weather/1.2.3").unwrap()
+    }
+
+    fn production_channel() -> ChannelSpec {
+        ChannelSpec {
+            name: "production".to_owned(),
+            domain: Some("weather.example.com".to_owned()),
+        }
+    }
+
+    const NO_FORCE: RegistrationOptions = RegistrationOptions {
+        re_register: false,
+        apply_domain: true,
+        check_remote_routes: false,
+    };
+
+    #[tokio::test]
+    async fn test_unregistered_revision_is_registered() {
+        let hippo = FakeHippo::with_revisions(vec![("weather", "1.2.2")]);
+        register_revision(&hippo, &test_bindle_id(), &NO_FORCE)
+            .await
+            .expect("registration should succeed");
+        assert_eq!(1, hippo.register_calls());
+    }
+
+    #[tokio::test]
+    async fn test_revision_already_registered_for_same_bindle_is_success() {
+        let hippo = FakeHippo::with_revisions(vec![("weather", "1.2.3")]);
+        register_revision(&hippo, &test_bindle_id(), &NO_FORCE)
+            .await
+            .expect("existing registration should count as success");
+        assert_eq!(0, hippo.register_calls());
+    }
+
+    #[tokio::test]
+    async fn test_revision_registered_for_different_bindle_is_error() {
+        let hippo = FakeHippo::with_revisions(vec![("climate", "1.2.3")]);
+        let result = register_revision(&hippo, &test_bindle_id(), &NO_FORCE).await;
+        assert_eq!(0, hippo.register_calls());
+        let message = result.unwrap_err().to_string();
+        assert!(
+            message.contains("climate/1.2.3"),
+            "check error names the other bindle: '{}'",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revision_is_registered_if_hippo_cannot_list_revisions() {
+        let hippo = FakeHippo::with_revisions(vec![]).without_revision_listing();
+        let (_, warnings) = register_revision(&hippo, &test_bindle_id(), &NO_FORCE)
+            .await
+            .expect("registration should go ahead without the check")
+            .into();
+        assert_eq!(1, hippo.register_calls());
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("cannot list revisions"));
+    }
+
+    #[tokio::test]
+    async fn test_re_register_skips_the_existing_revision_check() {
+        let hippo = FakeHippo::with_revisions(vec![("weather", "1.2.3")]);
+        let options = RegistrationOptions {
+            re_register: true,
+            apply_domain: true,
+            check_remote_routes: false,
+        };
+        register_revision(&hippo, &test_bindle_id(), &options)
+            .await
+            .expect("forced registration should succeed");
+        assert_eq!(1, hippo.register_calls());
+    }
+
+    #[tokio::test]
+    async fn test_channel_is_created_with_domain() {
+        let hippo = FakeHippo::with_revisions(vec![]);
+        let (_, warnings) = apply_channel_domain(&hippo, "weather", &production_channel())
+            .await
+            .unwrap()
+            .into();
+        assert!(warnings.is_empty());
+        assert_eq!(
+            Some("weather.example.com".to_owned()),
+            hippo.channel_domain("production")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_domain_owned_by_another_app_is_a_warning() {
+        let hippo = FakeHippo::with_revisions(vec![])
+            .with_channel("production", Some("old.example.com"))
+            .with_taken_domain("weather.example.com");
+        let (_, warnings) = apply_channel_domain(&hippo, "weather", &production_channel())
+            .await
+            .unwrap()
+            .into();
+        assert_eq!(1, warnings.len());
+        assert!(
+            warnings[0].contains("belongs to a different application"),
+            "check warning explains conflict: '{}'",
+            warnings[0]
+        );
+        assert_eq!(
+            Some("old.example.com".to_owned()),
+            hippo.channel_domain("production")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_matching_domain_is_left_alone() {
+        // The domain is "taken" so any attempt to set it would produce a warning
+        let hippo = FakeHippo::with_revisions(vec![])
+            .with_channel("production", Some("weather.example.com"))
+            .with_taken_domain("weather.example.com");
+        let (_, warnings) = apply_channel_domain(&hippo, "weather", &production_channel())
+            .await
+            .unwrap()
+            .into();
+        assert!(warnings.is_empty());
+    }
+
+    fn local_routes(paths: &[&str]) -> Vec<Route> {
+        paths
+            .iter()
+            .map(|p| Route {
+                path: p.to_string(),
+                parcel_sha: format!("{}-sha", p),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_route_served_by_another_app_is_an_error() {
+        let hippo = FakeHippo::with_revisions(vec![])
+            .with_route("weather.example.com", "climate", "/forecast/")
+            .with_route("other.example.com", "tides", "/");
+        let routes = local_routes(&["/", "/forecast"]);
+        let message = check_remote_routes(&hippo, "weather", Some(&production_channel()), &routes)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.contains("/forecast is already served by application climate app"),
+            "names the owning app: '{}'",
+            message
+        );
+        assert!(
+            !message.contains("tides"),
+            "only checks the channel's domain: '{}'",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shadowed_routes_are_warnings() {
+        let hippo = FakeHippo::with_revisions(vec![])
+            .with_route("weather.example.com", "climate", "/static/...")
+            .with_route("weather.example.com", "weather", "/forecast");
+        let routes = local_routes(&["/static/weather.css", "/forecast", "/..."]);
+        let (_, warnings) =
+            check_remote_routes(&hippo, "weather", Some(&production_channel()), &routes)
+                .await
+                .unwrap()
+                .into();
+        assert_eq!(2, warnings.len(), "{:?}", warnings);
+        assert!(
+            warnings[0].contains("/static/weather.css")
+                && warnings[0]
+                    .contains("is shadowed by route /static/... of application climate app"),
+            "names the shadowing app: '{}'",
+            warnings[0]
+        );
+        assert!(
+            warnings[1].contains("Route /... on weather.example.com shadows route /static/..."),
+            "{}",
+            warnings[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_check_is_skipped_without_route_listing() {
+        let hippo = FakeHippo::with_revisions(vec![])
+            .with_route("weather.example.com", "climate", "/")
+            .without_route_listing();
+        let (_, warnings) = check_remote_routes(
+            &hippo,
+            "weather",
+            Some(&production_channel()),
+            &local_routes(&["/"]),
+        )
+        .await
+        .unwrap()
+        .into();
+        assert_eq!(1, warnings.len());
+        assert!(
+            warnings[0].contains("cannot list the routes"),
+            "explains the check was skipped: '{}'",
+            warnings[0]
+        );
+    }
 }
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, convert::TryFrom};
 
+use crate::templating::{substitute, TemplateVariables};
+
 // type FeatureMap = BTreeMap<String, BTreeMap<String, String>>;
 
 type AnnotationMap = BTreeMap<String, String>;
@@ -9,6 +11,7 @@ type AnnotationMap = BTreeMap<String, String>;
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub(crate) struct RawHippoFacts {
     pub bindle: BindleSpec,
+    pub channel: Option<ChannelSpec>,
     pub annotations: Option<AnnotationMap>,
     pub handler: Option<Vec<RawHandler>>,
     pub export: Option<Vec<RawExport>>,
@@ -23,6 +26,13 @@ pub struct BindleSpec {
     pub authors: Option<Vec<String>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ChannelSpec {
+    pub name: String,
+    pub domain: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub(crate) struct RawHandler {
@@ -31,6 +41,26 @@ pub(crate) struct RawHandler {
     pub external: Option<RawExternalRef>,
     pub route: String,
     pub files: Option<Vec<String>>,
+    pub build: Option<BuildSpec>,
+    #[serde(rename = "file_rules")]
+    pub file_rules: Option<Vec<FileRule>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct BuildSpec {
+    /// A shell command which builds the handler's module.
+    pub command: String,
+}
+
+/// Annotations for those of a handler's files which match a glob, such as
+/// cache headers for assets whose names are hashed. If a file matches more
+/// than one rule, the first one applies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct FileRule {
+    pub glob: String,
+    pub annotations: AnnotationMap,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -51,6 +81,7 @@ pub(crate) struct RawExternalRef {
 
 pub struct HippoFacts {
     pub bindle: BindleSpec,
+    pub channel: Option<ChannelSpec>,
     pub annotations: Option<AnnotationMap>,
     pub entries: Vec<HippoFactsEntry>,
 }
@@ -60,12 +91,15 @@ pub struct LocalHandler {
     pub entrypoint: Option<String>,
     pub route: String,
     pub files: Option<Vec<String>>,
+    pub build: Option<BuildSpec>,
+    pub file_rules: Vec<FileRule>,
 }
 
 pub struct ExternalHandler {
     pub external: ExternalRef,
     pub route: String,
     pub files: Option<Vec<String>>,
+    pub file_rules: Vec<FileRule>,
 }
 
 enum HandlerModule {
@@ -93,11 +127,15 @@ pub enum HippoFactsEntry {
 }
 
 impl HippoFacts {
-    pub fn read_from(source: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+    pub fn read_from(
+        source: impl AsRef<std::path::Path>,
+        variables: &TemplateVariables,
+    ) -> anyhow::Result<Self> {
         // Immediate-call closure lets us use the try operator
         let read_result = (|| {
             let content = std::fs::read_to_string(&source)?;
-            let spec = toml::from_str::<RawHippoFacts>(&content)?;
+            let document = toml::from_str::<toml::Value>(&content)?;
+            let spec: RawHippoFacts = substitute(document, variables)?.try_into()?;
             Self::try_from(&spec)
         })();
         read_result.map_err(|e: anyhow::Error| {
@@ -126,6 +164,7 @@ impl TryFrom<&RawHippoFacts> for HippoFacts {
         }
         Ok(Self {
             bindle: raw.bindle.clone(),
+            channel: raw.channel.clone(),
             annotations: raw.annotations.clone(),
             entries,
         })
@@ -146,7 +185,7 @@ impl TryFrom<&RawHandler> for HippoFactsEntry {
                 raw.route
             )),
         }?;
-        if let Some(_) = &raw.entrypoint {
+        if raw.entrypoint.is_some() {
             if let HandlerModule::External(_) = &handler_module {
                 anyhow::bail!(
                     "Route '{}' may not specify an entrypoint on an external reference",
@@ -154,17 +193,39 @@ impl TryFrom<&RawHandler> for HippoFactsEntry {
                 );
             }
         }
+        if raw.build.is_some() {
+            if let HandlerModule::External(_) = &handler_module {
+                anyhow::bail!(
+                    "Route '{}' may not specify a build command on an external reference",
+                    raw.route
+                );
+            }
+        }
+        let file_rules = raw.file_rules.clone().unwrap_or_default();
+        for rule in &file_rules {
+            if let Err(e) = glob::Pattern::new(&rule.glob) {
+                anyhow::bail!(
+                    "Route '{}' has a file rule with invalid glob '{}': {}",
+                    raw.route,
+                    rule.glob,
+                    e
+                );
+            }
+        }
         let entry = match handler_module {
             HandlerModule::File(name) => Self::LocalHandler(LocalHandler {
                 name,
                 entrypoint: raw.entrypoint.clone(),
                 route: raw.route.clone(),
                 files: raw.files.clone(),
+                build: raw.build.clone(),
+                file_rules,
             }),
             HandlerModule::External(external) => Self::ExternalHandler(ExternalHandler {
                 external,
                 route: raw.route.clone(),
                 files: raw.files.clone(),
+                file_rules,
             }),
         };
         Ok(entry)
@@ -205,6 +266,14 @@ impl HippoFactsEntry {
         }
     }
 
+    pub fn file_rules(&self) -> &[FileRule] {
+        match self {
+            Self::LocalHandler(h) => &h.file_rules,
+            Self::ExternalHandler(h) => &h.file_rules,
+            Self::Export(_) => &[],
+        }
+    }
+
     pub fn external_ref(&self) -> Option<ExternalRef> {
         match self {
             Self::LocalHandler(_) => None,
@@ -212,6 +281,102 @@ impl HippoFactsEntry {
             Self::Export(_) => None,
         }
     }
+
+    pub fn route(&self) -> Option<String> {
+        match self {
+            Self::LocalHandler(h) => Some(h.route.clone()),
+            Self::ExternalHandler(h) => Some(h.route.clone()),
+            Self::Export(_) => None,
+        }
+    }
+
+    pub fn build(&self) -> Option<&BuildSpec> {
+        match self {
+            Self::LocalHandler(h) => h.build.as_ref(),
+            Self::ExternalHandler(_) => None,
+            Self::Export(_) => None,
+        }
+    }
+
+    /// The name by which the entry can be selected with `--handler`: the module
+    /// file for local handlers and exports, or the handler ID for external refs.
+    pub fn handler_name(&self) -> String {
+        match self {
+            Self::LocalHandler(h) => h.name.clone(),
+            Self::ExternalHandler(h) => h.external.handler_id.clone(),
+            Self::Export(e) => e.name.clone(),
+        }
+    }
+}
+
+/// Which entries of a HIPPOFACTS file to build, when building only part of an
+/// application.
+pub struct HandlerSelection {
+    pub handlers: Vec<String>,
+    pub routes: Vec<String>,
+    /// Whether to add a `-partial` prerelease tag to the version, so that a partial
+    /// bindle can't be mistaken for the full application.
+    pub tag_version: bool,
+}
+
+impl HippoFacts {
+    /// Returns the spec with only the selected entries. Each entry brings its own
+    /// asset group (and, for external refs, the groups those require), so the
+    /// result still expands to a complete invoice for the selected handlers.
+    pub fn select(self, selection: &HandlerSelection) -> anyhow::Result<Self> {
+        let names: Vec<_> = self.entries.iter().map(|e| e.handler_name()).collect();
+        let routes: Vec<_> = self.entries.iter().flat_map(|e| e.route()).collect();
+        for handler in &selection.handlers {
+            if !names.contains(handler) {
+                anyhow::bail!(
+                    "No handler named '{}'. Available handlers are: {}",
+                    handler,
+                    names.join(", ")
+                );
+            }
+        }
+        for route in &selection.routes {
+            if !routes.contains(route) {
+                anyhow::bail!(
+                    "No handler serves route '{}'. Available routes are: {}",
+                    route,
+                    routes.join(", ")
+                );
+            }
+        }
+
+        let entries = self
+            .entries
+            .into_iter()
+            .filter(|e| {
+                selection.handlers.contains(&e.handler_name())
+                    || e.route().is_some_and(|r| selection.routes.contains(&r))
+            })
+            .collect();
+        let bindle = if selection.tag_version {
+            BindleSpec {
+                version: partial_version(&self.bindle.version),
+                ..self.bindle
+            }
+        } else {
+            self.bindle
+        };
+
+        Ok(Self {
+            bindle,
+            channel: self.channel,
+            annotations: self.annotations,
+            entries,
+        })
+    }
+}
+
+fn partial_version(version: &str) -> String {
+    // The tag has to go before any build metadata to be part of the prerelease.
+    match version.split_once('+') {
+        Some((version, build)) => format!("{}-partial+{}", version, build),
+        None => format!("{}-partial", version),
+    }
 }
 
 fn no_handlers() -> anyhow::Error {
@@ -239,14 +404,6 @@ mod test {
             }
         }
 
-        pub fn route(&self) -> Option<String> {
-            match self {
-                Self::LocalHandler(h) => Some(h.route.clone()),
-                Self::ExternalHandler(h) => Some(h.route.clone()),
-                Self::Export(_) => None,
-            }
-        }
-
         pub fn export_id(&self) -> Option<String> {
             match self {
                 Self::LocalHandler(_) => None,
@@ -297,10 +454,138 @@ mod test {
         assert_eq!(0, handlers[1].files().len());
     }
 
+    #[test]
+    fn test_can_read_channel_domain() {
+        let raw: RawHippoFacts = toml::from_str(
+            r#"
+        [bindle]
+        name = "birds"
+        version = "1.2.4"
+
+        [channel]
+        name = "production"
+        domain = "birds.example.com"
+
+        [[handler]]
+        name = "penguin.wasm"
+        route = "/birds/flightless"
+        "#,
+        )
+        .expect("error parsing test TOML");
+        let facts = HippoFacts::try_from(&raw).expect("error parsing raw to HF");
+
+        let channel = facts.channel.expect("channel section not read");
+        assert_eq!("production", channel.name);
+        assert_eq!("birds.example.com", channel.domain.unwrap());
+    }
+
+    #[test]
+    fn test_can_read_build_command() {
+        let raw: RawHippoFacts = toml::from_str(
+            r#"
+        [bindle]
+        name = "birds"
+        version = "1.2.4"
+
+        [[handler]]
+        name = "target/wasm32-wasi/release/penguin.wasm"
+        route = "/birds/flightless"
+        build.command = "cargo build --target wasm32-wasi --release"
+
+        [[handler]]
+        name = "cassowary.wasm"
+        route = "/birds/savage/rending"
+        "#,
+        )
+        .expect("error parsing test TOML");
+        let facts = HippoFacts::try_from(&raw).expect("error parsing raw to HF");
+
+        let build = facts.entries[0].build().expect("build command not read");
+        assert_eq!("cargo build --target wasm32-wasi --release", build.command);
+        assert!(facts.entries[1].build().is_none());
+    }
+
+    #[test]
+    fn test_external_refs_cannot_have_build_commands() {
+        let raw: RawHippoFacts = toml::from_str(
+            r#"
+        [bindle]
+        name = "birds"
+        version = "1.2.4"
+
+        [[handler]]
+        route = "/static"
+        external.bindleId = "deislabs/fileserver/1.0.3"
+        external.handlerId = "static"
+        build.command = "make"
+        "#,
+        )
+        .expect("error parsing test TOML");
+        assert!(HippoFacts::try_from(&raw).is_err());
+    }
+
+    #[test]
+    fn test_can_read_file_rules() {
+        let raw: RawHippoFacts = toml::from_str(
+            r#"
+        [bindle]
+        name = "birds"
+        version = "1.2.4"
+
+        [[handler]]
+        name = "penguin.wasm"
+        route = "/birds/flightless"
+        files = ["static/**/*"]
+
+        [[handler.file_rules]]
+        glob = "static/*.*.js"
+        annotations = { cache-control = "public, max-age=31536000, immutable" }
+
+        [[handler.file_rules]]
+        glob = "static/*.html"
+        annotations = { cache-control = "no-cache" }
+        "#,
+        )
+        .expect("error parsing test TOML");
+        let facts = HippoFacts::try_from(&raw).expect("error parsing raw to HF");
+
+        let rules = facts.entries[0].file_rules();
+        assert_eq!(2, rules.len());
+        assert_eq!("static/*.*.js", rules[0].glob);
+        assert_eq!(
+            "public, max-age=31536000, immutable",
+            rules[0].annotations["cache-control"]
+        );
+    }
+
+    #[test]
+    fn test_file_rules_must_have_valid_globs() {
+        let raw: RawHippoFacts = toml::from_str(
+            r#"
+        [bindle]
+        name = "birds"
+        version = "1.2.4"
+
+        [[handler]]
+        name = "penguin.wasm"
+        route = "/birds/flightless"
+
+        [[handler.file_rules]]
+        glob = "static/[*.js"
+        annotations = { cache-control = "no-cache" }
+        "#,
+        )
+        .expect("error parsing test TOML");
+        assert!(HippoFacts::try_from(&raw).is_err());
+    }
+
     #[test]
     fn test_parse_externals() {
-        let facts = HippoFacts::read_from("./testdata/external1/HIPPOFACTS")
-            .expect("error reading facts file");
+        let facts = HippoFacts::read_from(
+            "./testdata/external1/HIPPOFACTS",
+            &TemplateVariables::default(),
+        )
+        .expect("error reading facts file");
 
         assert_eq!("toastbattle", &facts.bindle.name);
 
@@ -316,14 +601,18 @@ mod test {
 
     #[test]
     fn test_external_refs_cannot_have_own_entrypoints() {
-        let facts = HippoFacts::read_from("./testdata/external_bad/HIPPOFACTS");
+        let facts = HippoFacts::read_from(
+            "./testdata/external_bad/HIPPOFACTS",
+            &TemplateVariables::default(),
+        );
         assert!(facts.is_err());
     }
 
     #[test]
     fn test_parse_exports() {
         let facts =
-            HippoFacts::read_from("./testdata/lib1/HIPPOFACTS").expect("error reading facts file");
+            HippoFacts::read_from("./testdata/lib1/HIPPOFACTS", &TemplateVariables::default())
+                .expect("error reading facts file");
 
         assert_eq!("server", &facts.bindle.name);
 
@@ -362,4 +651,100 @@ mod test {
             );
         }
     }
+
+    fn five_handler_facts() -> HippoFacts {
+        let raw: RawHippoFacts = toml::from_str(
+            r#"
+        [bindle]
+        name = "zoo"
+        version = "1.0.0"
+
+        [[handler]]
+        name = "hippo.wasm"
+        route = "/hippo"
+        files = ["hippo/*.png"]
+
+        [[handler]]
+        name = "lion.wasm"
+        route = "/lion"
+        files = ["lion/*.png"]
+
+        [[handler]]
+        name = "tiger.wasm"
+        route = "/tiger"
+
+        [[handler]]
+        name = "bear.wasm"
+        route = "/bear"
+
+        [[handler]]
+        route = "/static"
+        external.bindleId = "deislabs/fileserver/1.0.3"
+        external.handlerId = "static"
+        "#,
+        )
+        .expect("error parsing test TOML");
+        HippoFacts::try_from(&raw).expect("error parsing raw to HF")
+    }
+
+    fn selecting(handlers: &[&str], routes: &[&str]) -> HandlerSelection {
+        HandlerSelection {
+            handlers: handlers.iter().map(|s| s.to_string()).collect(),
+            routes: routes.iter().map(|s| s.to_string()).collect(),
+            tag_version: true,
+        }
+    }
+
+    #[test]
+    fn test_select_by_handler_and_route() {
+        let facts = five_handler_facts()
+            .select(&selecting(&["lion.wasm", "static"], &["/bear"]))
+            .expect("error selecting handlers");
+
+        let names: Vec<_> = facts.entries.iter().map(|e| e.handler_name()).collect();
+        assert_eq!(vec!["lion.wasm", "bear.wasm", "static"], names);
+        assert_eq!(1, facts.entries[0].files().len());
+        assert_eq!("1.0.0-partial", facts.bindle.version);
+    }
+
+    #[test]
+    fn test_partial_tag_can_be_turned_off() {
+        let selection = HandlerSelection {
+            tag_version: false,
+            ..selecting(&["lion.wasm"], &[])
+        };
+        let facts = five_handler_facts().select(&selection).unwrap();
+        assert_eq!("1.0.0", facts.bindle.version);
+    }
+
+    #[test]
+    fn test_partial_tag_goes_before_build_metadata() {
+        assert_eq!("1.0.0-rc1-partial", partial_version("1.0.0-rc1"));
+        assert_eq!("1.0.0-partial+abc123", partial_version("1.0.0+abc123"));
+    }
+
+    #[test]
+    fn test_selecting_unknown_handler_lists_available_ones() {
+        let err = five_handler_facts()
+            .select(&selecting(&["giraffe.wasm"], &[]))
+            .err()
+            .expect("selecting a nonexistent handler should fail")
+            .to_string();
+        assert!(err.contains("giraffe.wasm"), "names the bad handler: '{}'", err);
+        assert!(
+            err.contains("hippo.wasm, lion.wasm, tiger.wasm, bear.wasm, static"),
+            "lists the available handlers: '{}'",
+            err
+        );
+    }
+
+    #[test]
+    fn test_selecting_unknown_route_lists_available_ones() {
+        let err = five_handler_facts()
+            .select(&selecting(&[], &["/giraffe"]))
+            .err()
+            .expect("selecting a nonexistent route should fail")
+            .to_string();
+        assert!(err.contains("/hippo, /lion"), "lists the routes: '{}'", err);
+    }
 }
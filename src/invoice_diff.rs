@@ -0,0 +1,465 @@
+//! Comparing a local invoice with the server's invoice for the same bindle, so
+//! that pushing a bindle which is already there can succeed if the two match,
+//! and explain what differs if they don't.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::bindle_utils::InvoiceHelpers;
+
+/// How two invoices for the same bindle ID differ. Each list of parcels is
+/// sorted by parcel name.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvoiceConflict {
+    /// The fields of the invoices, other than their parcels, which differ,
+    /// named as in an invoice file, e.g. `description` or `group`.
+    pub fields: Vec<String>,
+    pub only_local: Vec<ParcelSummary>,
+    pub only_remote: Vec<ParcelSummary>,
+    /// Parcels with the same name but different content, or different labels
+    /// or conditions.
+    pub changed: Vec<ChangedParcel>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParcelSummary {
+    pub name: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangedParcel {
+    pub name: String,
+    /// The fields of the parcels which differ, e.g. `sha256` or `conditions`.
+    pub fields: Vec<String>,
+    pub local: ParcelSummary,
+    pub remote: ParcelSummary,
+}
+
+impl InvoiceConflict {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+            && self.only_local.is_empty()
+            && self.only_remote.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+impl std::fmt::Display for InvoiceConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.fields.is_empty() {
+            writeln!(f, "  differs in:  {}", self.fields.join(", "))?;
+        }
+        for p in &self.only_local {
+            writeln!(
+                f,
+                "  only local:  {} ({}, {} bytes)",
+                p.name, p.sha256, p.size
+            )?;
+        }
+        for p in &self.only_remote {
+            writeln!(
+                f,
+                "  only remote: {} ({}, {} bytes)",
+                p.name, p.sha256, p.size
+            )?;
+        }
+        for c in &self.changed {
+            writeln!(
+                f,
+                "  changed:     {} ({}; local {}, {} bytes; remote {}, {} bytes)",
+                c.name,
+                c.fields.join(", "),
+                c.local.sha256,
+                c.local.size,
+                c.remote.sha256,
+                c.remote.size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares two invoices field by field. The fields which the server sets,
+/// the signatures and whether the bindle is yanked, are ignored, as is the
+/// order of the parcels, groups and condition lists.
+pub fn compare_invoices(local: &bindle::Invoice, remote: &bindle::Invoice) -> InvoiceConflict {
+    let mut conflict = InvoiceConflict {
+        fields: differing_fields(&invoice_fields(local), &invoice_fields(remote)),
+        ..InvoiceConflict::default()
+    };
+
+    let local = parcels_by_name(local);
+    let mut remote = parcels_by_name(remote);
+    for (name, local) in local {
+        match remote.remove(&name) {
+            None => conflict.only_local.push(summary(local)),
+            Some(remote) => {
+                let fields = differing_fields(&parcel_fields(local), &parcel_fields(remote));
+                if !fields.is_empty() {
+                    conflict.changed.push(ChangedParcel {
+                        name,
+                        fields,
+                        local: summary(local),
+                        remote: summary(remote),
+                    });
+                }
+            }
+        }
+    }
+    conflict.only_remote = remote.into_values().map(summary).collect();
+    conflict
+}
+
+/// The fields of `invoice` which the client sets, other than the parcels, in
+/// a canonical form.
+fn invoice_fields(invoice: &bindle::Invoice) -> BTreeMap<&'static str, serde_json::Value> {
+    let groups: BTreeMap<_, _> = invoice
+        .group
+        .iter()
+        .flatten()
+        .map(|g| (g.name.clone(), g))
+        .collect();
+    vec![
+        ("bindleVersion", json(&invoice.bindle_version)),
+        ("description", json(&invoice.bindle.description)),
+        ("authors", json(&invoice.bindle.authors)),
+        ("annotations", json(&invoice.annotations)),
+        ("group", json(&groups)),
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// The fields of `parcel`'s label and conditions, in a canonical form.
+fn parcel_fields(parcel: &bindle::Parcel) -> BTreeMap<&'static str, serde_json::Value> {
+    let label = &parcel.label;
+    let conditions = parcel.conditions.as_ref().map(|c| {
+        let sorted = |list: &Option<Vec<String>>| {
+            list.as_ref()
+                .map(|l| l.iter().cloned().sorted().collect_vec())
+        };
+        (sorted(&c.member_of), sorted(&c.requires))
+    });
+    vec![
+        ("sha256", json(&label.sha256)),
+        ("size", json(&label.size)),
+        ("mediaType", json(&label.media_type)),
+        ("annotations", json(&label.annotations)),
+        ("feature", json(&label.feature)),
+        ("origin", json(&label.origin)),
+        ("conditions", json(&conditions)),
+    ]
+    .into_iter()
+    .collect()
+}
+
+fn json(value: &impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or(serde_json::Value::Null)
+}
+
+fn differing_fields(
+    local: &BTreeMap<&'static str, serde_json::Value>,
+    remote: &BTreeMap<&'static str, serde_json::Value>,
+) -> Vec<String> {
+    local
+        .iter()
+        .filter(|(field, value)| remote.get(*field) != Some(value))
+        .map(|(field, _)| field.to_string())
+        .sorted()
+        .collect()
+}
+
+/// Whether two invoices have the same required closure, by the digests of its
+/// parcels. Parcel names and order, and parcels outside the closure, such as
+/// those in optional groups, are ignored. This is a cheaper check than
+/// `compare_invoices` for whether a rebuild produced the same application.
+pub fn closures_equal(a: &bindle::Invoice, b: &bindle::Invoice) -> bool {
+    closure_shas(a) == closure_shas(b)
+}
+
+fn closure_shas(invoice: &bindle::Invoice) -> BTreeSet<String> {
+    invoice
+        .required_closure()
+        .into_iter()
+        .map(|p| p.label.sha256)
+        .collect()
+}
+
+fn parcels_by_name(invoice: &bindle::Invoice) -> BTreeMap<String, &bindle::Parcel> {
+    invoice
+        .parcel
+        .iter()
+        .flatten()
+        .map(|p| (p.label.name.clone(), p))
+        .collect()
+}
+
+fn summary(parcel: &bindle::Parcel) -> ParcelSummary {
+    ParcelSummary {
+        name: parcel.label.name.clone(),
+        sha256: parcel.label.sha256.clone(),
+        size: parcel.label.size,
+    }
+}
+
+/// The error for a push which found a different invoice for the same bindle
+/// already on the server. `main` exits with a distinct code for this, so that
+/// a pipeline can tell it apart from other failures.
+#[derive(Debug)]
+pub struct PushConflict {
+    pub bindle_id: bindle::Id,
+    pub conflict: InvoiceConflict,
+}
+
+impl PushConflict {
+    /// The error on one line, without the differences.
+    pub fn summary(&self) -> String {
+        format!(
+            "The Bindle server already has a different {}",
+            self.bindle_id
+        )
+    }
+}
+
+impl std::fmt::Display for PushConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:\n{}", self.summary(), self.conflict)
+    }
+}
+
+impl std::error::Error for PushConflict {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, test_invoice};
+    use std::str::FromStr;
+
+    fn invoice_of(parcels: Vec<bindle::Parcel>) -> bindle::Invoice {
+        let id = bindle::Id::from_str("birds/1.4.2").unwrap();
+        test_invoice(&id, parcels)
+    }
+
+    #[test]
+    fn test_order_and_signatures_are_ignored() {
+        let local = invoice_of(vec![
+            parcel_for("birds.wasm", b"birds"),
+            parcel_for("index.html", b"index"),
+        ]);
+        let mut remote = invoice_of(vec![
+            parcel_for("index.html", b"index"),
+            parcel_for("birds.wasm", b"birds"),
+        ]);
+        remote.signature = Some(vec![bindle::Signature {
+            by: "server@example.com".to_owned(),
+            signature: "c2lnbmVk".to_owned(),
+            key: "a2V5".to_owned(),
+            role: bindle::SignatureRole::Host,
+            at: 1_600_000_000,
+        }]);
+        assert!(compare_invoices(&local, &remote).is_empty());
+    }
+
+    #[test]
+    fn test_differences_are_reported_by_kind() {
+        let local = invoice_of(vec![
+            parcel_for("birds.wasm", b"birds v2"),
+            parcel_for("new.css", b"new"),
+            parcel_for("index.html", b"index"),
+        ]);
+        let remote = invoice_of(vec![
+            parcel_for("index.html", b"index"),
+            parcel_for("old.css", b"old"),
+            parcel_for("birds.wasm", b"birds"),
+        ]);
+
+        let conflict = compare_invoices(&local, &remote);
+
+        assert_eq!(vec!["new.css"], names(&conflict.only_local));
+        assert_eq!(vec!["old.css"], names(&conflict.only_remote));
+        assert_eq!(1, conflict.changed.len());
+        let changed = &conflict.changed[0];
+        assert_eq!("birds.wasm", changed.name);
+        assert_eq!(vec!["sha256", "size"], changed.fields);
+        assert_eq!(8, changed.local.size);
+        assert_eq!(5, changed.remote.size);
+        assert_ne!(changed.local.sha256, changed.remote.sha256);
+
+        let json = serde_json::to_value(&conflict).unwrap();
+        assert_eq!("old.css", json["onlyRemote"][0]["name"]);
+        assert_eq!(5, json["changed"][0]["remote"]["size"]);
+    }
+
+    /// The conflict between an invoice and a copy of it changed by `change`.
+    fn conflict_after(change: impl Fn(&mut bindle::Invoice)) -> InvoiceConflict {
+        let local = invoice_of(vec![
+            optional(parcel_for("birds.wasm", b"birds")),
+            parcel_for("index.html", b"index"),
+        ]);
+        let mut remote = local.clone();
+        change(&mut remote);
+        compare_invoices(&local, &remote)
+    }
+
+    fn first_parcel(invoice: &mut bindle::Invoice) -> &mut bindle::Parcel {
+        &mut invoice.parcel.as_mut().unwrap()[0]
+    }
+
+    #[test]
+    fn test_server_set_fields_are_ignored() {
+        assert!(conflict_after(|remote| {
+            remote.yanked = Some(true);
+            remote.yanked_signature = Some(vec![]);
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_differing_description_is_reported() {
+        let conflict =
+            conflict_after(|remote| remote.bindle.description = Some("Tweets".to_owned()));
+        assert_eq!(vec!["description"], conflict.fields);
+        assert!(conflict.to_string().contains("differs in:  description"));
+    }
+
+    #[test]
+    fn test_differing_authors_are_reported() {
+        let conflict = conflict_after(|remote| {
+            remote.bindle.authors = Some(vec!["someone@example.com".to_owned()])
+        });
+        assert_eq!(vec!["authors"], conflict.fields);
+    }
+
+    #[test]
+    fn test_differing_invoice_annotations_are_reported() {
+        let conflict = conflict_after(|remote| {
+            remote.annotations = Some(
+                vec![("team".to_owned(), "ornithology".to_owned())]
+                    .into_iter()
+                    .collect(),
+            )
+        });
+        assert_eq!(vec!["annotations"], conflict.fields);
+    }
+
+    #[test]
+    fn test_differing_groups_are_reported_but_not_their_order() {
+        let group = |name: &str| bindle::Group {
+            name: name.to_owned(),
+            required: None,
+            satisfied_by: None,
+        };
+        let local = bindle::Invoice {
+            group: Some(vec![group("extras"), group("debug")]),
+            ..invoice_of(vec![])
+        };
+        let reordered = bindle::Invoice {
+            group: Some(vec![group("debug"), group("extras")]),
+            ..local.clone()
+        };
+        assert!(compare_invoices(&local, &reordered).is_empty());
+
+        let mut required = reordered;
+        required.group.as_mut().unwrap()[0].required = Some(true);
+        assert_eq!(vec!["group"], compare_invoices(&local, &required).fields);
+    }
+
+    #[test]
+    fn test_differing_bindle_version_is_reported() {
+        let conflict = conflict_after(|remote| remote.bindle_version = "2.0".to_owned());
+        assert_eq!(vec!["bindleVersion"], conflict.fields);
+    }
+
+    #[test]
+    fn test_differing_parcel_conditions_are_reported_but_not_their_order() {
+        let conflict = conflict_after(|remote| first_parcel(remote).conditions = None);
+        assert_eq!(1, conflict.changed.len());
+        assert_eq!(vec!["conditions"], conflict.changed[0].fields);
+        assert!(conflict.to_string().contains("birds.wasm (conditions;"));
+
+        fn with_groups(groups: &'static [&'static str]) -> impl Fn(&mut bindle::Invoice) {
+            move |remote| {
+                first_parcel(remote).conditions = Some(bindle::Condition {
+                    member_of: Some(groups.iter().map(|g| g.to_string()).collect()),
+                    requires: None,
+                })
+            }
+        }
+        let local = conflict_after(with_groups(&["extras", "debug"]));
+        let reordered = conflict_after(with_groups(&["debug", "extras"]));
+        assert_eq!(local, reordered);
+    }
+
+    #[test]
+    fn test_differing_parcel_annotations_are_reported() {
+        let conflict = conflict_after(|remote| {
+            first_parcel(remote).label.annotations = Some(
+                vec![("cache-control".to_owned(), "immutable".to_owned())]
+                    .into_iter()
+                    .collect(),
+            )
+        });
+        assert_eq!(vec!["annotations"], conflict.changed[0].fields);
+    }
+
+    #[test]
+    fn test_differing_media_type_is_reported() {
+        let conflict = conflict_after(|remote| {
+            first_parcel(remote).label.media_type = "application/wasm".to_owned()
+        });
+        assert_eq!(vec!["mediaType"], conflict.changed[0].fields);
+    }
+
+    #[test]
+    fn test_differing_features_are_reported() {
+        let conflict = conflict_after(|remote| {
+            let feature = vec![(
+                "wagi".to_owned(),
+                vec![("file".to_owned(), "true".to_owned())]
+                    .into_iter()
+                    .collect(),
+            )];
+            first_parcel(remote).label.feature = Some(feature.into_iter().collect())
+        });
+        assert_eq!(vec!["feature"], conflict.changed[0].fields);
+    }
+
+    fn optional(mut parcel: bindle::Parcel) -> bindle::Parcel {
+        parcel.conditions = Some(bindle::Condition {
+            member_of: Some(vec!["extras".to_owned()]),
+            requires: None,
+        });
+        parcel
+    }
+
+    #[test]
+    fn test_closures_equal_ignores_order_and_optional_parcels() {
+        let built = invoice_of(vec![
+            parcel_for("birds.wasm", b"birds"),
+            parcel_for("index.html", b"index"),
+        ]);
+        let rebuilt = invoice_of(vec![
+            parcel_for("index.html", b"index"),
+            optional(parcel_for("debug.wasm", b"debug")),
+            parcel_for("birds.wasm", b"birds"),
+        ]);
+        assert!(closures_equal(&built, &rebuilt));
+
+        let changed = invoice_of(vec![
+            parcel_for("index.html", b"index"),
+            parcel_for("birds.wasm", b"birds v2"),
+        ]);
+        assert!(!closures_equal(&built, &changed));
+    }
+
+    fn names(parcels: &[ParcelSummary]) -> Vec<&str> {
+        parcels.iter().map(|p| p.name.as_str()).collect()
+    }
+}
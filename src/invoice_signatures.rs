@@ -0,0 +1,279 @@
+//! Checking that an invoice is signed in every role which an environment
+//! requires before it trusts a bindle, for example both a creator and an
+//! approver for production.
+//!
+//! A signature counts for a role only if it was made by a key which the key
+//! ring trusts in that role, and it verifies against the invoice as it is now.
+
+use bindle::signature::KeyRing;
+use bindle::{Invoice, Signature, SignatureRole, VerificationStrategy};
+use itertools::Itertools;
+use std::path::Path;
+
+/// The error for an invoice which lacks a valid signature in some required
+/// role.
+#[derive(Debug)]
+pub struct UnsatisfiedRoles {
+    pub bindle_id: bindle::Id,
+    /// In the order they were required.
+    pub roles: Vec<SignatureRole>,
+}
+
+impl std::fmt::Display for UnsatisfiedRoles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} has no valid signature in the required role(s): {}",
+            self.bindle_id,
+            self.roles.iter().map(role_name).join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnsatisfiedRoles {}
+
+/// Checks that, for each of `required_roles`, the invoice has at least one
+/// valid signature in that role by a key in `keyring`. If any role lacks one,
+/// the error lists every such role.
+pub fn verify_invoice_signatures(
+    invoice: &Invoice,
+    required_roles: &[SignatureRole],
+    keyring: &KeyRing,
+) -> Result<(), UnsatisfiedRoles> {
+    let signatures = invoice.signature.as_deref().unwrap_or_default();
+    let unsatisfied = required_roles
+        .iter()
+        .unique_by(|r| role_name(r))
+        .filter(|role| {
+            !signatures
+                .iter()
+                .any(|s| &s.role == *role && is_valid(invoice, s, keyring))
+        })
+        .cloned()
+        .collect_vec();
+    if unsatisfied.is_empty() {
+        Ok(())
+    } else {
+        Err(UnsatisfiedRoles {
+            bindle_id: invoice.bindle.id.clone(),
+            roles: unsatisfied,
+        })
+    }
+}
+
+/// Reads a Bindle key ring, such as the `keyring.toml` which `bindle keys`
+/// maintains.
+pub fn read_keyring(path: impl AsRef<Path>) -> anyhow::Result<KeyRing> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Error reading key ring {}: {}", path.display(), e))?;
+    toml::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("Error parsing key ring {}: {}", path.display(), e))
+}
+
+/// Whether `signature` is by a key which `keyring` trusts in the signature's
+/// role, and verifies against `invoice`.
+fn is_valid(invoice: &Invoice, signature: &Signature, keyring: &KeyRing) -> bool {
+    let trusted = keyring
+        .key
+        .iter()
+        .any(|k| k.key == signature.key && k.roles.contains(&signature.role));
+    // Bindle verifies all the signatures in the roles it is given, so it is
+    // given only this one, to keep one bad signature from hiding the others.
+    let only_this_signature = Invoice {
+        signature: Some(vec![signature.clone()]),
+        ..invoice.clone()
+    };
+    trusted
+        && VerificationStrategy::MultipleAttestation(vec![signature.role.clone()])
+            .verify(only_this_signature, keyring)
+            .is_ok()
+}
+
+fn role_name(role: &SignatureRole) -> String {
+    format!("{:?}", role).to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, test_invoice};
+    use bindle::signature::KeyEntry;
+    use bindle::SecretKeyEntry;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    struct Signers {
+        developer: SecretKeyEntry,
+        lead: SecretKeyEntry,
+        server: SecretKeyEntry,
+        keyring: KeyRing,
+    }
+
+    /// A developer, a lead and a server, whose keys the key ring trusts as
+    /// creator, approver and host.
+    fn signers() -> Signers {
+        let developer =
+            SecretKeyEntry::new("dev@example.com".to_owned(), vec![SignatureRole::Creator]);
+        let lead =
+            SecretKeyEntry::new("lead@example.com".to_owned(), vec![SignatureRole::Approver]);
+        let server =
+            SecretKeyEntry::new("server@example.com".to_owned(), vec![SignatureRole::Host]);
+        let keyring = KeyRing::new(
+            [&developer, &lead, &server]
+                .iter()
+                .map(|k| KeyEntry::try_from(*k).unwrap())
+                .collect(),
+        );
+        Signers {
+            developer,
+            lead,
+            server,
+            keyring,
+        }
+    }
+
+    fn signed_invoice(signatures: Vec<(&SecretKeyEntry, SignatureRole)>) -> Invoice {
+        let id = bindle::Id::from_str("signed/1.0.0").unwrap();
+        let mut invoice = test_invoice(&id, vec![parcel_for("signed.wasm", b"signed")]);
+        for (key, role) in signatures {
+            invoice.sign(role, key).unwrap();
+        }
+        invoice
+    }
+
+    #[test]
+    fn test_every_required_role_is_satisfied() {
+        let signers = signers();
+        let invoice = signed_invoice(vec![
+            (&signers.developer, SignatureRole::Creator),
+            (&signers.lead, SignatureRole::Approver),
+            (&signers.server, SignatureRole::Host),
+        ]);
+        let required = [SignatureRole::Creator, SignatureRole::Approver];
+        assert!(verify_invoice_signatures(&invoice, &required, &signers.keyring).is_ok());
+        assert!(verify_invoice_signatures(&invoice, &[], &signers.keyring).is_ok());
+    }
+
+    #[test]
+    fn test_unsatisfied_roles_are_listed() {
+        let signers = signers();
+        let untrusted = SecretKeyEntry::new(
+            "forger@example.com".to_owned(),
+            vec![SignatureRole::Approver],
+        );
+        let invoice = signed_invoice(vec![
+            (&signers.developer, SignatureRole::Creator),
+            (&untrusted, SignatureRole::Approver),
+        ]);
+        let required = [
+            SignatureRole::Creator,
+            SignatureRole::Approver,
+            SignatureRole::Host,
+        ];
+        let err = verify_invoice_signatures(&invoice, &required, &signers.keyring).unwrap_err();
+        assert_eq!(
+            vec!["approver", "host"],
+            err.roles.iter().map(role_name).collect_vec()
+        );
+        assert!(
+            err.to_string().contains("approver, host"),
+            "lists the roles: '{}'",
+            err
+        );
+    }
+
+    #[test]
+    fn test_signature_by_a_key_not_trusted_in_the_role_is_rejected() {
+        let signers = signers();
+        // The server's key is trusted only as host.
+        let invoice = signed_invoice(vec![
+            (&signers.developer, SignatureRole::Creator),
+            (&signers.server, SignatureRole::Approver),
+        ]);
+        let err = verify_invoice_signatures(
+            &invoice,
+            &[SignatureRole::Creator, SignatureRole::Approver],
+            &signers.keyring,
+        )
+        .unwrap_err();
+        assert_eq!(
+            vec!["approver"],
+            err.roles.iter().map(role_name).collect_vec()
+        );
+    }
+
+    #[test]
+    fn test_tampered_invoice_is_rejected() {
+        let signers = signers();
+        let mut invoice = signed_invoice(vec![(&signers.developer, SignatureRole::Creator)]);
+        invoice
+            .parcel
+            .as_mut()
+            .unwrap()
+            .push(parcel_for("injected.wasm", b"injected"));
+        let err = verify_invoice_signatures(&invoice, &[SignatureRole::Creator], &signers.keyring)
+            .unwrap_err();
+        assert_eq!(1, err.roles.len());
+    }
+
+    #[test]
+    fn test_forged_signature_is_rejected() {
+        let signers = signers();
+        let forger =
+            SecretKeyEntry::new("dev@example.com".to_owned(), vec![SignatureRole::Creator]);
+        // The forger signs with their own key, then claims the signature is
+        // by the trusted developer's key.
+        let mut invoice = signed_invoice(vec![(&forger, SignatureRole::Creator)]);
+        let trusted_key = signed_invoice(vec![(&signers.developer, SignatureRole::Creator)])
+            .signature
+            .unwrap()[0]
+            .key
+            .clone();
+        invoice.signature.as_mut().unwrap()[0].key = trusted_key;
+        let err = verify_invoice_signatures(&invoice, &[SignatureRole::Creator], &signers.keyring)
+            .unwrap_err();
+        assert_eq!(1, err.roles.len());
+    }
+
+    #[test]
+    fn test_one_bad_signature_does_not_hide_a_good_one() {
+        let signers = signers();
+        let untrusted = SecretKeyEntry::new(
+            "forger@example.com".to_owned(),
+            vec![SignatureRole::Creator],
+        );
+        let invoice = signed_invoice(vec![
+            (&untrusted, SignatureRole::Creator),
+            (&signers.developer, SignatureRole::Creator),
+        ]);
+        assert!(
+            verify_invoice_signatures(&invoice, &[SignatureRole::Creator], &signers.keyring)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_unsigned_invoice_satisfies_no_role() {
+        let signers = signers();
+        let invoice = signed_invoice(vec![]);
+        let err = verify_invoice_signatures(&invoice, &[SignatureRole::Creator], &signers.keyring)
+            .unwrap_err();
+        assert_eq!(1, err.roles.len());
+    }
+
+    #[test]
+    fn test_keyring_is_read_from_toml() {
+        let signers = signers();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keyring.toml");
+        std::fs::write(&path, toml::to_string(&signers.keyring).unwrap()).unwrap();
+
+        let keyring = read_keyring(&path).unwrap();
+        let invoice = signed_invoice(vec![(&signers.developer, SignatureRole::Creator)]);
+        assert!(verify_invoice_signatures(&invoice, &[SignatureRole::Creator], &keyring).is_ok());
+
+        std::fs::write(&path, "key = 'not a list'").unwrap();
+        assert!(read_keyring(&path).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! A short, human-readable description of a bindle, for commands which show
+//! a user what a bindle contains.
+
+use itertools::Itertools;
+
+use crate::bindle_utils::{InvoiceHelpers, ParcelHelpers, HOST_VERSION_ANNOTATION};
+
+/// A multi-line summary of an invoice: its ID and description, how many
+/// parcels it has and their total size, its groups, its entrypoint, and the
+/// versions of the host it needs. The
+/// closure size is that of the entrypoint and the parcels it requires,
+/// directly or indirectly, which is what serving the application needs.
+pub fn summarize_invoice(invoice: &bindle::Invoice) -> String {
+    let parcels = invoice
+        .parcel
+        .iter()
+        .flatten()
+        .unique_by(|p| &p.label.sha256)
+        .collect_vec();
+    let total_size: u64 = parcels.iter().map(|p| p.label.size).sum();
+    let groups = invoice.group.iter().flatten().map(|g| &g.name).join(", ");
+
+    let mut lines = vec![
+        ("id", invoice.bindle.id.to_string()),
+        (
+            "description",
+            invoice
+                .bindle
+                .description
+                .clone()
+                .unwrap_or_else(|| "(none)".to_owned()),
+        ),
+        ("parcels", parcels.len().to_string()),
+        ("total size", format!("{} bytes", total_size)),
+    ];
+    match invoice.entrypoint_parcel() {
+        None => {
+            lines.push(("closure size", "(no entrypoint)".to_owned()));
+        }
+        Some(entrypoint) => {
+            let closure = std::iter::once(entrypoint.clone())
+                .chain(invoice.parcels_required_by(entrypoint))
+                .unique_by(|p| p.label.sha256.clone())
+                .collect_vec();
+            let closure_size: u64 = closure.iter().map(|p| p.label.size).sum();
+            lines.push((
+                "closure size",
+                format!("{} bytes ({} parcels)", closure_size, closure.len()),
+            ));
+        }
+    }
+    lines.push((
+        "groups",
+        if groups.is_empty() {
+            "(none)".to_owned()
+        } else {
+            groups
+        },
+    ));
+    lines.push((
+        "entrypoint",
+        match invoice.entrypoint_parcel() {
+            None => "(none)".to_owned(),
+            Some(p) => format!(
+                "{} (route {})",
+                p.label.name,
+                p.wagi_feature("route").unwrap_or_default()
+            ),
+        },
+    ));
+    let has_host_annotation = invoice
+        .annotations
+        .iter()
+        .flatten()
+        .any(|(key, _)| key == HOST_VERSION_ANNOTATION);
+    lines.push((
+        "host version",
+        match invoice.host_requirement() {
+            Some(requirement) => requirement.to_string(),
+            None if has_host_annotation => "(invalid)".to_owned(),
+            None => "(any)".to_owned(),
+        },
+    ));
+
+    lines
+        .iter()
+        .map(|(name, value)| format!("{:<14}{}", format!("{}:", name), value))
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, test_invoice};
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    fn fixture() -> bindle::Invoice {
+        let id = bindle::Id::from_str("birds/1.4.2").unwrap();
+        let mut handler = parcel_for("birds.wasm", b"birds module");
+        let wagi: BTreeMap<_, _> = vec![("route".to_owned(), "/".to_owned())]
+            .into_iter()
+            .collect();
+        handler.label.feature = Some(vec![("wagi".to_owned(), wagi)].into_iter().collect());
+        handler.conditions = Some(bindle::Condition {
+            member_of: None,
+            requires: Some(vec!["files".to_owned()]),
+        });
+        let mut asset = parcel_for("index.html", b"<html />");
+        asset.conditions = Some(bindle::Condition {
+            member_of: Some(vec!["files".to_owned()]),
+            requires: None,
+        });
+        let mut invoice = test_invoice(
+            &id,
+            vec![handler, asset, parcel_for("unused.txt", b"unused")],
+        );
+        invoice.bindle.description = Some("An app about birds".to_owned());
+        invoice.annotations = Some(
+            vec![("hippo_host_version".to_owned(), ">=1.2".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        invoice.group = Some(vec![bindle::Group {
+            name: "files".to_owned(),
+            required: None,
+            satisfied_by: None,
+        }]);
+        invoice
+    }
+
+    #[test]
+    fn test_summary_of_fixture_invoice() {
+        assert_eq!(
+            "id:           birds/1.4.2
+description:  An app about birds
+parcels:      3
+total size:   26 bytes
+closure size: 20 bytes (2 parcels)
+groups:       files
+entrypoint:   birds.wasm (route /)
+host version: >=1.2.0",
+            summarize_invoice(&fixture())
+        );
+    }
+
+    #[test]
+    fn test_summary_without_entrypoint_or_groups() {
+        let id = bindle::Id::from_str("plain/1.0.0").unwrap();
+        let summary = summarize_invoice(&test_invoice(&id, vec![parcel_for("a.txt", b"a")]));
+        assert!(summary.contains("description:  (none)"), "{}", summary);
+        assert!(
+            summary.contains("closure size: (no entrypoint)"),
+            "{}",
+            summary
+        );
+        assert!(summary.contains("groups:       (none)"), "{}", summary);
+        assert!(summary.contains("entrypoint:   (none)"), "{}", summary);
+        assert!(summary.contains("host version: (any)"), "{}", summary);
+    }
+}
@@ -1,12 +1,51 @@
-use clap::App;
+use clap::{App, Arg};
 
+use error_report::ErrorOutput;
+use report_format::ReportFormat;
+
+mod annotation_schema;
+mod bindle_client;
+mod bindle_events;
+mod bindle_exporter;
+mod bindle_fetcher;
 mod bindle_pusher;
+mod bindle_search;
 mod bindle_utils;
+mod bindle_verifier;
 mod bindle_writer;
+mod build_hooks;
+mod cert_pinning;
 mod command;
+mod credential_helper;
+mod credentials_file;
+mod debug_bundle;
+mod digest_backend;
+mod download_session;
+mod error_report;
 mod expander;
+mod expansion_plan;
+mod file_hash;
+mod hippo_client;
 mod hippo_notifier;
 mod hippofacts;
+mod invoice_diff;
+mod invoice_signatures;
+mod invoice_summary;
+#[cfg(feature = "negotiate-proxy")]
+mod negotiate;
+mod parcel_layout;
+mod parcel_store;
+mod path_mapping;
+mod ping;
+mod profiles;
+mod report_format;
+mod retry;
+mod sbom;
+mod standalone;
+mod templating;
+#[cfg(test)]
+mod testing;
+mod vendor;
 mod warnings;
 
 /// Indicate which flags are required for bindle builds
@@ -22,6 +61,9 @@ enum BindleBuildRequirements {
     NoRequirements,
 }
 
+const ARG_JSON_ERRORS: &str = "json_errors";
+const ARG_DEBUG_BUNDLE: &str = "debug_bundle";
+
 const ABOUT_HIPPO: &str = r#"Create and manage Hippo applications.
 
 The hippo commandline utility provides many tools for managing Hippo applications,
@@ -39,13 +81,60 @@ async fn main() -> anyhow::Result<()> {
         .author("Deis Labs")
         .about("The Hippo commandline client")
         .long_about(ABOUT_HIPPO)
+        .arg(
+            Arg::new(ARG_JSON_ERRORS)
+                .long("json-errors")
+                .global(true)
+                .about("If the command fails, print a JSON description of the error to stdout"),
+        )
+        .arg(
+            Arg::new(ARG_DEBUG_BUNDLE)
+                .long("debug-bundle")
+                .takes_value(true)
+                .global(true)
+                .about("Write a zip file of the command's HTTP requests, configuration and errors, with credentials removed, to attach to a bug report"),
+        )
         .subcommands(command::apps())
         .get_matches();
 
-    match matches.subcommand() {
+    let error_format = match matches.subcommand() {
+        _ if matches.is_present(ARG_JSON_ERRORS) => Some(ReportFormat::Json),
+        Some((_, args)) if args.is_present(ARG_JSON_ERRORS) => Some(ReportFormat::Json),
+        Some((name, args)) => command::structured_output(name, args),
+        None => None,
+    };
+
+    let debug_bundle = matches.value_of(ARG_DEBUG_BUNDLE).or_else(|| {
+        matches
+            .subcommand()
+            .and_then(|(_, args)| args.value_of(ARG_DEBUG_BUNDLE))
+    });
+    let trace = debug_bundle.map(|_| debug_bundle::start());
+
+    let result = match matches.subcommand() {
         // Make a vague attempt to keep these in alphabetical order
         //Some((push.name(), args)) => println!("push"),
         Some((name, args)) => command::exec(name, args).await,
         _ => Err(anyhow::anyhow!("No matching command. Try 'hippo help'")),
+    };
+
+    if let (Some(path), Some(trace)) = (debug_bundle, trace) {
+        match trace.write_bundle(path, result.as_ref().err()) {
+            Ok(()) => eprintln!("Wrote debug bundle {}", path),
+            Err(e) => eprintln!("warning: could not write debug bundle: {:#}", e),
+        }
+    }
+
+    // A pipeline may want to bump the version on a conflict, but not on other
+    // failures, so it needs to tell them apart, by the exit code or, if it
+    // asked, by the kind of error in the JSON report.
+    if let Err(e) = &result {
+        let output = ErrorOutput::from_error(e);
+        if let Some(format) = error_format {
+            output.print(format)?;
+        }
+        eprintln!("Error: {:?}", e);
+        std::process::exit(output.error.kind.exit_code());
     }
+    result
 }
@@ -0,0 +1,176 @@
+//! Negotiate (SPNEGO/Kerberos) authentication to an HTTP proxy.
+//!
+//! The token is set on the proxy of the HTTP client rather than on requests, so
+//! that it goes only to the proxy: on plain HTTP requests which it forwards, and
+//! on the CONNECT request which opens a tunnel for HTTPS. The origin server never
+//! sees it.
+
+use std::sync::Arc;
+
+/// Produces the initial Negotiate token for a proxy host.
+pub trait NegotiateTokenSource {
+    fn initial_token(&self, proxy_host: &str) -> anyhow::Result<Vec<u8>>;
+}
+
+/// A proxy for all requests, which authenticates with a Negotiate token for
+/// its host. The token is obtained once, when the proxy is created.
+pub fn negotiate_proxy(
+    proxy_url: &str,
+    token_source: &dyn NegotiateTokenSource,
+) -> anyhow::Result<reqwest::Proxy> {
+    let url = reqwest::Url::parse(proxy_url)
+        .map_err(|e| anyhow::anyhow!("Invalid proxy URL {}: {}", proxy_url, e))?;
+    let proxy_host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("Proxy URL {} has no host", proxy_url))?;
+    let token = token_source.initial_token(proxy_host).map_err(|e| {
+        anyhow::anyhow!(
+            "Negotiate authentication to proxy {} failed: {}",
+            proxy_host,
+            e
+        )
+    })?;
+    let token = base64::encode(&token);
+    crate::debug_bundle::add_secret(&token);
+    let mut header = reqwest::header::HeaderValue::from_str(&format!("Negotiate {}", token))?;
+    header.set_sensitive(true);
+    Ok(reqwest::Proxy::all(url)?.custom_http_auth(header))
+}
+
+/// The platform's Negotiate implementation, if it has one that we support.
+pub fn platform_token_source() -> Option<Arc<dyn NegotiateTokenSource + Send + Sync>> {
+    #[cfg(target_os = "linux")]
+    {
+        Some(Arc::new(gssapi::GssapiTokenSource))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod gssapi {
+    use libgssapi::{
+        context::{ClientCtx, CtxFlags},
+        credential::{Cred, CredUsage},
+        name::Name,
+        oid::{OidSet, GSS_MECH_SPNEGO, GSS_NT_HOSTBASED_SERVICE},
+    };
+
+    /// Obtains tokens from the user's Kerberos credentials cache via GSSAPI.
+    pub struct GssapiTokenSource;
+
+    impl super::NegotiateTokenSource for GssapiTokenSource {
+        fn initial_token(&self, proxy_host: &str) -> anyhow::Result<Vec<u8>> {
+            let result = (|| {
+                let service = format!("HTTP@{}", proxy_host);
+                let name = Name::new(service.as_bytes(), Some(&GSS_NT_HOSTBASED_SERVICE))?
+                    .canonicalize(Some(&GSS_MECH_SPNEGO))?;
+                let mut mechs = OidSet::new()?;
+                mechs.add(&GSS_MECH_SPNEGO)?;
+                let cred = Cred::acquire(None, None, CredUsage::Initiate, Some(&mechs))?;
+                let mut ctx = ClientCtx::new(
+                    cred,
+                    name,
+                    CtxFlags::GSS_C_MUTUAL_FLAG,
+                    Some(&GSS_MECH_SPNEGO),
+                );
+                let token = ctx.step(None)?;
+                Ok(token.map(|t| t.to_vec()).unwrap_or_default())
+            })();
+            result.map_err(|e: libgssapi::error::Error| anyhow::anyhow!("{}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_utils::BindleConnectionInfo;
+    use crate::testing::{http_response, serve_once};
+
+    struct FakeKerberos;
+
+    impl NegotiateTokenSource for FakeKerberos {
+        fn initial_token(&self, proxy_host: &str) -> anyhow::Result<Vec<u8>> {
+            Ok(format!("ticket-for-{}", proxy_host).into_bytes())
+        }
+    }
+
+    fn id() -> bindle::Id {
+        "birds/1.0.0".parse().unwrap()
+    }
+
+    /// The URL of a fake proxy, which answers one request, and a handle which
+    /// gives the request it received.
+    fn proxy(response: String) -> (String, std::thread::JoinHandle<String>) {
+        let (url, proxy) = serve_once(response);
+        (url.trim_end_matches("/v1").to_owned(), proxy)
+    }
+
+    fn expected_header() -> String {
+        format!(
+            "proxy-authorization: negotiate {}",
+            base64::encode("ticket-for-127.0.0.1")
+        )
+        .to_lowercase()
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_requests_are_authenticated_to_the_proxy() {
+        let (proxy_url, proxy) = proxy(http_response("404 Not Found", ""));
+        let connection = BindleConnectionInfo::new("http://bindle.test/v1", false, None, None)
+            .with_negotiate_proxy_using(&proxy_url, Some(Arc::new(FakeKerberos)))
+            .unwrap();
+        let _ = connection.client().unwrap().get_invoice(&id()).await;
+
+        let request = proxy.join().unwrap();
+        assert!(
+            request.starts_with("GET http://bindle.test/v1/_i/birds/1.0.0 "),
+            "{}",
+            request
+        );
+        assert!(
+            request.to_lowercase().contains(&expected_header()),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn test_https_requests_authenticate_only_the_tunnel() {
+        let (proxy_url, proxy) = proxy(http_response("407 Proxy Authentication Required", ""));
+        let connection = BindleConnectionInfo::new("https://bindle.test/v1", false, None, None)
+            .with_negotiate_proxy_using(&proxy_url, Some(Arc::new(FakeKerberos)))
+            .unwrap();
+        assert!(connection.client().unwrap().get_invoice(&id()).await.is_err());
+
+        // The token is in the CONNECT request to the proxy; the request to the
+        // origin server would go through the tunnel, which the proxy can't read.
+        let request = proxy.join().unwrap();
+        assert!(request.starts_with("CONNECT bindle.test:443 "), "{}", request);
+        assert!(
+            request.to_lowercase().contains(&expected_header()),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_platform_falls_back_to_no_proxy_auth() {
+        let (url, server) = serve_once(http_response("404 Not Found", ""));
+        let connection = BindleConnectionInfo::new(&url, false, None, None)
+            .with_negotiate_proxy_using("http://proxy.test:3128", None)
+            .unwrap();
+        let _ = connection.client().unwrap().get_invoice(&id()).await;
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /v1/_i/birds/1.0.0 "), "{}", request);
+        assert!(
+            !request.to_lowercase().contains("proxy-authorization"),
+            "{}",
+            request
+        );
+    }
+}
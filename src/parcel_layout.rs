@@ -0,0 +1,147 @@
+//! Where parcel files go in a directory of parcels, such as the parcel cache or
+//! an exported archive, so that the layout can match what other tools expect.
+
+use std::path::{Path, PathBuf};
+
+const SHA256: &str = "{sha256}";
+const SHARD1: &str = "{shard1}";
+const SHARD2: &str = "{shard2}";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParcelLayout {
+    /// `<sha256>.dat`, as in a Bindle standalone directory.
+    Flat,
+    /// `<ab>/<cd>/<sha256>`, where `ab` and `cd` are the first two pairs of hex
+    /// digits of the digest, so that no directory holds too many files. This
+    /// is the default.
+    Nested,
+    /// A path made by substituting `{sha256}`, `{shard1}` (the first two hex
+    /// digits) and `{shard2}` (the next two) in a template, such as
+    /// `{shard1}/{sha256}.bin`.
+    Template(String),
+}
+
+impl ParcelLayout {
+    /// Parses `flat`, `nested` or a template. A template must name the file by
+    /// its `{sha256}`, so that different content can't map to the same path.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "flat" => Ok(Self::Flat),
+            "nested" => Ok(Self::Nested),
+            template => {
+                let file_name = template.rsplit('/').next().unwrap_or_default();
+                let is_valid = file_name.matches(SHA256).count() == 1
+                    && !file_name.contains(SHARD1)
+                    && !file_name.contains(SHARD2)
+                    && template.split('/').all(|s| !s.is_empty() && s != "..");
+                if is_valid {
+                    Ok(Self::Template(template.to_owned()))
+                } else {
+                    Err(anyhow::anyhow!(
+                        "Invalid parcel layout '{}': use 'flat', 'nested', or a relative path template whose file name contains {{sha256}} once, e.g. '{{shard1}}/{{sha256}}.dat'",
+                        template
+                    ))
+                }
+            }
+        }
+    }
+
+    fn template(&self) -> &str {
+        match self {
+            Self::Flat => "{sha256}.dat",
+            Self::Nested => "{shard1}/{shard2}/{sha256}",
+            Self::Template(template) => template.as_str(),
+        }
+    }
+
+    /// The path of the parcel with the given digest, relative to the parcels
+    /// directory, using forward slashes.
+    pub fn relative_path(&self, sha256: &str) -> String {
+        self.template()
+            .replace(SHARD1, sha256.get(0..2).unwrap_or(sha256))
+            .replace(SHARD2, sha256.get(2..4).unwrap_or_default())
+            .replace(SHA256, sha256)
+    }
+
+    /// The path of the parcel with the given digest under `dir`.
+    pub fn path_in(&self, dir: &Path, sha256: &str) -> PathBuf {
+        self.relative_path(sha256)
+            .split('/')
+            .fold(dir.to_path_buf(), |path, segment| path.join(segment))
+    }
+
+    /// The digest of the parcel at `relative_path`, if the layout would put a
+    /// parcel there.
+    pub fn sha256_at(&self, relative_path: &str) -> Option<String> {
+        let file_name_template = self.template().rsplit('/').next()?;
+        let (prefix, suffix) = file_name_template.split_once(SHA256)?;
+        let file_name = relative_path.rsplit('/').next()?;
+        let sha256 = file_name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+        if self.relative_path(sha256) == relative_path {
+            Some(sha256.to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHA: &str = "abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789";
+
+    #[test]
+    fn test_flat_layout_uses_dat_files() {
+        assert_eq!(
+            format!("{}.dat", SHA),
+            ParcelLayout::Flat.relative_path(SHA)
+        );
+    }
+
+    #[test]
+    fn test_nested_layout_shards_by_leading_digits() {
+        assert_eq!(
+            format!("ab/cd/{}", SHA),
+            ParcelLayout::Nested.relative_path(SHA)
+        );
+    }
+
+    #[test]
+    fn test_template_layout_substitutes_placeholders() {
+        let layout = ParcelLayout::parse("blobs/{shard1}/{sha256}.bin").unwrap();
+        assert_eq!(format!("blobs/ab/{}.bin", SHA), layout.relative_path(SHA));
+        let expected: PathBuf = ["base", "blobs", "ab", &format!("{}.bin", SHA)]
+            .iter()
+            .collect();
+        assert_eq!(expected, layout.path_in(Path::new("base"), SHA));
+    }
+
+    #[test]
+    fn test_layouts_are_parsed() {
+        assert_eq!(ParcelLayout::Flat, ParcelLayout::parse("flat").unwrap());
+        assert_eq!(ParcelLayout::Nested, ParcelLayout::parse("nested").unwrap());
+        assert!(ParcelLayout::parse("{shard1}/{shard2}").is_err());
+        assert!(ParcelLayout::parse("{sha256}/{shard1}").is_err());
+        assert!(ParcelLayout::parse("{sha256}-{sha256}").is_err());
+        assert!(ParcelLayout::parse("/{sha256}").is_err());
+        assert!(ParcelLayout::parse("../{sha256}").is_err());
+    }
+
+    #[test]
+    fn test_digest_is_recovered_from_path() {
+        for layout in [
+            ParcelLayout::Flat,
+            ParcelLayout::Nested,
+            ParcelLayout::parse("{shard2}/x-{sha256}.bin").unwrap(),
+        ] {
+            let path = layout.relative_path(SHA);
+            assert_eq!(Some(SHA.to_owned()), layout.sha256_at(&path));
+        }
+        assert_eq!(
+            None,
+            ParcelLayout::Nested.sha256_at(&format!("ab/ff/{}", SHA))
+        );
+        assert_eq!(None, ParcelLayout::Flat.sha256_at(SHA));
+    }
+}
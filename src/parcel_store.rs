@@ -0,0 +1,654 @@
+//! A content-addressed store of parcel files, shared between all the applications
+//! prepared on this machine, so that a parcel used by many applications is staged
+//! by linking to a single copy.
+//!
+//! Entries are stored under `<root>` in the nested layout (`<ab>/<cd>/<sha256>`)
+//! unless another parcel layout is chosen, and the time each was last used is
+//! recorded in `<root>/.last-used/<sha256>`. Staging holds a shared lock on the
+//! store, and garbage collection an exclusive one, so that entries cannot be pruned
+//! while a prepare is linking to them. Entries are written to a temporary file and
+//! renamed into place, so concurrent prepares which add the same parcel are safe.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+use crate::bindle_utils::InvoiceHelpers;
+use crate::digest_backend::Sha2Backend;
+use crate::file_hash::file_sha256;
+use crate::parcel_layout::ParcelLayout;
+
+const LOCK_FILE: &str = ".lock";
+const LAST_USED_DIR: &str = ".last-used";
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ParcelStore {
+    root: PathBuf,
+    layout: ParcelLayout,
+}
+
+/// Holds a lock on the store. The lock is released when this is dropped.
+pub struct StoreLock {
+    _file: File,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GcReport {
+    pub removed_count: usize,
+    pub removed_bytes: u64,
+    pub retained_bytes: u64,
+}
+
+impl std::fmt::Display for GcReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "removed {} parcels ({} bytes); {} bytes remain",
+            self.removed_count, self.removed_bytes, self.retained_bytes
+        )
+    }
+}
+
+/// What the store lacks of a bindle, i.e. what upgrading to it would download.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeltaReport {
+    pub bindle_id: String,
+    /// The names of the parcels which the store does not have.
+    pub missing: Vec<String>,
+    pub missing_bytes: u64,
+    /// The number of parcels which the store already has.
+    pub cached_count: usize,
+}
+
+impl DeltaReport {
+    pub fn new(invoice: &bindle::Invoice, cache: &ParcelStore) -> Self {
+        let delta = delta_against_cache(invoice, cache);
+        Self {
+            bindle_id: invoice.bindle.id.to_string(),
+            missing: delta.iter().map(|p| p.label.name.clone()).collect(),
+            missing_bytes: delta.iter().map(|p| p.label.size).sum(),
+            cached_count: invoice.required_closure().len() - delta.len(),
+        }
+    }
+}
+
+impl std::fmt::Display for DeltaReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} parcels ({} bytes) not in the cache; {} already cached",
+            self.bindle_id,
+            self.missing.len(),
+            self.missing_bytes,
+            self.cached_count
+        )?;
+        for name in &self.missing {
+            write!(f, "\n  {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+struct StoreEntry {
+    sha256: String,
+    size: u64,
+    last_used: u64,
+}
+
+impl ParcelStore {
+    pub fn open(root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(root.join(LAST_USED_DIR)).map_err(|e| {
+            anyhow::anyhow!(
+                "Can't create parcel cache at {}: {}",
+                root.to_string_lossy(),
+                e
+            )
+        })?;
+        Ok(Self {
+            root,
+            layout: ParcelLayout::Nested,
+        })
+    }
+
+    /// Lays out the entries in the store differently, e.g. to share a store with
+    /// another tool. Every command using the store must use the same layout.
+    pub fn with_layout(self, layout: ParcelLayout) -> Self {
+        Self { layout, ..self }
+    }
+
+    /// The store in the user's cache directory, e.g. `~/.cache/hippo/cas`.
+    pub fn open_default() -> anyhow::Result<Self> {
+        let root = default_root().ok_or_else(|| {
+            anyhow::anyhow!("Can't find a cache directory for the parcel cache: set $HOME")
+        })?;
+        Self::open(root)
+    }
+
+    /// Locks the store for staging. Any number of processes may stage at once,
+    /// but this waits for any garbage collection to finish.
+    pub fn lock_shared(&self) -> anyhow::Result<StoreLock> {
+        let file = self.open_lock_file()?;
+        file.lock_shared()?;
+        Ok(StoreLock { _file: file })
+    }
+
+    fn try_lock_exclusive(&self) -> anyhow::Result<StoreLock> {
+        let file = self.open_lock_file()?;
+        file.try_lock_exclusive().map_err(|_| {
+            anyhow::anyhow!("The parcel cache is in use by another hippo command: try again when it has finished")
+        })?;
+        Ok(StoreLock { _file: file })
+    }
+
+    fn open_lock_file(&self) -> anyhow::Result<File> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(LOCK_FILE))?;
+        Ok(file)
+    }
+
+    /// Places the content of `source`, whose digest is `sha256`, at `dest`. If the
+    /// store has the content, `dest` is linked to it; otherwise the content is
+    /// added to the store first. The caller must hold a lock on the store.
+    pub fn stage(&self, sha256: &str, source: &Path, dest: &Path) -> anyhow::Result<()> {
+        // If dest is already a link into the store, writing through it would
+        // change the stored content.
+        remove_if_exists(dest)?;
+        if self.link_if_present(sha256, dest)? {
+            return Ok(());
+        }
+        if self.insert(sha256, source)? {
+            self.link_entry(sha256, dest)?;
+            self.record_use(sha256)?;
+        } else {
+            std::fs::copy(source, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the store has an entry for the digest. This does not check the
+    /// entry's content, which staging does.
+    pub fn contains(&self, sha256: &str) -> bool {
+        self.entry_path(sha256).is_file()
+    }
+
+    fn entry_path(&self, sha256: &str) -> PathBuf {
+        self.layout.path_in(&self.root, sha256)
+    }
+
+    fn last_used_path(&self, sha256: &str) -> PathBuf {
+        self.root.join(LAST_USED_DIR).join(sha256)
+    }
+
+    /// Links `dest` to the stored content, if the store has it and it is intact.
+    /// A corrupt entry is removed, so that the caller re-stages the parcel.
+    fn link_if_present(&self, sha256: &str, dest: &Path) -> anyhow::Result<bool> {
+        let entry = self.entry_path(sha256);
+        if !entry.is_file() {
+            return Ok(false);
+        }
+        if file_sha256(&entry, &Sha2Backend)? != sha256 {
+            self.remove_entry(sha256)?;
+            return Ok(false);
+        }
+        self.link_entry(sha256, dest)?;
+        self.record_use(sha256)?;
+        Ok(true)
+    }
+
+    /// Copies `source` into the store. This returns false, and leaves the store
+    /// unchanged, if the content of `source` does not have the expected digest.
+    fn insert(&self, sha256: &str, source: &Path) -> anyhow::Result<bool> {
+        let temp_path = self.root.join(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let digest = copy_and_hash(source, &temp_path)?;
+        if digest != sha256 {
+            remove_if_exists(&temp_path)?;
+            return Ok(false);
+        }
+        // Another process may have added the same content meanwhile; as its bytes
+        // are identical, it doesn't matter whose rename wins.
+        let entry = self.entry_path(sha256);
+        if let Some(parent) = entry.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&temp_path, entry)?;
+        Ok(true)
+    }
+
+    fn link_entry(&self, sha256: &str, dest: &Path) -> anyhow::Result<()> {
+        let entry = self.entry_path(sha256);
+        // Hard links don't work across file systems, so fall back to copying.
+        if std::fs::hard_link(&entry, dest).is_err() {
+            std::fs::copy(&entry, dest)?;
+        }
+        Ok(())
+    }
+
+    fn record_use(&self, sha256: &str) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        std::fs::write(self.last_used_path(sha256), now.to_string())?;
+        Ok(())
+    }
+
+    fn remove_entry(&self, sha256: &str) -> anyhow::Result<()> {
+        remove_if_exists(&self.entry_path(sha256))?;
+        remove_if_exists(&self.last_used_path(sha256))?;
+        Ok(())
+    }
+
+    /// Removes the least recently used entries until the store holds no more than
+    /// `max_size` bytes. This fails if the store is in use.
+    pub fn gc(&self, max_size: u64) -> anyhow::Result<GcReport> {
+        let _lock = self.try_lock_exclusive()?;
+
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|e| std::cmp::Reverse(e.last_used));
+
+        let mut report = GcReport::default();
+        let mut full = false;
+        for entry in entries {
+            // Once an entry doesn't fit, remove it and everything used less recently,
+            // even if a smaller, older entry would fit.
+            full = full || report.retained_bytes + entry.size > max_size;
+            if !full {
+                report.retained_bytes += entry.size;
+            } else {
+                self.remove_entry(&entry.sha256)?;
+                report.removed_count += 1;
+                report.removed_bytes += entry.size;
+            }
+        }
+        Ok(report)
+    }
+
+    fn entries(&self) -> anyhow::Result<Vec<StoreEntry>> {
+        let mut files = vec![];
+        list_files(&self.root, "", &mut files)?;
+        let mut entries = vec![];
+        for (relative_path, path) in files {
+            let sha256 = match self.layout.sha256_at(&relative_path) {
+                Some(sha256) if is_sha256(&sha256) => sha256,
+                _ => continue,
+            };
+            let metadata = std::fs::metadata(&path)?;
+            let last_used = std::fs::read_to_string(self.last_used_path(&sha256))
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+                .unwrap_or(0); // never recorded, so the first to go
+            entries.push(StoreEntry {
+                sha256,
+                size: metadata.len(),
+                last_used,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// The parcels of `invoice`'s required closure which the store does not have,
+/// each listed once, so that an upgrade need only download what is new.
+pub fn delta_against_cache(invoice: &bindle::Invoice, cache: &ParcelStore) -> Vec<bindle::Parcel> {
+    invoice
+        .required_closure()
+        .into_iter()
+        .filter(|p| !cache.contains(&p.label.sha256))
+        .collect()
+}
+
+fn default_root() -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_dir.join("hippo").join("cas"))
+}
+
+/// Lists the files under `dir`, with their paths relative to the store root,
+/// skipping the store's own bookkeeping files.
+fn list_files(
+    dir: &Path,
+    relative_dir: &str,
+    files: &mut Vec<(String, PathBuf)>,
+) -> anyhow::Result<()> {
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().to_string();
+        if relative_dir.is_empty() && name.starts_with('.') {
+            continue;
+        }
+        let relative_path = if relative_dir.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative_dir, name)
+        };
+        if dir_entry.file_type()?.is_dir() {
+            list_files(&dir_entry.path(), &relative_path, files)?;
+        } else {
+            files.push((relative_path, dir_entry.path()));
+        }
+    }
+    Ok(())
+}
+
+fn is_sha256(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Copies `source` to `dest` and gives the sha256 of the copy, so that what is
+/// checked is what was stored.
+fn copy_and_hash(source: &Path, dest: &Path) -> anyhow::Result<String> {
+    std::fs::copy(source, dest)?;
+    file_sha256(dest, &Sha2Backend)
+}
+
+fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a size such as `5G`, `500M`, `64K` or `1024`.
+pub fn parse_size(text: &str) -> anyhow::Result<u64> {
+    let text = text.trim();
+    let (number, multiplier) = match text.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&text[..text.len() - 1], 1024),
+        Some('M') => (&text[..text.len() - 1], 1024 * 1024),
+        Some('G') => (&text[..text.len() - 1], 1024 * 1024 * 1024),
+        Some('T') => (&text[..text.len() - 1], 1024 * 1024 * 1024 * 1024),
+        _ => (text, 1),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid size '{}': use a number of bytes, optionally followed by K, M, G or T",
+            text
+        )
+    })?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("Invalid size '{}': it is too large", text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, sha256_of, test_invoice};
+    use itertools::Itertools;
+    use std::str::FromStr;
+
+    struct TestStore {
+        dir: tempfile::TempDir,
+        store: ParcelStore,
+    }
+
+    impl TestStore {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let store = ParcelStore::open(dir.path().join("cas")).unwrap();
+            Self { dir, store }
+        }
+
+        fn source_file(&self, name: &str, content: &[u8]) -> PathBuf {
+            let path = self.dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+
+        fn dest_file(&self, name: &str) -> PathBuf {
+            let dir = self.dir.path().join("staging");
+            std::fs::create_dir_all(&dir).unwrap();
+            dir.join(name)
+        }
+
+        fn stage(&self, name: &str, content: &[u8]) -> PathBuf {
+            let source = self.source_file(name, content);
+            let dest = self.dest_file(name);
+            let _lock = self.store.lock_shared().unwrap();
+            self.store
+                .stage(&sha256_of(content), &source, &dest)
+                .unwrap();
+            dest
+        }
+
+        fn set_last_used(&self, content: &[u8], millis: u64) {
+            let path = self.store.last_used_path(&sha256_of(content));
+            std::fs::write(path, millis.to_string()).unwrap();
+        }
+
+        fn has_entry(&self, content: &[u8]) -> bool {
+            self.store.contains(&sha256_of(content))
+        }
+    }
+
+    #[test]
+    fn test_staged_content_is_reused_from_store() {
+        let store = TestStore::new();
+        let content = b"a very large wasm runtime shim";
+        store.stage("shim.wasm", content);
+
+        // The second project's copy needn't even exist for it to be staged.
+        let sha256 = sha256_of(content);
+        let missing_source = store.dir.path().join("other-project-shim.wasm");
+        let dest = store.dest_file("other.dat");
+        store.store.stage(&sha256, &missing_source, &dest).unwrap();
+
+        assert_eq!(content, &std::fs::read(&dest).unwrap()[..]);
+    }
+
+    #[test]
+    fn test_corrupt_entry_is_restaged() {
+        let store = TestStore::new();
+        let content = b"precious bytes";
+        store.stage("precious.txt", content);
+        std::fs::write(store.store.entry_path(&sha256_of(content)), b"bit rot").unwrap();
+
+        let dest = store.stage("precious-again.txt", content);
+
+        assert_eq!(content, &std::fs::read(&dest).unwrap()[..]);
+        let repaired = std::fs::read(store.store.entry_path(&sha256_of(content))).unwrap();
+        assert_eq!(content, &repaired[..]);
+    }
+
+    #[test]
+    fn test_source_not_matching_digest_is_not_stored() {
+        let store = TestStore::new();
+        let source = store.source_file("changed.txt", b"changed since it was hashed");
+        let dest = store.dest_file("changed.dat");
+        let claimed_sha256 = sha256_of(b"original content");
+
+        store.store.stage(&claimed_sha256, &source, &dest).unwrap();
+
+        assert!(dest.is_file());
+        assert!(!store.store.entry_path(&claimed_sha256).exists());
+    }
+
+    #[test]
+    fn test_restaging_does_not_write_through_to_store() {
+        let store = TestStore::new();
+        let dest = store.stage("index.html", b"<h1>hello</h1>");
+        store.stage("other.html", b"<h1>goodbye</h1>");
+
+        // Stage different content to the same place, as a later prepare might.
+        let source = store.source_file("index.html", b"<h1>goodbye</h1>");
+        let _lock = store.store.lock_shared().unwrap();
+        store
+            .store
+            .stage(&sha256_of(b"<h1>goodbye</h1>"), &source, &dest)
+            .unwrap();
+
+        assert!(store.has_entry(b"<h1>hello</h1>"));
+        let original =
+            std::fs::read(store.store.entry_path(&sha256_of(b"<h1>hello</h1>"))).unwrap();
+        assert_eq!(b"<h1>hello</h1>", &original[..]);
+    }
+
+    #[test]
+    fn test_gc_removes_least_recently_used_entries() {
+        let store = TestStore::new();
+        store.stage("old.txt", b"0123456789");
+        store.stage("middle.txt", b"abcdefghij");
+        store.stage("new.txt", b"ABCDEFGHIJ");
+        store.set_last_used(b"0123456789", 1000);
+        store.set_last_used(b"abcdefghij", 2000);
+        store.set_last_used(b"ABCDEFGHIJ", 3000);
+
+        let report = store.store.gc(25).unwrap();
+
+        assert_eq!(1, report.removed_count);
+        assert_eq!(10, report.removed_bytes);
+        assert_eq!(20, report.retained_bytes);
+        assert!(!store.has_entry(b"0123456789"));
+        assert!(store.has_entry(b"abcdefghij"));
+        assert!(store.has_entry(b"ABCDEFGHIJ"));
+    }
+
+    #[test]
+    fn test_gc_does_not_run_while_store_is_in_use() {
+        let store = TestStore::new();
+        store.stage("file.txt", b"in use");
+
+        let lock = store.store.lock_shared().unwrap();
+        let err = store.store.gc(0).unwrap_err().to_string();
+        assert!(err.contains("in use"), "check error is helpful: '{}'", err);
+        assert!(store.has_entry(b"in use"));
+
+        drop(lock);
+        store.store.gc(0).unwrap();
+        assert!(!store.has_entry(b"in use"));
+    }
+
+    #[test]
+    fn test_simultaneous_prepares_can_stage_the_same_content() {
+        let store = TestStore::new();
+        let content = b"shared by every project in the monorepo".to_vec();
+        let sha256 = sha256_of(&content);
+        let root = store.store.root.clone();
+
+        let stagings: Vec<_> = (0..8)
+            .map(|i| {
+                let source = store.source_file(&format!("shim{}.wasm", i), &content);
+                let dest = store.dest_file(&format!("shim{}.dat", i));
+                let root = root.clone();
+                let sha256 = sha256.clone();
+                std::thread::spawn(move || {
+                    // Each thread opens the store separately, as a separate process would.
+                    let store = ParcelStore::open(root).unwrap();
+                    let _lock = store.lock_shared().unwrap();
+                    store.stage(&sha256, &source, &dest).unwrap();
+                    dest
+                })
+            })
+            .collect();
+
+        for staging in stagings {
+            let dest = staging.join().unwrap();
+            assert_eq!(content, std::fs::read(dest).unwrap());
+        }
+        assert_eq!(
+            sha256,
+            file_sha256(&store.store.entry_path(&sha256), &Sha2Backend).unwrap()
+        );
+        assert_eq!(1, store.store.entries().unwrap().len());
+    }
+
+    #[test]
+    fn test_entries_follow_the_store_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = b"laid out like the other tool expects";
+        let sha256 = sha256_of(content);
+        let source = dir.path().join("source.txt");
+        std::fs::write(&source, content).unwrap();
+        let dest = dir.path().join("staged.dat");
+
+        let store = ParcelStore::open(dir.path().join("cas"))
+            .unwrap()
+            .with_layout(ParcelLayout::Flat);
+        store.stage(&sha256, &source, &dest).unwrap();
+
+        let entry = dir.path().join("cas").join(format!("{}.dat", sha256));
+        assert_eq!(entry, store.entry_path(&sha256));
+        assert!(entry.is_file());
+        assert_eq!(1, store.entries().unwrap().len());
+
+        // A store opened with another layout doesn't see the entry
+        let nested = ParcelStore::open(dir.path().join("cas")).unwrap();
+        assert!(nested.entries().unwrap().is_empty());
+    }
+
+    fn upgraded_invoice() -> bindle::Invoice {
+        // Only the parcels the bindle always needs are downloaded
+        let mut optional = parcel_for("extra.dat", b"extra");
+        optional.conditions = Some(bindle::Condition {
+            member_of: Some(vec!["extras".to_owned()]),
+            requires: None,
+        });
+        let id = bindle::Id::from_str("birds/2.0.0").unwrap();
+        test_invoice(
+            &id,
+            vec![
+                parcel_for("runtime.wasm", b"runtime"),
+                parcel_for("birds.wasm", b"birds v2"),
+                parcel_for("logo.png", b"logo"),
+                parcel_for("index.html", b"index v2"),
+                parcel_for("home.html", b"index v2"),
+                optional,
+            ],
+        )
+    }
+
+    #[test]
+    fn test_delta_lists_only_parcels_not_in_the_store() {
+        let store = TestStore::new();
+        store.stage("runtime.wasm", b"runtime");
+        store.stage("logo.png", b"logo");
+
+        let names = delta_against_cache(&upgraded_invoice(), &store.store)
+            .into_iter()
+            .map(|p| p.label.name)
+            .collect_vec();
+        assert_eq!(vec!["birds.wasm", "index.html"], names);
+    }
+
+    #[test]
+    fn test_delta_report_counts_missing_bytes_and_cached_parcels() {
+        let store = TestStore::new();
+        store.stage("runtime.wasm", b"runtime");
+        store.stage("logo.png", b"logo");
+
+        let report = DeltaReport::new(&upgraded_invoice(), &store.store);
+
+        assert_eq!(
+            DeltaReport {
+                bindle_id: "birds/2.0.0".to_owned(),
+                missing: vec!["birds.wasm".to_owned(), "index.html".to_owned()],
+                missing_bytes: (b"birds v2".len() + b"index v2".len()) as u64,
+                cached_count: 2,
+            },
+            report
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(1024, parse_size("1024").unwrap());
+        assert_eq!(64 * 1024, parse_size("64K").unwrap());
+        assert_eq!(500 * 1024 * 1024, parse_size("500m").unwrap());
+        assert_eq!(5 * 1024 * 1024 * 1024, parse_size("5G").unwrap());
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("20000000T").is_err());
+    }
+}
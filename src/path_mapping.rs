@@ -0,0 +1,226 @@
+//! Mapping between native file system paths and the forward-slash paths used
+//! in HIPPOFACTS globs and parcel names.
+//!
+//! The Windows rules are written as pure string functions so that they can be
+//! tested on any platform.
+
+use std::path::{Path, PathBuf};
+
+/// The glob pattern which matches `pattern` relative to `base_dir`. This always
+/// uses forward slashes, and escapes any glob metacharacters in `base_dir`.
+pub fn glob_pattern(base_dir: &Path, pattern: &str) -> String {
+    let base_dir = base_dir.to_string_lossy();
+    if cfg!(target_os = "windows") {
+        windows::glob_pattern(&base_dir, pattern)
+    } else {
+        join_glob(&base_dir, pattern)
+    }
+}
+
+/// The parcel name for a file, given its path relative to the HIPPOFACTS
+/// directory. Parcel names always use forward slashes.
+pub fn parcel_name(relative_path: &Path) -> anyhow::Result<String> {
+    let relative_path = relative_path
+        .to_str()
+        .ok_or_else(|| anyhow::Error::msg("Can't convert back to relative path"))?;
+    if cfg!(target_os = "windows") {
+        Ok(windows::parcel_name(relative_path))
+    } else {
+        Ok(relative_path.trim_start_matches("./").to_owned())
+    }
+}
+
+/// The path of the file named by a parcel, relative to `base_dir`.
+pub fn parcel_path(base_dir: &Path, parcel_name: &str) -> PathBuf {
+    parcel_name
+        .split('/')
+        .fold(base_dir.to_path_buf(), |path, segment| path.join(segment))
+}
+
+/// Removes the `\\?\` prefix which Windows APIs (and so users) sometimes add
+/// to absolute paths. The glob crate finds nothing under such paths.
+pub fn without_verbatim_prefix(path: &Path) -> PathBuf {
+    if cfg!(target_os = "windows") {
+        PathBuf::from(windows::without_verbatim_prefix(&path.to_string_lossy()))
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// A path to use for staging, which on Windows does not run into the 260
+/// character limit on path names. `path` should be absolute.
+pub fn staging_path(path: &Path) -> PathBuf {
+    if cfg!(target_os = "windows") {
+        PathBuf::from(windows::extended_length_path(&path.to_string_lossy()))
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn join_glob(base_dir: &str, pattern: &str) -> String {
+    let base_dir = glob::Pattern::escape(base_dir.trim_end_matches('/'));
+    let pattern = pattern.trim_start_matches("./");
+    format!("{}/{}", base_dir, pattern)
+}
+
+mod windows {
+    const VERBATIM_PREFIX: &str = r"\\?\";
+    const VERBATIM_UNC_PREFIX: &str = r"\\?\UNC\";
+
+    pub fn forward_slashes(path: &str) -> String {
+        path.replace('\\', "/")
+    }
+
+    pub fn without_verbatim_prefix(path: &str) -> String {
+        if let Some(unc) = path.strip_prefix(VERBATIM_UNC_PREFIX) {
+            format!(r"\\{}", unc)
+        } else if let Some(local) = path.strip_prefix(VERBATIM_PREFIX) {
+            local.to_owned()
+        } else {
+            path.to_owned()
+        }
+    }
+
+    pub fn glob_pattern(base_dir: &str, pattern: &str) -> String {
+        let base_dir = forward_slashes(&without_verbatim_prefix(base_dir));
+        super::join_glob(&base_dir, &forward_slashes(pattern))
+    }
+
+    pub fn parcel_name(relative_path: &str) -> String {
+        forward_slashes(relative_path)
+            .trim_start_matches("./")
+            .to_owned()
+    }
+
+    /// Converts an absolute path to the `\\?\` form, which Windows does not
+    /// limit in length. That form is not normalised by Windows, so this
+    /// resolves `.` and `..` and uses only backslashes.
+    pub fn extended_length_path(path: &str) -> String {
+        if path.starts_with(VERBATIM_PREFIX) {
+            return path.to_owned();
+        }
+        let path = forward_slashes(path);
+        let (prefix, rest) = if let Some(unc) = path.strip_prefix("//") {
+            (VERBATIM_UNC_PREFIX.to_owned(), unc.to_owned())
+        } else if is_drive_absolute(&path) {
+            (
+                format!("{}{}", VERBATIM_PREFIX, &path[..2]),
+                path[2..].to_owned(),
+            )
+        } else {
+            // Relative and drive-relative paths can't be made verbatim
+            return path.replace('/', "\\");
+        };
+
+        let mut segments: Vec<&str> = vec![];
+        for segment in rest.split('/') {
+            match segment {
+                "" | "." => (),
+                ".." => {
+                    segments.pop();
+                }
+                _ => segments.push(segment),
+            }
+        }
+        if prefix == VERBATIM_UNC_PREFIX {
+            format!("{}{}", prefix, segments.join("\\"))
+        } else {
+            format!("{}\\{}", prefix, segments.join("\\"))
+        }
+    }
+
+    fn is_drive_absolute(path: &str) -> bool {
+        let bytes = path.as_bytes();
+        bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'/'
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::windows;
+    use super::*;
+
+    #[test]
+    fn test_windows_globs_use_forward_slashes() {
+        assert_eq!(
+            "C:/Users/ivan/app/static/**/*",
+            windows::glob_pattern(r"C:\Users\ivan\app", "static/**/*")
+        );
+        assert_eq!(
+            "C:/Users/ivan/app/static/**/*",
+            windows::glob_pattern(r"C:\Users\ivan\app\", r"static\**\*")
+        );
+    }
+
+    #[test]
+    fn test_windows_globs_work_from_verbatim_and_unc_dirs() {
+        assert_eq!(
+            "C:/app/*.html",
+            windows::glob_pattern(r"\\?\C:\app", "*.html")
+        );
+        assert_eq!(
+            "//server/share/app/*.html",
+            windows::glob_pattern(r"\\server\share\app", "*.html")
+        );
+        assert_eq!(
+            "//server/share/app/*.html",
+            windows::glob_pattern(r"\\?\UNC\server\share\app", "*.html")
+        );
+    }
+
+    #[test]
+    fn test_glob_metacharacters_in_base_dir_are_escaped() {
+        assert_eq!(
+            "/home/[[]wip[]]/app/*.txt",
+            join_glob("/home/[wip]/app", "*.txt")
+        );
+        assert_eq!(
+            "C:/[[]wip[]]/app/*.txt",
+            windows::glob_pattern(r"C:\[wip]\app", "*.txt")
+        );
+    }
+
+    #[test]
+    fn test_windows_parcel_names_use_forward_slashes() {
+        assert_eq!(
+            "static/css/site.css",
+            windows::parcel_name(r"static\css\site.css")
+        );
+        assert_eq!(
+            "static/css/site.css",
+            windows::parcel_name(r".\static\css\site.css")
+        );
+        assert_eq!("site.css", windows::parcel_name("site.css"));
+    }
+
+    #[test]
+    fn test_windows_staging_paths_are_extended_length() {
+        assert_eq!(
+            r"\\?\C:\stage\parcels\abc.dat",
+            windows::extended_length_path(r"C:\stage\parcels\abc.dat")
+        );
+        assert_eq!(
+            r"\\?\C:\stage\parcels",
+            windows::extended_length_path(r"C:\work\..\stage\.\parcels")
+        );
+        assert_eq!(
+            r"\\?\UNC\server\share\stage",
+            windows::extended_length_path(r"\\server\share\stage")
+        );
+        assert_eq!(
+            r"\\?\C:\already",
+            windows::extended_length_path(r"\\?\C:\already")
+        );
+        assert_eq!(
+            r"stage\parcels",
+            windows::extended_length_path("stage/parcels")
+        );
+    }
+
+    #[test]
+    fn test_parcel_path_follows_name_segments() {
+        let path = parcel_path(Path::new("base"), "static/css/site.css");
+        let expected: PathBuf = ["base", "static", "css", "site.css"].iter().collect();
+        assert_eq!(expected, path);
+    }
+}
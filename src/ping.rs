@@ -0,0 +1,256 @@
+//! Checking that a Bindle server can be reached and accepts our credentials,
+//! so that a batch of operations can fail at the start rather than part way
+//! through. The check is a request for an invoice which does not exist. Every
+//! Bindle server can answer it, with the same authentication as the rest of
+//! the API, and it costs the server very little.
+
+use bindle::client::tokens::TokenManager;
+
+use crate::debug_bundle::{send_traced, ResponseRecording};
+
+const PING_PATH: &str = "_i/hippo-ping/0.0.0";
+
+/// What a server which answered a ping said about itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerInfo {
+    pub url: String,
+    /// The `Server` header of the response, if the server sends one.
+    pub version: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum PingError {
+    /// No response was received: the server is down, or the URL is wrong.
+    Unreachable { url: String, message: String },
+    /// The server's certificate does not have a pinned key.
+    Untrusted { url: String, message: String },
+    /// The server refused the credentials, or needs some which were not given,
+    /// or the credentials could not be got.
+    Unauthorized { url: String, message: String },
+    /// The server answered with an error which says nothing about the
+    /// credentials, e.g. because the URL is not a Bindle server's.
+    Failed {
+        url: String,
+        status_code: reqwest::StatusCode,
+    },
+}
+
+impl std::fmt::Display for PingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreachable { url, message } => {
+                write!(f, "Bindle server {} is unreachable: {}", url, message)
+            }
+            Self::Untrusted { url, message } => {
+                write!(f, "Bindle server {} is not trusted: {}", url, message)
+            }
+            Self::Unauthorized { url, message } => write!(
+                f,
+                "Could not authenticate to Bindle server {}: {}",
+                url, message
+            ),
+            Self::Failed { url, status_code } => {
+                write!(f, "Bindle server {} returned {}", url, status_code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PingError {}
+
+/// Sends an authenticated request to the server with `client`, and reports
+/// whether it was answered and the credentials accepted. As the invoice asked
+/// for does not exist, "not found" is the answer expected, though a server
+/// which restricts reading invoices may answer "forbidden" instead.
+pub async fn ping(
+    client: &reqwest::Client,
+    base_url: &str,
+    auth: &(impl TokenManager + Sync),
+) -> Result<ServerInfo, PingError> {
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), PING_PATH);
+    let request = auth
+        .apply_auth_header(client.get(&url))
+        .await
+        .map_err(|e| PingError::Unauthorized {
+            url: base_url.to_owned(),
+            message: e.to_string(),
+        })?;
+    let response = send_traced("bindle", request, ResponseRecording::Whole, |request| {
+        request.send()
+    })
+    .await
+    .map_err(|e| match tls_error(&e) {
+        Some(tls) => PingError::Untrusted {
+            url: base_url.to_owned(),
+            message: tls.to_string(),
+        },
+        None => PingError::Unreachable {
+            url: base_url.to_owned(),
+            message: e.to_string(),
+        },
+    })?;
+
+    let status_code = response.status();
+    if status_code == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(PingError::Unauthorized {
+            url: base_url.to_owned(),
+            message: format!("the server returned {}", status_code),
+        });
+    }
+    let answered = status_code.is_success()
+        || status_code == reqwest::StatusCode::NOT_FOUND
+        || status_code == reqwest::StatusCode::FORBIDDEN;
+    if !answered {
+        return Err(PingError::Failed {
+            url: base_url.to_owned(),
+            status_code,
+        });
+    }
+    let version = response
+        .headers()
+        .get(reqwest::header::SERVER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned());
+    Ok(ServerInfo {
+        url: base_url.to_owned(),
+        version,
+    })
+}
+
+/// The TLS error which made the request fail, if it was one, such as a
+/// certificate which is not trusted or does not have a pinned key.
+fn tls_error(e: &reqwest::Error) -> Option<&rustls::Error> {
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if let Some(tls) = e.downcast_ref::<rustls::Error>() {
+            return Some(tls);
+        }
+        // The connector wraps the TLS error in I/O errors, whose `source`
+        // skips the error they wrap.
+        source = match e.downcast_ref::<std::io::Error>() {
+            Some(io) => io
+                .get_ref()
+                .map(|inner| inner as &(dyn std::error::Error + 'static)),
+            None => e.source(),
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_utils::BindleConnectionInfo;
+    use crate::testing::{self, serve_once};
+    use std::net::TcpListener;
+
+    fn connection(url: &str) -> BindleConnectionInfo {
+        BindleConnectionInfo::new(
+            url,
+            false,
+            Some("alice".to_owned()),
+            Some("s3cret".to_owned()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_server_version() {
+        let (url, server) = serve_once(
+            "HTTP/1.1 404 Not Found\r\nServer: bindle/0.5.0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        let info = connection(&url).ping().await.unwrap();
+        assert_eq!(Some("bindle/0.5.0".to_owned()), info.version);
+
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("GET /v1/_i/hippo-ping/0.0.0 "),
+            "{}",
+            request
+        );
+        assert!(
+            request.to_lowercase().contains("authorization: basic"),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ping_distinguishes_refused_credentials() {
+        let (url, _server) = serve_once(
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        match connection(&url).ping().await {
+            Err(PingError::Unauthorized { message, .. }) => {
+                assert!(message.contains("401"), "{}", message)
+            }
+            other => panic!("expected Unauthorized, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_accepts_a_server_which_forbids_reading_the_invoice() {
+        let (url, _server) = serve_once(testing::http_response("403 Forbidden", ""));
+        assert!(connection(&url).ping().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_other_errors_as_failures() {
+        let (url, _server) = serve_once(testing::http_response("502 Bad Gateway", ""));
+        match connection(&url).ping().await {
+            Err(PingError::Failed { status_code, .. }) => {
+                assert_eq!(reqwest::StatusCode::BAD_GATEWAY, status_code)
+            }
+            other => panic!("expected Failed, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_credentials_which_could_not_be_got() {
+        struct NoToken;
+        #[async_trait::async_trait]
+        impl TokenManager for NoToken {
+            async fn apply_auth_header(
+                &self,
+                _builder: reqwest::RequestBuilder,
+            ) -> bindle::client::Result<reqwest::RequestBuilder> {
+                Err(bindle::client::ClientError::Other(
+                    "the token has expired".to_owned(),
+                ))
+            }
+        }
+
+        let client = reqwest::Client::new();
+        match ping(&client, "http://bindle.test/v1", &NoToken).await {
+            Err(PingError::Unauthorized { message, .. }) => {
+                assert!(message.contains("expired"), "{}", message)
+            }
+            other => panic!("expected Unauthorized, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_distinguishes_unreachable_server() {
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/v1", listener.local_addr().unwrap())
+        };
+        match connection(&url).ping().await {
+            Err(PingError::Unreachable { .. }) => (),
+            other => panic!("expected Unreachable, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_refuses_a_server_without_a_pinned_key() {
+        let url = testing::serve_tls(testing::http_response("200 OK", "")).await;
+        let connection = BindleConnectionInfo::new(&url, false, None, None)
+            .with_pinned_certificates(vec!["00".repeat(32)])
+            .unwrap();
+        match connection.ping().await {
+            Err(PingError::Untrusted { message, .. }) => {
+                assert!(message.contains("pin mismatch"), "{}", message)
+            }
+            other => panic!("expected Untrusted, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Named sets of Bindle connection settings, so that someone who works with
+//! several servers can choose one with `--bindle-profile <name>` rather than
+//! repeating its URL and credentials. Profiles are kept in a TOML file, by
+//! default `~/.hippo/profiles.toml`:
+//!
+//! ```toml
+//! [staging]
+//! url = "https://bindle.staging.example.com/v1"
+//! timeout-secs = 30
+//! auth = { method = "credentials-file", path = "/home/me/.docker/config.json" }
+//!
+//! [local]
+//! url = "https://localhost:8080/v1"
+//! insecure = true
+//! ```
+//!
+//! Settings given on the command line override those from the profile.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// The environment variable which names the profiles file, if it is not in
+/// the default place.
+pub const PROFILES_FILE_VAR: &str = "HIPPO_PROFILES_FILE";
+
+/// The settings for connecting to a Bindle server. Settings which are not
+/// given are left to the command line, or to the defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    pub url: Option<String>,
+    pub insecure: Option<bool>,
+    pub auth: Option<ProfileAuth>,
+    /// The longest to wait for each request to the server.
+    pub timeout_secs: Option<u64>,
+    /// As for `--pin-cert-sha256`.
+    pub pin_cert_sha256: Option<Vec<String>>,
+}
+
+/// How to authenticate to the server.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "method", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ProfileAuth {
+    None,
+    Basic { username: String, password: String },
+    CredentialsFile { path: PathBuf },
+    CredentialHelper { command: String },
+}
+
+impl Profile {
+    /// This profile, with each setting which `overrides` gives replaced by
+    /// that one.
+    pub fn overridden_by(self, overrides: Profile) -> Profile {
+        Profile {
+            url: overrides.url.or(self.url),
+            insecure: overrides.insecure.or(self.insecure),
+            auth: overrides.auth.or(self.auth),
+            timeout_secs: overrides.timeout_secs.or(self.timeout_secs),
+            pin_cert_sha256: overrides.pin_cert_sha256.or(self.pin_cert_sha256),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Profiles {
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl Profiles {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            anyhow::anyhow!("Error reading profiles file {}: {}", path.display(), e)
+        })?;
+        toml::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("Error parsing profiles file {}: {}", path.display(), e))
+    }
+
+    /// The profiles in the file named by `HIPPO_PROFILES_FILE`, or else in
+    /// `~/.hippo/profiles.toml`.
+    pub fn load_default() -> anyhow::Result<Self> {
+        let path = default_path().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Can't find the profiles file: set ${} or $HOME",
+                PROFILES_FILE_VAR
+            )
+        })?;
+        Self::load(path)
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<&Profile> {
+        self.profiles.get(name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "No profile named '{}': the profiles are {}",
+                name,
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    std::env::var_os(PROFILES_FILE_VAR)
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".hippo").join("profiles.toml"))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_utils::BindleConnectionInfo;
+    use std::time::Duration;
+
+    const PROFILES: &str = r#"
+[staging]
+url = "https://bindle.staging.test/v1"
+timeout-secs = 30
+auth = { method = "basic", username = "alice", password = "s3cret" }
+
+[local]
+url = "https://localhost:8080/v1"
+insecure = true
+"#;
+
+    fn profiles() -> Profiles {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profiles.toml");
+        std::fs::write(&path, PROFILES).unwrap();
+        Profiles::load(&path).unwrap()
+    }
+
+    #[test]
+    fn test_profiles_are_loaded_by_name() {
+        let profiles = profiles();
+        let staging = profiles.get("staging").unwrap();
+        assert_eq!(
+            Some("https://bindle.staging.test/v1"),
+            staging.url.as_deref()
+        );
+        assert_eq!(Some(30), staging.timeout_secs);
+        assert_eq!(
+            Some(ProfileAuth::Basic {
+                username: "alice".to_owned(),
+                password: "s3cret".to_owned()
+            }),
+            staging.auth
+        );
+        assert_eq!(Some(true), profiles.get("local").unwrap().insecure);
+
+        let message = profiles.get("production").unwrap_err().to_string();
+        assert!(message.contains("local, staging"), "{}", message);
+    }
+
+    #[test]
+    fn test_given_settings_override_the_profile() {
+        let overrides = Profile {
+            url: Some("https://other.test/v1".to_owned()),
+            auth: Some(ProfileAuth::CredentialHelper {
+                command: "helper".to_owned(),
+            }),
+            ..Profile::default()
+        };
+        let merged = profiles()
+            .get("staging")
+            .unwrap()
+            .clone()
+            .overridden_by(overrides);
+        assert_eq!(Some("https://other.test/v1"), merged.url.as_deref());
+        assert_eq!(Some(30), merged.timeout_secs);
+        assert!(matches!(
+            merged.auth,
+            Some(ProfileAuth::CredentialHelper { .. })
+        ));
+    }
+
+    #[test]
+    fn test_connection_can_be_made_from_a_profile() {
+        let profiles = profiles();
+        let connection =
+            BindleConnectionInfo::from_profile(&profiles, "staging", Profile::default()).unwrap();
+        assert_eq!("https://bindle.staging.test/v1", connection.base_url());
+        assert_eq!(Some(Duration::from_secs(30)), connection.timeout());
+        assert!(
+            BindleConnectionInfo::from_profile(&profiles, "production", Profile::default())
+                .is_err()
+        );
+
+        // A profile without a URL can't make a connection on its own
+        let local = Profile {
+            url: None,
+            ..profiles.get("local").unwrap().clone()
+        };
+        assert!(BindleConnectionInfo::from_settings(&local).is_err());
+    }
+
+    #[test]
+    fn test_connection_from_a_profile_takes_overrides() {
+        let overrides = Profile {
+            url: Some("https://other.test/v1".to_owned()),
+            ..Profile::default()
+        };
+        let connection =
+            BindleConnectionInfo::from_profile(&profiles(), "staging", overrides).unwrap();
+        assert_eq!("https://other.test/v1", connection.base_url());
+        assert_eq!(Some(Duration::from_secs(30)), connection.timeout());
+    }
+
+    #[test]
+    fn test_unknown_settings_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profiles.toml");
+        std::fs::write(
+            &path,
+            "[staging]\nurl = \"https://x.test\"\ninsecur = true\n",
+        )
+        .unwrap();
+        assert!(Profiles::load(&path).is_err());
+    }
+}
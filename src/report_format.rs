@@ -0,0 +1,110 @@
+//! Rendering the reports which commands print, as text for people or as JSON
+//! or YAML for scripts.
+
+use serde::Serialize;
+
+/// The values accepted by a command's `--output` for a report.
+pub const REPORT_FORMATS: &[&str] = &["text", "json", "yaml"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    /// The report's `Display` form.
+    Text,
+    Json,
+    Yaml,
+}
+
+impl ReportFormat {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "yaml" => Ok(Self::Yaml),
+            _ => Err(anyhow::anyhow!(
+                "Invalid output format '{}': choose one of {}",
+                text,
+                REPORT_FORMATS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Renders a report in the given format. The JSON and YAML forms have the same
+/// fields, so a script can use either.
+pub fn render<T: Serialize + std::fmt::Display>(
+    report: &T,
+    format: ReportFormat,
+) -> anyhow::Result<String> {
+    match format {
+        ReportFormat::Text => Ok(report.to_string()),
+        ReportFormat::Json => Ok(serde_json::to_string_pretty(report)?),
+        ReportFormat::Yaml => Ok(serde_yaml::to_string(report)?),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_verifier::VerifyReport;
+    use crate::parcel_store::GcReport;
+
+    fn verify_report() -> VerifyReport {
+        VerifyReport {
+            checked: 3,
+            missing: vec![("missing.txt".to_owned(), "abc".to_owned())],
+            corrupt: vec![],
+            errors: vec![(
+                "flaky.txt".to_owned(),
+                "def".to_owned(),
+                "connection reset".to_owned(),
+            )],
+        }
+    }
+
+    fn gc_report() -> GcReport {
+        GcReport {
+            removed_count: 2,
+            removed_bytes: 2048,
+            retained_bytes: 1024,
+        }
+    }
+
+    #[test]
+    fn test_reports_round_trip_through_json() {
+        let json = render(&verify_report(), ReportFormat::Json).unwrap();
+        assert_eq!(
+            verify_report(),
+            serde_json::from_str::<VerifyReport>(&json).unwrap()
+        );
+        let json = render(&gc_report(), ReportFormat::Json).unwrap();
+        assert!(json.contains("\"removedBytes\": 2048"), "{}", json);
+        assert_eq!(
+            gc_report(),
+            serde_json::from_str::<GcReport>(&json).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reports_round_trip_through_yaml() {
+        let yaml = render(&verify_report(), ReportFormat::Yaml).unwrap();
+        assert_eq!(
+            verify_report(),
+            serde_yaml::from_str::<VerifyReport>(&yaml).unwrap()
+        );
+        let yaml = render(&gc_report(), ReportFormat::Yaml).unwrap();
+        assert!(yaml.contains("removedBytes: 2048"), "{}", yaml);
+        assert_eq!(
+            gc_report(),
+            serde_yaml::from_str::<GcReport>(&yaml).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_text_is_the_display_form() {
+        assert_eq!(
+            "removed 2 parcels (2048 bytes); 1024 bytes remain",
+            render(&gc_report(), ReportFormat::Text).unwrap()
+        );
+        assert!(ReportFormat::parse("xml").is_err());
+    }
+}
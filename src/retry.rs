@@ -0,0 +1,225 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+
+/// How long to keep retrying a request which a server has rejected because it
+/// is overloaded (HTTP 429 or 503).
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The longest a server may ask us to wait before we give up.
+    pub max_wait: Duration,
+    /// The most times to send a request, including the first.
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_wait: Duration::from_secs(60),
+            max_attempts: 5,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Backoff {
+    /// Wait this long, then retry.
+    Wait(Duration),
+    /// The server asked for a longer wait than the policy allows.
+    TooLong(Duration),
+}
+
+/// How long to wait before retrying after a rejected attempt, given the wait
+/// the server asked for, if any. Without a `Retry-After` header, this backs
+/// off exponentially from one second.
+pub fn backoff(requested: Option<Duration>, attempt: u32, policy: &RetryPolicy) -> Backoff {
+    match requested {
+        Some(wait) if wait > policy.max_wait => Backoff::TooLong(wait),
+        Some(wait) => Backoff::Wait(wait),
+        None => {
+            let wait = Duration::from_secs(1 << attempt.saturating_sub(1).min(16));
+            Backoff::Wait(wait.min(policy.max_wait))
+        }
+    }
+}
+
+/// Parses a `Retry-After` header value, which is either a number of seconds or
+/// an HTTP date. A date in the past means no wait.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Whether a response means the server wants us to slow down.
+pub fn is_rate_limited(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+}
+
+/// The outcome of `send_with_retry` when the server keeps rejecting requests.
+#[derive(Debug)]
+pub enum RetryError {
+    Http(reqwest::Error),
+    /// The server asked us to wait for longer than the policy allows.
+    WaitTooLong {
+        requested: Duration,
+        max_wait: Duration,
+    },
+}
+
+impl From<reqwest::Error> for RetryError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+/// Sends a request, retrying while the server responds that it is overloaded,
+/// and waiting for as long as its `Retry-After` header asks. Once the attempts
+/// run out, this returns the last rejection so that the caller reports it as
+/// it would any other error response.
+pub async fn send_with_retry(
+    request: reqwest::RequestBuilder,
+    policy: &RetryPolicy,
+) -> Result<reqwest::Response, RetryError> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        // Requests with streaming bodies can't be cloned, so can't be retried.
+        let retry_request = request.try_clone();
+        let response = match retry_request {
+            Some(r) => r.send().await?,
+            None => return Ok(request.send().await?),
+        };
+        match retry_wait(&response, attempt, policy)? {
+            None => return Ok(response),
+            Some(wait) => tokio::time::sleep(wait).await,
+        }
+    }
+}
+
+/// How long to wait before retrying, if `response` to the `attempt`th try of a
+/// request means that it should be retried. This warns that it is waiting, and
+/// is an error if the server asked for a longer wait than the policy allows.
+pub fn retry_wait(
+    response: &reqwest::Response,
+    attempt: u32,
+    policy: &RetryPolicy,
+) -> Result<Option<Duration>, RetryError> {
+    if !is_rate_limited(response.status()) || attempt >= policy.max_attempts {
+        return Ok(None);
+    }
+    let requested = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_retry_after(v, Utc::now()));
+    match backoff(requested, attempt, policy) {
+        Backoff::TooLong(requested) => Err(RetryError::WaitTooLong {
+            requested,
+            max_wait: policy.max_wait,
+        }),
+        Backoff::Wait(wait) => {
+            eprintln!(
+                "{}",
+                format!(
+                    "warning: {} asked us to slow down; retrying in {}s",
+                    response.url().host_str().unwrap_or("server"),
+                    wait.as_secs()
+                )
+                .yellow()
+            );
+            Ok(Some(wait))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_retry_after_can_be_delta_seconds() {
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            parse_retry_after("120", now())
+        );
+        assert_eq!(
+            Some(Duration::from_secs(0)),
+            parse_retry_after(" 0 ", now())
+        );
+    }
+
+    #[test]
+    fn test_retry_after_can_be_an_http_date() {
+        assert_eq!(
+            Some(Duration::from_secs(90)),
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now())
+        );
+        assert_eq!(
+            Some(Duration::from_secs(0)),
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now())
+        );
+    }
+
+    #[test]
+    fn test_unparseable_retry_after_is_ignored() {
+        assert_eq!(None, parse_retry_after("soon", now()));
+        assert_eq!(None, parse_retry_after("", now()));
+    }
+
+    #[test]
+    fn test_requested_wait_is_honoured_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            max_wait: Duration::from_secs(30),
+            max_attempts: 5,
+        };
+        assert_eq!(
+            Backoff::Wait(Duration::from_secs(30)),
+            backoff(Some(Duration::from_secs(30)), 1, &policy)
+        );
+        assert_eq!(
+            Backoff::TooLong(Duration::from_secs(31)),
+            backoff(Some(Duration::from_secs(31)), 1, &policy)
+        );
+    }
+
+    #[test]
+    fn test_absent_retry_after_backs_off_exponentially() {
+        let policy = RetryPolicy {
+            max_wait: Duration::from_secs(5),
+            max_attempts: 5,
+        };
+        assert_eq!(
+            Backoff::Wait(Duration::from_secs(1)),
+            backoff(None, 1, &policy)
+        );
+        assert_eq!(
+            Backoff::Wait(Duration::from_secs(2)),
+            backoff(None, 2, &policy)
+        );
+        assert_eq!(
+            Backoff::Wait(Duration::from_secs(4)),
+            backoff(None, 3, &policy)
+        );
+        assert_eq!(
+            Backoff::Wait(Duration::from_secs(5)),
+            backoff(None, 4, &policy)
+        );
+    }
+}
@@ -0,0 +1,770 @@
+//! Software bills of materials for bindles, in CycloneDX 1.4 or SPDX 2.3 JSON.
+//! The invoice is the root component and each parcel is a component of it.
+//! Groups are not components in their own right: a parcel which requires a
+//! group depends on the group's members, and the invoice depends on its
+//! global parcels and on the members of its required groups. Annotations
+//! become CycloneDX properties or SPDX annotations.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::bindle_utils::ParcelHelpers;
+
+const TOOL_NAME: &str = "hippo";
+const TOOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SbomFormat {
+    CycloneDx,
+    Spdx,
+}
+
+impl SbomFormat {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "cyclonedx" => Ok(Self::CycloneDx),
+            "spdx" => Ok(Self::Spdx),
+            _ => Err(anyhow::anyhow!(
+                "Invalid SBOM format '{}': choose 'cyclonedx' or 'spdx'",
+                text
+            )),
+        }
+    }
+}
+
+/// Where to write an SBOM for the bindle, and in what format.
+#[derive(Clone, Debug)]
+pub struct SbomOptions {
+    pub path: PathBuf,
+    pub format: SbomFormat,
+}
+
+/// Writes an SBOM for the invoice to a file, dated now.
+pub fn write_sbom(invoice: &bindle::Invoice, options: &SbomOptions) -> anyhow::Result<()> {
+    let text = generate_sbom(invoice, options.format, Utc::now())?;
+    write_file(&options.path, &text).map_err(|e| {
+        anyhow::anyhow!(
+            "Error writing SBOM to {}: {}",
+            options.path.to_string_lossy(),
+            e
+        )
+    })
+}
+
+fn write_file(path: &Path, text: &str) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+/// The SBOM for an invoice, as pretty-printed JSON.
+pub fn generate_sbom(
+    invoice: &bindle::Invoice,
+    format: SbomFormat,
+    created: DateTime<Utc>,
+) -> anyhow::Result<String> {
+    let graph = DependencyGraph::of(invoice);
+    let created = created.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let text = match format {
+        SbomFormat::CycloneDx => {
+            serde_json::to_string_pretty(&cyclonedx(invoice, &graph, created))?
+        }
+        SbomFormat::Spdx => serde_json::to_string_pretty(&spdx(invoice, &graph, created)?)?,
+    };
+    Ok(text)
+}
+
+/// Which parcels the invoice and each parcel depend on, by index into the
+/// invoice's parcel list.
+struct DependencyGraph {
+    root: Vec<usize>,
+    parcels: Vec<Vec<usize>>,
+}
+
+impl DependencyGraph {
+    fn of(invoice: &bindle::Invoice) -> Self {
+        let parcels = parcel_list(invoice);
+        let members_of = |groups: &[String]| -> Vec<usize> {
+            parcels
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| groups.iter().any(|g| p.is_member_of(g)))
+                .map(|(i, _)| i)
+                .collect()
+        };
+
+        let required_groups: Vec<String> = invoice
+            .group
+            .iter()
+            .flatten()
+            .filter(|g| g.required.unwrap_or(false))
+            .map(|g| g.name.clone())
+            .collect();
+        let mut root: Vec<usize> = parcels
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.memberships().is_empty())
+            .map(|(i, _)| i)
+            .collect();
+        for i in members_of(&required_groups) {
+            if !root.contains(&i) {
+                root.push(i);
+            }
+        }
+        root.sort_unstable();
+
+        let parcel_dependencies = parcels
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                members_of(&p.requires())
+                    .into_iter()
+                    .filter(|&j| j != i)
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            root,
+            parcels: parcel_dependencies,
+        }
+    }
+}
+
+fn parcel_list(invoice: &bindle::Invoice) -> Vec<&bindle::Parcel> {
+    invoice.parcel.iter().flatten().collect()
+}
+
+fn sorted_annotations(annotations: &Option<bindle::AnnotationMap>) -> Vec<(&String, &String)> {
+    let mut annotations: Vec<_> = annotations.iter().flatten().collect();
+    annotations.sort();
+    annotations
+}
+
+// CycloneDX
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CycloneDxBom {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    version: u32,
+    metadata: CycloneDxMetadata,
+    components: Vec<CycloneDxComponent>,
+    dependencies: Vec<CycloneDxDependency>,
+}
+
+#[derive(Serialize)]
+struct CycloneDxMetadata {
+    timestamp: String,
+    tools: Vec<CycloneDxTool>,
+    component: CycloneDxComponent,
+}
+
+#[derive(Serialize)]
+struct CycloneDxTool {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct CycloneDxComponent {
+    #[serde(rename = "type")]
+    component_type: &'static str,
+    #[serde(rename = "bom-ref")]
+    bom_ref: String,
+    name: String,
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(rename = "mime-type", skip_serializing_if = "Option::is_none")]
+    mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hashes: Vec<CycloneDxHash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    properties: Vec<CycloneDxProperty>,
+}
+
+#[derive(Serialize)]
+struct CycloneDxHash {
+    alg: &'static str,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct CycloneDxProperty {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CycloneDxDependency {
+    #[serde(rename = "ref")]
+    dependency_ref: String,
+    depends_on: Vec<String>,
+}
+
+fn property(name: impl Into<String>, value: impl Into<String>) -> CycloneDxProperty {
+    CycloneDxProperty {
+        name: name.into(),
+        value: value.into(),
+    }
+}
+
+fn annotation_properties(annotations: &Option<bindle::AnnotationMap>) -> Vec<CycloneDxProperty> {
+    sorted_annotations(annotations)
+        .into_iter()
+        .map(|(k, v)| property(format!("bindle:annotation:{}", k), v.as_str()))
+        .collect()
+}
+
+fn cyclonedx_parcel_ref(parcel: &bindle::Parcel) -> String {
+    format!("{}@{}", parcel.label.name, parcel.label.sha256)
+}
+
+fn cyclonedx(
+    invoice: &bindle::Invoice,
+    graph: &DependencyGraph,
+    timestamp: String,
+) -> CycloneDxBom {
+    let id = &invoice.bindle.id;
+    let parcels = parcel_list(invoice);
+    let root_ref = id.to_string();
+
+    let root = CycloneDxComponent {
+        component_type: "application",
+        bom_ref: root_ref.clone(),
+        name: id.name().to_owned(),
+        version: id.version_string(),
+        description: invoice.bindle.description.clone(),
+        mime_type: None,
+        hashes: vec![],
+        properties: annotation_properties(&invoice.annotations),
+    };
+
+    let components = parcels
+        .iter()
+        .map(|p| {
+            let mut properties = vec![property("bindle:size", p.label.size.to_string())];
+            let memberships = p.memberships();
+            if !memberships.is_empty() {
+                properties.push(property("bindle:memberOf", memberships.join(",")));
+            }
+            properties.extend(annotation_properties(&p.label.annotations));
+            CycloneDxComponent {
+                component_type: "file",
+                bom_ref: cyclonedx_parcel_ref(p),
+                name: p.label.name.clone(),
+                version: id.version_string(),
+                description: None,
+                mime_type: Some(p.label.media_type.clone()),
+                hashes: vec![CycloneDxHash {
+                    alg: "SHA-256",
+                    content: p.label.sha256.clone(),
+                }],
+                properties,
+            }
+        })
+        .collect();
+
+    let refs_of = |indexes: &[usize]| -> Vec<String> {
+        indexes
+            .iter()
+            .map(|&i| cyclonedx_parcel_ref(parcels[i]))
+            .collect()
+    };
+    let dependencies = std::iter::once(CycloneDxDependency {
+        dependency_ref: root_ref,
+        depends_on: refs_of(&graph.root),
+    })
+    .chain(
+        parcels
+            .iter()
+            .zip(&graph.parcels)
+            .map(|(p, deps)| CycloneDxDependency {
+                dependency_ref: cyclonedx_parcel_ref(p),
+                depends_on: refs_of(deps),
+            }),
+    )
+    .collect();
+
+    CycloneDxBom {
+        bom_format: "CycloneDX",
+        spec_version: "1.4",
+        version: 1,
+        metadata: CycloneDxMetadata {
+            timestamp,
+            tools: vec![CycloneDxTool {
+                name: TOOL_NAME,
+                version: TOOL_VERSION,
+            }],
+            component: root,
+        },
+        components,
+        dependencies,
+    }
+}
+
+// SPDX
+
+const SPDX_DOCUMENT_ID: &str = "SPDXRef-DOCUMENT";
+const SPDX_ROOT_ID: &str = "SPDXRef-Bindle";
+const SPDX_NO_ASSERTION: &str = "NOASSERTION";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpdxDocument {
+    spdx_version: &'static str,
+    data_license: &'static str,
+    #[serde(rename = "SPDXID")]
+    spdx_id: &'static str,
+    name: String,
+    document_namespace: String,
+    creation_info: SpdxCreationInfo,
+    document_describes: Vec<String>,
+    packages: Vec<SpdxPackage>,
+    relationships: Vec<SpdxRelationship>,
+}
+
+#[derive(Serialize)]
+struct SpdxCreationInfo {
+    created: String,
+    creators: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpdxPackage {
+    #[serde(rename = "SPDXID")]
+    spdx_id: String,
+    name: String,
+    version_info: String,
+    download_location: &'static str,
+    files_analyzed: bool,
+    primary_package_purpose: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    checksums: Vec<SpdxChecksum>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    annotations: Vec<SpdxAnnotation>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpdxChecksum {
+    algorithm: &'static str,
+    checksum_value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpdxAnnotation {
+    annotation_date: String,
+    annotation_type: &'static str,
+    annotator: String,
+    comment: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpdxRelationship {
+    spdx_element_id: String,
+    relationship_type: &'static str,
+    related_spdx_element: String,
+}
+
+fn spdx_parcel_id(index: usize) -> String {
+    format!("SPDXRef-Parcel-{}", index)
+}
+
+fn spdx(
+    invoice: &bindle::Invoice,
+    graph: &DependencyGraph,
+    created: String,
+) -> anyhow::Result<SpdxDocument> {
+    let id = &invoice.bindle.id;
+    let parcels = parcel_list(invoice);
+    let annotator = format!("Tool: {}-{}", TOOL_NAME, TOOL_VERSION);
+    let annotation = |key: &str, value: &str| SpdxAnnotation {
+        annotation_date: created.clone(),
+        annotation_type: "OTHER",
+        annotator: annotator.clone(),
+        comment: format!("{}={}", key, value),
+    };
+    let annotations_of = |annotations: &Option<bindle::AnnotationMap>| -> Vec<SpdxAnnotation> {
+        sorted_annotations(annotations)
+            .into_iter()
+            .map(|(k, v)| annotation(&format!("bindle:annotation:{}", k), v))
+            .collect()
+    };
+
+    let mut packages = vec![SpdxPackage {
+        spdx_id: SPDX_ROOT_ID.to_owned(),
+        name: id.name().to_owned(),
+        version_info: id.version_string(),
+        download_location: SPDX_NO_ASSERTION,
+        files_analyzed: false,
+        primary_package_purpose: "APPLICATION",
+        description: invoice.bindle.description.clone(),
+        checksums: vec![],
+        annotations: annotations_of(&invoice.annotations),
+    }];
+    for (i, p) in parcels.iter().enumerate() {
+        let mut annotations = vec![
+            annotation("bindle:mediaType", &p.label.media_type),
+            annotation("bindle:size", &p.label.size.to_string()),
+        ];
+        let memberships = p.memberships();
+        if !memberships.is_empty() {
+            annotations.push(annotation("bindle:memberOf", &memberships.join(",")));
+        }
+        annotations.extend(annotations_of(&p.label.annotations));
+        packages.push(SpdxPackage {
+            spdx_id: spdx_parcel_id(i),
+            name: p.label.name.clone(),
+            version_info: id.version_string(),
+            download_location: SPDX_NO_ASSERTION,
+            files_analyzed: false,
+            primary_package_purpose: "FILE",
+            description: None,
+            checksums: vec![SpdxChecksum {
+                algorithm: "SHA256",
+                checksum_value: p.label.sha256.clone(),
+            }],
+            annotations,
+        });
+    }
+
+    let depends_on = |from: String, to: &usize| SpdxRelationship {
+        spdx_element_id: from,
+        relationship_type: "DEPENDS_ON",
+        related_spdx_element: spdx_parcel_id(*to),
+    };
+    let mut relationships = vec![SpdxRelationship {
+        spdx_element_id: SPDX_DOCUMENT_ID.to_owned(),
+        relationship_type: "DESCRIBES",
+        related_spdx_element: SPDX_ROOT_ID.to_owned(),
+    }];
+    relationships.extend(
+        graph
+            .root
+            .iter()
+            .map(|i| depends_on(SPDX_ROOT_ID.to_owned(), i)),
+    );
+    for (i, deps) in graph.parcels.iter().enumerate() {
+        relationships.extend(deps.iter().map(|j| depends_on(spdx_parcel_id(i), j)));
+    }
+
+    // The namespace must be unique to this document, so it includes a digest
+    // of the invoice.
+    let invoice_digest = Sha256::digest(toml::to_string(invoice)?.as_bytes());
+    let document_namespace = format!(
+        "https://spdx.org/spdxdocs/{}-{}-{:x}",
+        id.name().replace('/', "-"),
+        id.version_string(),
+        invoice_digest
+    );
+
+    Ok(SpdxDocument {
+        spdx_version: "SPDX-2.3",
+        data_license: "CC0-1.0",
+        spdx_id: SPDX_DOCUMENT_ID,
+        name: id.to_string(),
+        document_namespace,
+        creation_info: SpdxCreationInfo {
+            created,
+            creators: vec![annotator],
+        },
+        document_describes: vec![SPDX_ROOT_ID.to_owned()],
+        packages,
+        relationships,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, test_invoice};
+    use std::collections::{BTreeMap, HashSet};
+    use std::str::FromStr;
+
+    fn conditional(name: &str, member_of: &[&str], requires: &[&str]) -> bindle::Parcel {
+        let mut parcel = parcel_for(name, name.as_bytes());
+        let list = |groups: &[&str]| {
+            if groups.is_empty() {
+                None
+            } else {
+                Some(groups.iter().map(|g| g.to_string()).collect())
+            }
+        };
+        parcel.conditions = Some(bindle::Condition {
+            member_of: list(member_of),
+            requires: list(requires),
+        });
+        parcel
+    }
+
+    fn test_app() -> bindle::Invoice {
+        let id = bindle::Id::from_str("birds/app/1.4.2").unwrap();
+        let mut handler = conditional("birds.wasm", &[], &["assets"]);
+        handler.label.media_type = "application/wasm".to_owned();
+        handler.label.annotations = Some(
+            vec![("git_sha".to_owned(), "0a1b2c3".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        let mut invoice = test_invoice(
+            &id,
+            vec![
+                handler,
+                conditional("index.html", &["assets"], &[]),
+                conditional("admin.wasm", &["admin"], &[]),
+            ],
+        );
+        invoice.group = Some(vec![
+            bindle::Group {
+                name: "assets".to_owned(),
+                required: None,
+                satisfied_by: None,
+            },
+            bindle::Group {
+                name: "admin".to_owned(),
+                required: Some(true),
+                satisfied_by: None,
+            },
+        ]);
+        invoice.annotations = Some(
+            vec![("team".to_owned(), "birds".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        invoice
+    }
+
+    fn created() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2021-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn generated(format: SbomFormat) -> serde_json::Value {
+        let text = generate_sbom(&test_app(), format, created()).unwrap();
+        serde_json::from_str(&text).unwrap()
+    }
+
+    fn strings(value: &serde_json::Value) -> Vec<&str> {
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect()
+    }
+
+    fn assert_has_fields(value: &serde_json::Value, fields: &[&str], what: &str) {
+        for field in fields {
+            assert!(
+                value.get(field).is_some(),
+                "{} lacks required field '{}': {}",
+                what,
+                field,
+                value
+            );
+        }
+    }
+
+    // Some of the required fields and value patterns of the CycloneDX 1.4 JSON
+    // schema, chosen by hand. This is not validation against the schema.
+    #[test]
+    fn test_cyclonedx_has_required_fields_and_consistent_refs() {
+        let bom = generated(SbomFormat::CycloneDx);
+        assert_has_fields(&bom, &["bomFormat", "specVersion", "version"], "BOM");
+        assert_eq!("CycloneDX", bom["bomFormat"]);
+        assert_eq!("1.4", bom["specVersion"]);
+
+        let sha256 = regex::Regex::new("^[a-fA-F0-9]{64}$").unwrap();
+        let component_types = ["application", "file"];
+        let root = &bom["metadata"]["component"];
+        let components = bom["components"].as_array().unwrap();
+        let mut refs = HashSet::new();
+        for component in std::iter::once(root).chain(components) {
+            assert_has_fields(component, &["type", "name"], "component");
+            assert!(component_types.contains(&component["type"].as_str().unwrap()));
+            assert!(
+                refs.insert(component["bom-ref"].as_str().unwrap()),
+                "bom-refs are unique"
+            );
+            for hash in component["hashes"].as_array().into_iter().flatten() {
+                assert_eq!("SHA-256", hash["alg"]);
+                assert!(sha256.is_match(hash["content"].as_str().unwrap()));
+            }
+            for property in component["properties"].as_array().into_iter().flatten() {
+                assert!(property["name"].is_string() && property["value"].is_string());
+            }
+        }
+        assert!(regex::Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}Z$")
+            .unwrap()
+            .is_match(bom["metadata"]["timestamp"].as_str().unwrap()));
+        for dependency in bom["dependencies"].as_array().unwrap() {
+            assert!(refs.contains(dependency["ref"].as_str().unwrap()));
+            for target in strings(&dependency["dependsOn"]) {
+                assert!(refs.contains(target), "dangling dependency {}", target);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cyclonedx_describes_parcels_and_groups() {
+        let bom = generated(SbomFormat::CycloneDx);
+        let root = &bom["metadata"]["component"];
+        assert_eq!("birds/app", root["name"]);
+        assert_eq!("1.4.2", root["version"]);
+        assert_eq!("bindle:annotation:team", root["properties"][0]["name"]);
+
+        let handler = &bom["components"][0];
+        assert_eq!("birds.wasm", handler["name"]);
+        assert_eq!("1.4.2", handler["version"]);
+        assert_eq!("application/wasm", handler["mime-type"]);
+        assert_eq!(sha_of("birds.wasm"), handler["hashes"][0]["content"]);
+        let properties: BTreeMap<_, _> = handler["properties"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| (p["name"].as_str().unwrap(), p["value"].as_str().unwrap()))
+            .collect();
+        assert_eq!(Some(&"10"), properties.get("bindle:size"));
+        assert_eq!(
+            Some(&"0a1b2c3"),
+            properties.get("bindle:annotation:git_sha")
+        );
+
+        let depends_on = |bom_ref: &str| -> Vec<String> {
+            let dependency = bom["dependencies"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|d| d["ref"] == bom_ref)
+                .unwrap();
+            strings(&dependency["dependsOn"])
+                .iter()
+                .map(|r| r.split('@').next().unwrap().to_owned())
+                .collect()
+        };
+        // The handler is global and admin is a required group, so the app
+        // depends on both; index.html comes in only through the handler.
+        assert_eq!(
+            vec!["birds.wasm", "admin.wasm"],
+            depends_on("birds/app/1.4.2")
+        );
+        let handler_ref = handler["bom-ref"].as_str().unwrap();
+        assert_eq!(vec!["index.html"], depends_on(handler_ref));
+    }
+
+    // Some of the required fields and value patterns of the SPDX 2.3 JSON
+    // schema, chosen by hand. This is not validation against the schema.
+    #[test]
+    fn test_spdx_has_required_fields_and_consistent_ids() {
+        let doc = generated(SbomFormat::Spdx);
+        assert_has_fields(
+            &doc,
+            &[
+                "spdxVersion",
+                "dataLicense",
+                "SPDXID",
+                "name",
+                "documentNamespace",
+                "creationInfo",
+            ],
+            "document",
+        );
+        assert_eq!("SPDX-2.3", doc["spdxVersion"]);
+        assert_eq!("CC0-1.0", doc["dataLicense"]);
+        assert!(doc["documentNamespace"]
+            .as_str()
+            .unwrap()
+            .starts_with("https://"));
+        assert_has_fields(
+            &doc["creationInfo"],
+            &["created", "creators"],
+            "creationInfo",
+        );
+
+        let spdx_id = regex::Regex::new(r"^SPDXRef-[a-zA-Z0-9.\-]+$").unwrap();
+        let mut ids = HashSet::new();
+        ids.insert(SPDX_DOCUMENT_ID);
+        for package in doc["packages"].as_array().unwrap() {
+            assert_has_fields(package, &["SPDXID", "name", "downloadLocation"], "package");
+            let id = package["SPDXID"].as_str().unwrap();
+            assert!(spdx_id.is_match(id), "invalid SPDXID {}", id);
+            assert!(ids.insert(id), "SPDXIDs are unique");
+            for checksum in package["checksums"].as_array().into_iter().flatten() {
+                assert_eq!("SHA256", checksum["algorithm"]);
+            }
+            for annotation in package["annotations"].as_array().into_iter().flatten() {
+                assert_has_fields(
+                    annotation,
+                    &["annotationDate", "annotationType", "annotator", "comment"],
+                    "annotation",
+                );
+            }
+        }
+        for relationship in doc["relationships"].as_array().unwrap() {
+            for end in &["spdxElementId", "relatedSpdxElement"] {
+                let id = relationship[end].as_str().unwrap();
+                assert!(ids.contains(id), "dangling relationship to {}", id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_spdx_describes_parcels_and_groups() {
+        let doc = generated(SbomFormat::Spdx);
+        let handler = &doc["packages"][1];
+        assert_eq!("birds.wasm", handler["name"]);
+        assert_eq!("1.4.2", handler["versionInfo"]);
+        assert_eq!(
+            sha_of("birds.wasm"),
+            handler["checksums"][0]["checksumValue"]
+        );
+        let comments: Vec<_> = handler["annotations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| a["comment"].as_str().unwrap())
+            .collect();
+        assert!(comments.contains(&"bindle:mediaType=application/wasm"));
+        assert!(comments.contains(&"bindle:annotation:git_sha=0a1b2c3"));
+
+        let depends_on: Vec<_> = doc["relationships"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|r| r["relationshipType"] == "DEPENDS_ON")
+            .map(|r| {
+                (
+                    r["spdxElementId"].as_str().unwrap(),
+                    r["relatedSpdxElement"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (SPDX_ROOT_ID, "SPDXRef-Parcel-0"),
+                (SPDX_ROOT_ID, "SPDXRef-Parcel-2"),
+                ("SPDXRef-Parcel-0", "SPDXRef-Parcel-1"),
+            ],
+            depends_on
+        );
+    }
+
+    fn sha_of(name: &str) -> String {
+        crate::testing::sha256_of(name.as_bytes())
+    }
+}
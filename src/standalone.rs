@@ -0,0 +1,253 @@
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+use bindle::{Invoice, Parcel};
+use serde::{Deserialize, Serialize};
+
+use crate::bindle_utils::{BindleSource, ParcelHelpers};
+use crate::digest_backend::Sha2Backend;
+use crate::file_hash::file_sha256;
+use crate::invoice_diff::closures_equal;
+
+/// A bindle stored on disk in the standalone layout written by `BindleWriter`:
+/// `<base>/<id sha>/invoice.toml` plus `<base>/<id sha>/parcels/<sha256>.dat`.
+pub struct StandaloneBindle {
+    pub invoice: Invoice,
+    pub parcels_dir: PathBuf,
+}
+
+impl StandaloneBindle {
+    pub async fn read(base_path: impl AsRef<Path>, bindle_id: &bindle::Id) -> anyhow::Result<Self> {
+        let bindle_dir = base_path.as_ref().join(bindle_id.sha());
+        let invoice_file = bindle_dir.join("invoice.toml");
+        let invoice_text = tokio::fs::read_to_string(&invoice_file)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Error reading standalone invoice {}: {}",
+                    invoice_file.to_string_lossy(),
+                    e
+                )
+            })?;
+        let invoice = toml::from_str(&invoice_text)?;
+        Ok(Self {
+            invoice,
+            parcels_dir: bindle_dir.join("parcels"),
+        })
+    }
+
+    pub fn parcel_path(&self, sha256: &str) -> PathBuf {
+        self.parcels_dir.join(format!("{}.dat", sha256))
+    }
+
+    fn local_parcel_shas(&self) -> anyhow::Result<Vec<String>> {
+        if !self.parcels_dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut shas = vec![];
+        for entry in std::fs::read_dir(&self.parcels_dir)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "dat").unwrap_or(false) {
+                if let Some(stem) = path.file_stem() {
+                    shas.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        Ok(shas)
+    }
+}
+
+/// The result of checking a local standalone bindle against the copy on a server.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompareReport {
+    /// Whether the local invoice declares the same id and parcels as the remote one.
+    pub invoice_matches: bool,
+    /// Whether the local invoice always needs the same parcels as the remote
+    /// one, whatever they are named.
+    pub closure_matches: bool,
+    /// Parcels the remote invoice declares but which are not present on disk.
+    pub missing: Vec<String>,
+    /// Parcel files on disk which the remote invoice does not declare.
+    pub extra: Vec<String>,
+    /// Parcel files on disk whose content does not hash to their declared sha.
+    pub corrupt: Vec<String>,
+}
+
+impl CompareReport {
+    pub fn is_match(&self) -> bool {
+        self.invoice_matches
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.corrupt.is_empty()
+    }
+
+    /// Whether the local bindle is the same application as the remote one,
+    /// though it may name its parcels differently or leave out optional ones.
+    /// Parcel files which are there must still be intact.
+    pub fn is_closure_match(&self) -> bool {
+        self.closure_matches && self.corrupt.is_empty()
+    }
+}
+
+impl std::fmt::Display for CompareReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.closure_matches {
+            writeln!(
+                f,
+                "invoice differs from the server's, and needs different parcels"
+            )?;
+        } else if !self.invoice_matches {
+            writeln!(f, "invoice differs from the server's")?;
+        }
+        for sha in &self.missing {
+            writeln!(f, "missing: {}", sha)?;
+        }
+        for sha in &self.extra {
+            writeln!(f, "extra:   {}", sha)?;
+        }
+        for sha in &self.corrupt {
+            writeln!(f, "corrupt: {}", sha)?;
+        }
+        if self.is_match() {
+            write!(f, "matches the server's copy")
+        } else {
+            write!(f, "does not match the server's copy")
+        }
+    }
+}
+
+/// Compares a local standalone bindle against the invoice on the server. Unlike
+/// comparing invoices, this hashes the parcel files on disk, so it catches
+/// local files which have been truncated or altered.
+pub async fn compare_standalone(
+    local: &StandaloneBindle,
+    client: &impl BindleSource,
+    id: &bindle::Id,
+) -> anyhow::Result<CompareReport> {
+    let remote = client.fetch_invoice(id).await?;
+
+    let remote_shas: HashSet<String> = stageable_parcels(&remote)
+        .map(|p| p.label.sha256.clone())
+        .collect();
+    let local_shas = local.local_parcel_shas()?;
+
+    let missing = remote_shas
+        .iter()
+        .filter(|sha| !local.parcel_path(sha).is_file())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let extra = local_shas
+        .iter()
+        .filter(|sha| !remote_shas.contains(*sha))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let mut corrupt = BTreeSet::new();
+    for sha in local_shas.iter().filter(|sha| remote_shas.contains(*sha)) {
+        if file_sha256(&local.parcel_path(sha), &Sha2Backend)? != *sha {
+            corrupt.insert(sha.clone());
+        }
+    }
+
+    Ok(CompareReport {
+        invoice_matches: same_invoice(&local.invoice, &remote),
+        closure_matches: closures_equal(&local.invoice, &remote),
+        missing: missing.into_iter().collect(),
+        extra: extra.into_iter().collect(),
+        corrupt: corrupt.into_iter().collect(),
+    })
+}
+
+// Parcels marked as not staged (the dependencies of external references) live in
+// another bindle, so a standalone copy is not expected to contain them.
+fn stageable_parcels(invoice: &Invoice) -> impl Iterator<Item = &Parcel> {
+    invoice
+        .parcel
+        .iter()
+        .flatten()
+        .filter(|p| !p.has_annotation("hippos_do_not_stage"))
+}
+
+fn same_invoice(first: &Invoice, second: &Invoice) -> bool {
+    first.bindle.id == second.bindle.id && parcel_labels(first) == parcel_labels(second)
+}
+
+fn parcel_labels(invoice: &Invoice) -> BTreeSet<(String, String, u64)> {
+    invoice
+        .parcel
+        .iter()
+        .flatten()
+        .map(|p| (p.label.name.clone(), p.label.sha256.clone(), p.label.size))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bindle_writer::BindleWriter;
+    use crate::testing::{parcel_for, test_invoice, FakeBindleServer};
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_compare_flags_missing_parcel() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        let files = vec![
+            ("one.txt", "one"),
+            ("two.txt", "two"),
+            ("three.txt", "three"),
+        ];
+        for (name, content) in &files {
+            std::fs::write(source_dir.path().join(name), content).unwrap();
+        }
+
+        let id = bindle::Id::from_str("mirror/test/1.0.0").unwrap();
+        let parcels: Vec<_> = files
+            .iter()
+            .map(|(n, c)| parcel_for(n, c.as_bytes()))
+            .collect();
+        let missing_sha = parcels[2].label.sha256.clone();
+        let invoice = test_invoice(&id, parcels);
+
+        BindleWriter::new(source_dir.path(), dest_dir.path())
+            .write(&invoice)
+            .await
+            .unwrap();
+        let local = StandaloneBindle::read(dest_dir.path(), &id).await.unwrap();
+        std::fs::remove_file(local.parcel_path(&missing_sha)).unwrap();
+
+        let server = FakeBindleServer::new().with_invoice(invoice);
+        let report = compare_standalone(&local, &server, &id).await.unwrap();
+
+        assert!(report.invoice_matches);
+        assert!(report.closure_matches);
+        assert_eq!(vec![missing_sha], report.missing);
+        assert!(report.extra.is_empty());
+        assert!(report.corrupt.is_empty());
+        assert!(!report.is_match());
+    }
+
+    #[tokio::test]
+    async fn test_renamed_parcel_is_a_closure_match() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        std::fs::write(source_dir.path().join("one.txt"), "one").unwrap();
+        std::fs::write(source_dir.path().join("uno.txt"), "one").unwrap();
+
+        let id = bindle::Id::from_str("mirror/test/1.0.0").unwrap();
+        let rebuilt = test_invoice(&id, vec![parcel_for("one.txt", b"one")]);
+        BindleWriter::new(source_dir.path(), dest_dir.path())
+            .write(&rebuilt)
+            .await
+            .unwrap();
+        let local = StandaloneBindle::read(dest_dir.path(), &id).await.unwrap();
+
+        let remote = test_invoice(&id, vec![parcel_for("uno.txt", b"one")]);
+        let server = FakeBindleServer::new().with_invoice(remote);
+        let report = compare_standalone(&local, &server, &id).await.unwrap();
+
+        assert!(!report.invoice_matches);
+        assert!(!report.is_match());
+        assert!(report.is_closure_match());
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use itertools::Itertools;
+
+/// Values for the `{{var.NAME}}` and `{{env.NAME}}` references in a HIPPOFACTS
+/// file. Write `{{{{` for a literal `{{`.
+#[derive(Default)]
+pub struct TemplateVariables {
+    vars: HashMap<String, String>,
+    env: HashMap<String, String>,
+    /// Whether to substitute in the bindle name. This is off by default so
+    /// that a variable can't change which bindle is built by accident.
+    pub substitute_bindle_name: bool,
+}
+
+impl TemplateVariables {
+    /// Values given by `set` take precedence over those from a values file.
+    pub fn new(
+        values: BTreeMap<String, String>,
+        set: Vec<(String, String)>,
+        env: HashMap<String, String>,
+    ) -> Self {
+        let mut vars: HashMap<_, _> = values.into_iter().collect();
+        vars.extend(set);
+        Self {
+            vars,
+            env,
+            substitute_bindle_name: false,
+        }
+    }
+
+    /// Reads a values file: a TOML file of `NAME = "value"` pairs.
+    pub fn read_values_file(path: impl AsRef<Path>) -> anyhow::Result<BTreeMap<String, String>> {
+        (|| {
+            let content = std::fs::read_to_string(&path)?;
+            Ok(toml::from_str(&content)?)
+        })()
+        .map_err(|e: anyhow::Error| {
+            anyhow::anyhow!(
+                "Error reading values file {}: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })
+    }
+
+    /// Parses a `NAME=value` setting.
+    pub fn parse_setting(text: &str) -> anyhow::Result<(String, String)> {
+        match text.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                Ok((name.trim().to_owned(), value.to_owned()))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Invalid setting '{}': use the form NAME=value",
+                text
+            )),
+        }
+    }
+
+    fn lookup(&self, reference: &str) -> anyhow::Result<Option<&String>> {
+        match reference.split_once('.') {
+            Some(("var", name)) => Ok(self.vars.get(name)),
+            Some(("env", name)) => Ok(self.env.get(name)),
+            _ => Err(anyhow::anyhow!(
+                "Unknown template reference '{}': use var.NAME or env.NAME",
+                reference
+            )),
+        }
+    }
+}
+
+/// Substitutes template references in every string value of a parsed
+/// HIPPOFACTS document. If any variables are undefined, the error lists them
+/// all.
+pub fn substitute(
+    document: toml::Value,
+    variables: &TemplateVariables,
+) -> anyhow::Result<toml::Value> {
+    let mut missing = vec![];
+    let document = substitute_value(document, &[], variables, &mut missing)?;
+    if missing.is_empty() {
+        Ok(document)
+    } else {
+        Err(anyhow::anyhow!(
+            "Undefined template variables: {}",
+            missing.iter().unique().join(", ")
+        ))
+    }
+}
+
+fn substitute_value(
+    value: toml::Value,
+    path: &[&str],
+    variables: &TemplateVariables,
+    missing: &mut Vec<String>,
+) -> anyhow::Result<toml::Value> {
+    match value {
+        toml::Value::String(text) => {
+            if path == ["bindle", "name"] && !variables.substitute_bindle_name {
+                Ok(toml::Value::String(text))
+            } else {
+                Ok(toml::Value::String(substitute_str(
+                    &text, variables, missing,
+                )?))
+            }
+        }
+        toml::Value::Array(values) => {
+            let values = values
+                .into_iter()
+                .map(|v| substitute_value(v, path, variables, missing))
+                .collect::<anyhow::Result<_>>()?;
+            Ok(toml::Value::Array(values))
+        }
+        toml::Value::Table(table) => {
+            let mut substituted = toml::value::Table::new();
+            for (key, v) in table {
+                let child_path = path.iter().copied().chain(vec![key.as_str()]).collect_vec();
+                let v = substitute_value(v, &child_path, variables, missing)?;
+                substituted.insert(key, v);
+            }
+            Ok(toml::Value::Table(substituted))
+        }
+        other => Ok(other),
+    }
+}
+
+fn substitute_str(
+    text: &str,
+    variables: &TemplateVariables,
+    missing: &mut Vec<String>,
+) -> anyhow::Result<String> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let template = &rest[start..];
+        if let Some(escaped) = template.strip_prefix("{{{{") {
+            result.push_str("{{");
+            rest = escaped;
+            continue;
+        }
+        let end = template
+            .find("}}")
+            .ok_or_else(|| anyhow::anyhow!("Unterminated template reference in '{}'", text))?;
+        let reference = template[2..end].trim();
+        match variables.lookup(reference)? {
+            Some(value) => result.push_str(value),
+            None => missing.push(reference.to_owned()),
+        }
+        rest = &template[end + 2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pairs(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn variables(
+        values_file: &[(&str, &str)],
+        set: &[(&str, &str)],
+        env: &[(&str, &str)],
+    ) -> TemplateVariables {
+        TemplateVariables::new(
+            pairs(values_file).into_iter().collect(),
+            pairs(set),
+            pairs(env).into_iter().collect(),
+        )
+    }
+
+    fn substitute_text(text: &str, variables: &TemplateVariables) -> anyhow::Result<String> {
+        let document: toml::Value = toml::from_str(text).unwrap();
+        Ok(toml::to_string(&substitute(document, variables)?).unwrap())
+    }
+
+    const HIPPOFACTS: &str = r#"
+        [bindle]
+        name = "app-{{var.ENV}}"
+        version = "1.0.0"
+
+        [[handler]]
+        name = "{{var.MODULE}}"
+        route = "{{var.PREFIX}}/{{env.ROUTE}}"
+        files = ["{{{{literal}}"]
+    "#;
+
+    fn substituted_handler(variables: &TemplateVariables) -> toml::Value {
+        let document: toml::Value = toml::from_str(HIPPOFACTS).unwrap();
+        let document = substitute(document, variables).unwrap();
+        document["handler"][0].clone()
+    }
+
+    #[test]
+    fn test_set_values_take_precedence_over_values_file() {
+        let vars = variables(
+            &[("MODULE", "file.wasm"), ("PREFIX", "/file")],
+            &[("PREFIX", "/set")],
+            &[("ROUTE", "env")],
+        );
+        let handler = substituted_handler(&vars);
+        assert_eq!("file.wasm", handler["name"].as_str().unwrap());
+        assert_eq!("/set/env", handler["route"].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_var_and_env_references_are_separate() {
+        // A variable does not satisfy an env reference, nor the other way round
+        let vars = variables(
+            &[("MODULE", "app.wasm"), ("ROUTE", "var")],
+            &[("PREFIX", "")],
+            &[("MODULE", "env.wasm"), ("ROUTE", "env")],
+        );
+        let handler = substituted_handler(&vars);
+        assert_eq!("app.wasm", handler["name"].as_str().unwrap());
+        assert_eq!("/env", handler["route"].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_doubled_braces_are_literal() {
+        let vars = variables(&[("MODULE", "a"), ("PREFIX", "")], &[], &[("ROUTE", "r")]);
+        let handler = substituted_handler(&vars);
+        assert_eq!("{{literal}}", handler["files"][0].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_every_undefined_variable_is_reported() {
+        let err = substitute_text(HIPPOFACTS, &variables(&[], &[], &[]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("var.MODULE"), "lists MODULE: '{}'", err);
+        assert!(err.contains("var.PREFIX"), "lists PREFIX: '{}'", err);
+        assert!(err.contains("env.ROUTE"), "lists ROUTE: '{}'", err);
+        assert!(
+            !err.contains("var.ENV"),
+            "bindle name is left alone: '{}'",
+            err
+        );
+    }
+
+    #[test]
+    fn test_bindle_name_is_substituted_only_when_enabled() {
+        let mut vars = variables(
+            &[("MODULE", "a"), ("PREFIX", ""), ("ENV", "staging")],
+            &[],
+            &[("ROUTE", "r")],
+        );
+        let document: toml::Value = toml::from_str(HIPPOFACTS).unwrap();
+        let unsubstituted = substitute(document.clone(), &vars).unwrap();
+        assert_eq!(
+            "app-{{var.ENV}}",
+            unsubstituted["bindle"]["name"].as_str().unwrap()
+        );
+
+        vars.substitute_bindle_name = true;
+        let substituted = substitute(document, &vars).unwrap();
+        assert_eq!(
+            "app-staging",
+            substituted["bindle"]["name"].as_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unknown_references_are_errors() {
+        let vars = variables(&[], &[], &[]);
+        let err = substitute_text("x = \"{{ secret.KEY }}\"", &vars).unwrap_err();
+        assert!(err.to_string().contains("secret.KEY"));
+        assert!(substitute_text("x = \"{{var.OPEN\"", &vars).is_err());
+    }
+
+    #[test]
+    fn test_settings_are_parsed() {
+        assert_eq!(
+            ("PREFIX".to_owned(), "/a=b".to_owned()),
+            TemplateVariables::parse_setting("PREFIX=/a=b").unwrap()
+        );
+        assert!(TemplateVariables::parse_setting("PREFIX").is_err());
+        assert!(TemplateVariables::parse_setting("=value").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Test doubles shared between the unit tests of several modules.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bindle::{Invoice, Parcel};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::bindle_utils::{BindleSource, InvoicePage, ParcelStream};
+
+/// An in-memory stand-in for a Bindle server.
+#[derive(Default)]
+pub struct FakeBindleServer {
+    pub invoices: HashMap<bindle::Id, Invoice>,
+    pub parcels: HashMap<String, Vec<u8>>,
+    /// The size of the chunks in which parcel streams are delivered.
+    pub chunk_size: usize,
+    /// Behave like a server without the query API.
+    pub query_unsupported: bool,
+    /// The storage quota the server reports as remaining, if any.
+    pub remaining_quota: Option<u64>,
+    chunks_served: Arc<AtomicUsize>,
+    parcel_requests: Arc<AtomicUsize>,
+    pages_served: Arc<AtomicUsize>,
+}
+
+impl FakeBindleServer {
+    pub fn new() -> Self {
+        Self {
+            chunk_size: 4,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_invoice(mut self, invoice: Invoice) -> Self {
+        self.invoices.insert(invoice.bindle.id.clone(), invoice);
+        self
+    }
+
+    pub fn with_parcel(mut self, content: &[u8]) -> Self {
+        self.parcels.insert(sha256_of(content), content.to_vec());
+        self
+    }
+
+    /// Behave like a server without the query API.
+    pub fn without_query_api(self) -> Self {
+        Self {
+            query_unsupported: true,
+            ..self
+        }
+    }
+
+    /// The number of chunks of parcel content that have been streamed to callers.
+    pub fn chunks_served(&self) -> usize {
+        self.chunks_served.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests for parcel content, whether or not the content
+    /// was then read.
+    pub fn parcel_requests(&self) -> usize {
+        self.parcel_requests.load(Ordering::SeqCst)
+    }
+
+    /// The number of pages of search results that have been returned to callers.
+    pub fn pages_served(&self) -> usize {
+        self.pages_served.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait::async_trait]
+impl BindleSource for FakeBindleServer {
+    async fn fetch_invoice(&self, id: &bindle::Id) -> anyhow::Result<Invoice> {
+        // Like a real server, refuse yanked invoices unless asked for them.
+        self.invoices
+            .get(id)
+            .filter(|inv| !inv.yanked.unwrap_or_default())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("invoice {} not found", id))
+    }
+
+    async fn fetch_yanked_invoice(&self, id: &bindle::Id) -> anyhow::Result<Invoice> {
+        self.invoices
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("invoice {} not found", id))
+    }
+
+    async fn fetch_parcel_stream(
+        &self,
+        _id: &bindle::Id,
+        sha256: &str,
+    ) -> anyhow::Result<ParcelStream> {
+        self.parcel_requests.fetch_add(1, Ordering::SeqCst);
+        let content = self
+            .parcels
+            .get(sha256)
+            .ok_or_else(|| anyhow::anyhow!("parcel {} not found", sha256))?;
+        let chunks: Vec<_> = content
+            .chunks(self.chunk_size)
+            .map(bytes::Bytes::copy_from_slice)
+            .collect();
+        let chunks_served = self.chunks_served.clone();
+        Ok(futures::stream::iter(chunks)
+            .map(move |chunk| {
+                chunks_served.fetch_add(1, Ordering::SeqCst);
+                Ok(chunk)
+            })
+            .boxed())
+    }
+
+    async fn parcel_exists(&self, _id: &bindle::Id, sha256: &str) -> anyhow::Result<bool> {
+        Ok(self.parcels.contains_key(sha256))
+    }
+
+    async fn remaining_quota(&self, _id: &bindle::Id) -> anyhow::Result<Option<u64>> {
+        Ok(self.remaining_quota)
+    }
+
+    async fn query_invoice_page(
+        &self,
+        query: &str,
+        offset: u64,
+        limit: u8,
+    ) -> anyhow::Result<InvoicePage> {
+        if self.query_unsupported {
+            return Err(crate::bindle_search::QueryUnsupported.into());
+        }
+        self.pages_served.fetch_add(1, Ordering::SeqCst);
+        let mut matches: Vec<_> = self
+            .invoices
+            .values()
+            .filter(|inv| inv.bindle.id.name().contains(query))
+            .collect();
+        matches.sort_by_key(|inv| inv.bindle.id.to_string());
+        let start = (offset as usize).min(matches.len());
+        let end = (start + limit as usize).min(matches.len());
+        Ok(InvoicePage {
+            invoices: matches[start..end].iter().map(|inv| (*inv).clone()).collect(),
+            more: end < matches.len(),
+        })
+    }
+}
+
+pub fn sha256_of(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+pub fn parcel_for(name: &str, content: &[u8]) -> Parcel {
+    Parcel {
+        label: bindle::Label {
+            name: name.to_owned(),
+            sha256: sha256_of(content),
+            media_type: "text/plain".to_owned(),
+            size: content.len() as u64,
+            origin: None,
+            annotations: None,
+            feature: None,
+        },
+        conditions: None,
+    }
+}
+
+pub fn test_invoice(id: &bindle::Id, parcels: Vec<Parcel>) -> Invoice {
+    Invoice {
+        bindle_version: "1.0.0".to_owned(),
+        yanked: None,
+        yanked_signature: None,
+        bindle: bindle::BindleSpec {
+            id: id.clone(),
+            description: None,
+            authors: None,
+        },
+        annotations: None,
+        parcel: Some(parcels),
+        group: None,
+        signature: None,
+    }
+}
+
+/// An HTTP/1.1 response with the given status line and body, after which the
+/// connection closes.
+pub fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Answers one request with `response`, returning the server's URL and a
+/// handle which gives the request it received.
+pub fn serve_once(response: impl Into<String>) -> (String, std::thread::JoinHandle<String>) {
+    let (url, handle) = serve_in_turn(vec![response.into()]);
+    let handle = std::thread::spawn(move || handle.join().unwrap().remove(0));
+    (url, handle)
+}
+
+/// Answers a request on each of a series of connections with the next of
+/// `responses`, returning the server's URL and a handle which gives the
+/// requests it received.
+pub fn serve_in_turn(responses: Vec<String>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/v1", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut requests = vec![];
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            // A body with a Content-Length is read; a streamed one is left
+            // unread, as the responses don't depend on it.
+            while !is_complete_request(&request) {
+                let count = stream.read(&mut buf).unwrap();
+                if count == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..count]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            requests.push(String::from_utf8_lossy(&request).to_string());
+        }
+        requests
+    });
+    (url, handle)
+}
+
+fn is_complete_request(request: &[u8]) -> bool {
+    let header_end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+        None => return false,
+        Some(position) => position + 4,
+    };
+    let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+    let content_length = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|length| length.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    request.len() >= header_end + content_length
+}
+
+/// The self-signed certificate for `localhost` which `serve_tls` presents.
+pub const TLS_CERTIFICATE: &[u8] = include_bytes!("../testdata/tls/cert.der");
+/// The SPKI SHA-256 digest of `TLS_CERTIFICATE`'s key, as computed by openssl.
+pub const TLS_CERTIFICATE_PIN: &str =
+    "269d1cae48d81f9d3efdf90a1c5429b768eb798395800d414f7f04e79651e447";
+
+/// Serves `response` over TLS, presenting `TLS_CERTIFICATE`, to each connection
+/// until the test ends. This returns the server's `https://localhost` URL.
+pub async fn serve_tls(response: impl Into<String>) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let response = Arc::new(response.into());
+    let key = rustls::PrivateKey(include_bytes!("../testdata/tls/key.der").to_vec());
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![rustls::Certificate(TLS_CERTIFICATE.to_vec())], key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!(
+        "https://localhost:{}/v1",
+        listener.local_addr().unwrap().port()
+    );
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let response = response.clone();
+            tokio::spawn(async move {
+                // A client which refuses the certificate ends the handshake.
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let mut buf = [0; 4096];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    });
+    url
+}
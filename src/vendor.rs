@@ -0,0 +1,264 @@
+//! Vendoring a bindle: downloading it and all its parcels into a local
+//! directory, with a lockfile recording exactly what was downloaded, so that
+//! it can later be restored and used without a Bindle server.
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bindle_fetcher::{fetch_parcel_to_file, FetchOptions};
+use crate::bindle_utils::{BindleSource, InvoiceHelpers};
+use crate::digest_backend::Sha2Backend;
+use crate::file_hash::file_sha256;
+use crate::standalone::StandaloneBindle;
+
+/// The name of the lockfile which `vendor` writes into the vendor directory.
+pub const LOCKFILE_NAME: &str = "hippo.lock";
+
+/// What a vendor directory holds: a bindle's invoice, by its digest, and each
+/// of its parcels.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockfile {
+    pub bindle_id: String,
+    /// The sha256 of the vendored `invoice.toml`.
+    pub invoice_sha256: String,
+    /// Each parcel once, sorted by sha256.
+    pub parcels: Vec<LockedParcel>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedParcel {
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl Lockfile {
+    pub fn read_from(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        (|| {
+            let text = std::fs::read_to_string(&path)?;
+            Ok(toml::from_str(&text)?)
+        })()
+        .map_err(|e: anyhow::Error| {
+            anyhow::anyhow!(
+                "Error reading lockfile {}: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })
+    }
+
+    pub fn write_to(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let text = toml::to_string_pretty(self)?;
+        std::fs::write(&path, text).map_err(|e| {
+            anyhow::anyhow!(
+                "Error writing lockfile {}: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })
+    }
+}
+
+/// Downloads a bindle and the parcels of its required closure into `dir`, in
+/// the standalone layout, and writes a lockfile for them as `dir/hippo.lock`.
+/// Parcels are downloaded and checked as `options` says, so by default each
+/// is checked against its digest, and parcels already in `dir` are re-hashed
+/// and downloaded again only if they are not right.
+pub async fn vendor(
+    client: &impl BindleSource,
+    id: &bindle::Id,
+    dir: impl AsRef<Path>,
+    options: &FetchOptions,
+) -> anyhow::Result<Lockfile> {
+    let invoice = client.fetch_invoice(id).await?;
+    let bindle_dir = dir.as_ref().join(id.sha());
+    let parcels_dir = bindle_dir.join("parcels");
+    tokio::fs::create_dir_all(&parcels_dir).await?;
+
+    let parcels = invoice
+        .required_closure()
+        .into_iter()
+        .sorted_by(|a, b| a.label.sha256.cmp(&b.label.sha256));
+    let mut locked = vec![];
+    for parcel in parcels {
+        let sha256 = &parcel.label.sha256;
+        let path = parcels_dir.join(format!("{}.dat", sha256));
+        fetch_parcel_to_file(client, id, &parcel, &path, options).await?;
+        locked.push(LockedParcel {
+            sha256: sha256.clone(),
+            size: tokio::fs::metadata(&path).await?.len(),
+        });
+    }
+
+    let invoice_text = toml::to_string_pretty(&invoice)?;
+    tokio::fs::write(bindle_dir.join("invoice.toml"), &invoice_text).await?;
+
+    let lockfile = Lockfile {
+        bindle_id: id.to_string(),
+        invoice_sha256: format!("{:x}", Sha256::digest(invoice_text.as_bytes())),
+        parcels: locked,
+    };
+    lockfile.write_to(dir.as_ref().join(LOCKFILE_NAME))?;
+    Ok(lockfile)
+}
+
+/// Reads a vendored bindle back from `dir`, without contacting any server. The
+/// invoice and every parcel must match the lockfile, and the invoice's required
+/// closure must not include any parcel which the lockfile lacks.
+pub fn restore(lockfile: &Lockfile, dir: impl AsRef<Path>) -> anyhow::Result<StandaloneBindle> {
+    let id = bindle::Id::try_from(lockfile.bindle_id.clone())?;
+    let bindle_dir = dir.as_ref().join(id.sha());
+    let invoice_file = bindle_dir.join("invoice.toml");
+    let invoice_text = std::fs::read_to_string(&invoice_file).map_err(|e| {
+        anyhow::anyhow!(
+            "Error reading vendored invoice {}: {}",
+            invoice_file.to_string_lossy(),
+            e
+        )
+    })?;
+    let invoice_sha256 = format!("{:x}", Sha256::digest(invoice_text.as_bytes()));
+    if invoice_sha256 != lockfile.invoice_sha256 {
+        return Err(anyhow::anyhow!(
+            "The vendored invoice for {} does not match the lockfile",
+            id
+        ));
+    }
+
+    let standalone = StandaloneBindle {
+        invoice: toml::from_str(&invoice_text)?,
+        parcels_dir: bindle_dir.join("parcels"),
+    };
+    for parcel in &lockfile.parcels {
+        let path = standalone.parcel_path(&parcel.sha256);
+        let actual = file_sha256(&path, &Sha2Backend).map_err(|e| {
+            anyhow::anyhow!("Vendored parcel {} can't be read: {}", parcel.sha256, e)
+        })?;
+        if actual != parcel.sha256 {
+            return Err(anyhow::anyhow!(
+                "Vendored parcel {} is corrupt: its content has sha256 {}",
+                parcel.sha256,
+                actual
+            ));
+        }
+    }
+    let unlocked = standalone
+        .invoice
+        .required_closure()
+        .into_iter()
+        .find(|p| !lockfile.parcels.iter().any(|l| l.sha256 == p.label.sha256));
+    if let Some(parcel) = unlocked {
+        return Err(anyhow::anyhow!(
+            "Parcel {} of {} is not in the lockfile",
+            parcel.label.name,
+            id
+        ));
+    }
+    Ok(standalone)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{parcel_for, test_invoice, FakeBindleServer};
+    use std::str::FromStr;
+
+    fn test_server() -> (bindle::Id, FakeBindleServer) {
+        let id = bindle::Id::from_str("vendored/app/1.0.0").unwrap();
+        let invoice = test_invoice(
+            &id,
+            vec![
+                parcel_for("app.wasm", b"wasm module"),
+                parcel_for("index.html", b"<html />"),
+                parcel_for("copy.html", b"<html />"),
+            ],
+        );
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(b"wasm module")
+            .with_parcel(b"<html />");
+        (id, server)
+    }
+
+    #[tokio::test]
+    async fn test_vendored_bindle_restores_offline() {
+        let (id, server) = test_server();
+        let dir = tempfile::tempdir().unwrap();
+
+        let lockfile = vendor(&server, &id, dir.path(), &FetchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(2, lockfile.parcels.len());
+        drop(server);
+
+        let lockfile_on_disk = Lockfile::read_from(dir.path().join(LOCKFILE_NAME)).unwrap();
+        assert_eq!(lockfile, lockfile_on_disk);
+        let restored = restore(&lockfile_on_disk, dir.path()).unwrap();
+        assert_eq!(id, restored.invoice.bindle.id);
+        assert_eq!(3, restored.invoice.parcel.as_ref().unwrap().len());
+        for parcel in restored.invoice.parcel.iter().flatten() {
+            let content = std::fs::read(restored.parcel_path(&parcel.label.sha256)).unwrap();
+            assert_eq!(parcel.label.size, content.len() as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parcels_in_optional_groups_are_not_vendored() {
+        let id = bindle::Id::from_str("vendored/optional/1.0.0").unwrap();
+        let mut optional = parcel_for("extra.dat", b"extra");
+        optional.conditions = Some(bindle::Condition {
+            member_of: Some(vec!["extras".to_owned()]),
+            requires: None,
+        });
+        let invoice = test_invoice(&id, vec![parcel_for("app.wasm", b"wasm module"), optional]);
+        let server = FakeBindleServer::new()
+            .with_invoice(invoice)
+            .with_parcel(b"wasm module")
+            .with_parcel(b"extra");
+        let dir = tempfile::tempdir().unwrap();
+
+        let lockfile = vendor(&server, &id, dir.path(), &FetchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, lockfile.parcels.len());
+        assert_eq!(1, server.parcel_requests());
+        assert!(restore(&lockfile, dir.path()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_parcel_is_refused_on_restore() {
+        let (id, server) = test_server();
+        let dir = tempfile::tempdir().unwrap();
+        let lockfile = vendor(&server, &id, dir.path(), &FetchOptions::default())
+            .await
+            .unwrap();
+
+        let victim = &lockfile.parcels[0].sha256;
+        let path = dir
+            .path()
+            .join(id.sha())
+            .join("parcels")
+            .join(format!("{}.dat", victim));
+        std::fs::write(&path, b"something else").unwrap();
+
+        let err = restore(&lockfile, dir.path()).err().unwrap().to_string();
+        assert!(err.contains(victim.as_str()), "names the parcel: '{}'", err);
+    }
+
+    #[tokio::test]
+    async fn test_vendor_refuses_corrupt_download() {
+        let (id, mut server) = test_server();
+        let sha = crate::testing::sha256_of(b"wasm module");
+        server.parcels.insert(sha, b"not the module".to_vec());
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(vendor(&server, &id, dir.path(), &FetchOptions::default())
+            .await
+            .is_err());
+        assert!(!dir.path().join(LOCKFILE_NAME).exists());
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Debug)]
 pub struct Warned<T> {
     pub value: T,
     pub warnings: Vec<String>,
@@ -0,0 +1,48 @@
+#!/bin/sh
+# A stub credential helper for the tests. The first argument chooses how it
+# behaves; hippo then passes the action ("get") and the server URL.
+mode="$1"
+url="$3"
+
+case "$mode" in
+  login)
+    echo "{\"username\": \"helper-user\", \"password\": \"secret for $url\"}"
+    ;;
+  token)
+    echo '{"token": "helper-token"}'
+    ;;
+  git)
+    while read -r line && [ -n "$line" ]; do
+      case "$line" in
+        host=*) host="${line#host=}" ;;
+        path=*) path="${line#path=}" ;;
+      esac
+    done
+    echo "username=user@$host"
+    echo "password=path $path"
+    ;;
+  fail)
+    echo "the vault is sealed" >&2
+    exit 3
+    ;;
+  malformed)
+    echo '{"username": '
+    ;;
+  nothing)
+    ;;
+  slow)
+    sleep 5
+    echo '{"token": "too-late"}'
+    ;;
+  verbose)
+    printf '{"token": "'
+    head -c 200000 /dev/zero | tr '\0' x
+    printf '"}\n'
+    ;;
+  orphan)
+    # The second argument is a file which the background command creates if
+    # it lives long enough. It keeps the output open meanwhile.
+    (sleep 1; touch "$2") &
+    sleep 5
+    ;;
+esac